@@ -0,0 +1,183 @@
+use nostr::{Event, EventBuilder, EventId, Keys, PublicKey};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::fmt::Debug;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// Returns the keys of an event author, if they are known.
+pub type GetKeys = Arc<dyn Fn(&PublicKey) -> Option<Keys> + Send + Sync>;
+
+/// Identity to authenticate as (NIP-42) when a relay sends an `AUTH` challenge.
+#[derive(Clone)]
+pub enum Auth {
+    /// Authenticate with these keys as soon as the challenge arrives.
+    Keys(Keys),
+    /// Authenticate as the author of each event before sending it.
+    EventAuthor(GetKeys),
+}
+
+impl Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Auth::Keys(k) => f.debug_tuple("Keys").field(&k.public_key()).finish(),
+            Auth::EventAuthor(_) => f.write_str("EventAuthor"),
+        }
+    }
+}
+
+/// Authentication state of a single connection.
+pub(crate) struct AuthState {
+    auth: Option<Auth>,
+    challenge: Option<String>,
+    authed: FxHashSet<PublicKey>,
+    /// `AUTH` events not yet answered with `OK`, with the event held back
+    /// until then when authenticating as its author.
+    pending: FxHashMap<EventId, Option<Arc<Event>>>,
+}
+
+impl AuthState {
+    pub(crate) fn new(auth: Option<Auth>) -> Self {
+        Self {
+            auth,
+            challenge: None,
+            authed: FxHashSet::default(),
+            pending: FxHashMap::default(),
+        }
+    }
+
+    /// Forgets the challenge of the previous connection. Returns the events
+    /// held back for an `AUTH` it didn't answer, to send on the next one.
+    pub(crate) fn reset(&mut self) -> Vec<Arc<Event>> {
+        self.challenge = None;
+        self.authed.clear();
+        self.pending.drain().filter_map(|(_, e)| e).collect()
+    }
+
+    /// `AUTH` event to send in response to `challenge`, if authenticating as a
+    /// fixed identity.
+    pub(crate) fn on_challenge(&mut self, challenge: String, url: &url::Url) -> Option<Event> {
+        self.challenge = Some(challenge);
+        self.authed.clear();
+        match &self.auth {
+            Some(Auth::Keys(keys)) => self.sign(keys.clone(), url, None),
+            Some(Auth::EventAuthor(_)) => None,
+            None => {
+                debug!("{url} requested authentication but no identity is configured");
+                None
+            }
+        }
+    }
+
+    /// `AUTH` event to send instead of `event`, if authenticating as event
+    /// authors and not yet authenticated as its author on this connection.
+    /// `event` is then returned by `on_ok` once the relay accepts it.
+    pub(crate) fn before_event(&mut self, event: &Arc<Event>, url: &url::Url) -> Option<Event> {
+        let Some(Auth::EventAuthor(get_keys)) = &self.auth else {
+            return None;
+        };
+        if self.challenge.is_none() || self.authed.contains(&event.pubkey) {
+            return None;
+        }
+        match get_keys(&event.pubkey) {
+            Some(keys) => self.sign(keys, url, Some(event.clone())),
+            None => {
+                warn!("no keys to authenticate as {} to {url}", event.pubkey);
+                None
+            }
+        }
+    }
+
+    /// Handles the `OK` of `event_id`. `None` if it isn't one of our `AUTH`
+    /// events, else the event held back for it, if any.
+    pub(crate) fn on_ok(
+        &mut self,
+        event_id: &EventId,
+        accepted: bool,
+        message: &str,
+        url: &url::Url,
+    ) -> Option<Option<Arc<Event>>> {
+        let held = self.pending.remove(event_id)?;
+        if !accepted {
+            warn!("{url} refused authentication: {message}");
+        }
+        Some(held)
+    }
+
+    fn sign(&mut self, keys: Keys, url: &url::Url, held: Option<Arc<Event>>) -> Option<Event> {
+        let challenge = self.challenge.clone()?;
+        match EventBuilder::auth(challenge, url.clone()).to_event(&keys) {
+            Ok(e) => {
+                self.authed.insert(keys.public_key());
+                self.pending.insert(e.id, held);
+                Some(e)
+            }
+            Err(e) => {
+                warn!("could not sign AUTH event for {url}: {e}");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Auth, AuthState};
+    use nostr::{EventBuilder, Keys, Kind, Tag};
+    use std::sync::Arc;
+
+    fn challenge_of(e: &nostr::Event) -> Option<&str> {
+        e.tags.iter().find_map(|t| match t {
+            Tag::Challenge(c) => Some(c.as_str()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn auth_as_bot() {
+        let bot = Keys::generate();
+        let url = url::Url::parse("wss://relay.example.com").unwrap();
+        let mut s = AuthState::new(Some(Auth::Keys(bot.clone())));
+        let e = s.on_challenge("abc".to_string(), &url).unwrap();
+        assert_eq!(e.kind, Kind::Authentication);
+        assert_eq!(e.pubkey, bot.public_key());
+        assert_eq!(challenge_of(&e), Some("abc"));
+        assert!(e.verify().is_ok());
+        let note = Arc::new(
+            EventBuilder::text_note("hi", [])
+                .to_event(&Keys::generate())
+                .unwrap(),
+        );
+        assert!(s.before_event(&note, &url).is_none());
+        assert_eq!(s.on_ok(&note.id, true, "", &url), None);
+        assert_eq!(s.on_ok(&e.id, true, "", &url), Some(None));
+        assert_eq!(s.on_ok(&e.id, true, "", &url), None);
+    }
+
+    #[test]
+    fn auth_as_event_author() {
+        let author = Keys::generate();
+        let url = url::Url::parse("wss://relay.example.com").unwrap();
+        let a = author.clone();
+        let mut s = AuthState::new(Some(Auth::EventAuthor(Arc::new(move |p| {
+            (p == &a.public_key()).then(|| a.clone())
+        }))));
+        let note = Arc::new(EventBuilder::text_note("hi", []).to_event(&author).unwrap());
+        assert!(s.before_event(&note, &url).is_none());
+        assert!(s.on_challenge("abc".to_string(), &url).is_none());
+        let e = s.before_event(&note, &url).unwrap();
+        assert_eq!(e.pubkey, author.public_key());
+        assert_eq!(challenge_of(&e), Some("abc"));
+        assert!(s.before_event(&note, &url).is_none());
+        let other = Arc::new(
+            EventBuilder::text_note("hi", [])
+                .to_event(&Keys::generate())
+                .unwrap(),
+        );
+        assert!(s.before_event(&other, &url).is_none());
+        // the note is held back until the relay accepts the AUTH
+        assert_eq!(s.on_ok(&e.id, true, "", &url), Some(Some(note.clone())));
+        s.on_challenge("def".to_string(), &url);
+        s.before_event(&note, &url).unwrap();
+        assert_eq!(s.reset(), vec![note]);
+    }
+}
@@ -59,13 +59,13 @@ impl Filter {
     fn ids_match(&self, event: &Event) -> bool {
         self.ids
             .as_ref()
-            .map_or(true, |ids| ids.is_empty() || ids.contains(&event.id))
+            .is_none_or(|ids| ids.is_empty() || ids.contains(&event.id))
     }
 
     fn authors_match(&self, event: &Event) -> bool {
-        self.authors.as_ref().map_or(true, |authors| {
-            authors.is_empty() || authors.contains(&event.pubkey)
-        })
+        self.authors
+            .as_ref()
+            .is_none_or(|authors| authors.is_empty() || authors.contains(&event.pubkey))
     }
 
     fn tag_match(&self, event: &Event) -> bool {
@@ -97,9 +97,9 @@ impl Filter {
     }
 
     fn kind_match(&self, event: &Event) -> bool {
-        self.kinds.as_ref().map_or(true, |kinds| {
-            kinds.is_empty() || kinds.contains(&event.kind)
-        })
+        self.kinds
+            .as_ref()
+            .is_none_or(|kinds| kinds.is_empty() || kinds.contains(&event.kind))
     }
 
     /// Determine if [Filter] match given [Event].
@@ -110,8 +110,8 @@ impl Filter {
         self.ids_match(event)
             && self.authors_match(event)
             && self.kind_match(event)
-            && self.since.map_or(true, |t| event.created_at >= t)
-            && self.until.map_or(true, |t| event.created_at <= t)
+            && self.since.is_none_or(|t| event.created_at >= t)
+            && self.until.is_none_or(|t| event.created_at <= t)
             && self.tag_match(event)
     }
 }
@@ -1,6 +1,11 @@
+mod auth;
 mod filter;
+mod status;
 
+use auth::AuthState;
+pub use auth::{Auth, GetKeys};
 pub use filter::Filter;
+use futures_util::future::{AbortHandle, Abortable};
 use futures_util::stream::FuturesUnordered;
 use futures_util::{SinkExt, Stream};
 use id_pool::IdPool;
@@ -9,17 +14,19 @@ use lru::LruCache;
 use nostr::{Event, JsonUtil, RelayMessage};
 use rustc_hash::{FxHashMap, FxHashSet};
 use serde::{Serialize, Serializer};
+pub use status::{ConnectionState, RelayStatus};
+use status::{StatusHandle, Statuses};
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::hash::Hash;
 use std::num::NonZeroUsize;
 use std::str::FromStr;
-use std::sync::atomic::{self, AtomicU32};
+use std::sync::atomic::{self, AtomicU32, AtomicU64, AtomicUsize};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tokio::net::TcpStream;
-use tokio::sync::broadcast;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::{broadcast, watch};
 use tokio::time::error::Elapsed;
 use tokio_stream::StreamExt;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
@@ -50,8 +57,14 @@ struct SendEvent<RelayId> {
 pub struct RelayPool<RelayId> {
     tx_for_filter_ops: Sender<FilterOp<RelayId>>,
     tx_for_send_event: Sender<SendEvent<RelayId>>,
-    tx_for_add_relay: Sender<(RelayId, url::Url)>,
+    tx_for_add_relay: Sender<(RelayId, url::Url, Option<Auth>)>,
+    tx_for_remove_relay: Sender<RelayId>,
     counter: AtomicU32,
+    statuses: Statuses<RelayId>,
+    subscriptions: Arc<AtomicUsize>,
+    sent_events: AtomicU64,
+    /// Set by `close`.
+    closing: watch::Sender<bool>,
 }
 
 struct SenderWithId<RelayId> {
@@ -109,53 +122,73 @@ impl<RelayId: Copy + Send + Sync + Eq + Hash + 'static> RelayPool<RelayId> {
         let (tx_for_events, mut rx_for_events) = tokio::sync::mpsc::channel(10);
         let (tx_for_filter_ops, mut rx_for_filter_ops) = tokio::sync::mpsc::channel(10);
         let (tx_for_send_event, mut rx_for_send_event) = tokio::sync::mpsc::channel(10);
-        let (tx_for_add_relay, mut rx_for_add_relay) = tokio::sync::mpsc::channel(10);
+        let (tx_for_add_relay, mut rx_for_add_relay) =
+            tokio::sync::mpsc::channel::<(RelayId, url::Url, Option<Auth>)>(10);
+        let (tx_for_remove_relay, mut rx_for_remove_relay) = tokio::sync::mpsc::channel(10);
         let mut relay_pool = FuturesUnordered::new();
         let broadcast_sender_cloned = broadcast_sender.clone();
+        let statuses = Statuses::default();
+        let statuses_cloned = statuses.clone();
+        let subscriptions = Arc::new(AtomicUsize::new(0));
+        let (closing, closing_receiver) = watch::channel(false);
         let subscription_loop = async move {
+            let mut handles = FxHashMap::default();
+            let new_relay = |id, url: url::Url, auth| {
+                let (handle, registration) = AbortHandle::new_pair();
+                let relay = subscribe_relay(
+                    url.clone(),
+                    ReceiverWithId {
+                        receiver: broadcast_sender_cloned.subscribe(),
+                        id,
+                    },
+                    SenderWithId {
+                        sender: tx_for_events.clone(),
+                        id,
+                    },
+                    user_agent.clone(),
+                    auth,
+                    StatusHandle::new(statuses_cloned.clone(), id, url),
+                    closing_receiver.clone(),
+                );
+                let relay = async move { (id, relay.await) };
+                (Abortable::new(relay, registration), handle)
+            };
             loop {
                 if relay_pool.is_empty() {
-                    if let Some((id, url)) = rx_for_add_relay.recv().await {
-                        relay_pool.push(subscribe_relay(
-                            url,
-                            ReceiverWithId {
-                                receiver: broadcast_sender_cloned.subscribe(),
-                                id,
-                            },
-                            SenderWithId {
-                                sender: tx_for_events.clone(),
-                                id,
-                            },
-                            user_agent.clone(),
-                        ));
+                    if let Some((id, url, auth)) = rx_for_add_relay.recv().await {
+                        let (relay, handle) = new_relay(id, url, auth);
+                        handles.insert(id, handle);
+                        relay_pool.push(relay);
                     } else {
                         break;
                     }
                 }
                 tokio::select! {
                     e = relay_pool.next() => {
-                        tracing::error!("{:?}", e.unwrap());
+                        // aborted relays were removed by `remove_relay`
+                        if let Some(Ok((id, e))) = e {
+                            handles.remove(&id);
+                            tracing::error!("{e:?}");
+                        }
                     }
-                    Some((id, url)) = rx_for_add_relay.recv() => {
-                        relay_pool.push(subscribe_relay(
-                            url,
-                            ReceiverWithId {
-                                receiver: broadcast_sender_cloned.subscribe(),
-                                id,
-                            },
-                            SenderWithId {
-                                sender: tx_for_events.clone(),
-                                id,
-                            },
-                            user_agent.clone(),
-                        ));
+                    Some((id, url, auth)) = rx_for_add_relay.recv() => {
+                        let (relay, handle) = new_relay(id, url, auth);
+                        handles.insert(id, handle);
+                        relay_pool.push(relay);
+                    }
+                    Some(id) = rx_for_remove_relay.recv() => {
+                        if let Some(handle) = handles.remove(&id) {
+                            handle.abort();
+                        }
+                        statuses_cloned.lock().unwrap().remove(&id);
                     }
                     else => break,
                 }
             }
         };
+        let subscriptions_cloned = subscriptions.clone();
         let collect_events = async move {
-            let mut subs = SubscriptionState::new(broadcast_sender);
+            let mut subs = SubscriptionState::new(broadcast_sender, subscriptions_cloned);
             loop {
                 tokio::select! {
                     Some(op) = rx_for_filter_ops.recv() => {
@@ -179,16 +212,66 @@ impl<RelayId: Copy + Send + Sync + Eq + Hash + 'static> RelayPool<RelayId> {
             tx_for_filter_ops,
             tx_for_send_event,
             tx_for_add_relay,
+            tx_for_remove_relay,
             counter: AtomicU32::new(0),
+            statuses,
+            subscriptions,
+            sent_events: AtomicU64::new(0),
+            closing,
         }
     }
 
+    /// Closes the connections to all relays, waiting up to `timeout` for them
+    /// to be closed. Relays added afterwards are not connected either.
+    pub async fn close(&self, timeout: Duration) {
+        self.closing.send_replace(true);
+        let connected = || {
+            self.statuses
+                .lock()
+                .unwrap()
+                .values()
+                .any(|s| s.state == ConnectionState::Connected)
+        };
+        let _ = tokio::time::timeout(timeout, async {
+            while connected() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+    }
+
+    /// Connection state of each relay.
+    pub fn relay_statuses(&self) -> Vec<RelayStatus<RelayId>> {
+        self.statuses.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Number of active subscriptions, each of which may be shared by
+    /// several `EventStream`s with the same filters.
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Number of events passed to `send` so far.
+    pub fn sent_event_count(&self) -> u64 {
+        self.sent_events.load(atomic::Ordering::Relaxed)
+    }
+
+    /// `auth` is the identity to authenticate as if the relay requires NIP-42.
     pub async fn add_relay(
         &self,
         relay_id: RelayId,
         url: url::Url,
-    ) -> Result<(), tokio::sync::mpsc::error::SendError<(RelayId, url::Url)>> {
-        self.tx_for_add_relay.send((relay_id, url)).await
+        auth: Option<Auth>,
+    ) -> Result<(), tokio::sync::mpsc::error::SendError<(RelayId, url::Url, Option<Auth>)>> {
+        self.tx_for_add_relay.send((relay_id, url, auth)).await
+    }
+
+    /// Disconnects from the relay `relay_id` and forgets it.
+    pub async fn remove_relay(
+        &self,
+        relay_id: RelayId,
+    ) -> Result<(), tokio::sync::mpsc::error::SendError<RelayId>> {
+        self.tx_for_remove_relay.send(relay_id).await
     }
 
     pub async fn subscribe(
@@ -236,6 +319,7 @@ impl<RelayId: Copy + Send + Sync + Eq + Hash + 'static> RelayPool<RelayId> {
     }
 
     pub async fn send(&self, event: Arc<nostr::Event>, relays: Arc<FxHashSet<RelayId>>) {
+        self.sent_events.fetch_add(1, atomic::Ordering::Relaxed);
         self.tx_for_send_event
             .send(SendEvent { event, relays })
             .await
@@ -265,6 +349,7 @@ struct SubscriptionState<RelayId> {
     broadcast_sender: tokio::sync::broadcast::Sender<RelayOp<RelayId>>,
     rate_limitter: RateLimitter,
     event_rate_limitter: RateLimitter,
+    subscriptions: Arc<AtomicUsize>,
 }
 
 impl Display for FilterId {
@@ -291,7 +376,10 @@ impl Serialize for FilterId {
 }
 
 impl<RelayId: Copy> SubscriptionState<RelayId> {
-    fn new(broadcast_sender: tokio::sync::broadcast::Sender<RelayOp<RelayId>>) -> Self {
+    fn new(
+        broadcast_sender: tokio::sync::broadcast::Sender<RelayOp<RelayId>>,
+        subscriptions: Arc<AtomicUsize>,
+    ) -> Self {
         Self {
             sub_id_to_filter_id: Default::default(),
             filter_to_id: Default::default(),
@@ -300,6 +388,7 @@ impl<RelayId: Copy> SubscriptionState<RelayId> {
             broadcast_sender,
             rate_limitter: RateLimitter::new(50, Duration::from_secs(1)),
             event_rate_limitter: RateLimitter::new(5, Duration::from_secs(1)),
+            subscriptions,
         }
     }
 
@@ -435,6 +524,8 @@ impl<RelayId: Copy> SubscriptionState<RelayId> {
                 self.sub(id, Arc::new(filters), tx, relays);
             }
         }
+        self.subscriptions
+            .store(self.filter_id_to_senders.len(), atomic::Ordering::Relaxed);
     }
 
     async fn handle_send_event(&mut self, op: SendEvent<RelayId>) {
@@ -517,16 +608,22 @@ async fn subscribe_relay<RelayId: Clone + Copy + Eq + Hash>(
     mut rx_for_ops: ReceiverWithId<RelayId>,
     tx_for_events: SenderWithId<RelayId>,
     user_agent: Arc<String>,
+    auth: Option<Auth>,
+    status: StatusHandle<RelayId>,
+    mut closing: watch::Receiver<bool>,
 ) -> Result<(), tokio_tungstenite::tungstenite::Error> {
-    async fn first_request(
+    #[allow(clippy::too_many_arguments)]
+    async fn first_request<RelayId: Copy + Eq + Hash>(
         message: &Option<ClientMessage>,
         url: &url::Url,
         subs: &mut HashMap<FilterId, Vec<Filter>>,
         user_agent: Arc<String>,
         last_connection_time: &mut SystemTime,
         connection_delay: &mut Duration,
+        status: &StatusHandle<RelayId>,
     ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, tokio_tungstenite::tungstenite::Error>
     {
+        status.set(ConnectionState::Connecting);
         let (mut ws, r) = loop {
             if SystemTime::now() < *last_connection_time + Duration::from_secs(60) {
                 error!("{url} is unstable. sleeping {connection_delay:?}");
@@ -547,10 +644,12 @@ async fn subscribe_relay<RelayId: Clone + Copy + Eq + Hash>(
             match r {
                 Ok((ws, r)) => break (ws, r),
                 Err(e) => {
+                    status.set(ConnectionState::Disconnected);
                     error!("failed to connect to {url}: {e}");
                 }
             }
         };
+        status.set(ConnectionState::Connected);
         debug!("connected to {url}: r = {r:?}, sub = {subs:?}");
         for (id, filters) in subs.iter() {
             let m = serde_json::to_string(&ClientMessage::Req {
@@ -570,8 +669,12 @@ async fn subscribe_relay<RelayId: Clone + Copy + Eq + Hash>(
         Ok(ws)
     }
     let mut subs = HashMap::with_capacity(10);
+    let mut auth = AuthState::new(auth);
     let mut last_connection_time = SystemTime::UNIX_EPOCH;
     let mut connection_delay = Duration::from_secs(5);
+    if *closing.borrow() {
+        return Ok(());
+    }
     let mut ws = loop {
         match rx_for_ops.recv().await {
             Ok(m) => {
@@ -582,6 +685,7 @@ async fn subscribe_relay<RelayId: Clone + Copy + Eq + Hash>(
                     user_agent.clone(),
                     &mut last_connection_time,
                     &mut connection_delay,
+                    &status,
                 )
                 .await?;
             }
@@ -599,7 +703,7 @@ async fn subscribe_relay<RelayId: Clone + Copy + Eq + Hash>(
                     match r {
                         Ok(r) => {
                             if let Err(unhandled_message) =
-                                handle_ops(r, &url, &mut ws, &mut subs).await {
+                                handle_ops(r, &url, &mut ws, &mut subs, &mut auth).await {
                                 break unhandled_message;
                             }
                         }
@@ -618,14 +722,23 @@ async fn subscribe_relay<RelayId: Clone + Copy + Eq + Hash>(
                         &tx_for_events,
                         &subs,
                         &mut waiting_for_pong,
+                        &mut auth,
                     ).await {
                         break None;
                     }
                 }
+                Ok(()) = closing.changed() => {
+                    if let Err(e) = ws.close(None).await {
+                        debug!("could not close {url}: {e}");
+                    }
+                    return Ok(());
+                }
                 else => return Ok(()),
             }
         };
-        if unhandled_message.is_none() && subs.is_empty() {
+        let held = auth.reset();
+        if unhandled_message.is_none() && held.is_empty() && subs.is_empty() {
+            status.set(ConnectionState::Idle);
             ws = loop {
                 match rx_for_ops.recv().await {
                     Ok(m) => {
@@ -636,6 +749,7 @@ async fn subscribe_relay<RelayId: Clone + Copy + Eq + Hash>(
                             user_agent.clone(),
                             &mut last_connection_time,
                             &mut connection_delay,
+                            &status,
                         )
                         .await?;
                     }
@@ -653,12 +767,27 @@ async fn subscribe_relay<RelayId: Clone + Copy + Eq + Hash>(
                 user_agent.clone(),
                 &mut last_connection_time,
                 &mut connection_delay,
+                &status,
             )
             .await?;
         }
+        // no challenge yet on the new connection, so these go out unauthenticated
+        for e in held {
+            send_client_message(&mut ws, &url, &ClientMessage::Event(e)).await;
+        }
     }
 }
 
+async fn send_client_message(
+    ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    url: &url::Url,
+    message: &ClientMessage,
+) {
+    let m = serde_json::to_string(message).unwrap();
+    debug!("{url} <== {m}");
+    let _ = ws.send(Message::Text(m)).await;
+}
+
 fn update_subs(message: &ClientMessage, subs: &mut HashMap<FilterId, Vec<Filter>>) {
     match message {
         ClientMessage::Req {
@@ -671,7 +800,7 @@ fn update_subs(message: &ClientMessage, subs: &mut HashMap<FilterId, Vec<Filter>
             // FIXME: `id` does not exist in `subs` when doing `cargo test media_test`
             subs.remove(id);
         }
-        ClientMessage::Event(_) => (),
+        ClientMessage::Event(_) | ClientMessage::Auth(_) => (),
     }
 }
 
@@ -680,8 +809,16 @@ async fn handle_ops(
     url: &url::Url,
     ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
     subs: &mut HashMap<FilterId, Vec<Filter>>,
+    auth: &mut AuthState,
 ) -> Result<(), Option<Arc<Event>>> {
     update_subs(&message, subs);
+    if let ClientMessage::Event(e) = &message {
+        if let Some(a) = auth.before_event(e, url) {
+            // `e` is sent once the relay accepts the `AUTH`, see `on_ok`.
+            send_client_message(ws, url, &ClientMessage::Auth(Arc::new(a))).await;
+            return Ok(());
+        }
+    }
     let m = serde_json::to_string(&message).unwrap();
     debug!("{url} <== {m}");
     if let Err(e) = ws.send(Message::Text(m)).await {
@@ -717,6 +854,7 @@ async fn handle_message<RelayId: Clone + Copy + PartialEq>(
     tx_for_events: &SenderWithId<RelayId>,
     subs: &HashMap<FilterId, Vec<Filter>>,
     waiting_for_pong: &mut bool,
+    auth: &mut AuthState,
 ) -> bool {
     match m {
         Ok(Some(Ok(m))) => {
@@ -731,6 +869,43 @@ async fn handle_message<RelayId: Clone + Copy + PartialEq>(
                             RelayMessage::Event { .. } => {
                                 tx_for_events.send(m).await;
                             }
+                            RelayMessage::Auth { challenge } => {
+                                debug!("{url} ==> {t}");
+                                if let Some(a) = auth.on_challenge(challenge.clone(), url) {
+                                    send_client_message(ws, url, &ClientMessage::Auth(Arc::new(a)))
+                                        .await;
+                                }
+                            }
+                            RelayMessage::Ok {
+                                event_id,
+                                status,
+                                message,
+                            } => match auth.on_ok(event_id, *status, message, url) {
+                                Some(held) => {
+                                    debug!("{url} ==> {t}");
+                                    // subscriptions closed for lack of authentication
+                                    // are sent again
+                                    for (id, filters) in subs {
+                                        send_client_message(
+                                            ws,
+                                            url,
+                                            &ClientMessage::Req {
+                                                subscription_id: *id,
+                                                filters: filters.clone(),
+                                            },
+                                        )
+                                        .await;
+                                    }
+                                    if let Some(e) = held {
+                                        send_client_message(ws, url, &ClientMessage::Event(e))
+                                            .await;
+                                    }
+                                }
+                                None => {
+                                    debug!("{url} ==> {t}");
+                                    tx_for_events.send(m).await;
+                                }
+                            },
                             _ => {
                                 debug!("{url} ==> {t}");
                                 tx_for_events.send(m).await;
@@ -798,6 +973,8 @@ enum ClientMessage {
     },
     /// Close
     Close(FilterId),
+    /// Auth
+    Auth(Arc<Event>),
 }
 
 impl Serialize for ClientMessage {
@@ -831,6 +1008,12 @@ impl Serialize for ClientMessage {
                 seq.serialize_element(id)?;
                 seq.end()
             }
+            ClientMessage::Auth(e) => {
+                let mut seq = serializer.serialize_seq(Some(2))?;
+                seq.serialize_element("AUTH")?;
+                seq.serialize_element(&**e)?;
+                seq.end()
+            }
         }
     }
 }
@@ -0,0 +1,83 @@
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+/// Connection state of a relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    /// Not connected as there is nothing to subscribe to or send.
+    Idle,
+    Connecting,
+    Connected,
+    /// The last connection attempt failed.
+    Disconnected,
+}
+
+#[derive(Debug, Clone)]
+pub struct RelayStatus<RelayId> {
+    pub id: RelayId,
+    pub url: url::Url,
+    pub state: ConnectionState,
+}
+
+pub(crate) type Statuses<RelayId> = Arc<Mutex<FxHashMap<RelayId, RelayStatus<RelayId>>>>;
+
+/// Reports the state of a single relay to the pool. The relay is
+/// `Disconnected` once its connection task holding this ends, however it ends.
+pub(crate) struct StatusHandle<RelayId: Copy + Eq + Hash> {
+    statuses: Statuses<RelayId>,
+    id: RelayId,
+}
+
+impl<RelayId: Copy + Eq + Hash> StatusHandle<RelayId> {
+    pub(crate) fn new(statuses: Statuses<RelayId>, id: RelayId, url: url::Url) -> Self {
+        statuses.lock().unwrap().insert(
+            id,
+            RelayStatus {
+                id,
+                url,
+                state: ConnectionState::Idle,
+            },
+        );
+        Self { statuses, id }
+    }
+
+    pub(crate) fn set(&self, state: ConnectionState) {
+        if let Some(s) = self.statuses.lock().unwrap().get_mut(&self.id) {
+            s.state = state;
+        }
+    }
+}
+
+impl<RelayId: Copy + Eq + Hash> Drop for StatusHandle<RelayId> {
+    fn drop(&mut self) {
+        if let Ok(mut statuses) = self.statuses.lock() {
+            if let Some(s) = statuses.get_mut(&self.id) {
+                s.state = ConnectionState::Disconnected;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionState, StatusHandle};
+
+    #[test]
+    fn status_handle() {
+        let statuses = Default::default();
+        let url = url::Url::parse("wss://relay.example.com").unwrap();
+        let h = StatusHandle::new(std::sync::Arc::clone(&statuses), 1, url.clone());
+        assert_eq!(statuses.lock().unwrap()[&1].state, ConnectionState::Idle);
+        h.set(ConnectionState::Connected);
+        let s = statuses.lock().unwrap()[&1].clone();
+        assert_eq!((s.url, s.state), (url, ConnectionState::Connected));
+        drop(h);
+        assert_eq!(
+            statuses.lock().unwrap()[&1].state,
+            ConnectionState::Disconnected
+        );
+    }
+}
@@ -1,14 +1,17 @@
+use crate::db::FailedDelivery;
 use crate::error::Error;
-use crate::rsa_keys::RSA_PRIVATE_KEY_FOR_SIGH;
+use crate::rsa_keys::{INSTANCE_RSA_PRIVATE_KEY_FOR_SIGH, RSA_PRIVATE_KEY_FOR_SIGH};
 use crate::server::{event_tag, AppState, WithContext};
+use crate::util::normalize_handle;
 use crate::{
-    html_to_text, HTTPS_DOMAIN, INBOX_RELAYS, NOTE_ID_PREFIX, OUTBOX_RELAYS, SECRET_KEY,
-    USER_AGENT, USER_ID_PREFIX,
+    federates_with, html_to_text, ACTOR_TTL_SECS, BRIDGE_FOLLOW_COUNTS, DELIVERY_MAX_ATTEMPTS,
+    DELIVERY_RETENTION_SECS, FEP_044F_QUOTES, HTTPS_DOMAIN, INBOX_RELAYS, INSTANCE_ACTOR_ID,
+    NOTE_ID_PREFIX, OUTBOX_RELAYS, READ_ONLY, SECRET_KEY, USER_AGENT, USER_ID_PREFIX,
 };
 use axum::http::{Method, Request, Uri};
 use base64::Engine;
 use chrono::{DateTime, Utc};
-use nostr_lib::{EventBuilder, JsonUtil, Metadata, RelayMetadata, UncheckedUrl};
+use nostr_lib::{EventBuilder, JsonUtil, Metadata, RelayMetadata, Timestamp, UncheckedUrl};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use reqwest::header::HeaderMap;
@@ -22,10 +25,10 @@ use sigh::alg::RsaSha256;
 use sigh::{Key, SigningConfig};
 use std::borrow::Cow;
 use std::collections::hash_map::Entry;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::AsyncWriteExt;
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 use url::Url;
 
 #[derive(Serialize, Clone, Debug)]
@@ -47,6 +50,14 @@ pub struct Note {
     pub quote: Option<String>,
     pub in_reply_to: Option<String>,
     pub tag: Vec<NoteTagForSer>,
+    pub summary: Option<String>,
+    pub sensitive: bool,
+    /// Long-form content (NIP-23), serialized as an `Article`.
+    pub article: bool,
+    /// Title of an article
+    pub name: Option<String>,
+    /// Signed Nostr event of the note, for verification by other bridges.
+    pub nostr_event: Option<Arc<nostr_lib::Event>>,
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -65,6 +76,13 @@ pub enum NoteTagForSer {
         href: String,
         name: String,
     },
+    /// FEP-e232 object link, used for quotes.
+    Link {
+        #[serde(rename = "mediaType")]
+        media_type: &'static str,
+        href: String,
+        name: String,
+    },
 }
 
 #[derive(Serialize, Clone, Debug, PartialEq)]
@@ -89,8 +107,11 @@ impl Serialize for Note {
     {
         use serde::ser::SerializeMap;
         let mut m = serializer.serialize_map(None)?;
-        m.serialize_entry("type", "Note")?;
+        m.serialize_entry("type", if self.article { "Article" } else { "Note" })?;
         m.serialize_entry("id", &format_args!("{NOTE_ID_PREFIX}{}", self.id))?;
+        if let Some(name) = &self.name {
+            m.serialize_entry("name", name)?;
+        }
         m.serialize_entry(
             "url",
             &[
@@ -109,6 +130,12 @@ impl Serialize for Note {
         m.serialize_entry("content", &self.content)?;
         m.serialize_entry("_misskey_content", &self.misskey_content)?;
         m.serialize_entry("published", &self.published)?;
+        if let Some(summary) = &self.summary {
+            m.serialize_entry("summary", summary)?;
+        }
+        if self.sensitive {
+            m.serialize_entry("sensitive", &true)?;
+        }
         if !self.attachment.is_empty() {
             m.serialize_entry("attachment", &self.attachment)?;
         }
@@ -118,10 +145,16 @@ impl Serialize for Note {
         if let Some(quote) = &self.quote {
             m.serialize_entry("quoteUrl", quote)?;
             m.serialize_entry("_misskey_quote", quote)?;
+            if *FEP_044F_QUOTES {
+                m.serialize_entry("quote", quote)?;
+            }
         }
         if !self.tag.is_empty() {
             m.serialize_entry("tag", &self.tag)?;
         }
+        if let Some(event) = &self.nostr_event {
+            m.serialize_entry("nostrEvent", event)?;
+        }
         m.end()
     }
 }
@@ -176,6 +209,30 @@ impl Serialize for DeleteForSer<'_> {
     }
 }
 
+/// `Delete` of a proxied actor itself.
+#[derive(Clone, Debug)]
+pub struct DeleteActorForSer<'a> {
+    pub actor: &'a str,
+    pub id: &'a str,
+}
+
+impl Serialize for DeleteActorForSer<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
+
+        let mut m = serializer.serialize_map(None)?;
+        m.serialize_entry("type", "Delete")?;
+        m.serialize_entry("id", &format_args!("{HTTPS_DOMAIN}/delete/{}", self.id))?;
+        m.serialize_entry("to", &["Public"])?;
+        m.serialize_entry("actor", &self.actor)?;
+        m.serialize_entry("object", &self.actor)?;
+        m.end()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ReactionForSer<'a> {
     pub content: Option<&'a str>,
@@ -183,6 +240,8 @@ pub struct ReactionForSer<'a> {
     pub object: &'a str,
     pub actor: &'a str,
     pub tag: Option<NoteTagForSer>,
+    /// Pleroma and Akkoma only show emoji reactions sent as `EmojiReact`
+    pub emoji_react: bool,
 }
 
 impl Serialize for ReactionForSer<'_> {
@@ -193,7 +252,11 @@ impl Serialize for ReactionForSer<'_> {
         use serde::ser::SerializeMap;
 
         let mut m = serializer.serialize_map(None)?;
-        m.serialize_entry("type", "Like")?;
+        if self.emoji_react && self.content.is_some() {
+            m.serialize_entry("type", "EmojiReact")?;
+        } else {
+            m.serialize_entry("type", "Like")?;
+        }
         m.serialize_entry("id", &format_args!("{HTTPS_DOMAIN}/reaction/{}", self.id))?;
         m.serialize_entry("actor", &self.actor)?;
         m.serialize_entry("object", &self.object)?;
@@ -207,12 +270,48 @@ impl Serialize for ReactionForSer<'_> {
     }
 }
 
+/// A zap of a bridged note, as a `Like` or an `EmojiReact` with ⚡.
+#[derive(Clone, Debug)]
+pub struct ZapForSer<'a> {
+    pub id: &'a str,
+    pub object: &'a str,
+    pub actor: &'a str,
+    pub sats: Option<u64>,
+    pub emoji_react: bool,
+}
+
+impl Serialize for ZapForSer<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
+
+        let mut m = serializer.serialize_map(None)?;
+        if self.emoji_react {
+            m.serialize_entry("type", "EmojiReact")?;
+            m.serialize_entry("content", "⚡")?;
+        } else {
+            m.serialize_entry("type", "Like")?;
+        }
+        m.serialize_entry("id", &format_args!("{HTTPS_DOMAIN}/zap/{}", self.id))?;
+        m.serialize_entry("actor", &self.actor)?;
+        m.serialize_entry("object", &self.object)?;
+        if let Some(sats) = self.sats {
+            m.serialize_entry("summary", &format_args!("⚡ Zapped {sats} sats"))?;
+        }
+        m.end()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct AnnounceForSer<'a> {
     pub id: &'a str,
     pub object: &'a str,
     pub actor: &'a str,
     pub published: &'a str,
+    /// The fediverse author of `object`, if it is a proxied fediverse post.
+    pub object_author: Option<&'a str>,
 }
 
 impl Serialize for AnnounceForSer<'_> {
@@ -228,6 +327,14 @@ impl Serialize for AnnounceForSer<'_> {
         m.serialize_entry("actor", &self.actor)?;
         m.serialize_entry("object", &self.object)?;
         m.serialize_entry("to", &["Public"])?;
+        let followers = format!("{}/followers", self.actor);
+        m.serialize_entry(
+            "cc",
+            &[followers.as_str()]
+                .into_iter()
+                .chain(self.object_author)
+                .collect::<Vec<_>>(),
+        )?;
         m.serialize_entry("published", &self.published)?;
         m.end()
     }
@@ -272,9 +379,19 @@ pub struct ActivityForDe<'a> {
 #[serde(rename_all = "camelCase")]
 pub struct NoteForDe {
     pub id: String,
+    // votes on polls have neither `content` nor `published`
+    #[serde(default)]
     pub content: String,
+    /// `content` by language. Mastodon only includes the language of the note.
+    #[serde(default)]
+    pub content_map: BTreeMap<String, String>,
+    pub language: Option<String>,
+    /// Lemmy community of a post.
+    pub audience: Option<String>,
     pub source: Option<Source>,
+    #[serde(default = "Utc::now")]
     pub published: DateTime<Utc>,
+    pub updated: Option<DateTime<Utc>>,
     pub in_reply_to: Option<String>,
     #[serde(default)]
     pub tag: Vec<NoteTagForDe>,
@@ -282,17 +399,57 @@ pub struct NoteForDe {
     pub attachment: Vec<AttachedImage>,
     #[serde(default)]
     pub url: ActorUrl,
-    pub attributed_to: String,
+    pub attributed_to: AttributedTo,
+    /// FEP-044f quote. Its `quoteAuthorization` is not checked.
+    pub quote: Option<IdOrObject>,
     pub quote_url: Option<String>,
     // threads.net only provides `_misskey_quote`
     #[serde(rename = "_misskey_quote")]
     pub misskey_quote: Option<String>,
-    #[serde(default)]
+    #[serde(rename = "_misskey_content")]
+    pub misskey_content: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_string_or_list")]
     pub to: Vec<String>,
-    #[serde(default)]
+    #[serde(default, deserialize_with = "deserialize_string_or_list")]
     pub cc: Vec<String>,
     pub sensitive: Option<bool>,
     pub summary: Option<String>,
+    pub name: Option<String>,
+    /// Options of a single choice `Question`
+    pub one_of: Option<Vec<QuestionOption>>,
+    /// Options of a multiple choice `Question`
+    pub any_of: Option<Vec<QuestionOption>>,
+    pub end_time: Option<DateTime<Utc>>,
+    /// When a `Question` was closed. It may be `true` instead on some software.
+    #[serde(default, deserialize_with = "deserialize_closed")]
+    pub closed: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct QuestionOption {
+    pub name: String,
+}
+
+fn deserialize_string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(match ListOrSingle::<String>::deserialize(deserializer)? {
+        ListOrSingle::Single(a) => vec![a],
+        ListOrSingle::Vec(l) => l.into_iter().filter_map(Option::from).collect(),
+    })
+}
+
+fn deserialize_closed<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(
+        match OptionForDe::<DateTime<Utc>>::deserialize(deserializer)? {
+            OptionForDe::Some(t) => Some(t),
+            OptionForDe::None(_) => None,
+        },
+    )
 }
 
 #[derive(Deserialize, Clone, Debug, PartialEq)]
@@ -344,26 +501,90 @@ pub enum ActivityForDeInner<'a> {
         #[serde(default)]
         tag: Vec<NoteTagForDe>,
     },
+    /// Emoji reaction of Misskey and Pleroma
+    EmojiReact {
+        object: Cow<'a, str>,
+        content: Option<Cow<'a, str>>,
+        id: Cow<'a, str>,
+        #[serde(default)]
+        tag: Vec<NoteTagForDe>,
+    },
     Announce {
         id: Cow<'a, str>,
-        object: Cow<'a, str>,
-        published: DateTime<Utc>,
+        object: AnnounceObject,
+        published: Option<DateTime<Utc>>,
         #[serde(default)]
         to: Vec<Cow<'a, str>>,
         #[serde(default)]
         cc: Vec<Cow<'a, str>>,
     },
     Update {
-        object: ActorOrProxied,
+        object: UpdateObject,
+    },
+    Move {
+        object: Cow<'a, str>,
+        target: Cow<'a, str>,
     },
     Create {
         object: Box<NoteForDe>,
     },
+    Accept {
+        object: IdOrObject,
+    },
     Delete(Delete<'a>),
+    /// Pinning of a note by Mastodon, whose target is `featured` of the actor.
+    Add {
+        object: IdOrObject,
+        target: Option<IdOrObject>,
+    },
+    /// Unpinning of a note.
+    Remove {
+        object: IdOrObject,
+        target: Option<IdOrObject>,
+    },
+    /// A report of users or notes.
+    Flag {
+        object: ListOrSingle<String>,
+        content: Option<Cow<'a, str>>,
+    },
     #[serde(untagged)]
     Other(Value),
 }
 
+/// Object of an `Announce`. Lemmy communities announce the `Create` of a post
+/// instead of the post itself.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum AnnounceObject {
+    Id(String),
+    Activity {
+        #[serde(rename = "type")]
+        kind: String,
+        object: IdOrObject,
+    },
+    Object {
+        id: String,
+    },
+}
+
+impl AnnounceObject {
+    /// Id of the announced note, unless another kind of activity is announced.
+    pub fn note_id(&self) -> Option<&str> {
+        match self {
+            AnnounceObject::Id(id) | AnnounceObject::Object { id } => Some(id),
+            AnnounceObject::Activity { kind, object } => (kind == "Create").then(|| object.id()),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum UpdateObject {
+    Actor(ActorOrProxied),
+    /// Edited note
+    Note(Box<NoteForDe>),
+}
+
 #[derive(Deserialize, Clone, Debug, PartialEq)]
 #[serde(untagged)]
 pub enum Delete<'a> {
@@ -376,6 +597,32 @@ pub enum Delete<'a> {
     },
 }
 
+impl Delete<'_> {
+    /// Id of the deleted object, `None` if the actor deletes itself. Some
+    /// servers delete a `Like` or an `Announce` by its id instead of undoing it.
+    pub fn object_id(&self, actor_id: &str) -> Option<&str> {
+        match self {
+            Delete::User { object } => (object != actor_id).then_some(object),
+            Delete::Note { object } => Some(&object.id),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum IdOrObject {
+    Id(String),
+    Object { id: String },
+}
+
+impl IdOrObject {
+    pub fn id(&self) -> &str {
+        match self {
+            IdOrObject::Id(id) | IdOrObject::Object { id } => id,
+        }
+    }
+}
+
 #[derive(Deserialize, Clone, Debug, PartialEq)]
 pub struct Tombstone<'a> {
     pub id: Cow<'a, str>,
@@ -410,17 +657,200 @@ pub struct UndoFollowActivity<'a> {
     pub id: &'a str,
 }
 
+/// Key of the instance actor, which signs GET requests.
+static GET_KEY_ID: Lazy<String> = Lazy::new(|| format!("{}#main-key", *INSTANCE_ACTOR_ID));
+
+fn requires_signature(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN
+}
+
+/// Whether a refetch answered with `status` shows that the object is deleted.
+fn confirms_deletion(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::GONE || status == reqwest::StatusCode::NOT_FOUND
+}
+
+/// Headers of a GET request of `url` signed with `key`.
+fn signed_get_headers(url: &Uri, key: &sigh::PrivateKey, key_id: &str) -> HeaderMap {
+    let digest = sha2::Sha256::digest([]);
+    let digest = base64::prelude::BASE64_STANDARD.encode(digest);
+    let mut r = Request::builder()
+        .method(Method::GET)
+        .uri(url)
+        .header(axum::http::header::ACCEPT, "application/activity+json")
+        .header(axum::http::header::USER_AGENT, &*USER_AGENT)
+        .header("host", url.host().unwrap())
+        .header(
+            "date",
+            httpdate::HttpDate::from(std::time::SystemTime::now()).to_string(),
+        )
+        .header("digest", format!("SHA-256={digest}"))
+        // Content-Type doesn't have to be text/plain but should not be empty to work with Mastodon
+        .header(axum::http::header::CONTENT_TYPE, "text/plain")
+        .body(())
+        .unwrap();
+    SigningConfig::new(RsaSha256, key, key_id)
+        .sign(&mut r)
+        .unwrap();
+    r.headers().clone()
+}
+
 impl AppState {
     pub async fn send_activity<S: AsRef<str>, A: Serialize>(
-        &self,
+        self: &Arc<Self>,
         inbox: &Uri,
         author: S,
         activity: A,
-    ) -> Result<(), Error> {
+    ) -> Result<Delivery, Error> {
+        let Some(host) = inbox.host() else {
+            return Err(Error::BadRequest(Some(format!("{inbox} has no host"))));
+        };
+        if !federates_with(host) {
+            info!("skipped delivery to {inbox} by the federation policy");
+            return Ok(Delivery::Skipped);
+        }
         let s = WithContext(activity);
-        let host = inbox.host().unwrap();
         let body = serde_json::to_string(&s).unwrap();
+        if *READ_ONLY {
+            // Delivered by `redeliver_failed_activities` once read-only mode is lifted.
+            info!("queued delivery to {inbox} in read-only mode");
+            self.db.push_failed_delivery(
+                0,
+                &FailedDelivery {
+                    inbox: inbox.to_string(),
+                    author: author.as_ref().to_string(),
+                    body,
+                    attempts: 0,
+                    first_failed_at: Timestamp::now().as_u64(),
+                },
+            );
+            return Ok(Delivery::Queued);
+        }
         info!("{inbox} <== {body}");
+        let state = self.clone();
+        let r = self
+            .delivery_batcher
+            .send(
+                host,
+                (inbox.clone(), author.as_ref().to_string(), body.clone()),
+                move |(inbox, author, body): (Uri, String, String)| {
+                    let state = state.clone();
+                    async move { state.deliver(&inbox, &author, body).await }
+                },
+            )
+            .await
+            .unwrap_or_else(|| Err(anyhow::anyhow!("delivery batch was cancelled").into()));
+        // Deliveries to hosts with an open circuit breaker are dropped.
+        if !matches!(r, Ok(status) if !is_retryable_status(status))
+            && !matches!(r, Err(Error::Unavailable(_)))
+        {
+            let now = Timestamp::now().as_u64();
+            self.db.push_failed_delivery(
+                now + retry_delay(1),
+                &FailedDelivery {
+                    inbox: inbox.to_string(),
+                    author: author.as_ref().to_string(),
+                    body,
+                    attempts: 1,
+                    first_failed_at: now,
+                },
+            );
+        }
+        r.map(Delivery::Sent)
+    }
+
+    /// Redelivers activities which failed to be delivered until they succeed
+    /// or run out of attempts.
+    pub async fn redeliver_failed_activities(&self) -> Result<(), Error> {
+        loop {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            if *READ_ONLY {
+                continue;
+            }
+            let now = Timestamp::now().as_u64();
+            // hosts are retried concurrently so that a slow one doesn't hold
+            // up the others
+            let mut by_host = rustc_hash::FxHashMap::<_, Vec<_>>::default();
+            for (key, d) in self.db.due_failed_deliveries(now) {
+                let host = d
+                    .inbox
+                    .parse::<Uri>()
+                    .ok()
+                    .and_then(|u| u.host().map(str::to_string));
+                by_host.entry(host).or_default().push((key, d));
+            }
+            futures_util::future::join_all(by_host.into_values().map(|ds| async move {
+                for (key, d) in ds {
+                    self.redeliver(d, now).await;
+                    self.db.remove_failed_delivery(&key);
+                }
+            }))
+            .await;
+        }
+    }
+
+    /// Retries `d`, queueing it again if it fails and has attempts left.
+    async fn redeliver(&self, mut d: FailedDelivery, now: u64) {
+        let Ok(inbox) = d.inbox.parse::<Uri>() else {
+            return;
+        };
+        if !inbox.host().is_some_and(federates_with) {
+            return;
+        }
+        let r = self
+            .deliver(&inbox, &d.author, d.body.clone())
+            .await
+            .map(Delivery::Sent);
+        let retry_at = retry_at(&mut d, &r, now);
+        self.update_delivery(&d.body, &inbox, &r, retry_at.is_some());
+        if let Some(t) = retry_at {
+            self.db.push_failed_delivery(t, &d);
+        }
+    }
+
+    /// Periodically reports follows which haven't been accepted in a day.
+    pub async fn check_pending_follows(&self) -> Result<(), Error> {
+        loop {
+            tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+            let (pending, confirmed) = self.db.follow_counts();
+            info!("follows: {pending} pending, {confirmed} confirmed");
+            let before = Timestamp::now().as_u64() - 60 * 60 * 24;
+            for id in self.db.stale_pending_follows(before) {
+                warn!("follow {id} has not been accepted");
+            }
+        }
+    }
+
+    /// Fails with `Error::Unavailable` without sending anything if the
+    /// circuit breaker of the host is open.
+    async fn deliver(
+        &self,
+        inbox: &Uri,
+        author: &str,
+        body: String,
+    ) -> Result<reqwest::StatusCode, Error> {
+        let host = inbox.host().unwrap();
+        let now = Timestamp::now().as_u64();
+        if let Some(secs) = self.circuit_breaker.open_for(host, now) {
+            info!("dropped delivery to {inbox} as {host} keeps failing, retrying in {secs}s");
+            return Err(Error::Unavailable(secs));
+        }
+        let r = self.post_activity(inbox, author, body).await;
+        self.circuit_breaker.record(
+            host,
+            matches!(r, Ok(status) if !is_retryable_status(status)),
+            now,
+        );
+        r
+    }
+
+    async fn post_activity(
+        &self,
+        inbox: &Uri,
+        author: &str,
+        body: String,
+    ) -> Result<reqwest::StatusCode, Error> {
+        let host = inbox.host().unwrap();
+        let _permit = self.host_limiter.acquire(host).await;
         let digest = sha2::Sha256::digest(&body);
         let digest = base64::prelude::BASE64_STANDARD.encode(digest);
         let mut r = Request::builder()
@@ -439,7 +869,7 @@ impl AppState {
             .header("digest", format!("SHA-256={digest}"))
             .body(body)
             .unwrap();
-        SigningConfig::new(RsaSha256, &RSA_PRIVATE_KEY_FOR_SIGH, author.as_ref())
+        SigningConfig::new(RsaSha256, &RSA_PRIVATE_KEY_FOR_SIGH, author)
             .sign(&mut r)
             .unwrap();
         let mut headers = HeaderMap::with_capacity(r.headers().len());
@@ -450,65 +880,130 @@ impl AppState {
         }));
         let r = self
             .http_client
-            .post(&inbox.to_string())
+            .post(inbox.to_string())
             .headers(headers)
             .body(r.into_body())
             .send()
-            .await?;
+            .await
+            .inspect_err(|_| self.metrics.delivery(false))?;
+        let status = r.status();
+        self.metrics.delivery(status.is_success());
         info!(
             "{inbox} ==> status: {}, headers: {:?}, body: {:?}",
-            r.status(),
+            status,
             r.headers().clone(),
             r.text().await
         );
-        Ok(())
+        Ok(status)
     }
 
-    pub async fn get_activity_json<T: DeserializeOwned>(&self, url: &Uri) -> Result<T, Error> {
-        let digest = sha2::Sha256::digest([]);
-        let digest = base64::prelude::BASE64_STANDARD.encode(digest);
-        let mut r = Request::builder()
-            .method(Method::GET)
-            .uri(url)
-            .header(axum::http::header::ACCEPT, "application/activity+json")
-            .header(axum::http::header::USER_AGENT, &*USER_AGENT)
-            .header("host", url.host().unwrap())
-            .header(
-                "date",
-                httpdate::HttpDate::from(std::time::SystemTime::now()).to_string(),
-            )
-            .header("digest", format!("SHA-256={digest}"))
-            // Content-Type doesn't have to be text/plain but should not be empty to work with Mastodon
-            .header(axum::http::header::CONTENT_TYPE, "text/plain")
-            .body(())
-            .unwrap();
-        const KEY_ID: &str = "https://worker-hidden-bonus-1869.n-mado.workers.dev";
-        SigningConfig::new(RsaSha256, &RSA_PRIVATE_KEY_FOR_SIGH, KEY_ID)
-            .sign(&mut r)
-            .unwrap();
-        let t = self
+    /// Returns the object and the URL it was finally served at after redirects.
+    /// The request is signed only if the server refuses an unsigned one, as
+    /// instances in secure mode do.
+    pub async fn get_activity_json_and_url<T: DeserializeOwned>(
+        &self,
+        url: &Uri,
+    ) -> Result<(T, Url), Error> {
+        let (t, final_url) = self.get_activity_text_and_url(url).await?;
+        Ok((serde_json::from_str(&t)?, final_url))
+    }
+
+    async fn get_activity_text_and_url(&self, url: &Uri) -> Result<(String, Url), Error> {
+        let r = self.get_activity_response(url).await?;
+        let final_url = r.url().clone();
+        if !final_url.host_str().is_some_and(federates_with) {
+            info!("refused {url} redirected to {final_url} by the federation policy");
+            return Err(Error::Forbidden);
+        }
+        let t = r.text().await?;
+        debug!("{url} ==> {t}");
+        Ok((t, final_url))
+    }
+
+    /// Whether a refetch of `id` confirms that it was deleted.
+    pub async fn is_gone(&self, id: &str) -> bool {
+        let Ok(url) = id.parse::<Uri>() else {
+            return false;
+        };
+        match self.get_activity_response(&url).await {
+            Ok(r) => confirms_deletion(r.status()),
+            Err(e) => {
+                debug!("could not refetch {id}: {e:?}");
+                false
+            }
+        }
+    }
+
+    async fn get_activity_response(&self, url: &Uri) -> Result<reqwest::Response, Error> {
+        let Some(host) = url.host().filter(|h| federates_with(h)) else {
+            info!("refused to fetch {url} by the federation policy");
+            return Err(Error::Forbidden);
+        };
+        let _permit = self.host_limiter.acquire(host).await;
+        let mut r = self
             .http_client
-            .get(&url.to_string())
-            .headers(r.headers().clone())
+            .get(url.to_string())
+            .header(reqwest::header::ACCEPT, "application/activity+json")
+            .header(reqwest::header::USER_AGENT, &*USER_AGENT)
             .send()
-            .await?
-            .text()
             .await?;
-        debug!("{url} ==> {t}");
-        Ok(serde_json::from_str(&t)?)
+        if requires_signature(r.status()) {
+            debug!("{url} ==> {}, retrying with a signature", r.status());
+            r = self
+                .http_client
+                .get(url.to_string())
+                .headers(signed_get_headers(
+                    url,
+                    &INSTANCE_RSA_PRIVATE_KEY_FOR_SIGH,
+                    &GET_KEY_ID,
+                ))
+                .send()
+                .await?;
+        }
+        Ok(r)
     }
 
     pub async fn get_activity_json_with_retry<T: DeserializeOwned>(
         &self,
         url: &Uri,
     ) -> Result<T, Error> {
-        match self.get_activity_json(url).await {
+        self.get_activity_json_and_url_with_retry(url)
+            .await
+            .map(|(a, _)| a)
+    }
+
+    pub async fn get_activity_json_and_url_with_retry<T: DeserializeOwned>(
+        &self,
+        url: &Uri,
+    ) -> Result<(T, Url), Error> {
+        let (t, final_url) = self
+            .in_flight_fetches
+            .run(
+                &url.to_string(),
+                self.get_activity_text_and_url_with_retry(url),
+            )
+            .await?;
+        Ok((serde_json::from_str(&t)?, final_url))
+    }
+
+    /// Concurrent fetches of the same URL share this, see `in_flight_fetches`.
+    async fn get_activity_text_and_url_with_retry(
+        &self,
+        url: &Uri,
+    ) -> Result<(String, Url), Error> {
+        let get = || async {
+            let (t, final_url) = self.get_activity_text_and_url(url).await?;
+            serde_json::from_str::<IgnoredAny>(&t)?;
+            Ok((t, final_url))
+        };
+        let start = std::time::Instant::now();
+        let r = match get().await {
             Ok(actor) => Ok(actor),
             Err(e) => {
                 warn!("could not get activity from {url}: {e:?}");
                 tokio::time::sleep(Duration::from_secs(30)).await;
                 debug!("retrying ...");
-                match self.get_activity_json(url).await {
+                match get().await {
                     Ok(actor) => {
                         debug!("retry successed");
                         Ok(actor)
@@ -519,7 +1014,9 @@ impl AppState {
                     }
                 }
             }
-        }
+        };
+        self.metrics.fetch(start.elapsed());
+        r
     }
 
     #[tracing::instrument(skip(self))]
@@ -527,56 +1024,93 @@ impl AppState {
         self.get_actor_data_and_if_its_new(id).await.map(|(a, _)| a)
     }
 
+    /// Fetches `id` again bypassing the cache, e.g. after it rotated its key.
+    pub async fn refetch_actor_data(&self, id: &str) -> Result<ActorOrProxied, Error> {
+        self.actor_cache.lock().pop(id);
+        self.get_actor_data(id).await
+    }
+
     #[tracing::instrument(skip(self))]
     pub async fn get_actor_data_and_if_its_new(
         &self,
         id: &str,
     ) -> Result<(ActorOrProxied, bool), Error> {
-        {
-            if let Some(actor) = self.actor_cache.lock().get(id) {
-                return Ok((actor.clone(), false));
+        let cached = self.actor_cache.lock().get(id).cloned();
+        match cached {
+            Some(ActorOrProxied::Actor(a))
+                if a.is_stale(Timestamp::now().as_u64(), *ACTOR_TTL_SECS) =>
+            {
+                debug!("refreshing {id}");
+            }
+            Some(actor) => {
+                self.touch_actor(id);
+                return Ok((actor, false));
             }
+            None => (),
         }
         if let Some(npub) = id.strip_prefix(USER_ID_PREFIX) {
             let actor = ActorOrProxied::Proxied(Arc::new(npub.to_string()));
             self.actor_cache.lock().push(id.to_string(), actor.clone());
             return Ok((actor, false));
         }
-        let actor: ActorOrProxied = self
-            .get_activity_json_with_retry(&id.parse::<Uri>().unwrap())
+        let uri = id.parse::<Uri>()?;
+        let (actor, final_url): (ActorOrProxied, _) = self
+            .get_activity_json_and_url_with_retry(&uri)
             .await
             .map_err(|e| {
                 Error::BadRequest(Some(format!("could not get user data from {id}: {e:?}")))
             })?;
+        if let ActorOrProxied::Actor(a) = &actor {
+            check_actor_redirect(&uri, &final_url, &a.id)?;
+        }
         let new = self.update_actor_metadata(&actor).await?;
-        self.actor_cache.lock().push(id.to_string(), actor.clone());
+        {
+            let mut cache = self.actor_cache.lock();
+            if let ActorOrProxied::Actor(a) = &actor {
+                if a.id != id {
+                    cache.push(a.id.clone(), actor.clone());
+                }
+            }
+            cache.push(id.to_string(), actor.clone());
+        }
+        if let ActorOrProxied::Actor(a) = &actor {
+            self.touch_actor(&a.id);
+        }
+        self.touch_actor(id);
         Ok((actor, new))
     }
 
     pub async fn update_actor_metadata(&self, actor: &ActorOrProxied) -> Result<bool, Error> {
         static R: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[[:word:].-]+$").unwrap());
         if let ActorOrProxied::Actor(actor) = &actor {
-            let nip05 = match (Url::parse(&actor.id)?.domain(), &actor.preferred_username) {
-                (Some(domain), Some(name)) if R.is_match(name) => Some(format!(
-                    "{}_at_{}@momostr.pink",
-                    name.to_lowercase(),
-                    domain.replace("at_", ".at_")
-                )),
+            let handle = match (Url::parse(&actor.id)?.domain(), &actor.preferred_username) {
+                (Some(domain), Some(name)) if R.is_match(name) => {
+                    normalize_handle(&format!("{name}@{domain}"))
+                }
                 _ => None,
             };
+            let nip05 = handle
+                .as_ref()
+                .and_then(|h| h.rsplit_once('@'))
+                .map(|(name, domain)| {
+                    format!(
+                        "{}_at_{}@momostr.pink",
+                        name.to_lowercase(),
+                        domain.replace("at_", ".at_")
+                    )
+                });
             let key = nostr_lib::Keys::new(actor.nsec.clone());
+            let (followers, following) = if *BRIDGE_FOLLOW_COUNTS {
+                tokio::join!(
+                    self.total_items(actor.followers.as_ref()),
+                    self.total_items(actor.following.as_ref()),
+                )
+            } else {
+                (None, None)
+            };
             let metadata = EventBuilder::new(
                 nostr_lib::Kind::Metadata,
-                Metadata {
-                    name: Some(actor.name.clone()),
-                    about: actor.summary.clone(),
-                    website: Some(actor.url.clone().unwrap_or_else(|| actor.id.clone())),
-                    picture: actor.icon.clone(),
-                    banner: actor.image.clone(),
-                    nip05,
-                    ..Default::default()
-                }
-                .as_json(),
+                actor_metadata(actor, nip05, followers, following).as_json(),
                 event_tag(
                     actor.id.clone(),
                     actor.tag.iter().filter_map(|t| match t {
@@ -632,12 +1166,15 @@ impl AppState {
             };
             if new {
                 let s = { serde_json::to_vec(&*self.activitypub_accounts.lock()).unwrap() };
-                tokio::fs::File::create("fediverse_accounts.json")
-                    .await
-                    .unwrap()
-                    .write_all(&s)
+                if let Err(e) = self
+                    .write_accounts(
+                        std::path::Path::new(crate::snapshot::FEDIVERSE_ACCOUNTS_PATH),
+                        &s,
+                    )
                     .await
-                    .unwrap()
+                {
+                    error!("could not write the fediverse accounts: {e}");
+                }
             }
             Ok(new)
         } else {
@@ -646,6 +1183,148 @@ impl AppState {
     }
 }
 
+/// The pinned notes of an actor, embedded or not.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FeaturedForDe {
+    #[serde(default)]
+    ordered_items: Vec<OptionForDe<IdOrObject>>,
+}
+
+impl AppState {
+    /// Ids of the notes in a `featured` collection.
+    pub async fn featured_ids(&self, featured: &str) -> Result<Vec<String>, Error> {
+        let c: FeaturedForDe = self
+            .get_activity_json_with_retry(&featured.parse()?)
+            .await?;
+        Ok(c.ordered_items
+            .into_iter()
+            .filter_map(Option::from)
+            .map(|i: IdOrObject| i.id().to_string())
+            .collect())
+    }
+
+    /// `totalItems` of a followers or following collection, fetching the
+    /// collection (but none of its pages) if only its id is embedded.
+    async fn total_items(&self, collection: Option<&CollectionRef>) -> Option<u64> {
+        let id = match collection? {
+            CollectionRef::Collection {
+                total_items: Some(n),
+                ..
+            } => return Some(*n),
+            CollectionRef::Collection { id: Some(id), .. } | CollectionRef::Id(id) => id,
+            CollectionRef::Collection { id: None, .. } => return None,
+        };
+        match self
+            .get_activity_json_and_url::<CollectionForDe>(&id.parse().ok()?)
+            .await
+        {
+            Ok((c, _)) => c.total_items,
+            Err(e) => {
+                debug!("could not get {id}: {e:?}");
+                None
+            }
+        }
+    }
+}
+
+fn actor_metadata(
+    actor: &Actor,
+    nip05: Option<String>,
+    followers: Option<u64>,
+    following: Option<u64>,
+) -> Metadata {
+    let mut metadata = Metadata {
+        name: Some(actor.name.clone()),
+        display_name: Some(actor.name.clone()),
+        about: actor.summary.clone(),
+        website: Some(
+            actor
+                .website
+                .clone()
+                .or_else(|| actor.url.clone())
+                .unwrap_or_else(|| actor.id.clone()),
+        ),
+        picture: actor.icon.clone(),
+        banner: actor.image.clone(),
+        nip05,
+        lud16: actor.lud16.clone(),
+        ..Default::default()
+    };
+    if let Some(n) = followers {
+        metadata = metadata.custom_field("followers_count", n);
+    }
+    if let Some(n) = following {
+        metadata = metadata.custom_field("following_count", n);
+    }
+    metadata
+}
+
+/// An actor may be served behind redirects, but only within the host it was
+/// requested from, and its `id` must be on that host too.
+fn check_actor_redirect(requested: &Uri, final_url: &Url, actor_id: &str) -> Result<(), Error> {
+    let host = requested.host();
+    if final_url.host_str() != host {
+        return Err(Error::BadRequest(Some(format!(
+            "{requested} redirected to {final_url} on another host"
+        ))));
+    }
+    if Url::parse(actor_id)?.host_str() != host {
+        return Err(Error::BadRequest(Some(format!(
+            "{requested} is served as {actor_id} on another host"
+        ))));
+    }
+    Ok(())
+}
+
+/// Outcome of `AppState::send_activity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// The inbox answered with this status.
+    Sent(reqwest::StatusCode),
+    /// Refused by the federation policy.
+    Skipped,
+    /// Queued until read-only mode is lifted.
+    Queued,
+}
+
+pub fn is_retryable_status(status: reqwest::StatusCode) -> bool {
+    status.is_server_error()
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+}
+
+/// Seconds to wait before the `attempts + 1`th attempt.
+fn retry_delay(attempts: u32) -> u64 {
+    (60 * 5) << attempts.min(8)
+}
+
+/// When to retry `d` after an attempt with the result `r`, if at all.
+fn retry_at(d: &mut FailedDelivery, r: &Result<Delivery, Error>, now: u64) -> Option<u64> {
+    if matches!(r, Ok(Delivery::Sent(status)) if !is_retryable_status(*status)) {
+        return None;
+    }
+    d.attempts += 1;
+    let t = next_retry_at(d, now);
+    if t.is_none() {
+        warn!(
+            "gave up delivering to {} after {} attempts",
+            d.inbox, d.attempts
+        );
+    }
+    t
+}
+
+fn next_retry_at(d: &FailedDelivery, now: u64) -> Option<u64> {
+    if d.attempts >= *DELIVERY_MAX_ATTEMPTS
+        || now.saturating_sub(d.first_failed_at) > *DELIVERY_RETENTION_SECS
+    {
+        None
+    } else {
+        Some(now + retry_delay(d.attempts))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ActorOrProxied {
     Proxied(Arc<String>),
@@ -655,6 +1334,8 @@ pub enum ActorOrProxied {
 #[derive(Debug)]
 pub struct Actor {
     pub public_key: sigh::PublicKey,
+    /// `endpoints.sharedInbox` if advertised, so that `broadcast_to_actors` posts
+    /// once per instance, else the personal `inbox`.
     pub inbox: Option<Uri>,
     pub summary: Option<String>,
     pub icon: Option<String>,
@@ -666,6 +1347,43 @@ pub struct Actor {
     pub id: String,
     pub preferred_username: Option<String>,
     pub tag: Vec<NoteTagForDe>,
+    pub also_known_as: Vec<String>,
+    pub followers: Option<CollectionRef>,
+    pub following: Option<CollectionRef>,
+    /// Collection of pinned notes.
+    pub featured: Option<String>,
+    /// From the "Website" profile field.
+    pub website: Option<String>,
+    /// The first profile field which is a lightning address.
+    pub lud16: Option<String>,
+    /// Unix time at which the actor was fetched, see `ACTOR_TTL_SECS`.
+    pub fetched_at: u64,
+}
+
+impl Actor {
+    /// Whether the actor should be fetched again as cached data such as its
+    /// key may be outdated.
+    pub fn is_stale(&self, now: u64, ttl: u64) -> bool {
+        now.saturating_sub(self.fetched_at) >= ttl
+    }
+}
+
+/// `followers` or `following` of an actor.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum CollectionRef {
+    Id(String),
+    #[serde(rename_all = "camelCase")]
+    Collection {
+        id: Option<String>,
+        total_items: Option<u64>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CollectionForDe {
+    total_items: Option<u64>,
 }
 
 pub static HASHTAG_LINK_REGEX: Lazy<Regex> = Lazy::new(|| {
@@ -676,27 +1394,63 @@ pub static HASHTAG_LINK_REGEX: Lazy<Regex> = Lazy::new(|| {
     .unwrap()
 });
 
+/// A hash is not a valid secret key if it is zero or not less than the curve
+/// order, so this is checked when an actor is parsed rather than when signing.
+fn nsec_from_hash(hash: &[u8]) -> Result<nostr_lib::SecretKey, nostr_lib::key::Error> {
+    nostr_lib::SecretKey::from_slice(hash)
+}
+
+/// Secret key of the Nostr account mirroring the actor `id`.
+pub fn actor_nsec(id: &str) -> Result<nostr_lib::SecretKey, nostr_lib::key::Error> {
+    let mut hasher = Sha3_256::default();
+    hasher.update(id.as_bytes());
+    hasher.update(SECRET_KEY.as_bytes());
+    nsec_from_hash(&hasher.finalize_fixed())
+}
+
+/// Name of an actor, never empty: the first of `name`, `preferredUsername`,
+/// a handle guessed from `id` and `id` itself that isn't blank.
+fn display_name(name: Option<&str>, preferred_username: Option<&str>, id: &str) -> String {
+    fn clean(s: &str) -> Option<String> {
+        let s: String = s.chars().filter(|c| !c.is_control()).collect();
+        let s = s.trim();
+        (!s.is_empty()).then(|| s.to_string())
+    }
+    name.and_then(clean)
+        .or_else(|| preferred_username.and_then(clean))
+        .or_else(|| {
+            let url = Url::parse(id).ok()?;
+            let user = url.path_segments()?.rfind(|s| !s.is_empty())?;
+            clean(&format!("{user}@{}", url.host_str()?))
+        })
+        .unwrap_or_else(|| id.chars().take(64).collect())
+}
+
 impl<'a> Deserialize<'a> for ActorOrProxied {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'a>,
     {
         let a = ActorForParse::deserialize(deserializer)?;
+        let fields: Vec<ProfileField> = match a.attachment {
+            Some(ListOrSingle::Single(f)) => vec![f],
+            Some(ListOrSingle::Vec(l)) => l.into_iter().filter_map(Option::from).collect(),
+            None => Vec::new(),
+        };
+        let (website, lud16) = profile_links(&fields);
         let summary = a.summary.map(|a| {
             HASHTAG_LINK_REGEX
                 .replace_all(&html_to_text(&a), "$tag")
                 .into_owned()
         });
-        let mut hasher = Sha3_256::default();
-        hasher.update(a.id.as_bytes());
-        hasher.update(SECRET_KEY.as_bytes());
-        let hash = hasher.finalize_fixed();
-        let nsec = nostr_lib::SecretKey::from_slice(&hash).unwrap();
         if let Some(npub) = a.url.proxied_from {
             Ok(ActorOrProxied::Proxied(Arc::new(npub)))
         } else if let Some(ProxyOf { proxied: npub }) = a.proxy_of {
             Ok(ActorOrProxied::Proxied(Arc::new(npub)))
         } else {
+            let nsec = actor_nsec(&a.id).map_err(|e| {
+                serde::de::Error::custom(format!("could not derive nsec of {}: {e}", a.id))
+            })?;
             Ok(ActorOrProxied::Actor(Arc::new(Actor {
                 public_key: a.public_key.public_key_pem,
                 inbox: a
@@ -707,16 +1461,24 @@ impl<'a> Deserialize<'a> for ActorOrProxied {
                 summary,
                 icon: a.icon.and_then(|a| a.get_first().map(|a| a.url)),
                 image: a.image.and_then(|a| a.get_first().map(|a| a.url)),
-                name: a
-                    .name
-                    .or_else(|| a.preferred_username.clone())
-                    .unwrap_or_else(|| a.id.clone()),
+                name: display_name(a.name.as_deref(), a.preferred_username.as_deref(), &a.id),
                 npub: nostr_lib::Keys::new(nsec.clone()).public_key(),
                 nsec,
                 url: a.url.url,
                 id: a.id.clone(),
                 preferred_username: a.preferred_username,
                 tag: a.tag,
+                also_known_as: match a.also_known_as {
+                    Some(ListOrSingle::Single(id)) => vec![id],
+                    Some(ListOrSingle::Vec(l)) => l.into_iter().filter_map(Option::from).collect(),
+                    None => Vec::new(),
+                },
+                followers: a.followers,
+                following: a.following,
+                featured: a.featured,
+                website,
+                lud16,
+                fetched_at: Timestamp::now().as_u64(),
             })))
         }
     }
@@ -739,6 +1501,112 @@ pub struct ActorForParse {
     proxy_of: Option<ProxyOf>,
     #[serde(default)]
     tag: Vec<NoteTagForDe>,
+    also_known_as: Option<ListOrSingle<String>>,
+    followers: Option<CollectionRef>,
+    following: Option<CollectionRef>,
+    featured: Option<String>,
+    attachment: Option<ListOrSingle<ProfileField>>,
+}
+
+/// An entry of `attachment` of an actor, of which `PropertyValue`s are the
+/// profile fields shown by Mastodon.
+#[derive(Deserialize, Clone, Debug)]
+struct ProfileField {
+    #[serde(rename = "type")]
+    kind: String,
+    name: Option<String>,
+    value: Option<String>,
+}
+
+/// The website and lightning address among profile fields, whose values are
+/// HTML.
+fn profile_links(fields: &[ProfileField]) -> (Option<String>, Option<String>) {
+    static HREF: Lazy<Regex> = Lazy::new(|| Regex::new(r#"href="([^"]+)""#).unwrap());
+    static TAG: Lazy<Regex> = Lazy::new(|| Regex::new(r"<[^>]*>").unwrap());
+    static LUD16: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"^[[:word:].+-]+@[[:word:]-]+(\.[[:word:]-]+)+$").unwrap());
+    let mut website = None;
+    let mut lud16 = None;
+    for f in fields.iter().filter(|f| f.kind == "PropertyValue") {
+        let Some(value) = &f.value else {
+            continue;
+        };
+        let text = TAG.replace_all(value, "");
+        let text = text.trim().trim_start_matches('⚡').trim();
+        if website.is_none()
+            && f.name
+                .as_deref()
+                .is_some_and(|n| n.trim().eq_ignore_ascii_case("website"))
+        {
+            website = HREF
+                .captures(value)
+                .map(|c| c[1].to_string())
+                .or_else(|| Some(text.to_string()))
+                .filter(|u| Url::parse(u).is_ok_and(|u| matches!(u.scheme(), "http" | "https")));
+        }
+        if lud16.is_none() && LUD16.is_match(text) {
+            lud16 = Some(text.to_string());
+        }
+    }
+    (website, lud16)
+}
+
+/// `attributedTo` of a note. When it is an array, the first `Person` or
+/// `Service` is the author and the rest are co-authors. A `Group` is the
+/// author only if no individual is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttributedTo {
+    pub author: String,
+    pub co_authors: Vec<String>,
+    /// Groups the note was posted to which aren't its author.
+    pub groups: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ActorRef {
+    Id(String),
+    Object {
+        #[serde(rename = "type")]
+        kind: String,
+        id: String,
+    },
+}
+
+impl<'de> Deserialize<'de> for AttributedTo {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let refs = match ListOrSingle::<ActorRef>::deserialize(deserializer)? {
+            ListOrSingle::Single(a) => vec![a],
+            ListOrSingle::Vec(l) => l.into_iter().filter_map(Option::from).collect(),
+        };
+        let mut ids = Vec::new();
+        let mut groups = Vec::new();
+        for a in refs {
+            match a {
+                ActorRef::Id(id) => ids.push(id),
+                ActorRef::Object { kind, id } if kind == "Person" || kind == "Service" => {
+                    ids.push(id)
+                }
+                ActorRef::Object { kind, id } if kind == "Group" => groups.push(id),
+                ActorRef::Object { .. } => {}
+            }
+        }
+        if ids.is_empty() && !groups.is_empty() {
+            ids.push(groups.remove(0));
+        }
+        let mut ids = ids.into_iter();
+        let author = ids
+            .next()
+            .ok_or_else(|| serde::de::Error::custom("no usable actor in attributedTo"))?;
+        Ok(AttributedTo {
+            author,
+            co_authors: ids.collect(),
+            groups,
+        })
+    }
 }
 
 #[derive(Deserialize, Clone, Debug, PartialEq)]
@@ -859,6 +1727,9 @@ pub struct UrlStruct {
 pub struct AttachedImage {
     pub url: String,
     pub media_type: Option<String>,
+    /// Alt text
+    pub name: Option<String>,
+    pub blurhash: Option<String>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -898,10 +1769,67 @@ struct EndPoints {
 
 #[cfg(test)]
 mod tests {
-    use super::{ListOrSingle, NoteForDe, UrlStruct};
-    use crate::activity::{ActivityForDeInner, ActorOrProxied, Delete, OptionForDe};
+    use super::{
+        actor_metadata, check_actor_redirect, confirms_deletion, display_name, nsec_from_hash,
+        profile_links, requires_signature, retry_at, signed_get_headers, AnnounceForSer,
+        CollectionRef, Delivery, ListOrSingle, Note, NoteForDe, ProfileField, UrlStruct,
+    };
+    use crate::activity::{ActivityForDeInner, ActorOrProxied, Delete, OptionForDe, UpdateObject};
     use serde::de::IgnoredAny;
 
+    const PEM: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAs8T30Ro4ga5Fo4ArMUfB\niBXwMtHIThmBZEYBhLFUOXNswDADd1LyIZ0yt2qDlIae646C9RWqXB3qrhr3TpcA\nBDBKc1XxffSAmOzNzoFJ2FdXET97KJ2hXhfILcuMPz3MMBBNbpmgOMb4tKFpiFqH\nYhZIJGeTOUQ8VjWaiH8szixKBByVbgZOWisD9Zf39nCSQ3JJ2LvrzUIhfmocfidL\nekUtwSSi7gzr/53KpS08jP5fCaHs7S5NsgeOE6KnWpNrM19hxk7CtRJqvEbAw4yG\nxcDdvW/UYqI6hHYVmYRRkYs4NO34ZfM6v/xcFgmsMwEBaNBE0itMCMziPJ9pvyCc\nQwIDAQAB\n-----END PUBLIC KEY-----\n";
+
+    #[test]
+    fn retry_at_1() {
+        use crate::db::FailedDelivery;
+        use crate::error::Error;
+        use reqwest::StatusCode;
+        let mut d = FailedDelivery {
+            inbox: "https://example.com/inbox".to_string(),
+            author: "https://momostr.pink/users/npub1".to_string(),
+            body: "{}".to_string(),
+            attempts: 1,
+            first_failed_at: 100,
+        };
+        assert_eq!(
+            retry_at(&mut d, &Ok(Delivery::Sent(StatusCode::OK)), 200),
+            None
+        );
+        assert_eq!(
+            retry_at(&mut d, &Ok(Delivery::Sent(StatusCode::GONE)), 200),
+            None
+        );
+        assert_eq!(d.attempts, 1);
+        assert!(
+            retry_at(&mut d, &Ok(Delivery::Sent(StatusCode::BAD_GATEWAY)), 200)
+                .is_some_and(|t| t > 200)
+        );
+        assert!(retry_at(&mut d, &Err(Error::Unavailable(60)), 200).is_some());
+        assert_eq!(d.attempts, 3);
+        d.attempts = u32::MAX - 1;
+        assert_eq!(retry_at(&mut d, &Err(Error::NotFound), 200), None);
+    }
+
+    #[test]
+    fn announce_cc() {
+        let a = AnnounceForSer {
+            id: "note1",
+            object: "https://example.com/notes/1",
+            actor: "https://momostr.pink/users/npub1",
+            published: "2024-03-03T12:00:00Z",
+            object_author: Some("https://example.com/users/a"),
+        };
+        let a = serde_json::to_value(&a).unwrap();
+        assert_eq!(a["to"], serde_json::json!(["Public"]));
+        assert_eq!(
+            a["cc"],
+            serde_json::json!([
+                "https://momostr.pink/users/npub1/followers",
+                "https://example.com/users/a"
+            ])
+        );
+    }
+
     #[test]
     fn activity_de_1() {
         let a = r##"{"@context":"https://www.w3.org/ns/activitystreams","id":"https://example.com/users/example#delete","type":"Delete","to":["https://www.w3.org/ns/activitystreams#Public"],"object":"https://example.com/users/example","signature":{"type":"RsaSignature2017","creator":"https://example.com/users/example#main-key","created":"2024-03-03T06:10:00Z","signatureValue":"GSezGidctZL35ZWgUf4Kw59qwQF+lb/soQ2pvBweNfk3+k2YfgVwCXN4wNBuLwOZ2jAiRyKYlwSC6V52FhgIU0CCUjIYSCUSijPkqbfdj7KshCH3RxrVymqe1jbh+O6epZY5WRDbe93a7NHgiYCdjdWvUR8jNeoHjkOdpq4gB1GoCtfF68tZX/ExnuT28b8kh5EkWyuxp46tQ//uhCKDUI5wCD3oB9PZV7NoeV0tp2xKEjRFQf3dZbUTpdHO8k24sCDl3+aRm9jWnsQ7I/K4FYrFq0RPLxstxq5lnNKhGOpLswYFjNvCW2C4qX3IVce+6aYDcoP+E26QQlgmknxhiA=="}}"##;
@@ -923,7 +1851,12 @@ mod tests {
     fn activity_de_3() {
         let a = r##"{"type":"Update","object":{"@context":["https://www.w3.org/ns/activitystreams","https://w3id.org/security/v1"],"type":"Person","id":"https://example.com/users/a","preferredUsername":"a","name":"test","inbox":"https://momostr.pink/inbox","sharedInbox":"https://momostr.pink/inbox","endpoints":{"sharedInbox":"https://momostr.pink/inbox"},"summary":"list","icon":{"type":"Image","url":"https://image.nostr.build/12f71e76bb9bd2b9b4bea58348c08d78ab7550566a468bb524021bc9875a15c7.jpg"},"manuallyApprovesFollowers":false,"discoverable":true,"publicKey":{"id":"https://example.com/users/a","type":"Key","owner":"https://example.com/users/a","publicKeyPem":"-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAs8T30Ro4ga5Fo4ArMUfB\niBXwMtHIThmBZEYBhLFUOXNswDADd1LyIZ0yt2qDlIae646C9RWqXB3qrhr3TpcA\nBDBKc1XxffSAmOzNzoFJ2FdXET97KJ2hXhfILcuMPz3MMBBNbpmgOMb4tKFpiFqH\nYhZIJGeTOUQ8VjWaiH8szixKBByVbgZOWisD9Zf39nCSQ3JJ2LvrzUIhfmocfidL\nekUtwSSi7gzr/53KpS08jP5fCaHs7S5NsgeOE6KnWpNrM19hxk7CtRJqvEbAw4yG\nxcDdvW/UYqI6hHYVmYRRkYs4NO34ZfM6v/xcFgmsMwEBaNBE0itMCMziPJ9pvyCc\nQwIDAQAB\n-----END PUBLIC KEY-----\n"}}}"##;
         let a: crate::activity::ActivityForDeInner = serde_json::from_str(a).unwrap();
-        assert!(matches!(a, ActivityForDeInner::Update { .. }));
+        assert!(matches!(
+            a,
+            ActivityForDeInner::Update {
+                object: UpdateObject::Actor(_)
+            }
+        ));
     }
 
     #[test]
@@ -940,6 +1873,47 @@ mod tests {
         }
     }
 
+    #[test]
+    fn actor_shared_inbox() {
+        let actor = |name: &str, endpoints: serde_json::Value| {
+            let a = serde_json::json!({
+                "type": "Person",
+                "id": format!("https://example.com/users/{name}"),
+                "inbox": format!("https://example.com/users/{name}/inbox"),
+                "endpoints": endpoints,
+                "publicKey": { "publicKeyPem": PEM },
+            });
+            match serde_json::from_str(&a.to_string()).unwrap() {
+                ActorOrProxied::Actor(a) => a.inbox.clone().unwrap().to_string(),
+                ActorOrProxied::Proxied(_) => panic!(),
+            }
+        };
+        let shared = serde_json::json!({ "sharedInbox": "https://example.com/inbox" });
+        assert_eq!(actor("a", shared.clone()), "https://example.com/inbox");
+        assert_eq!(actor("b", shared), "https://example.com/inbox");
+        assert_eq!(
+            actor("c", serde_json::json!({})),
+            "https://example.com/users/c/inbox"
+        );
+    }
+
+    #[test]
+    fn actor_is_stale() {
+        let a = serde_json::json!({
+            "type": "Person",
+            "id": "https://example.com/users/a",
+            "inbox": "https://example.com/users/a/inbox",
+            "publicKey": { "publicKeyPem": PEM },
+        });
+        let ActorOrProxied::Actor(a) = serde_json::from_str(&a.to_string()).unwrap() else {
+            panic!()
+        };
+        let now = nostr_lib::Timestamp::now().as_u64();
+        assert!(a.fetched_at <= now && a.fetched_at + 60 > now);
+        assert!(!a.is_stale(a.fetched_at + 59, 60));
+        assert!(a.is_stale(a.fetched_at + 60, 60));
+    }
+
     #[test]
     fn actor_de_2() {
         let a = r##"{"@context":["https://www.w3.org/ns/activitystreams","https://w3id.org/security/v1"],"type":"Person","id":"https://example.com/users/a","preferredUsername":"a","name":"test","inbox":"https://momostr.pink/inbox","sharedInbox":"https://momostr.pink/inbox","endpoints":{"sharedInbox":"https://momostr.pink/inbox"},"summary":"list","icon":{"type":"Image","url":"https://image.nostr.build/12f71e76bb9bd2b9b4bea58348c08d78ab7550566a468bb524021bc9875a15c7.jpg"},"manuallyApprovesFollowers":false,"discoverable":true,"publicKey":{"id":"https://example.com/users/a","type":"Key","owner":"https://example.com/users/a","publicKeyPem":"-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAs8T30Ro4ga5Fo4ArMUfB\niBXwMtHIThmBZEYBhLFUOXNswDADd1LyIZ0yt2qDlIae646C9RWqXB3qrhr3TpcA\nBDBKc1XxffSAmOzNzoFJ2FdXET97KJ2hXhfILcuMPz3MMBBNbpmgOMb4tKFpiFqH\nYhZIJGeTOUQ8VjWaiH8szixKBByVbgZOWisD9Zf39nCSQ3JJ2LvrzUIhfmocfidL\nekUtwSSi7gzr/53KpS08jP5fCaHs7S5NsgeOE6KnWpNrM19hxk7CtRJqvEbAw4yG\nxcDdvW/UYqI6hHYVmYRRkYs4NO34ZfM6v/xcFgmsMwEBaNBE0itMCMziPJ9pvyCc\nQwIDAQAB\n-----END PUBLIC KEY-----\n"}}"##;
@@ -990,4 +1964,253 @@ mod tests {
         let a: OptionForDe<UrlStruct> = serde_json::from_str(s).unwrap();
         assert_eq!(a, OptionForDe::None(IgnoredAny));
     }
+
+    #[test]
+    fn nsec_from_hash_1() {
+        assert!(nsec_from_hash(&[0; 32]).is_err());
+        assert!(nsec_from_hash(&[0xff; 32]).is_err());
+        assert!(nsec_from_hash(&[1; 32]).is_ok());
+    }
+
+    #[test]
+    fn actor_behind_redirect() {
+        let requested = "https://example.com/users/a".parse().unwrap();
+        let same_host = url::Url::parse("https://example.com/@a").unwrap();
+        let other_host = url::Url::parse("https://evil.example/@a").unwrap();
+        assert!(check_actor_redirect(&requested, &same_host, "https://example.com/@a").is_ok());
+        assert!(check_actor_redirect(&requested, &other_host, "https://example.com/@a").is_err());
+        assert!(check_actor_redirect(&requested, &same_host, "https://evil.example/@a").is_err());
+    }
+
+    #[test]
+    fn note_with_nostr_event() {
+        let keys = nostr_lib::Keys::generate();
+        let event = nostr_lib::EventBuilder::text_note("hello", [])
+            .to_event(&keys)
+            .unwrap();
+        let note = Note {
+            author: "https://momostr.pink/users/npub1".to_string(),
+            id: "note1".to_string(),
+            nevent: "nevent1".to_string(),
+            content: "hello".to_string(),
+            misskey_content: "hello".to_string(),
+            published: "2024-03-18T02:24:24Z".to_string(),
+            attachment: Vec::new(),
+            quote: None,
+            in_reply_to: None,
+            tag: Vec::new(),
+            summary: None,
+            sensitive: false,
+            article: false,
+            name: None,
+            nostr_event: Some(std::sync::Arc::new(event.clone())),
+        };
+        let v = serde_json::to_value(&note).unwrap();
+        assert_eq!(v["nostrEvent"]["id"], event.id.to_hex());
+        assert_eq!(v["nostrEvent"]["sig"], event.sig.to_string());
+        let e = nostr_lib::Event::from_value(v["nostrEvent"].clone()).unwrap();
+        assert!(e.verify().is_ok());
+
+        let note = Note {
+            nostr_event: None,
+            ..note
+        };
+        assert!(serde_json::to_value(&note)
+            .unwrap()
+            .get("nostrEvent")
+            .is_none());
+    }
+
+    #[test]
+    fn undo_create() {
+        let a = r#"{"type":"Undo","id":"https://example.com/undo/1","actor":"https://example.com/users/a","object":{"type":"Create","actor":"https://example.com/users/a","object":{"type":"Note","id":"https://example.com/notes/1","content":"a","published":"2024-03-18T02:24:24Z","attributedTo":"https://example.com/users/a"}}}"#;
+        let a: ActivityForDeInner = serde_json::from_str(a).unwrap();
+        let ActivityForDeInner::Undo { object, .. } = a else {
+            panic!()
+        };
+        let ActivityForDeInner::Create { object } = *object.activity_inner else {
+            panic!()
+        };
+        assert_eq!(object.id, "https://example.com/notes/1");
+
+        let a = r#"{"type":"Undo","id":"https://example.com/undo/1","actor":"https://example.com/users/a","object":{"type":"Create","actor":"https://example.com/users/a","object":"https://example.com/notes/1"}}"#;
+        let a: ActivityForDeInner = serde_json::from_str(a).unwrap();
+        let ActivityForDeInner::Undo { object, .. } = a else {
+            panic!()
+        };
+        assert!(matches!(
+            *object.activity_inner,
+            ActivityForDeInner::Other(_)
+        ));
+    }
+
+    #[test]
+    fn attributed_to_array() {
+        let note = |attributed_to: serde_json::Value| {
+            serde_json::from_value::<NoteForDe>(serde_json::json!({
+                "id": "https://example.com/notes/1",
+                "content": "a",
+                "published": "2024-03-18T02:24:24Z",
+                "attributedTo": attributed_to,
+            }))
+        };
+        let n = note(serde_json::json!([
+            {"type": "Group", "id": "https://example.com/c/group"},
+            {"type": "Person", "id": "https://example.com/users/a"},
+            "https://example.com/users/b",
+        ]))
+        .unwrap();
+        assert_eq!(n.attributed_to.author, "https://example.com/users/a");
+        assert_eq!(
+            n.attributed_to.co_authors,
+            vec!["https://example.com/users/b".to_string()]
+        );
+        assert_eq!(
+            n.attributed_to.groups,
+            vec!["https://example.com/c/group".to_string()]
+        );
+        let n = note(serde_json::json!("https://example.com/users/a")).unwrap();
+        assert!(n.attributed_to.co_authors.is_empty());
+        let n = note(serde_json::json!([{"type": "Group", "id": "https://example.com/c/group"}]))
+            .unwrap();
+        assert_eq!(n.attributed_to.author, "https://example.com/c/group");
+        assert!(n.attributed_to.groups.is_empty());
+        assert!(
+            note(serde_json::json!([{"type": "Image", "id": "https://example.com/a"}])).is_err()
+        );
+    }
+
+    #[test]
+    fn quote_authorization() {
+        let n: NoteForDe = serde_json::from_value(serde_json::json!({
+            "id": "https://example.com/notes/2",
+            "content": "a",
+            "published": "2024-03-18T02:24:24Z",
+            "attributedTo": "https://example.com/users/a",
+            "quote": "https://example.com/notes/1",
+            "quoteAuthorization": {
+                "type": "QuoteAuthorization",
+                "id": "https://example.com/authorizations/1",
+                "interactionTarget": "https://example.com/notes/1",
+            },
+        }))
+        .unwrap();
+        assert_eq!(n.quote.unwrap().id(), "https://example.com/notes/1");
+    }
+
+    #[test]
+    fn display_name_fallback() {
+        let id = "https://example.com/users/alice";
+        assert_eq!(display_name(Some(" \u{7}"), Some("alice"), id), "alice");
+        assert_eq!(display_name(Some(" Alice\n"), Some("alice"), id), "Alice");
+        assert_eq!(display_name(None, Some(""), id), "alice@example.com");
+        assert_eq!(
+            display_name(None, None, "https://example.com/"),
+            "https://example.com/"
+        );
+    }
+
+    #[test]
+    fn follow_counts_in_metadata() {
+        let a: ActorOrProxied = serde_json::from_value(serde_json::json!({
+            "type": "Person",
+            "id": "https://example.com/users/a",
+            "name": "a",
+            "followers": {"type": "OrderedCollection", "totalItems": 10},
+            "following": "https://example.com/users/a/following",
+            "publicKey": {"publicKeyPem": PEM},
+        }))
+        .unwrap();
+        let ActorOrProxied::Actor(a) = a else {
+            panic!()
+        };
+        assert_eq!(
+            a.followers,
+            Some(CollectionRef::Collection {
+                id: None,
+                total_items: Some(10)
+            })
+        );
+        let m = actor_metadata(&a, None, Some(10), Some(3));
+        assert_eq!(m.custom["followers_count"], 10);
+        assert_eq!(m.custom["following_count"], 3);
+        assert!(actor_metadata(&a, None, None, None).custom.is_empty());
+    }
+
+    #[test]
+    fn profile_fields_in_metadata() {
+        let a: ActorOrProxied = serde_json::from_value(serde_json::json!({
+            "type": "Person",
+            "id": "https://example.com/users/a",
+            "url": "https://example.com/@a",
+            "summary": "<p>hello</p>",
+            "icon": {"type": "Image", "url": "https://example.com/icon.png"},
+            "image": {"type": "Image", "url": "https://example.com/banner.png"},
+            "attachment": [
+                {"type": "PropertyValue", "name": "Pronouns", "value": "they/them"},
+                {"type": "PropertyValue", "name": "Website", "value": "<a href=\"https://a.example.org/\" rel=\"me nofollow noopener\"><span class=\"invisible\">https://</span><span class=\"\">a.example.org/</span></a>"},
+                {"type": "PropertyValue", "name": "⚡", "value": "a@getalby.com"},
+                {"type": "IdentityProof", "name": "a", "signatureValue": "x"},
+            ],
+            "publicKey": {"publicKeyPem": PEM},
+        }))
+        .unwrap();
+        let ActorOrProxied::Actor(a) = a else {
+            panic!()
+        };
+        let m = actor_metadata(&a, None, None, None);
+        assert_eq!(m.website.as_deref(), Some("https://a.example.org/"));
+        assert_eq!(m.lud16.as_deref(), Some("a@getalby.com"));
+        assert_eq!(m.about.as_deref(), Some("hello"));
+        assert_eq!(m.picture.as_deref(), Some("https://example.com/icon.png"));
+        assert_eq!(m.banner.as_deref(), Some("https://example.com/banner.png"));
+        let fields = [ProfileField {
+            kind: "PropertyValue".to_string(),
+            name: Some("Website".to_string()),
+            value: Some("not a url".to_string()),
+        }];
+        assert_eq!(profile_links(&fields), (None, None));
+    }
+
+    #[test]
+    fn accept_de() {
+        let a = r#"{"type":"Accept","actor":"https://example.com/users/a","object":{"type":"Follow","id":"https://momostr.pink/follow/npub1/a","actor":"https://momostr.pink/users/npub1","object":"https://example.com/users/a"}}"#;
+        let a: ActivityForDeInner = serde_json::from_str(a).unwrap();
+        let ActivityForDeInner::Accept { object } = a else {
+            panic!()
+        };
+        assert_eq!(object.id(), "https://momostr.pink/follow/npub1/a");
+        let a = r#"{"type":"Accept","actor":"https://example.com/users/a","object":"https://momostr.pink/follow/npub1/a"}"#;
+        let a: ActivityForDeInner = serde_json::from_str(a).unwrap();
+        assert!(matches!(a, ActivityForDeInner::Accept { .. }));
+    }
+
+    #[test]
+    fn signed_get() {
+        use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+        use sigh::Key;
+        assert!(requires_signature(reqwest::StatusCode::UNAUTHORIZED));
+        assert!(requires_signature(reqwest::StatusCode::FORBIDDEN));
+        assert!(!requires_signature(reqwest::StatusCode::NOT_FOUND));
+        assert!(confirms_deletion(reqwest::StatusCode::GONE));
+        assert!(confirms_deletion(reqwest::StatusCode::NOT_FOUND));
+        assert!(!confirms_deletion(reqwest::StatusCode::UNAUTHORIZED));
+        assert!(!confirms_deletion(reqwest::StatusCode::SERVICE_UNAVAILABLE));
+        let url: axum::http::Uri = "https://locked.example.com/users/a".parse().unwrap();
+        let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let pem = key.to_pkcs8_pem(LineEnding::default()).unwrap();
+        let private_key = sigh::PrivateKey::from_pem(pem.as_bytes()).unwrap();
+        let pem = rsa::RsaPublicKey::from(&key)
+            .to_public_key_pem(LineEnding::default())
+            .unwrap();
+        let public_key = sigh::PublicKey::from_pem(pem.as_bytes()).unwrap();
+        let key_id = "https://momostr.example.com/users/npub1bot";
+        let mut r = axum::http::Request::get(url.clone()).body(()).unwrap();
+        *r.headers_mut() = signed_get_headers(&url, &private_key, key_id);
+        let signature = sigh::Signature::from(&r);
+        assert_eq!(signature.key_id(), Some(key_id));
+        let headers = signature.headers().unwrap();
+        assert_eq!(&headers[..3], ["(request-target)", "host", "date"]);
+        assert!(signature.verify(&public_key).unwrap());
+    }
 }
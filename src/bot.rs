@@ -1,10 +1,29 @@
 use crate::nostr_to_ap::update_follow_list;
 use crate::server::AppState;
-use crate::{BOT_SEC, NPUB_REG};
-use nostr_lib::{Event, EventBuilder, Keys, Kind, Marker, Tag};
+use crate::{AMPLIFY_AUTHORS, AMPLIFY_HASHTAGS, BOT_KEYS, BOT_SEC, NPUB_REG};
+use nostr_lib::{Event, EventBuilder, Keys, Kind, Marker, PublicKey, Tag};
 use relay_pool::Filter;
 use std::sync::Arc;
 use std::time::Duration;
+use tracing::{error, info};
+
+/// Restarts the mirror of `npub`, following again the accounts it follows.
+pub async fn restart_npub(state: &Arc<AppState>, npub: &PublicKey) {
+    state.db.restart_npub(npub);
+    if let Some(e) = state
+        .get_nostr_event_with_timeout(
+            Filter {
+                authors: Some([*npub].into_iter().collect()),
+                kinds: Some([Kind::ContactList].into_iter().collect()),
+                ..Default::default()
+            },
+            Duration::from_secs(10),
+        )
+        .await
+    {
+        update_follow_list(state, e.event).await;
+    }
+}
 
 pub async fn handle_message_to_bot(state: &Arc<AppState>, event: Arc<Event>) {
     let command = NPUB_REG.replace_all(&event.content, "");
@@ -51,25 +70,12 @@ pub async fn handle_message_to_bot(state: &Arc<AppState>, event: Arc<Event>) {
             }
         } else if command == "restart my mirror" {
             if stopped {
-                state.db.restart_npub(npub);
-                if let Some(e) = state
-                    .get_nostr_event_with_timeout(
-                        Filter {
-                            authors: Some([*npub].into_iter().collect()),
-                            kinds: Some([Kind::ContactList].into_iter().collect()),
-                            ..Default::default()
-                        },
-                        Duration::from_secs(10),
-                    )
-                    .await
-                {
-                    update_follow_list(state, e.event).await;
-                }
+                restart_npub(state, npub).await;
                 if state
                     .nostr_account_to_followers
                     .lock()
                     .get(npub)
-                    .map_or(false, |a| !a.is_empty())
+                    .is_some_and(|a| !a.is_empty())
                 {
                     "Restarted.".to_string()
                 } else {
@@ -83,7 +89,7 @@ pub async fn handle_message_to_bot(state: &Arc<AppState>, event: Arc<Event>) {
                 .nostr_account_to_followers
                 .lock()
                 .get(npub)
-                .map_or(false, |a| !a.is_empty())
+                .is_some_and(|a| !a.is_empty())
             {
                 "Your mirror is not stopped. Your mirror is already working.".to_string()
             } else {
@@ -133,3 +139,57 @@ pub async fn handle_message_to_bot(state: &Arc<AppState>, event: Arc<Event>) {
         .unwrap();
     state.nostr_send(Arc::new(e)).await;
 }
+
+/// Reposts by the bot a note newly delivered from `actor_id`, if it matches
+/// the amplification rules. Notes fetched only as context of another one,
+/// such as thread parents, are not amplified.
+pub async fn amplify(state: &Arc<AppState>, event: &Event, actor_id: &str) {
+    if let Some(repost) = amplification(event, actor_id) {
+        info!("amplifying {}", event.id);
+        state.nostr_send(Arc::new(repost)).await;
+    }
+}
+
+fn amplification(event: &Event, actor_id: &str) -> Option<Event> {
+    if !is_amplified(event, actor_id, &AMPLIFY_HASHTAGS, &AMPLIFY_AUTHORS) {
+        return None;
+    }
+    EventBuilder::repost(event, None)
+        .to_event(&BOT_KEYS)
+        .map_err(|e| error!("could not sign a repost of {}: {e}", event.id))
+        .ok()
+}
+
+fn is_amplified(event: &Event, actor_id: &str, hashtags: &[&str], authors: &[&str]) -> bool {
+    authors.contains(&actor_id)
+        || event.tags.iter().any(|t| match t {
+            Tag::Hashtag(h) => hashtags.iter().any(|a| a.eq_ignore_ascii_case(h)),
+            _ => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_amplified;
+    use nostr_lib::{EventBuilder, Keys, Kind, Tag};
+
+    #[test]
+    fn amplified_note_is_reposted() {
+        let note = EventBuilder::text_note("#Nostr", [Tag::Hashtag("Nostr".to_string())])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let actor = "https://example.com/users/a";
+        assert!(is_amplified(&note, actor, &["nostr"], &[]));
+        assert!(is_amplified(&note, actor, &[], &[actor]));
+        assert!(!is_amplified(
+            &note,
+            actor,
+            &["other"],
+            &["https://example.com/users/b"]
+        ));
+        let repost = EventBuilder::repost(&note, None)
+            .to_event(&Keys::generate())
+            .unwrap();
+        assert_eq!(repost.kind, Kind::Repost);
+    }
+}
@@ -1,12 +1,17 @@
 use crate::server::InternalApId;
 use lru::LruCache;
 use nostr_lib::key::PublicKey;
+use nostr_lib::EventId;
 use parking_lot::Mutex;
 use rocksdb::DB as Rocks;
 use rustc_hash::FxHashSet;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use std::fs::create_dir_all;
+use std::marker::PhantomData;
 use std::num::NonZeroUsize;
-use std::sync::atomic::{self, AtomicU32};
+use std::path::Path;
+use std::sync::atomic::{self, AtomicU32, AtomicUsize};
 use std::sync::Arc;
 
 #[derive(Debug)]
@@ -17,13 +22,20 @@ pub struct Db {
     event_id_to_inboxes: Rocks,
     nostr_to_followee: Rocks,
     nostr_to_followee_cache: Mutex<LruCache<nostr_lib::PublicKey, Arc<FxHashSet<Arc<String>>>>>,
-    ap_id_to_event_id: Rocks,
-    ap_id_to_event_id_cache: Mutex<LruCache<InternalApId<'static>, Option<nostr_lib::EventId>>>,
+    ap_id_to_event_id: ApIdToEventId,
     stopped_npub: Rocks,
     stopped_npub_on_memory: Mutex<FxHashSet<PublicKey>>,
     stopped_ap: Rocks,
     stopped_ap_on_memory: Mutex<FxHashSet<String>>,
+    deleted_npub: Rocks,
+    deleted_npub_on_memory: Mutex<FxHashSet<PublicKey>>,
     event_counter: AtomicU32,
+    failed_deliveries: FailedDeliveries,
+    follow_states: FollowStates,
+    deleted_events: DeletedEvents,
+    inbox_queue: Queue<QueuedActivity>,
+    /// JSON of the events to publish, held back in read-only mode.
+    event_queue: Queue<String>,
 }
 
 impl Db {
@@ -56,7 +68,7 @@ impl Db {
                 .collect(),
         );
         let ap_id_to_event_id =
-            Rocks::open(&opts, config_dir.join(env!("ROCKS_DB_AP_ID_TO_EVENT_ID"))).unwrap();
+            ApIdToEventId::open(config_dir.join(env!("ROCKS_DB_AP_ID_TO_EVENT_ID")));
         let stopped_ap = Rocks::open(&opts, config_dir.join(env!("ROCKS_DB_STOPPED_AP"))).unwrap();
         let stopped_ap_on_memory = Mutex::new(
             stopped_ap
@@ -64,6 +76,34 @@ impl Db {
                 .map(|a| String::from_utf8(a.unwrap().0.to_vec()).unwrap())
                 .collect(),
         );
+        let deleted_npub = Rocks::open(
+            &opts,
+            config_dir.join(option_env!("ROCKS_DB_DELETED_NPUB").unwrap_or("deleted_npub.rocksdb")),
+        )
+        .unwrap();
+        let deleted_npub_on_memory = Mutex::new(
+            deleted_npub
+                .iterator(rocksdb::IteratorMode::Start)
+                .map(|a| PublicKey::from_slice(&a.unwrap().0).unwrap())
+                .collect(),
+        );
+        let failed_deliveries = FailedDeliveries::open(config_dir.join(
+            option_env!("ROCKS_DB_FAILED_DELIVERIES").unwrap_or("failed_deliveries.rocksdb"),
+        ));
+        let follow_states = FollowStates::open(
+            config_dir
+                .join(option_env!("ROCKS_DB_FOLLOW_STATES").unwrap_or("follow_states.rocksdb")),
+        );
+        let deleted_events = DeletedEvents::open(
+            config_dir
+                .join(option_env!("ROCKS_DB_DELETED_EVENTS").unwrap_or("deleted_events.rocksdb")),
+        );
+        let inbox_queue = Queue::open(
+            config_dir.join(option_env!("ROCKS_DB_INBOX_QUEUE").unwrap_or("inbox_queue.rocksdb")),
+        );
+        let event_queue = Queue::open(
+            config_dir.join(option_env!("ROCKS_DB_EVENT_QUEUE").unwrap_or("event_queue.rocksdb")),
+        );
         Self {
             inbox_to_id,
             id_to_inbox,
@@ -73,11 +113,17 @@ impl Db {
             nostr_to_followee,
             nostr_to_followee_cache: Mutex::new(LruCache::new(NonZeroUsize::new(1000).unwrap())),
             ap_id_to_event_id,
-            ap_id_to_event_id_cache: Mutex::new(LruCache::new(NonZeroUsize::new(1000).unwrap())),
             stopped_npub,
             stopped_npub_on_memory,
             stopped_ap,
             stopped_ap_on_memory,
+            deleted_npub,
+            deleted_npub_on_memory,
+            failed_deliveries,
+            follow_states,
+            deleted_events,
+            inbox_queue,
+            event_queue,
         }
     }
 
@@ -156,31 +202,26 @@ impl Db {
         ap_id: InternalApId<'static>,
         event_id: nostr_lib::EventId,
     ) {
-        self.ap_id_to_event_id
-            .put(ap_id.as_bytes(), event_id.to_bytes())
-            .unwrap();
-        self.ap_id_to_event_id_cache
-            .lock()
-            .push(ap_id, Some(event_id));
+        self.ap_id_to_event_id.insert(ap_id, event_id)
     }
 
     pub fn get_event_id_from_ap_id(
         &self,
         ap_id: &InternalApId<'static>,
     ) -> Option<nostr_lib::EventId> {
-        {
-            if let Some(a) = self.ap_id_to_event_id_cache.lock().get(ap_id) {
-                return *a;
-            }
-        }
-        let r = Some(
-            nostr_lib::EventId::from_slice(&self.ap_id_to_event_id.get(ap_id.as_bytes()).unwrap()?)
-                .unwrap(),
-        );
-        self.ap_id_to_event_id_cache
-            .lock()
-            .put(ap_id.clone().into_owned(), r);
-        r
+        self.ap_id_to_event_id.get(ap_id)
+    }
+
+    /// Every version of an edited note, oldest first.
+    pub fn get_event_ids_from_ap_id(
+        &self,
+        ap_id: &InternalApId<'static>,
+    ) -> Vec<nostr_lib::EventId> {
+        self.ap_id_to_event_id.get_all(ap_id)
+    }
+
+    pub fn remove_ap_id_to_event_id(&self, ap_id: &InternalApId<'static>) {
+        self.ap_id_to_event_id.remove(ap_id)
     }
 
     pub fn is_stopped_npub(&self, npub: &PublicKey) -> bool {
@@ -197,6 +238,17 @@ impl Db {
         self.stopped_npub.delete(npub.to_bytes()).unwrap();
     }
 
+    /// Whether `npub` asked for its account to be removed. Its actor is a `Tombstone`.
+    pub fn is_deleted_npub(&self, npub: &PublicKey) -> bool {
+        self.deleted_npub_on_memory.lock().contains(npub)
+    }
+
+    pub fn delete_npub(&self, npub: &PublicKey) {
+        self.stop_npub(npub);
+        self.deleted_npub_on_memory.lock().insert(*npub);
+        self.deleted_npub.put(npub.to_bytes(), []).unwrap();
+    }
+
     pub fn is_stopped_ap(&self, id: &str) -> bool {
         self.stopped_ap_on_memory.lock().contains(id)
     }
@@ -210,4 +262,531 @@ impl Db {
         self.stopped_ap_on_memory.lock().remove(id);
         self.stopped_ap.delete(id.as_bytes()).unwrap();
     }
+
+    pub fn push_failed_delivery(&self, next_retry_at: u64, delivery: &FailedDelivery) {
+        self.failed_deliveries.push(next_retry_at, delivery);
+    }
+
+    /// Deliveries due at `now`, with the keys to remove them by once retried.
+    pub fn due_failed_deliveries(&self, now: u64) -> Vec<(QueueKey, FailedDelivery)> {
+        self.failed_deliveries.due(now)
+    }
+
+    pub fn remove_failed_delivery(&self, key: &[u8]) {
+        self.failed_deliveries.remove(key);
+    }
+
+    /// Records that `author` deleted `event_id` with a Kind 5.
+    pub fn insert_deleted_event(&self, event_id: &EventId, author: &PublicKey) {
+        self.deleted_events.insert(event_id, author);
+    }
+
+    /// The user who deleted `event_id`, if it was deleted.
+    pub fn get_deleted_event(&self, event_id: &EventId) -> Option<PublicKey> {
+        self.deleted_events.get(event_id)
+    }
+
+    pub fn push_queued_activity(&self, activity: &QueuedActivity) {
+        self.inbox_queue.push(activity);
+    }
+
+    /// The queued activities in order of arrival, with the keys to remove
+    /// them by once they are processed.
+    pub fn queued_activities(&self) -> Vec<(QueueKey, QueuedActivity)> {
+        self.inbox_queue.entries()
+    }
+
+    pub fn remove_queued_activity(&self, key: &[u8]) {
+        self.inbox_queue.remove(key);
+    }
+
+    pub fn replace_queued_activity(&self, key: &[u8], activity: &QueuedActivity) {
+        self.inbox_queue.replace(key, activity);
+    }
+
+    pub fn queued_activity_count(&self) -> usize {
+        self.inbox_queue.len()
+    }
+
+    pub fn push_queued_event(&self, event: &nostr_lib::Event) {
+        self.event_queue.push(&nostr_lib::JsonUtil::as_json(event));
+    }
+
+    /// The events held back in read-only mode in order.
+    pub fn queued_events(&self) -> Vec<(QueueKey, nostr_lib::Event)> {
+        self.event_queue
+            .entries()
+            .into_iter()
+            .filter_map(|(key, e)| Some((key, nostr_lib::JsonUtil::from_json(e).ok()?)))
+            .collect()
+    }
+
+    pub fn remove_queued_event(&self, key: &[u8]) {
+        self.event_queue.remove(key);
+    }
+
+    pub fn insert_pending_follow(&self, follow_id: &str, now: u64) {
+        self.follow_states
+            .put(follow_id, FollowState::Pending { since: now });
+    }
+
+    /// Returns `false` if we never sent the follow or it was already confirmed.
+    pub fn confirm_follow(&self, follow_id: &str, now: u64) -> bool {
+        self.follow_states.confirm(follow_id, now)
+    }
+
+    pub fn remove_follow(&self, follow_id: &str) {
+        self.follow_states.remove(follow_id);
+    }
+
+    /// Numbers of pending and confirmed follows.
+    pub fn follow_counts(&self) -> (usize, usize) {
+        self.follow_states.counts()
+    }
+
+    /// Ids of follows pending since before `before`.
+    pub fn stale_pending_follows(&self, before: u64) -> Vec<String> {
+        self.follow_states.pending_before(before)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FailedDelivery {
+    pub inbox: String,
+    pub author: String,
+    pub body: String,
+    pub attempts: u32,
+    pub first_failed_at: u64,
+}
+
+/// Deliveries waiting to be retried, keyed by the time of the next attempt so
+/// that due entries come first.
+#[derive(Debug)]
+struct FailedDeliveries(Rocks);
+
+impl FailedDeliveries {
+    fn open(path: impl AsRef<Path>) -> Self {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.set_max_log_file_size(0);
+        Self(Rocks::open(&opts, path).unwrap())
+    }
+
+    fn push(&self, next_retry_at: u64, delivery: &FailedDelivery) {
+        let mut key = Vec::with_capacity(12);
+        key.extend(next_retry_at.to_be_bytes());
+        key.extend(rand::random::<u32>().to_be_bytes());
+        self.0
+            .put(key, rmp_serde::to_vec(delivery).unwrap())
+            .unwrap();
+    }
+
+    fn due(&self, now: u64) -> Vec<(QueueKey, FailedDelivery)> {
+        let mut due = Vec::new();
+        for a in self.0.iterator(rocksdb::IteratorMode::Start) {
+            let (key, value) = a.unwrap();
+            if u64::from_be_bytes(key[..8].try_into().unwrap()) > now {
+                break;
+            }
+            due.push((key, rmp_serde::from_slice(&value).unwrap()));
+        }
+        due
+    }
+
+    fn remove(&self, key: &[u8]) {
+        self.0.delete(key).unwrap();
+    }
+}
+
+/// An inbound request to `/inbox` with what is needed to verify its signature.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueuedActivity {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+    /// Attempts which failed with a retryable error.
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+/// Key of an entry of a `Queue`.
+pub type QueueKey = Box<[u8]>;
+
+/// Entries held in read-only mode, keyed by their time of arrival so that
+/// they are processed in order. They are removed only once processed, so
+/// that they survive a crash while the queue is drained.
+#[derive(Debug)]
+struct Queue<T>(Rocks, AtomicUsize, PhantomData<T>);
+
+impl<T: Serialize + DeserializeOwned> Queue<T> {
+    fn open(path: impl AsRef<Path>) -> Self {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.set_max_log_file_size(0);
+        let db = Rocks::open(&opts, path).unwrap();
+        let len = db.iterator(rocksdb::IteratorMode::Start).count();
+        Self(db, AtomicUsize::new(len), PhantomData)
+    }
+
+    fn len(&self) -> usize {
+        self.1.load(atomic::Ordering::Relaxed)
+    }
+
+    fn push(&self, item: &T) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap();
+        let mut key = Vec::with_capacity(20);
+        key.extend(now.as_nanos().to_be_bytes());
+        key.extend(rand::random::<u32>().to_be_bytes());
+        self.0.put(key, rmp_serde::to_vec(item).unwrap()).unwrap();
+        self.1.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    /// Replaces the entry at `key`, keeping its place in the queue.
+    fn replace(&self, key: &[u8], item: &T) {
+        self.0.put(key, rmp_serde::to_vec(item).unwrap()).unwrap();
+    }
+
+    fn entries(&self) -> Vec<(QueueKey, T)> {
+        self.0
+            .iterator(rocksdb::IteratorMode::Start)
+            .map(|a| {
+                let (key, value) = a.unwrap();
+                (key, rmp_serde::from_slice(&value).unwrap())
+            })
+            .collect()
+    }
+
+    fn remove(&self, key: &[u8]) {
+        if self.0.get_pinned(key).unwrap().is_some() {
+            self.0.delete(key).unwrap();
+            self.1.fetch_sub(1, atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+/// Event ids of bridged activities, keyed by `InternalApId`. Written through
+/// to disk so that activities are not bridged again after a restart; only the
+/// recently used entries are kept in memory. The value holds every event
+/// bridged from the object, oldest first, as the versions of an edited note
+/// are published as separate events.
+#[derive(Debug)]
+struct ApIdToEventId {
+    db: Rocks,
+    cache: Mutex<LruCache<InternalApId<'static>, Vec<nostr_lib::EventId>>>,
+}
+
+impl ApIdToEventId {
+    fn open(path: impl AsRef<Path>) -> Self {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.set_max_log_file_size(0);
+        Self {
+            db: Rocks::open(&opts, path).unwrap(),
+            cache: Mutex::new(LruCache::new(NonZeroUsize::new(1000).unwrap())),
+        }
+    }
+
+    fn insert(&self, ap_id: InternalApId<'static>, event_id: nostr_lib::EventId) {
+        let mut ids = self.get_all(&ap_id);
+        if ids.contains(&event_id) {
+            return;
+        }
+        ids.push(event_id);
+        let bytes: Vec<u8> = ids.iter().flat_map(|id| id.to_bytes()).collect();
+        self.db.put(ap_id.as_bytes(), bytes).unwrap();
+        self.cache.lock().push(ap_id, ids);
+    }
+
+    fn remove(&self, ap_id: &InternalApId<'static>) {
+        self.db.delete(ap_id.as_bytes()).unwrap();
+        self.cache.lock().pop(ap_id);
+    }
+
+    /// The first event bridged from `ap_id`.
+    fn get(&self, ap_id: &InternalApId<'static>) -> Option<nostr_lib::EventId> {
+        self.get_all(ap_id).first().copied()
+    }
+
+    fn get_all(&self, ap_id: &InternalApId<'static>) -> Vec<nostr_lib::EventId> {
+        if let Some(a) = self.cache.lock().get(ap_id) {
+            return a.clone();
+        }
+        let Some(bytes) = self.db.get_pinned(ap_id.as_bytes()).unwrap() else {
+            return Vec::new();
+        };
+        let r: Vec<_> = bytes
+            .chunks_exact(32)
+            .map(|id| nostr_lib::EventId::from_slice(id).unwrap())
+            .collect();
+        self.cache.lock().put(ap_id.clone(), r.clone());
+        r
+    }
+}
+
+/// Authors of the Kind 5 deletions of Nostr events, keyed by event id, so that
+/// deleted notes are served as `Tombstone`s.
+#[derive(Debug)]
+struct DeletedEvents(Rocks);
+
+impl DeletedEvents {
+    fn open(path: impl AsRef<Path>) -> Self {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.set_max_log_file_size(0);
+        Self(Rocks::open(&opts, path).unwrap())
+    }
+
+    /// Keeps the first deletion recorded for `event_id`.
+    fn insert(&self, event_id: &EventId, author: &PublicKey) {
+        if self.0.get_pinned(event_id.as_bytes()).unwrap().is_none() {
+            self.0.put(event_id.as_bytes(), author.to_bytes()).unwrap();
+        }
+    }
+
+    fn get(&self, event_id: &EventId) -> Option<PublicKey> {
+        let a = self.0.get_pinned(event_id.as_bytes()).unwrap()?;
+        Some(PublicKey::from_slice(&a).unwrap())
+    }
+}
+
+/// State of a follow sent on behalf of a Nostr user, until the remote accepts it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FollowState {
+    Pending { since: u64 },
+    Confirmed { since: u64 },
+}
+
+/// Follow states keyed by the id of the `Follow` activity.
+#[derive(Debug)]
+struct FollowStates(Rocks);
+
+impl FollowStates {
+    fn open(path: impl AsRef<Path>) -> Self {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.set_max_log_file_size(0);
+        Self(Rocks::open(&opts, path).unwrap())
+    }
+
+    fn get(&self, follow_id: &str) -> Option<FollowState> {
+        self.0
+            .get_pinned(follow_id.as_bytes())
+            .unwrap()
+            .map(|a| rmp_serde::from_slice(&a).unwrap())
+    }
+
+    fn put(&self, follow_id: &str, state: FollowState) {
+        self.0
+            .put(follow_id.as_bytes(), rmp_serde::to_vec(&state).unwrap())
+            .unwrap();
+    }
+
+    fn confirm(&self, follow_id: &str, now: u64) -> bool {
+        if let Some(FollowState::Pending { .. }) = self.get(follow_id) {
+            self.put(follow_id, FollowState::Confirmed { since: now });
+            true
+        } else {
+            false
+        }
+    }
+
+    fn remove(&self, follow_id: &str) {
+        self.0.delete(follow_id.as_bytes()).unwrap();
+    }
+
+    fn states(&self) -> impl Iterator<Item = (String, FollowState)> + '_ {
+        self.0.iterator(rocksdb::IteratorMode::Start).map(|a| {
+            let (key, value) = a.unwrap();
+            (
+                String::from_utf8(key.to_vec()).unwrap(),
+                rmp_serde::from_slice(&value).unwrap(),
+            )
+        })
+    }
+
+    fn counts(&self) -> (usize, usize) {
+        self.states()
+            .fold((0, 0), |(pending, confirmed), (_, s)| match s {
+                FollowState::Pending { .. } => (pending + 1, confirmed),
+                FollowState::Confirmed { .. } => (pending, confirmed + 1),
+            })
+    }
+
+    fn pending_before(&self, before: u64) -> Vec<String> {
+        self.states()
+            .filter_map(|(id, s)| match s {
+                FollowState::Pending { since } if since < before => Some(id),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ApIdToEventId, DeletedEvents, FailedDeliveries, FailedDelivery, FollowState, FollowStates,
+        Queue, QueuedActivity,
+    };
+    use crate::server::InternalApId;
+    use std::borrow::Cow;
+
+    #[test]
+    fn failed_deliveries_survive_reopen() {
+        let path = std::env::temp_dir().join(format!(
+            "momostr-failed-deliveries-{}",
+            rand::random::<u64>()
+        ));
+        let delivery = FailedDelivery {
+            inbox: "https://example.com/inbox".to_string(),
+            author: "https://momostr.pink/users/npub1".to_string(),
+            body: "{}".to_string(),
+            attempts: 1,
+            first_failed_at: 100,
+        };
+        {
+            let q = FailedDeliveries::open(&path);
+            q.push(200, &delivery);
+            q.push(400, &delivery);
+        }
+        let q = FailedDeliveries::open(&path);
+        let deliveries =
+            |due: Vec<(_, FailedDelivery)>| due.into_iter().map(|(_, d)| d).collect::<Vec<_>>();
+        assert!(q.due(199).is_empty());
+        // kept until it is removed after the attempt
+        assert_eq!(deliveries(q.due(300)), vec![delivery.clone()]);
+        let (key, _) = q.due(300).remove(0);
+        q.remove(&key);
+        assert!(q.due(300).is_empty());
+        assert_eq!(deliveries(q.due(400)), vec![delivery]);
+        drop(q);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn accept_confirms_pending_follow() {
+        let path =
+            std::env::temp_dir().join(format!("momostr-follow-states-{}", rand::random::<u64>()));
+        let s = FollowStates::open(&path);
+        assert!(!s.confirm("https://momostr.pink/follow/a/b", 200));
+        s.put(
+            "https://momostr.pink/follow/a/b",
+            FollowState::Pending { since: 100 },
+        );
+        s.put(
+            "https://momostr.pink/follow/a/c",
+            FollowState::Pending { since: 300 },
+        );
+        assert_eq!(s.counts(), (2, 0));
+        assert_eq!(
+            s.pending_before(200),
+            vec!["https://momostr.pink/follow/a/b".to_string()]
+        );
+        assert!(s.confirm("https://momostr.pink/follow/a/b", 200));
+        assert_eq!(
+            s.get("https://momostr.pink/follow/a/b"),
+            Some(FollowState::Confirmed { since: 200 })
+        );
+        assert!(!s.confirm("https://momostr.pink/follow/a/b", 300));
+        assert_eq!(s.counts(), (1, 1));
+        assert!(s.pending_before(200).is_empty());
+        drop(s);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn ap_id_to_event_id_survives_reopen() {
+        let path = std::env::temp_dir().join(format!("momostr-ap-id-{}", rand::random::<u64>()));
+        let ap_id =
+            InternalApId::get_unchecked(Cow::Borrowed("https://example.com/notes/1")).into_owned();
+        let event_id = nostr_lib::EventId::all_zeros();
+        {
+            let m = ApIdToEventId::open(&path);
+            assert_eq!(m.get(&ap_id), None);
+            m.insert(ap_id.clone(), event_id);
+        }
+        let m = ApIdToEventId::open(&path);
+        assert_eq!(m.get(&ap_id), Some(event_id));
+        let edit = nostr_lib::EventBuilder::text_note("edited", [])
+            .to_event(&nostr_lib::Keys::generate())
+            .unwrap()
+            .id;
+        m.insert(ap_id.clone(), edit);
+        m.insert(ap_id.clone(), edit);
+        drop(m);
+        let m = ApIdToEventId::open(&path);
+        assert_eq!(m.get(&ap_id), Some(event_id));
+        assert_eq!(m.get_all(&ap_id), vec![event_id, edit]);
+        m.remove(&ap_id);
+        assert!(m.get_all(&ap_id).is_empty());
+        drop(m);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn deleted_events_survive_reopen() {
+        let path =
+            std::env::temp_dir().join(format!("momostr-deleted-events-{}", rand::random::<u64>()));
+        let event_id = nostr_lib::EventId::all_zeros();
+        let author = nostr_lib::Keys::generate().public_key();
+        {
+            let d = DeletedEvents::open(&path);
+            assert_eq!(d.get(&event_id), None);
+            d.insert(&event_id, &author);
+        }
+        let d = DeletedEvents::open(&path);
+        assert_eq!(d.get(&event_id), Some(author));
+        d.insert(&event_id, &nostr_lib::Keys::generate().public_key());
+        assert_eq!(d.get(&event_id), Some(author));
+        drop(d);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn queued_entries_survive_until_processed() {
+        let path =
+            std::env::temp_dir().join(format!("momostr-inbox-queue-{}", rand::random::<u64>()));
+        let activity = |body: &str| QueuedActivity {
+            method: "POST".to_string(),
+            uri: "/inbox".to_string(),
+            headers: vec![("digest".to_string(), b"SHA-256=...".to_vec())],
+            body: body.as_bytes().to_vec(),
+            attempts: 0,
+        };
+        {
+            let q = Queue::open(&path);
+            q.push(&activity("1"));
+            q.push(&activity("2"));
+            let entries = q.entries();
+            assert_eq!(
+                entries.iter().map(|(_, a)| a.clone()).collect::<Vec<_>>(),
+                vec![activity("1"), activity("2")]
+            );
+            // the process exits after processing only the first entry
+            q.remove(&entries[0].0);
+        }
+        let q: Queue<QueuedActivity> = Queue::open(&path);
+        assert_eq!(q.len(), 1);
+        let entries = q.entries();
+        assert_eq!(
+            entries.iter().map(|(_, a)| a.clone()).collect::<Vec<_>>(),
+            vec![activity("2")]
+        );
+        let retried = QueuedActivity {
+            attempts: 1,
+            ..activity("2")
+        };
+        q.replace(&entries[0].0, &retried);
+        assert_eq!(q.len(), 1);
+        assert_eq!(q.entries()[0].1, retried);
+        q.remove(&entries[0].0);
+        q.remove(&entries[0].0);
+        assert_eq!(q.len(), 0);
+        assert!(q.entries().is_empty());
+        drop(q);
+        std::fs::remove_dir_all(path).unwrap();
+    }
 }
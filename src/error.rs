@@ -1,4 +1,4 @@
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
 use axum::response::{IntoResponse, Response};
 use std::sync::Arc;
 use tracing::error;
@@ -9,6 +9,14 @@ pub enum Error {
     NotFound,
     NotFoundWithMsg(String),
     BadRequest(Option<String>),
+    /// Missing or invalid authentication.
+    Unauthorized(String),
+    /// Refused by the federation policy.
+    Forbidden,
+    /// Overloaded, retry after this many seconds.
+    Unavailable(u64),
+    /// Rate limited, retry after this many seconds.
+    TooManyRequests(u64),
 }
 
 impl<T> From<T> for Error
@@ -20,6 +28,22 @@ where
     }
 }
 
+impl Error {
+    /// Whether the request may succeed later. Refused requests, invalid
+    /// signatures, unreachable actors and malformed bodies won't.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Internal(e) => !e.is::<serde_json::Error>(),
+            Error::Unavailable(_) | Error::TooManyRequests(_) => true,
+            Error::NotFound
+            | Error::NotFoundWithMsg(_)
+            | Error::BadRequest(_)
+            | Error::Unauthorized(_)
+            | Error::Forbidden => false,
+        }
+    }
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
         match self {
@@ -33,6 +57,20 @@ impl IntoResponse for Error {
             }
             Error::BadRequest(None) => (StatusCode::BAD_REQUEST, "Bad Request").into_response(),
             Error::BadRequest(Some(msg)) => (StatusCode::BAD_REQUEST, msg).into_response(),
+            Error::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg).into_response(),
+            Error::Forbidden => (StatusCode::FORBIDDEN, "Forbidden").into_response(),
+            Error::Unavailable(secs) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, secs.to_string())],
+                "Service Unavailable",
+            )
+                .into_response(),
+            Error::TooManyRequests(secs) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, secs.to_string())],
+                "Too Many Requests",
+            )
+                .into_response(),
         }
     }
 }
@@ -2,22 +2,44 @@ use crate::error::Error;
 use crate::USER_AGENT;
 use axum::http::HeaderValue;
 use futures_util::{SinkExt, StreamExt};
-use itertools::Itertools;
+use itertools::{Either, Itertools};
 use nostr_lib::{Event, EventBuilder, EventId, Keys, SecretKey};
 use rustc_hash::{FxHashMap, FxHashSet};
 use serde::{Serialize, Serializer};
+use std::future::Future;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::oneshot;
 use tokio_tungstenite::connect_async;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio_tungstenite::tungstenite::Message;
 use tracing::{debug, error, info};
 
 #[derive(Debug)]
-pub struct EventDeletionQueue(tokio::sync::mpsc::Sender<(EventId, SecretKey)>);
+enum Op {
+    Delete(EventId, SecretKey),
+    /// Answered once the deletions queued before it are sent.
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Debug)]
+pub struct EventDeletionQueue(tokio::sync::mpsc::Sender<Op>);
 
 impl EventDeletionQueue {
     pub fn new(http_client: Arc<reqwest::Client>) -> Self {
+        Self::with_sender(move |es, ids| {
+            let http_client = http_client.clone();
+            async move { delete_async(es, &http_client, &ids).await }
+        })
+    }
+
+    /// Sends each batch of deletions with `send`, in the order they're queued.
+    fn with_sender<F>(
+        send: impl Fn(Vec<(EventId, SecretKey)>, String) -> F + Send + 'static,
+    ) -> Self
+    where
+        F: Future<Output = Result<(), Error>> + Send,
+    {
         let (sender, mut receiver) = tokio::sync::mpsc::channel(1_000);
         tokio::spawn(async move {
             loop {
@@ -26,25 +48,43 @@ impl EventDeletionQueue {
                 if n == 0 {
                     break;
                 }
-                let ids = buff
-                    .iter()
-                    .format_with(", ", |(e, _), f| f(&format_args!("{e}")))
-                    .to_string();
-                debug!("start deletion of {ids}");
-                if let Err(e) = delete_async(buff, &http_client, &ids).await {
-                    error!("{e:?}");
+                let (buff, flushes): (Vec<_>, Vec<_>) =
+                    buff.into_iter().partition_map(|op| match op {
+                        Op::Delete(e, nsec) => Either::Left((e, nsec)),
+                        Op::Flush(s) => Either::Right(s),
+                    });
+                if !buff.is_empty() {
+                    let ids = buff
+                        .iter()
+                        .format_with(", ", |(e, _), f| f(&format_args!("{e}")))
+                        .to_string();
+                    debug!("start deletion of {ids}");
+                    if let Err(e) = send(buff, ids.clone()).await {
+                        error!("{e:?}");
+                    }
+                    debug!("deleted {ids}");
+                }
+                for s in flushes {
+                    let _ = s.send(());
                 }
-                debug!("deleted {ids}");
             }
         });
         Self(sender)
     }
 
     pub fn delete(&self, event_id: EventId, nsec: SecretKey) {
-        if let Err(e) = self.0.try_send((event_id, nsec)) {
+        if let Err(e) = self.0.try_send(Op::Delete(event_id, nsec)) {
             error!("{e}")
         }
     }
+
+    /// Waits for the deletions queued so far to be sent.
+    pub async fn flush(&self) {
+        let (sender, receiver) = oneshot::channel();
+        if self.0.send(Op::Flush(sender)).await.is_ok() {
+            let _ = receiver.await;
+        }
+    }
 }
 
 #[tracing::instrument(skip_all)]
@@ -154,3 +194,41 @@ impl Serialize for ClientMessage {
         seq.end()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::EventDeletionQueue;
+    use nostr_lib::{EventBuilder, Keys};
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn flush_waits_for_queued_deletions() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let q = EventDeletionQueue::with_sender({
+            let sent = sent.clone();
+            move |es, _| {
+                let sent = sent.clone();
+                async move {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    sent.lock().extend(es.into_iter().map(|(e, _)| e));
+                    Ok(())
+                }
+            }
+        });
+        let keys = Keys::generate();
+        let ids: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|c| EventBuilder::text_note(c, []).to_event(&keys).unwrap().id)
+            .collect();
+        let nsec = keys.secret_key().unwrap().clone();
+        q.delete(ids[0], nsec.clone());
+        q.delete(ids[1], nsec.clone());
+        q.flush().await;
+        assert_eq!(*sent.lock(), ids[..2]);
+        q.delete(ids[2], nsec);
+        q.flush().await;
+        assert_eq!(*sent.lock(), ids);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::activity::{Actor, ActorOrProxied};
+use crate::error::Error;
+use crate::server::AppState;
+use lru::LruCache;
+use nostr_lib::nips::nip01::Coordinate;
+use nostr_lib::{EventBuilder, EventId, Kind, PublicKey, Tag, Timestamp};
+use parking_lot::Mutex;
+use relay_pool::Filter;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+/// Actors whose last activity is at least `threshold` seconds before `now`.
+/// The least recently active come last in `last_activity`.
+pub fn idle_actors(last_activity: &LruCache<String, u64>, now: u64, threshold: u64) -> Vec<String> {
+    last_activity
+        .iter()
+        .rev()
+        .take_while(|(_, t)| now.saturating_sub(**t) >= threshold)
+        .map(|(id, _)| id.clone())
+        .collect()
+}
+
+/// Records the activity of `id` at `now`. An actor pushed out of the bounded
+/// `last_activity` would never be seen as idle again, so it is evicted from
+/// `actors` right away.
+fn record_activity(
+    last_activity: &mut LruCache<String, u64>,
+    actors: &Mutex<LruCache<String, ActorOrProxied>>,
+    id: &str,
+    now: u64,
+) {
+    if let Some((evicted, _)) = last_activity
+        .push(id.to_string(), now)
+        .filter(|(e, _)| e != id)
+    {
+        actors.lock().pop(&evicted);
+    }
+}
+
+/// Tags of a Kind 5 deleting the Kind 0 of `public_key`, by id if it is known
+/// and by coordinate for the relays which only keep the latest version.
+fn metadata_deletion_tags(public_key: PublicKey, metadata: Option<EventId>) -> Vec<Tag> {
+    metadata
+        .map(Tag::event)
+        .into_iter()
+        .chain([
+            Tag::from(Coordinate::new(Kind::Metadata, public_key)),
+            Tag::Kind(Kind::Metadata),
+        ])
+        .collect()
+}
+
+impl AppState {
+    /// Records that the fediverse actor `id` sent or was sent an activity.
+    pub fn touch_actor(&self, id: &str) {
+        record_activity(
+            &mut self.actor_activity.lock(),
+            &self.actor_cache,
+            id,
+            Timestamp::now().as_u64(),
+        );
+    }
+
+    /// Periodically evicts actors idle for `threshold` from the in-memory
+    /// caches. They are fetched and bridged again on their next activity.
+    /// With `delete_metadata`, their Kind 0 is also deleted from the metadata
+    /// relays.
+    pub async fn evict_idle_actors(
+        &self,
+        threshold: Option<Duration>,
+        delete_metadata: bool,
+    ) -> Result<(), Error> {
+        let Some(threshold) = threshold else {
+            return Ok(());
+        };
+        loop {
+            tokio::time::sleep(threshold.min(Duration::from_secs(60 * 60))).await;
+            let evicted = {
+                let mut activity = self.actor_activity.lock();
+                let idle = idle_actors(&activity, Timestamp::now().as_u64(), threshold.as_secs());
+                let mut cache = self.actor_cache.lock();
+                idle.into_iter()
+                    .filter_map(|id| {
+                        activity.pop(&id);
+                        cache.pop(&id)
+                    })
+                    .collect::<Vec<_>>()
+            };
+            info!("evicted {} idle actors", evicted.len());
+            if !delete_metadata {
+                continue;
+            }
+            for actor in evicted {
+                if let ActorOrProxied::Actor(a) = actor {
+                    self.delete_actor_metadata(&a).await;
+                }
+            }
+        }
+    }
+
+    /// Deletes the Kind 0 of `actor` from the metadata relays.
+    pub async fn delete_actor_metadata(&self, actor: &Actor) {
+        let keys = nostr_lib::Keys::new(actor.nsec.clone());
+        let metadata = self
+            .get_nostr_event_with_timeout(
+                Filter {
+                    kinds: Some([Kind::Metadata].into_iter().collect()),
+                    authors: Some([keys.public_key()].into_iter().collect()),
+                    limit: Some(1),
+                    ..Default::default()
+                },
+                Duration::from_secs(10),
+            )
+            .await;
+        let tags = metadata_deletion_tags(keys.public_key(), metadata.map(|m| m.event.id));
+        let deletion = EventBuilder::new(Kind::EventDeletion, "", tags)
+            .to_event(&keys)
+            .unwrap();
+        self.nostr
+            .send(Arc::new(deletion), self.metadata_relays.clone())
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{idle_actors, metadata_deletion_tags, record_activity};
+    use crate::activity::ActorOrProxied;
+    use lru::LruCache;
+    use nostr_lib::{EventBuilder, Keys, Kind};
+    use parking_lot::Mutex;
+    use std::num::NonZeroUsize;
+    use std::sync::Arc;
+
+    #[test]
+    fn idle_actor_is_evicted() {
+        let mut activity = LruCache::new(NonZeroUsize::new(2).unwrap());
+        activity.put("https://example.com/users/a".to_string(), 1_000);
+        activity.put("https://example.com/users/b".to_string(), 5_000);
+        let day = 60 * 60 * 24;
+        assert!(idle_actors(&activity, 1_000 + day - 1, day).is_empty());
+        assert_eq!(
+            idle_actors(&activity, 1_000 + day, day),
+            vec!["https://example.com/users/a".to_string()]
+        );
+        activity.put("https://example.com/users/c".to_string(), 6_000);
+        assert_eq!(activity.len(), 2, "bounded");
+    }
+
+    #[test]
+    fn actor_pushed_out_of_activity_is_evicted() {
+        let mut activity = LruCache::new(NonZeroUsize::new(2).unwrap());
+        let actors = Mutex::new(LruCache::new(NonZeroUsize::new(10).unwrap()));
+        for id in ["a", "b", "c"] {
+            let proxied = ActorOrProxied::Proxied(Arc::new(id.to_string()));
+            actors.lock().put(id.to_string(), proxied);
+            record_activity(&mut activity, &actors, id, 1_000);
+        }
+        record_activity(&mut activity, &actors, "c", 2_000);
+        assert!(!actors.lock().contains("a"));
+        assert!(actors.lock().contains("b"));
+        assert!(actors.lock().contains("c"));
+    }
+
+    #[test]
+    fn metadata_deletion_1() {
+        let keys = Keys::generate();
+        let metadata = EventBuilder::metadata(&nostr_lib::Metadata::new().name("a"))
+            .to_event(&keys)
+            .unwrap();
+        let tags = metadata_deletion_tags(keys.public_key(), Some(metadata.id));
+        let deletion = EventBuilder::new(Kind::EventDeletion, "", tags)
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(deletion.event_ids().collect::<Vec<_>>(), [&metadata.id]);
+        assert_eq!(
+            deletion
+                .coordinates()
+                .next()
+                .map(|c| (c.kind, c.public_key)),
+            Some((Kind::Metadata, keys.public_key()))
+        );
+        let tags = metadata_deletion_tags(keys.public_key(), None);
+        assert_eq!(tags.len(), 2);
+    }
+}
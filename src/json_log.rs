@@ -0,0 +1,150 @@
+use serde_json::{json, Map, Value};
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Formats each event as a line of JSON with its fields and the fields of
+/// the spans it is in, which are recorded by `JsonFields`.
+pub struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut fields = JsonVisitor::default();
+        event.record(&mut fields);
+        let spans = ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|s| s.from_root())
+            .map(|span| {
+                let extensions = span.extensions();
+                let fields = extensions
+                    .get::<FormattedFields<JsonFields>>()
+                    .and_then(|f| serde_json::from_str::<Value>(&f.fields).ok())
+                    .unwrap_or_else(|| json!({}));
+                json!({ "name": span.name(), "fields": fields })
+            })
+            .collect::<Vec<_>>();
+        let metadata = event.metadata();
+        let line = json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "fields": fields.0,
+            "spans": spans,
+        });
+        writeln!(writer, "{line}")
+    }
+}
+
+/// Records span fields as a JSON object.
+pub struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut v = JsonVisitor::default();
+        fields.record(&mut v);
+        write!(writer, "{}", Value::Object(v.0))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> fmt::Result {
+        let mut v = JsonVisitor(match serde_json::from_str(&current.fields) {
+            Ok(Value::Object(m)) => m,
+            _ => Map::new(),
+        });
+        fields.record(&mut v);
+        current.fields = Value::Object(v.0).to_string();
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}").into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JsonFields, JsonFormat};
+    use parking_lot::Mutex;
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    #[test]
+    fn json_log_1() {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let b = buf.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .with_writer(move || WriteTo(b.clone()))
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("inbox", actor = "https://example.com/users/a");
+            let _guard = span.enter();
+            tracing::info!(kind = 1, "bridged \"note\"");
+        });
+        let line: Value = serde_json::from_slice(&buf.lock()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "bridged \"note\"");
+        assert_eq!(line["fields"]["kind"], 1);
+        assert_eq!(line["spans"][0]["name"], "inbox");
+        assert_eq!(
+            line["spans"][0]["fields"]["actor"],
+            "https://example.com/users/a"
+        );
+    }
+
+    struct WriteTo(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for WriteTo {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
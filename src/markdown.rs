@@ -0,0 +1,334 @@
+use html_escape::{encode_double_quoted_attribute, encode_text};
+use std::borrow::Cow;
+use std::fmt::Write;
+
+/// Rewrites the plain text and the link targets of rendered markdown.
+pub trait Rewrite {
+    /// Appends the HTML of a run of plain text outside of links and code.
+    fn text(&self, text: &str, html: &mut String) {
+        html.push_str(&encode_text(text));
+    }
+
+    /// Target of a link or an image.
+    fn url<'a>(&self, url: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(url)
+    }
+}
+
+struct Plain;
+
+impl Rewrite for Plain {}
+
+/// Renders the markdown of long-form content (NIP-23) as HTML. Only the
+/// common subset is supported: headings, paragraphs, lists, blockquotes,
+/// code, rules, emphasis, links and images. Raw HTML is escaped.
+#[cfg(test)]
+pub fn markdown_to_html(md: &str) -> String {
+    markdown_to_html_with(md, &Plain)
+}
+
+/// `markdown_to_html` with plain text and links rewritten by `rw`.
+pub fn markdown_to_html_with(md: &str, rw: &dyn Rewrite) -> String {
+    let mut r = Renderer {
+        html: String::new(),
+        paragraph: Vec::new(),
+        quote: Vec::new(),
+        list: None,
+        rw,
+    };
+    let mut lines = md.lines();
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim_start();
+        if let Some(lang) = trimmed.strip_prefix("```") {
+            r.flush();
+            let lang = lang.trim();
+            if lang.is_empty() {
+                r.html.push_str("<pre><code>");
+            } else {
+                let _ = write!(
+                    r.html,
+                    "<pre><code class=\"language-{}\">",
+                    encode_double_quoted_attribute(lang)
+                );
+            }
+            for line in lines.by_ref() {
+                if line.trim_start().starts_with("```") {
+                    break;
+                }
+                r.html.push_str(&encode_text(line));
+                r.html.push('\n');
+            }
+            r.html.push_str("</code></pre>");
+        } else if let Some(quoted) = trimmed.strip_prefix('>') {
+            r.flush_paragraph();
+            r.close_list();
+            r.quote.push(quoted.strip_prefix(' ').unwrap_or(quoted));
+        } else if trimmed.is_empty() {
+            r.flush();
+        } else if let Some((level, text)) = heading(trimmed) {
+            r.flush();
+            let _ = write!(r.html, "<h{level}>{}</h{level}>", inline(text, rw));
+        } else if r.paragraph.is_empty() && is_rule(trimmed) {
+            r.flush();
+            r.html.push_str("<hr>");
+        } else if let Some((list, text)) = list_item(trimmed) {
+            r.flush_paragraph();
+            r.flush_quote();
+            if r.list != Some(list) {
+                r.close_list();
+                r.html.push_str(if list == List::Ordered {
+                    "<ol>"
+                } else {
+                    "<ul>"
+                });
+                r.list = Some(list);
+            }
+            let _ = write!(r.html, "<li>{}</li>", inline(text, rw));
+        } else {
+            r.flush_quote();
+            r.close_list();
+            r.paragraph.push(line.trim());
+        }
+    }
+    r.flush();
+    r.html
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum List {
+    Ordered,
+    Unordered,
+}
+
+struct Renderer<'a> {
+    html: String,
+    paragraph: Vec<&'a str>,
+    quote: Vec<&'a str>,
+    list: Option<List>,
+    rw: &'a dyn Rewrite,
+}
+
+impl Renderer<'_> {
+    fn flush(&mut self) {
+        self.flush_paragraph();
+        self.flush_quote();
+        self.close_list();
+    }
+
+    fn flush_paragraph(&mut self) {
+        if !self.paragraph.is_empty() {
+            let _ = write!(
+                self.html,
+                "<p>{}</p>",
+                inline(&self.paragraph.join("\n"), self.rw)
+            );
+            self.paragraph.clear();
+        }
+    }
+
+    fn flush_quote(&mut self) {
+        if !self.quote.is_empty() {
+            let _ = write!(
+                self.html,
+                "<blockquote>{}</blockquote>",
+                markdown_to_html_with(&self.quote.join("\n"), self.rw)
+            );
+            self.quote.clear();
+        }
+    }
+
+    fn close_list(&mut self) {
+        match self.list.take() {
+            Some(List::Ordered) => self.html.push_str("</ol>"),
+            Some(List::Unordered) => self.html.push_str("</ul>"),
+            None => {}
+        }
+    }
+}
+
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+    let text = &line[level..];
+    if text.is_empty() {
+        Some((level, text))
+    } else {
+        text.strip_prefix(' ')
+            .map(|t| (level, t.trim().trim_end_matches('#').trim_end()))
+    }
+}
+
+fn is_rule(line: &str) -> bool {
+    let line: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+    line.len() >= 3
+        && ['-', '*', '_']
+            .into_iter()
+            .any(|d| line.chars().all(|c| c == d))
+}
+
+fn list_item(line: &str) -> Option<(List, &str)> {
+    for marker in ["- ", "* ", "+ "] {
+        if let Some(text) = line.strip_prefix(marker) {
+            return Some((List::Unordered, text.trim()));
+        }
+    }
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    if digits == 0 || digits > 9 {
+        return None;
+    }
+    line[digits..]
+        .strip_prefix(". ")
+        .or_else(|| line[digits..].strip_prefix(") "))
+        .map(|text| (List::Ordered, text.trim()))
+}
+
+/// Only these schemes are kept in links and images.
+fn is_safe_url(url: &str) -> bool {
+    ["https://", "http://", "nostr:", "mailto:"]
+        .iter()
+        .any(|s| url.starts_with(s))
+}
+
+/// `[text](url)` at the start of `s`, returning the text, the URL and the rest.
+fn link(s: &str) -> Option<(&str, &str, &str)> {
+    let s = s.strip_prefix('[')?;
+    let mut depth = 0;
+    let end = s.char_indices().find_map(|(i, c)| match c {
+        '[' => {
+            depth += 1;
+            None
+        }
+        ']' if depth == 0 => Some(i),
+        ']' => {
+            depth -= 1;
+            None
+        }
+        _ => None,
+    })?;
+    let (text, rest) = (&s[..end], s[end + 1..].strip_prefix('(')?);
+    let close = rest.find(')')?;
+    let url = rest[..close].split_whitespace().next().unwrap_or("");
+    Some((text, url, &rest[close + 1..]))
+}
+
+fn inline(s: &str, rw: &dyn Rewrite) -> String {
+    let mut html = String::with_capacity(s.len());
+    let mut rest = s;
+    let mut prev = ' ';
+    // start of the plain text not yet written
+    let mut plain = 0;
+    while let Some(c) = rest.chars().next() {
+        let pos = s.len() - rest.len();
+        let before = rest;
+        if c == '\\' {
+            if let Some(e) = rest[1..]
+                .chars()
+                .next()
+                .filter(|e| e.is_ascii_punctuation())
+            {
+                rw.text(&s[plain..pos], &mut html);
+                html.push_str(&encode_text(&e.to_string()));
+                rest = &rest[1 + e.len_utf8()..];
+                prev = e;
+            }
+        } else if c == '`' {
+            if let Some(end) = rest[1..].find('`') {
+                rw.text(&s[plain..pos], &mut html);
+                let _ = write!(html, "<code>{}</code>", encode_text(&rest[1..1 + end]));
+                rest = &rest[end + 2..];
+                prev = '`';
+            }
+        } else if c == '!' {
+            if let Some((alt, url, r)) = link(&rest[1..]) {
+                rw.text(&s[plain..pos], &mut html);
+                if is_safe_url(url) {
+                    let _ = write!(
+                        html,
+                        "<img src=\"{}\" alt=\"{}\">",
+                        encode_double_quoted_attribute(&rw.url(url)),
+                        encode_double_quoted_attribute(alt)
+                    );
+                } else {
+                    html.push_str(&encode_text(alt));
+                }
+                rest = r;
+                prev = ')';
+            }
+        } else if c == '[' {
+            if let Some((text, url, r)) = link(rest) {
+                rw.text(&s[plain..pos], &mut html);
+                // the text of links is not rewritten, which would nest links
+                if is_safe_url(url) {
+                    let _ = write!(
+                        html,
+                        "<a href=\"{}\">{}</a>",
+                        encode_double_quoted_attribute(&rw.url(url)),
+                        inline(text, &Plain)
+                    );
+                } else {
+                    html.push_str(&inline(text, rw));
+                }
+                rest = r;
+                prev = ')';
+            }
+        } else if c == '*' || c == '_' {
+            // `_` only delimits at word boundaries, as in snake_case
+            let boundary = c == '*' || !prev.is_alphanumeric();
+            let strong: String = [c, c].iter().collect();
+            if boundary && rest.starts_with(&strong) {
+                if let Some(end) = rest[2..].find(&strong).filter(|e| *e > 0) {
+                    rw.text(&s[plain..pos], &mut html);
+                    let _ = write!(html, "<strong>{}</strong>", inline(&rest[2..2 + end], rw));
+                    rest = &rest[end + 4..];
+                    prev = c;
+                }
+            } else if boundary {
+                if let Some(end) = rest[1..].find(c).filter(|e| *e > 0) {
+                    rw.text(&s[plain..pos], &mut html);
+                    let _ = write!(html, "<em>{}</em>", inline(&rest[1..1 + end], rw));
+                    rest = &rest[end + 2..];
+                    prev = c;
+                }
+            }
+        }
+        if rest.len() == before.len() {
+            rest = &rest[c.len_utf8()..];
+            prev = c;
+        } else {
+            plain = s.len() - rest.len();
+        }
+    }
+    rw.text(&s[plain..], &mut html);
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::markdown_to_html;
+
+    #[test]
+    fn markdown_1() {
+        let md = "# Title\n\nSome **bold** and *italic* text\nwith `code` and a [link](https://example.com).\n\n- a\n- b\n\n1. one\n2. two\n\n> quoted\n\n```rust\nlet a = 1 < 2;\n```\n\n---\n![img](https://example.com/a.png)";
+        assert_eq!(
+            markdown_to_html(md),
+            "<h1>Title</h1>\
+             <p>Some <strong>bold</strong> and <em>italic</em> text\nwith <code>code</code> and a <a href=\"https://example.com\">link</a>.</p>\
+             <ul><li>a</li><li>b</li></ul>\
+             <ol><li>one</li><li>two</li></ol>\
+             <blockquote><p>quoted</p></blockquote>\
+             <pre><code class=\"language-rust\">let a = 1 &lt; 2;\n</code></pre>\
+             <hr>\
+             <p><img src=\"https://example.com/a.png\" alt=\"img\"></p>"
+        );
+    }
+
+    #[test]
+    fn markdown_escapes_html() {
+        assert_eq!(
+            markdown_to_html("<script>a</script> snake_case_name [x](javascript:alert)"),
+            "<p>&lt;script&gt;a&lt;/script&gt; snake_case_name x</p>"
+        );
+    }
+}
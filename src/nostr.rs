@@ -1,6 +1,6 @@
 use crate::error::Error;
 use crate::server::AppState;
-use crate::RelayId;
+use crate::{RelayId, READ_ONLY};
 use cached::Cached;
 use futures_util::StreamExt;
 use nostr_lib::event::Event;
@@ -10,7 +10,7 @@ use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::OnceCell;
 use tokio::time::{timeout_at, Instant};
-use tracing::debug;
+use tracing::{debug, info};
 
 // allowed because `Proxied` is rare
 #[allow(clippy::large_enum_variant)]
@@ -137,6 +137,12 @@ impl AppState {
     }
 
     pub async fn nostr_send(&self, event: Arc<Event>) {
+        if *READ_ONLY {
+            // Published by `drain_queued_activities` once read-only mode is lifted.
+            info!("queued {} in read-only mode", event.id);
+            self.db.push_queued_event(&event);
+            return;
+        }
         self.nostr.send(event, self.main_relays.clone()).await
     }
 
@@ -1,14 +1,19 @@
 use crate::activity::{
-    Actor, ActorOrProxied, AnnounceForSer, Attachment, CreateForSer, DeleteForSer, FollowActivity,
-    ImageForSe, Note, NoteForDe, NoteTagForSer, ReactionForSer, UndoFollowActivity, UpdateForSer,
+    Actor, ActorOrProxied, AnnounceForSer, Attachment, CreateForSer, DeleteActorForSer,
+    DeleteForSer, Delivery, FollowActivity, ImageForSe, Note, NoteForDe, NoteTagForSer,
+    ReactionForSer, UndoFollowActivity, UpdateForSer, ZapForSer,
 };
 use crate::bot::handle_message_to_bot;
 use crate::error::Error;
+use crate::markdown::{markdown_to_html_with, Rewrite};
 use crate::nostr::{get_nostr_user_data, NostrUser};
 use crate::server::{metadata_to_activity, AppState};
+use crate::software::Software;
+use crate::util::{normalize_handle, sanitize_unicode};
 use crate::{
-    RelayId, AP_RELAYS, BOT_PUB, DOMAIN, HTTPS_DOMAIN, NOTE_ID_PREFIX, NPUB_REG, OUTBOX_RELAYS,
-    REVERSE_DNS, USER_ID_PREFIX,
+    RelayId, AP_RELAYS, BOT_PUB, DOMAIN, HASHTAG_URL_PREFIX, HTTPS_DOMAIN, INCLUDE_NOSTR_EVENT,
+    MAX_OUTBOX_RELAYS, NOSTR_VIEWER, NOTE_ID_PREFIX, NPUB_REG, OUTBOX_RELAYS, REVERSE_DNS,
+    SANITIZE_UNICODE, TRUNCATE_CONTENT_GRAPHEMES, USER_AGENT, USER_ID_PREFIX, ZAP_ACTIVITY,
 };
 use futures_util::StreamExt;
 use html_escape::{encode_double_quoted_attribute, encode_text};
@@ -19,7 +24,7 @@ use nostr_lib::nips::nip19::{Nip19Event, Nip19Profile};
 use nostr_lib::nips::nip48::Protocol;
 use nostr_lib::types::Metadata;
 use nostr_lib::util::JsonUtil;
-use nostr_lib::{Event, EventId, FromBech32, Marker, PublicKey, Tag, ToBech32};
+use nostr_lib::{Event, EventId, FromBech32, Marker, PublicKey, Tag, Timestamp, ToBech32};
 use once_cell::sync::Lazy;
 use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use regex::{Captures, Regex};
@@ -28,31 +33,34 @@ use reqwest::header;
 use rustc_hash::{FxHashMap, FxHashSet};
 use serde::Serialize;
 use std::borrow::Cow;
+use std::collections::hash_map::Entry;
 use std::fmt::Write;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::OnceCell;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, trace};
 
 async fn broadcast_to_actors<A: Serialize, S: AsRef<str>>(
-    state: &AppState,
+    state: &Arc<AppState>,
     activity: A,
     author: &str,
     r: impl Iterator<Item = S>,
     to_relay: bool,
-) -> FxHashSet<axum::http::Uri> {
+) -> FxHashMap<axum::http::Uri, Result<Delivery, Error>> {
     #[allow(clippy::mutable_key_type)]
-    let mut sent = FxHashSet::default();
+    let mut sent = FxHashMap::default();
     for actor_id in r {
         match state.get_actor_data(actor_id.as_ref()).await {
             Ok(actor) => match actor {
                 ActorOrProxied::Actor(actor) => {
                     if let Some(inbox) = &actor.inbox {
-                        if sent.insert(inbox.clone()) {
-                            if let Err(e) = state.send_activity(inbox, author, &activity).await {
+                        if let Entry::Vacant(v) = sent.entry(inbox.clone()) {
+                            let r = state.send_activity(inbox, author, &activity).await;
+                            if let Err(e) = &r {
                                 error!("could not send activity: {e:?}");
                             }
+                            v.insert(r);
                         }
                     }
                 }
@@ -68,16 +76,48 @@ async fn broadcast_to_actors<A: Serialize, S: AsRef<str>>(
     if to_relay {
         for inbox in &*AP_RELAYS {
             let inbox = axum::http::Uri::from_str(inbox).unwrap();
-            if sent.insert(inbox.clone()) {
-                if let Err(e) = state.send_activity(&inbox, author, &activity).await {
+            if let Entry::Vacant(v) = sent.entry(inbox) {
+                let r = state.send_activity(v.key(), author, &activity).await;
+                if let Err(e) = &r {
                     error!("could not send activity: {e:?}");
                 }
+                v.insert(r);
             }
         }
     }
     sent
 }
 
+/// Fediverse actors mentioned by `event` with `p` tags or in its content,
+/// and whether it tags the bot.
+fn note_recipients(
+    event: &Event,
+    accounts: &FxHashMap<PublicKey, Arc<String>>,
+) -> (Vec<Arc<String>>, bool) {
+    let tagged = event
+        .tags
+        .iter()
+        .filter_map(|t| match t {
+            Tag::PublicKey {
+                public_key,
+                uppercase: false,
+                ..
+            } => Some(*public_key),
+            _ => None,
+        })
+        .collect_vec();
+    let to_bot = tagged.contains(&BOT_PUB);
+    let mut ps = Vec::new();
+    for public_key in tagged.into_iter().chain(content_mentions(&event.content)) {
+        if let Some(a) = accounts.get(&public_key) {
+            if !ps.contains(a) {
+                ps.push(a.clone());
+            }
+        }
+    }
+    (ps, to_bot)
+}
+
 #[tracing::instrument(skip_all)]
 fn handle_event(
     state: &Arc<AppState>,
@@ -114,24 +154,8 @@ fn handle_event(
         return;
     }
     match event.kind {
-        nostr_lib::Kind::TextNote => {
-            let mut ps = Vec::new();
-            let mut to_bot = false;
-            for t in &event.tags {
-                if let Tag::PublicKey {
-                    public_key,
-                    uppercase: false,
-                    ..
-                } = t
-                {
-                    if public_key == &*BOT_PUB {
-                        to_bot = true;
-                    }
-                    if let Some(a) = state.activitypub_accounts.lock().get(public_key) {
-                        ps.push(a.clone());
-                    }
-                }
-            }
+        nostr_lib::Kind::TextNote | nostr_lib::Kind::LongFormTextNote => {
+            let (ps, to_bot) = note_recipients(&event, &state.activitypub_accounts.lock());
             if to_bot {
                 let state = state.clone();
                 let event = event.clone();
@@ -144,7 +168,7 @@ fn handle_event(
                 let followers = {
                     let l = state.nostr_account_to_followers.lock();
                     let followers = l.get(event.author_ref());
-                    if !ps.is_empty() || !followers.as_ref().map_or(true, |a| a.is_empty()) {
+                    if !ps.is_empty() || !followers.as_ref().is_none_or(|a| a.is_empty()) {
                         Some(followers.cloned().unwrap_or_default())
                     } else {
                         None
@@ -178,11 +202,12 @@ fn handle_event(
                             true,
                         )
                         .await;
+                        state.record_deliveries(event.id, &inboxes);
                         state
                             .db
                             .insert_event_id_to_inbox(
                                 event.id.as_bytes(),
-                                inboxes.into_iter().map(|l| l.to_string()),
+                                inboxes.into_keys().map(|l| l.to_string()),
                             )
                             .await;
                     }
@@ -234,46 +259,43 @@ fn handle_event(
                     return;
                 };
                 let reacted_p = reacted_event.event.author_ref();
-                let e = match get_ap_id_from_proxied_event(&reacted_event.event) {
-                    Ok(a) => a,
+                let followers = state
+                    .nostr_account_to_followers
+                    .lock()
+                    .get(reacted_p)
+                    .cloned();
+                let (e, software) = match get_ap_id_from_proxied_event(&reacted_event.event) {
+                    Ok(a) => {
+                        let software = match url::Url::parse(&a) {
+                            Ok(u) => match u.host_str() {
+                                Some(host) => state.get_software(host),
+                                None => None,
+                            },
+                            Err(_) => None,
+                        };
+                        (a, software)
+                    }
                     Err(GetProxiedEventError::NotProxiedEvent) => {
-                        format!("{NOTE_ID_PREFIX}{}", e.to_bech32().unwrap())
+                        if followers.as_ref().is_none_or(|a| a.is_empty()) {
+                            trace!("reacted note is not bridged");
+                            return;
+                        }
+                        (format!("{NOTE_ID_PREFIX}{}", e.to_bech32().unwrap()), None)
                     }
                     Err(GetProxiedEventError::ProxiedByOtherBried(_)) => {
                         return;
                     }
                 };
                 let author = format!("{USER_ID_PREFIX}{}", event.author().to_bech32().unwrap());
-                let followers = state
-                    .nostr_account_to_followers
-                    .lock()
-                    .get(reacted_p)
-                    .cloned();
-                let tmp: String;
-                let p = state
-                    .activitypub_accounts
-                    .lock()
-                    .get(reacted_p)
-                    .map(|a| a.clone());
+                let p = state.activitypub_accounts.lock().get(reacted_p).cloned();
+                let content = reaction_content(&event.content, emoji.is_some());
                 let activity = ReactionForSer {
                     actor: &author,
                     id: &event.id.to_bech32().unwrap(),
                     object: &e,
-                    content: if emoji.is_some() {
-                        if event.content.starts_with(':') {
-                            Some(&event.content)
-                        } else {
-                            tmp = format!(":{}:", event.content);
-                            Some(&tmp)
-                        }
-                    } else if event.content == "-" {
-                        Some("👎")
-                    } else if event.content == "+" {
-                        None
-                    } else {
-                        Some(&event.content)
-                    },
+                    content: content.as_deref(),
                     tag: emoji,
+                    emoji_react: software == Some(Software::Pleroma),
                 };
                 broadcast_to_actors(
                     &state,
@@ -291,19 +313,96 @@ fn handle_event(
                 .await;
             });
         }
+        nostr_lib::Kind::ZapReceipt => {
+            let Some(zap) = zap(&event) else { return };
+            if state.db.is_stopped_npub(&zap.sender) {
+                return;
+            }
+            let Some(recipient) = state
+                .activitypub_accounts
+                .lock()
+                .get(&zap.recipient)
+                .cloned()
+            else {
+                return;
+            };
+            if state.zap_receipts.lock().put(event.id, ()).is_some() {
+                debug!("zap receipt {} is already bridged", event.id);
+                return;
+            }
+            let id = event.id.to_bech32().unwrap();
+            let state = state.clone();
+            tokio::spawn(async move {
+                if !is_zap_signer(&state, &recipient, event.pubkey).await {
+                    debug!(
+                        "zap receipt {} is not signed by the recipient's LNURL server",
+                        event.id
+                    );
+                    return;
+                }
+                let Some(zapped) = state.get_note(zap.event).await else {
+                    return;
+                };
+                let Ok(object) = get_ap_id_from_proxied_event(&zapped.event) else {
+                    trace!("zapped note is not bridged");
+                    return;
+                };
+                let author = format!("{USER_ID_PREFIX}{}", zap.sender.to_bech32().unwrap());
+                let activity = ZapForSer {
+                    id: &id,
+                    object: &object,
+                    actor: &author,
+                    sats: zap.msats.map(|a| a / 1000),
+                    emoji_react: *ZAP_ACTIVITY == ZapActivity::EmojiReact,
+                };
+                broadcast_to_actors(
+                    &state,
+                    activity,
+                    &author,
+                    [recipient.as_str()].into_iter(),
+                    false,
+                )
+                .await;
+            });
+        }
+        kind if kind == nostr_lib::Kind::from(REQUEST_TO_VANISH) => {
+            if !vanishes_from(&event, &state.relay_url) {
+                debug!("request to vanish {} is for other relays", event.id);
+                return;
+            }
+            let state = state.clone();
+            tokio::spawn(async move {
+                delete_account(&state, &event, AccountDeletion::Account).await;
+            });
+        }
         nostr_lib::Kind::EventDeletion => {
             info!("event deletion");
+            if let Some(deletion) = account_deletion(&event) {
+                let state = state.clone();
+                let event = event.clone();
+                tokio::spawn(async move {
+                    delete_account(&state, &event, deletion).await;
+                });
+            }
             let author = format!("{USER_ID_PREFIX}{}", event.author().to_bech32().unwrap());
             let id = event.id.to_bech32().unwrap();
             for tag in &event.tags {
                 match tag {
                     Tag::Event { event_id, .. } => {
                         let event_id = *event_id;
+                        let deleter = event.pubkey;
                         let state = state.clone();
                         let author = author.clone();
                         let id = id.clone();
                         tokio::spawn(async move {
+                            // anyone can publish a Kind 5, so only deletions of
+                            // the deleter's own events are honored
+                            match state.get_note(event_id).await {
+                                Some(note) if note.event.pubkey == deleter => (),
+                                _ => return,
+                            }
                             let inboxes = state.db.delete_event_id(event_id.as_bytes()).await;
+                            state.db.insert_deleted_event(&event_id, &deleter);
                             for i in inboxes {
                                 if let Err(e) = state
                                     .send_activity(
@@ -332,13 +431,11 @@ fn handle_event(
                 }
             }
         }
-        nostr_lib::Kind::ContactList => {
-            if !state.db.is_stopped_npub(event.author_ref()) {
-                let state = state.clone();
-                tokio::spawn(async move {
-                    update_follow_list(&state, event).await;
-                });
-            }
+        nostr_lib::Kind::ContactList if !state.db.is_stopped_npub(event.author_ref()) => {
+            let state = state.clone();
+            tokio::spawn(async move {
+                update_follow_list(&state, event).await;
+            });
         }
         nostr_lib::Kind::Repost => {
             if state.db.is_stopped_npub(event.author_ref()) {
@@ -356,11 +453,7 @@ fn handle_event(
                         uppercase: false,
                         ..
                     } => {
-                        p = state
-                            .activitypub_accounts
-                            .lock()
-                            .get(public_key)
-                            .map(|a| a.clone());
+                        p = state.activitypub_accounts.lock().get(public_key).cloned();
                     }
                     _ => (),
                 }
@@ -370,9 +463,7 @@ fn handle_event(
                 tokio::spawn(async move {
                     let e = match get_ap_id_from_id_of_proxied_event(&state, e).await {
                         Ok(a) | Err(GetProxiedEventError::ProxiedByOtherBried(a)) => a,
-                        Err(GetProxiedEventError::NotProxiedEvent) => {
-                            format!("{NOTE_ID_PREFIX}{}", e.to_bech32().unwrap())
-                        }
+                        Err(GetProxiedEventError::NotProxiedEvent) => note_url(&e),
                     };
                     let author = format!("{USER_ID_PREFIX}{}", event.author().to_bech32().unwrap());
                     let followers = state
@@ -387,6 +478,7 @@ fn handle_event(
                             id: &event.id.to_bech32().unwrap(),
                             object: &e,
                             published: &event.created_at.to_human_datetime(),
+                            object_author: p.as_ref().map(|a| a.as_str()),
                         },
                         &author,
                         p.as_ref().map(|a| a.as_str()).into_iter().chain(
@@ -402,10 +494,27 @@ fn handle_event(
                 });
             }
         }
+        nostr_lib::Kind::RelayList
+            if *MAX_OUTBOX_RELAYS > 0 && !state.db.is_stopped_npub(event.author_ref()) =>
+        {
+            let followed = state
+                .nostr_account_to_followers
+                .lock()
+                .get(event.author_ref())
+                .is_some_and(|f| !f.is_empty());
+            if followed {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    state
+                        .update_outbox_relays(&event, crate::get_filter())
+                        .await;
+                });
+            }
+        }
         nostr_lib::Kind::Metadata => {
             let l = state.nostr_account_to_followers.lock();
             let followers = l.get(event.author_ref());
-            if !followers.as_ref().map_or(true, |a| a.is_empty()) {
+            if !followers.as_ref().is_none_or(|a| a.is_empty()) {
                 if let Ok(metadata) = Metadata::from_json(&event.content) {
                     debug!("metadata update");
                     let followers = followers.unwrap().clone();
@@ -436,36 +545,194 @@ fn handle_event(
                 }
             };
         }
+        kind if is_list_kind(kind) => {
+            trace!("skipped list of kind {kind}");
+        }
         _ => (),
     }
 }
 
+/// Strips leading mentions of `bot` (e.g. `nostr:npub1... stop my mirror`),
+/// which are only addressing a command to it. Also returns whether `bot` is
+/// still mentioned in the rest of the content.
+fn strip_bot_mention<'a>(content: &'a str, bot: &PublicKey) -> (&'a str, bool) {
+    let is_bot = |c: &Captures| {
+        PublicKey::from_bech32(&c[1])
+            .or_else(|_| Nip19Profile::from_bech32(&c[1]).map(|p| p.public_key))
+            .is_ok_and(|p| &p == bot)
+    };
+    let mut rest = content;
+    while let Some(c) = NPUB_REG.captures(rest.trim_start()) {
+        if c.get(0).unwrap().start() != 0 || !is_bot(&c) {
+            break;
+        }
+        rest = &rest.trim_start()[c.get(0).unwrap().end()..];
+    }
+    if rest.len() != content.len() {
+        rest = rest.trim_start();
+    }
+    let mentioned = NPUB_REG.captures_iter(rest).any(|c| is_bot(&c));
+    (rest, mentioned)
+}
+
+/// AP `summary` and `sensitive` of a note. A content warning (NIP-36) takes
+/// precedence over a `subject` (NIP-14), which is only used when the warning
+/// has no reason.
+fn summary(tags: &[Tag]) -> (Option<String>, bool) {
+    let mut subject = None;
+    let mut content_warning = None;
+    for t in tags {
+        match t {
+            Tag::Subject(s) if !s.is_empty() => subject = Some(s),
+            Tag::ContentWarning { reason } => content_warning = Some(reason),
+            _ => (),
+        }
+    }
+    match content_warning {
+        Some(Some(reason)) if !reason.is_empty() => (Some(reason.clone()), true),
+        Some(_) => (subject.cloned(), true),
+        None => (subject.cloned(), false),
+    }
+}
+
+/// Metadata of long-form content (NIP-23).
+#[derive(Debug, Default, PartialEq)]
+struct Article {
+    title: Option<String>,
+    summary: Option<String>,
+    published_at: Option<Timestamp>,
+}
+
+impl Article {
+    fn from_tags(tags: &[Tag]) -> Self {
+        let mut a = Article::default();
+        for t in tags {
+            match t {
+                Tag::Title(s) if !s.is_empty() => a.title = Some(s.clone()),
+                Tag::Summary(s) if !s.is_empty() => a.summary = Some(s.clone()),
+                Tag::PublishedAt(t) => a.published_at = Some(*t),
+                _ => (),
+            }
+        }
+        a
+    }
+}
+
+/// NIP-51 lists and sets. These have no counterpart on ActivityPub to be
+/// bridged as, so they shouldn't be treated like notes.
+fn is_list_kind(kind: nostr_lib::Kind) -> bool {
+    matches!(
+        kind.as_u64(),
+        10000..=10007
+            | 10009
+            | 10015
+            | 10030
+            | 10050
+            | 10101
+            | 10102
+            | 30000..=30005
+            | 30007
+            | 30015
+            | 30030
+            | 30063
+            | 30267
+            | 39089
+            | 39092
+    )
+}
+
 pub async fn watch(
     mut event_stream: EventStream<RelayId>,
     state: &Arc<AppState>,
 ) -> Result<(), Error> {
     while let Some(e) = event_stream.next().await {
-        handle_event(state, e);
+        if state.seen_events.lock().put(e.event.id, ()).is_none() {
+            handle_event(state, e);
+        }
     }
     Err(Error::Internal(anyhow::anyhow!("unexpected").into()))
 }
 
 struct Quote {
-    ap_id: String,
+    /// `None` if the quoted note can't be fetched as an AP object
+    ap_id: Option<String>,
     author_npub: PublicKey,
 }
 
+/// Media type of FEP-e232 links to AP objects.
+const OBJECT_LINK_MEDIA_TYPE: &str =
+    "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"";
+
+/// Resolves the note `event_id`, returning also whether it's a native Nostr
+/// note rather than one proxied from the fediverse.
+async fn resolve_quote(state: &AppState, event_id: &EventId) -> Option<(Quote, bool)> {
+    let e = state.get_note(*event_id).await?;
+    Some(match get_ap_id_from_proxied_event(&e.event) {
+        Ok(url) | Err(GetProxiedEventError::ProxiedByOtherBried(url)) => (
+            Quote {
+                ap_id: Some(url),
+                author_npub: e.event.author(),
+            },
+            false,
+        ),
+        Err(GetProxiedEventError::NotProxiedEvent) => (
+            Quote {
+                ap_id: Some(native_quote_url(event_id)),
+                author_npub: e.event.author(),
+            },
+            true,
+        ),
+    })
+}
+
+/// Note quoted with a NIP-18 `q` tag.
+fn q_tag(tags: &[Tag]) -> Option<EventId> {
+    tags.iter().find_map(|t| match t {
+        Tag::Generic(nostr_lib::TagKind::Custom(k), v) if k == "q" => {
+            EventId::from_hex(v.first()?).ok()
+        }
+        _ => None,
+    })
+}
+
+/// Users mentioned with `nostr:npub` or `nostr:nprofile` URIs in `content`.
+fn content_mentions(content: &str) -> Vec<PublicKey> {
+    NPUB_REG
+        .captures_iter(content)
+        .filter_map(|c| {
+            PublicKey::from_bech32(&c[1])
+                .or_else(|_| Nip19Profile::from_bech32(&c[1]).map(|p| p.public_key))
+                .ok()
+        })
+        .unique()
+        .collect()
+}
+
+/// `quoteUrl` of a quoted native Nostr note, which is served by the note
+/// endpoint.
+fn native_quote_url(event_id: &EventId) -> String {
+    note_url(event_id)
+}
+
+static NEVENT: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?:nostr:)?(nevent1[0-9a-z]{50,}|note1[0-9a-z]{50,})").unwrap());
+
+/// Finds bare URLs. Trailing punctuation and unbalanced closing parentheses
+/// are not part of a URL.
+fn link_finder() -> LinkFinder {
+    let mut link_finder = LinkFinder::new();
+    link_finder.kinds(&[LinkKind::Url]);
+    link_finder
+}
+
 #[tracing::instrument(skip_all)]
-async fn media<'a>(
+async fn media(
     state: &Arc<AppState>,
-    content: &'a str,
+    content: &str,
     handle_cache: &mut FxHashMap<PublicKey, Arc<(String, String)>>,
 ) -> (Vec<Attachment>, Content, Option<Quote>) {
     pub static NON_SPACE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\S").unwrap());
-    pub static NEVENT: Lazy<Regex> =
-        Lazy::new(|| Regex::new(r"(?:nostr:)?(nevent1[0-9a-z]{50,}|note1[0-9a-z]{50,})").unwrap());
-    let mut link_finder = LinkFinder::new();
-    link_finder.kinds(&[LinkKind::Url]);
+    let link_finder = link_finder();
     let mut attachments = Vec::new();
     #[derive(Debug)]
     enum Segment<'a> {
@@ -605,21 +872,11 @@ async fn media<'a>(
             Segment::Image(_) => {}
             Segment::Event(event_id, s) => {
                 if quote.is_none() {
-                    if let Some(e) = state.get_note(*event_id).await {
-                        if let Ok(url) | Err(GetProxiedEventError::ProxiedByOtherBried(url)) =
-                            get_ap_id_from_proxied_event(&e.event)
-                        {
-                            quote = Some(Quote {
-                                ap_id: url,
-                                author_npub: e.event.author(),
-                            })
-                        } else {
-                            quote = Some(Quote {
-                                ap_id: format!("{NOTE_ID_PREFIX}{}", event_id.to_bech32().unwrap()),
-                                author_npub: e.event.author(),
-                            });
+                    if let Some((q, native)) = resolve_quote(state, event_id).await {
+                        quote = Some(q);
+                        if native {
                             nostr_quoted = Some(*s);
-                        };
+                        }
                     } else {
                         unresolved_quote = Some(*s);
                     }
@@ -652,7 +909,7 @@ async fn media<'a>(
                     Ok(id) | Err(GetProxiedEventError::ProxiedByOtherBried(id)) => {
                         content.link(get_url_from_ap_id(state, &id).await.as_ref());
                     }
-                    Err(_) => content.link(&format!("https://coracle.social/{s}")),
+                    Err(_) => content.link(&format!("{}/{s}", *NOSTR_VIEWER)),
                 };
             }
         }
@@ -664,12 +921,15 @@ async fn media<'a>(
         }
         let tmp1: String;
         let tmp2: Cow<str>;
-        let url = if let Some(nevent) = nostr_quoted {
-            tmp1 = format!("https://coracle.social/{nevent}");
-            encode_double_quoted_attribute(&tmp1)
-        } else {
-            tmp2 = get_url_from_ap_id(state, &q.ap_id).await;
-            encode_double_quoted_attribute(tmp2.as_ref())
+        let url = match (nostr_quoted, &q.ap_id) {
+            (None, Some(ap_id)) => {
+                tmp2 = get_url_from_ap_id(state, ap_id).await;
+                encode_double_quoted_attribute(tmp2.as_ref())
+            }
+            (nevent, _) => {
+                tmp1 = format!("{}/{}", *NOSTR_VIEWER, nevent.unwrap());
+                encode_double_quoted_attribute(&tmp1)
+            }
         };
         write!(
             &mut content.html,
@@ -678,12 +938,105 @@ async fn media<'a>(
         .unwrap();
     }
     if let Some(s) = unresolved_quote {
-        let url = format!("https://coracle.social/{s}");
+        let url = format!("{}/{s}", *NOSTR_VIEWER);
         content.link(&url);
     }
     (attachments, content, quote)
 }
 
+/// Where a NIP-27 reference in an article links to.
+enum Reference {
+    Mention { url: String, handle: String },
+    Link(String),
+}
+
+/// Rewrites NIP-27 references and bare URLs in the markdown of articles as
+/// `media` does in notes, keyed by their bech32.
+struct ArticleLinks(FxHashMap<String, Reference>);
+
+impl ArticleLinks {
+    async fn resolve(
+        state: &Arc<AppState>,
+        content: &str,
+        handle_cache: &mut FxHashMap<PublicKey, Arc<(String, String)>>,
+    ) -> Self {
+        let mut references = FxHashMap::default();
+        for c in NPUB_REG.captures_iter(content) {
+            if let Ok(p) = PublicKey::from_bech32(&c[1])
+                .or_else(|_| Nip19Profile::from_bech32(&c[1]).map(|p| p.public_key))
+            {
+                let (url, handle) =
+                    (*get_ap_id_and_handle_from_public_key(state, &p, handle_cache).await).clone();
+                references.insert(c[1].to_string(), Reference::Mention { url, handle });
+            }
+        }
+        for c in NEVENT.captures_iter(content) {
+            let s = &c[1];
+            let Ok(event_id) = Nip19Event::from_bech32(s)
+                .map(|e| e.event_id)
+                .or_else(|_| EventId::from_bech32(s))
+            else {
+                continue;
+            };
+            let url = match get_ap_id_from_id_of_proxied_event(state, event_id).await {
+                Ok(id) | Err(GetProxiedEventError::ProxiedByOtherBried(id)) => {
+                    get_url_from_ap_id(state, &id).await.into_owned()
+                }
+                Err(_) => format!("{}/{s}", *NOSTR_VIEWER),
+            };
+            references.insert(s.to_string(), Reference::Link(url));
+        }
+        Self(references)
+    }
+
+    fn references(&self, text: &str, html: &mut String) {
+        static REFERENCE: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"(?:nostr:)?((?:npub1|nprofile1|nevent1|note1)[0-9a-z]{50,})").unwrap()
+        });
+        let mut pos = 0;
+        for c in REFERENCE.captures_iter(text) {
+            let m = c.get(0).unwrap();
+            html.push_str(&encode_text(&text[pos..m.start()]));
+            match self.0.get(&c[1]) {
+                Some(Reference::Mention { url, handle }) => write!(
+                    html,
+                    "<a href=\"{}\" class=\"u-url mention\">{}</a>",
+                    encode_double_quoted_attribute(url),
+                    encode_text(handle)
+                )
+                .unwrap(),
+                Some(Reference::Link(url)) => {
+                    let url = encode_double_quoted_attribute(url);
+                    write!(html, r#"<a href="{url}">{url}</a>"#).unwrap();
+                }
+                None => html.push_str(&encode_text(m.as_str())),
+            }
+            pos = m.end();
+        }
+        html.push_str(&encode_text(&text[pos..]));
+    }
+}
+
+impl Rewrite for ArticleLinks {
+    fn text(&self, text: &str, html: &mut String) {
+        let mut pos = 0;
+        for link in link_finder().links(text) {
+            self.references(&text[pos..link.start()], html);
+            let url = encode_double_quoted_attribute(link.as_str());
+            write!(html, r#"<a href="{url}">{url}</a>"#).unwrap();
+            pos = link.end();
+        }
+        self.references(&text[pos..], html);
+    }
+
+    fn url<'a>(&self, url: &'a str) -> Cow<'a, str> {
+        match url.strip_prefix("nostr:").and_then(|b| self.0.get(b)) {
+            Some(Reference::Mention { url, .. } | Reference::Link(url)) => Cow::Owned(url.clone()),
+            None => Cow::Borrowed(url),
+        }
+    }
+}
+
 async fn get_url_from_ap_id<'a>(state: &AppState, id: &'a str) -> Cow<'a, str> {
     async fn get_url_from_ap_id_aux(state: &AppState, id: &str) -> Option<String> {
         let id = id.parse::<axum::http::Uri>().ok()?;
@@ -750,8 +1103,8 @@ impl Content {
         let html_s = HASHTAG.replace_all(&html_s, |c: &Captures| {
             let url_tag = utf8_percent_encode(&c[1], NON_ALPHANUMERIC);
             format!(
-                r#"<a href="https://coracle.social/topics/{url_tag}" rel="tag">#{}</a>"#,
-                &c[1]
+                r#"<a href="{}{url_tag}" rel="tag">#{}</a>"#,
+                *HASHTAG_URL_PREFIX, &c[1]
             )
         });
         write!(
@@ -778,13 +1131,307 @@ impl Content {
         write!(&mut self.html, r#"<a href="{html_url}">{html_url}</a>"#).unwrap();
         self.misskey.push_str(url);
     }
+
+    /// Truncates the content to `max` graphemes followed by a link to `url`
+    /// if it is longer.
+    pub fn truncate(&mut self, max: usize, url: &str) {
+        let Some((end, open)) = html_cut(&self.html, max) else {
+            return;
+        };
+        let mut html = String::with_capacity(end + url.len() * 2 + 32);
+        html.push_str(&self.html[..end]);
+        html.push('…');
+        for tag in open.iter().rev() {
+            write!(&mut html, "</{tag}>").unwrap();
+        }
+        self.html = html;
+        if let Some(end) = text_cut(&self.misskey, max) {
+            self.misskey.truncate(end);
+            self.misskey.push('…');
+        }
+        write!(&mut self.html, "<br>").unwrap();
+        self.misskey.push('\n');
+        self.link(url);
+    }
+}
+
+/// Whether `c` continues the grapheme of `prev`, after UAX #29: Hangul
+/// syllable sequences, combining marks (`Extend` and `SpacingMark`), joiners,
+/// variation selectors, skin tones and tags. `ri` is whether `prev` is a
+/// regional indicator starting a flag.
+fn extends_grapheme(prev: char, c: char, ri: bool) -> bool {
+    let regional_indicator = ('\u{1F1E6}'..='\u{1F1FF}').contains(&c);
+    prev == '\u{200D}'
+        || (prev == '\r' && c == '\n')
+        || (ri && regional_indicator)
+        || hangul_extends(prev, c)
+        || unicode_normalization::char::is_combining_mark(c)
+        || matches!(
+            c as u32,
+            0x200D | 0xFE00..=0xFE0F | 0x1F3FB..=0x1F3FF | 0xE0020..=0xE007F | 0xE0100..=0xE01EF
+        )
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Hangul {
+    L,
+    V,
+    T,
+    Lv,
+    Lvt,
+}
+
+fn hangul(c: char) -> Option<Hangul> {
+    Some(match c as u32 {
+        0x1100..=0x115F | 0xA960..=0xA97C => Hangul::L,
+        0x1160..=0x11A7 | 0xD7B0..=0xD7C6 => Hangul::V,
+        0x11A8..=0x11FF | 0xD7CB..=0xD7FB => Hangul::T,
+        c @ 0xAC00..=0xD7A3 if (c - 0xAC00) % 28 == 0 => Hangul::Lv,
+        0xAC00..=0xD7A3 => Hangul::Lvt,
+        _ => return None,
+    })
+}
+
+/// Rules GB6 to GB8 of UAX #29.
+fn hangul_extends(prev: char, c: char) -> bool {
+    use Hangul::*;
+    matches!(
+        (hangul(prev), hangul(c)),
+        (Some(L), Some(L | V | Lv | Lvt)) | (Some(Lv | V), Some(V | T)) | (Some(Lvt | T), Some(T))
+    )
+}
+
+/// Counts the graphemes of text a character at a time.
+struct Graphemes {
+    prev: Option<char>,
+    ri: bool,
+    count: usize,
+}
+
+impl Graphemes {
+    /// Whether `c` starts the `max + 1`th grapheme.
+    fn exceeds(&mut self, c: char, max: usize) -> bool {
+        let starts = self.prev.is_none_or(|p| !extends_grapheme(p, c, self.ri));
+        self.ri = ('\u{1F1E6}'..='\u{1F1FF}').contains(&c) && (starts || !self.ri);
+        self.prev = Some(c);
+        if starts {
+            if self.count == max {
+                return true;
+            }
+            self.count += 1;
+        }
+        false
+    }
 }
 
+/// Byte offset of the end of the first `max` graphemes of `s`, `None` if it
+/// isn't longer.
+fn text_cut(s: &str, max: usize) -> Option<usize> {
+    let mut g = Graphemes {
+        prev: None,
+        ri: false,
+        count: 0,
+    };
+    s.char_indices()
+        .find(|(_, c)| g.exceeds(*c, max))
+        .map(|(i, _)| i)
+}
+
+/// Byte offset of the end of the first `max` graphemes of the text of `html`
+/// and the elements open there, `None` if it isn't longer. Tags are skipped
+/// and entities count as a single character, so neither is ever split.
+fn html_cut(html: &str, max: usize) -> Option<(usize, Vec<&str>)> {
+    const VOID: [&str; 4] = ["br", "hr", "img", "wbr"];
+    let mut g = Graphemes {
+        prev: None,
+        ri: false,
+        count: 0,
+    };
+    let mut open = Vec::new();
+    let mut i = 0;
+    while let Some(c) = html[i..].chars().next() {
+        if c == '<' {
+            let end = html[i..].find('>').map_or(html.len(), |e| i + e + 1);
+            let tag = html[i + 1..end].trim_end_matches('>');
+            if let Some(name) = tag.strip_prefix('/') {
+                if let Some(p) = open.iter().rposition(|t| *t == name.trim()) {
+                    open.truncate(p);
+                }
+            } else if !tag.ends_with('/') {
+                let name = tag.split(|c: char| c.is_whitespace()).next().unwrap_or("");
+                if !VOID.contains(&name) {
+                    open.push(name);
+                }
+            }
+            i = end;
+            continue;
+        }
+        if g.exceeds(c, max) {
+            return Some((i, open));
+        }
+        i += match html[i..].find(';') {
+            Some(e) if c == '&' && e < 10 => e + 1,
+            _ => c.len_utf8(),
+        };
+    }
+    None
+}
+
+#[derive(Debug)]
 enum GetProxiedEventError {
     NotProxiedEvent,
     ProxiedByOtherBried(String),
 }
 
+/// `content` of a `Like` for a Nostr reaction. Plain likes have none.
+fn reaction_content(content: &str, is_custom_emoji: bool) -> Option<Cow<'_, str>> {
+    if is_custom_emoji {
+        if content.starts_with(':') {
+            Some(Cow::Borrowed(content))
+        } else {
+            Some(Cow::Owned(format!(":{content}:")))
+        }
+    } else if content == "-" {
+        Some(Cow::Borrowed("👎"))
+    } else if content == "+" || content.is_empty() {
+        None
+    } else {
+        Some(Cow::Borrowed(content))
+    }
+}
+
+/// Activity zaps of bridged notes are sent as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZapActivity {
+    Like,
+    /// `EmojiReact` with ⚡, which is shown as a reaction on Misskey and Pleroma.
+    EmojiReact,
+}
+
+impl FromStr for ZapActivity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "like" => Ok(Self::Like),
+            "emoji-react" => Ok(Self::EmojiReact),
+            s => Err(format!("unknown zap activity: {s}")),
+        }
+    }
+}
+
+/// A zap (NIP-57), from its receipt.
+#[derive(Debug, PartialEq, Eq)]
+struct Zap {
+    sender: PublicKey,
+    recipient: PublicKey,
+    event: EventId,
+    msats: Option<u64>,
+}
+
+fn zap(receipt: &Event) -> Option<Zap> {
+    let (mut description, mut bolt11, mut event, mut recipient) = (None, None, None, None);
+    for tag in &receipt.tags {
+        match tag {
+            Tag::Description(d) => description = Some(d),
+            Tag::Bolt11(b) => bolt11 = Some(b),
+            Tag::Event { event_id, .. } => event = Some(*event_id),
+            Tag::PublicKey {
+                public_key,
+                uppercase: false,
+                ..
+            } => recipient = Some(*public_key),
+            _ => (),
+        }
+    }
+    let request = Event::from_json(description?).ok()?;
+    if request.kind != nostr_lib::Kind::ZapRequest || request.verify().is_err() {
+        return None;
+    }
+    let requested = request.tags.iter().find_map(|t| match t {
+        Tag::Amount { millisats, .. } => Some(*millisats),
+        _ => None,
+    });
+    let invoiced = bolt11.and_then(|b| bolt11_msats(b));
+    // the invoice must be for the amount requested, see NIP-57 Appendix F
+    if requested.is_some() && invoiced.is_some() && requested != invoiced {
+        return None;
+    }
+    let msats = requested.or(invoiced);
+    Some(Zap {
+        sender: request.pubkey,
+        recipient: recipient?,
+        event: event?,
+        msats,
+    })
+}
+
+/// Whether zap receipts for `recipient` are signed by `signer`, the
+/// `nostrPubkey` of the LNURL server of its lightning address.
+async fn is_zap_signer(state: &Arc<AppState>, recipient: &str, signer: PublicKey) -> bool {
+    let Ok(ActorOrProxied::Actor(actor)) = state.get_actor_data(recipient).await else {
+        return false;
+    };
+    let Some(url) = actor.lud16.as_deref().and_then(lnurlp_url) else {
+        return false;
+    };
+    let r = state
+        .http_client
+        .get(&url)
+        .header(reqwest::header::USER_AGENT, &*USER_AGENT)
+        .timeout(std::time::Duration::from_secs(5))
+        .send()
+        .await;
+    match r {
+        Ok(r) => r
+            .json::<serde_json::Value>()
+            .await
+            .is_ok_and(|j| lnurl_nostr_pubkey(&j) == Some(signer)),
+        Err(e) => {
+            debug!("could not fetch {url}: {e}");
+            false
+        }
+    }
+}
+
+/// LUD-16 pay endpoint of a lightning address.
+fn lnurlp_url(lud16: &str) -> Option<String> {
+    let (name, domain) = lud16.split_once('@')?;
+    if name.is_empty() || domain.is_empty() || domain.contains('/') {
+        return None;
+    }
+    Some(format!("https://{domain}/.well-known/lnurlp/{name}"))
+}
+
+/// The key signing zap receipts of an LNURL pay endpoint, if it allows zaps.
+fn lnurl_nostr_pubkey(json: &serde_json::Value) -> Option<PublicKey> {
+    if json.get("allowsNostr")?.as_bool() != Some(true) {
+        return None;
+    }
+    PublicKey::from_hex(json.get("nostrPubkey")?.as_str()?).ok()
+}
+
+/// Amount in millisats of a BOLT11 invoice, from its human-readable part.
+fn bolt11_msats(invoice: &str) -> Option<u64> {
+    let invoice = invoice.to_ascii_lowercase();
+    let hrp = &invoice[..invoice.rfind('1')?];
+    let amount = ["lnbcrt", "lnbc", "lntbs", "lntb"]
+        .iter()
+        .find_map(|p| hrp.strip_prefix(p))?;
+    let (digits, multiplier) = match amount.char_indices().last()? {
+        (i, c @ ('m' | 'u' | 'n' | 'p')) => (&amount[..i], Some(c)),
+        _ => (amount, None),
+    };
+    let n: u64 = digits.parse().ok()?;
+    match multiplier {
+        None => n.checked_mul(100_000_000_000),
+        Some('m') => n.checked_mul(100_000_000),
+        Some('u') => n.checked_mul(100_000),
+        Some('n') => n.checked_mul(100),
+        Some(_) => Some(n / 10),
+    }
+}
+
 #[tracing::instrument(skip_all)]
 fn get_ap_id_from_proxied_event(event: &Event) -> Result<String, GetProxiedEventError> {
     let mut proxy = None;
@@ -834,14 +1481,41 @@ impl Note {
     pub async fn from_nostr_event(state: &Arc<AppState>, event: &Event) -> Option<Self> {
         let author_opt_outed = state.db.is_stopped_npub(event.author_ref());
         let id = event.id.to_bech32().unwrap();
-        let published = event.created_at.to_human_datetime();
+        let article = (event.kind == nostr_lib::Kind::LongFormTextNote)
+            .then(|| Article::from_tags(&event.tags));
+        let published = article
+            .as_ref()
+            .and_then(|a| a.published_at)
+            .unwrap_or(event.created_at)
+            .to_human_datetime();
         let mut handle_cache = FxHashMap::default();
-        let (attachment, content, quote) = media(state, &event.content, &mut handle_cache).await;
+        let content = sanitize_unicode(&event.content, *SANITIZE_UNICODE);
+        let (content, mentions_bot) = strip_bot_mention(&content, &BOT_PUB);
+        let content_mentions = content_mentions(content);
+        let (attachment, mut content, quote) = if article.is_some() {
+            let links = ArticleLinks::resolve(state, content, &mut handle_cache).await;
+            let content = Content {
+                html: markdown_to_html_with(content, &links),
+                misskey: content.to_string(),
+            };
+            (Vec::new(), content, None)
+        } else {
+            media(state, content, &mut handle_cache).await
+        };
+        let quote = match (quote, q_tag(&event.tags)) {
+            (None, Some(q)) => resolve_quote(state, &q).await.map(|(q, _)| q),
+            (quote, _) => quote,
+        };
+        // articles are meant to be read in full
+        if let (Some(max), None) = (*TRUNCATE_CONTENT_GRAPHEMES, &article) {
+            content.truncate(max, &format!("{NOTE_ID_PREFIX}{id}"));
+        }
         let mut reply = None;
         let mut root = None;
         let mut reply_positional = None;
         let mut event_has_marker = false;
         let mut tag = Vec::new();
+        let mut tagged = FxHashSet::default();
         for t in &event.tags {
             match t {
                 Tag::Event {
@@ -883,10 +1557,11 @@ impl Note {
                         // TODO: notify the author that their mention doesn't mirrored
                         return None;
                     }
-                    if !quote
-                        .as_ref()
-                        .map_or(false, |a| &a.author_npub == public_key)
-                    {
+                    if public_key == &*BOT_PUB && !mentions_bot {
+                        continue;
+                    }
+                    tagged.insert(*public_key);
+                    if quote.as_ref().is_none_or(|a| &a.author_npub != public_key) {
                         let (href, name) = (*get_ap_id_and_handle_from_public_key(
                             state,
                             public_key,
@@ -900,9 +1575,31 @@ impl Note {
                 _ => (),
             }
         }
+        // Clients don't always add `p` tags for the users mentioned in the
+        // content, without which fediverse users aren't notified.
+        for public_key in content_mentions {
+            if tagged.contains(&public_key) {
+                continue;
+            }
+            if author_opt_outed && state.activitypub_accounts.lock().contains_key(&public_key) {
+                return None;
+            }
+            let (href, name) =
+                (*get_ap_id_and_handle_from_public_key(state, &public_key, &mut handle_cache)
+                    .await)
+                    .clone();
+            tag.push(NoteTagForSer::Mention { href, name });
+        }
         if author_opt_outed {
             return None;
         }
+        if let Some(href) = quote.as_ref().and_then(|q| q.ap_id.clone()) {
+            tag.push(NoteTagForSer::Link {
+                media_type: OBJECT_LINK_MEDIA_TYPE,
+                name: format!("RE: {href}"),
+                href,
+            });
+        }
         let mut in_reply_to = None;
         if let Some(e) = reply.or(root).or({
             if event_has_marker {
@@ -916,7 +1613,7 @@ impl Note {
                     in_reply_to = Some(a);
                 }
                 Err(GetProxiedEventError::NotProxiedEvent) => {
-                    in_reply_to = Some(format!("{NOTE_ID_PREFIX}{}", e.to_bech32().unwrap()));
+                    in_reply_to = Some(note_url(&e));
                 }
                 Err(GetProxiedEventError::ProxiedByOtherBried(_)) => {
                     info!(
@@ -927,6 +1624,10 @@ impl Note {
                 }
             }
         }
+        let (summary, sensitive) = match article.as_ref().and_then(|a| a.summary.clone()) {
+            Some(s) => (Some(s), false),
+            None => summary(&event.tags),
+        };
         let author = format!(
             "{USER_ID_PREFIX}{}",
             event.author_ref().to_bech32().unwrap()
@@ -947,12 +1648,109 @@ impl Note {
             published,
             attachment,
             in_reply_to,
-            quote: quote.map(|a| a.ap_id),
+            quote: quote.and_then(|a| a.ap_id),
             tag,
+            summary,
+            sensitive,
+            article: article.is_some(),
+            name: article.and_then(|a| a.title),
+            nostr_event: INCLUDE_NOSTR_EVENT.then(|| Arc::new(event.clone())),
+        })
+    }
+
+    /// Minimal representation of a note which can't be fully converted, so
+    /// that replies to it on the fediverse have a parent to dereference.
+    /// Media, mentions and the parent of the note are not resolved. Notes of
+    /// opted-out authors and notes bridged by other bridges have none.
+    pub fn stub_from_nostr_event(state: &AppState, event: &Event) -> Option<Self> {
+        if state.db.is_stopped_npub(event.author_ref())
+            || matches!(
+                get_ap_id_from_proxied_event(event),
+                Err(GetProxiedEventError::ProxiedByOtherBried(_))
+            )
+        {
+            return None;
+        }
+        let article = (event.kind == nostr_lib::Kind::LongFormTextNote)
+            .then(|| Article::from_tags(&event.tags));
+        let mut content = Content {
+            html: String::with_capacity(event.content.len()),
+            misskey: String::with_capacity(event.content.len()),
+        };
+        content.span(&sanitize_unicode(&event.content, *SANITIZE_UNICODE));
+        let (summary, sensitive) = match article.as_ref().and_then(|a| a.summary.clone()) {
+            Some(s) => (Some(s), false),
+            None => summary(&event.tags),
+        };
+        Some(Note {
+            author: format!(
+                "{USER_ID_PREFIX}{}",
+                event.author_ref().to_bech32().unwrap()
+            ),
+            id: event.id.to_bech32().unwrap(),
+            nevent: Nip19Event::new(event.id, OUTBOX_RELAYS.iter().copied())
+                .to_bech32()
+                .unwrap(),
+            content: content.html,
+            misskey_content: content.misskey,
+            published: event.created_at.to_human_datetime(),
+            attachment: Vec::new(),
+            quote: None,
+            in_reply_to: None,
+            tag: Vec::new(),
+            summary,
+            sensitive,
+            article: article.is_some(),
+            name: article.and_then(|a| a.title),
+            nostr_event: INCLUDE_NOSTR_EVENT.then(|| Arc::new(event.clone())),
         })
     }
 }
 
+/// AP id of a Nostr event, served by this server.
+pub fn note_url(event_id: &EventId) -> String {
+    format!("{NOTE_ID_PREFIX}{}", event_id.to_bech32().unwrap())
+}
+
+/// Id of the `Follow` of the actor `followee` by the Nostr user `npub`.
+pub fn follow_id(npub: &str, followee: &str) -> String {
+    format!(
+        "{HTTPS_DOMAIN}/follow/{npub}/{}",
+        utf8_percent_encode(followee, NON_ALPHANUMERIC)
+    )
+}
+
+/// Sends the `Undo` of the `Follow` of the actor `followee` by `npub`.
+pub async fn send_unfollow(
+    state: &Arc<AppState>,
+    npub: &str,
+    inbox: &axum::http::Uri,
+    followee: &str,
+) {
+    let author = format!("{USER_ID_PREFIX}{npub}");
+    let escaped_id = utf8_percent_encode(followee, NON_ALPHANUMERIC);
+    let follow_id = follow_id(npub, followee);
+    state.db.remove_follow(&follow_id);
+    if let Err(e) = state
+        .send_activity(
+            inbox,
+            &author,
+            UndoFollowActivity {
+                object: FollowActivity {
+                    actor: &author,
+                    object: followee,
+                    id: Some(&follow_id),
+                },
+                actor: &author,
+                id: &format!("{HTTPS_DOMAIN}/unfollow/{author}/{escaped_id}"),
+            },
+        )
+        .await
+    {
+        error!("could not send activity: {e:?}");
+    }
+}
+
 #[tracing::instrument(skip_all)]
 async fn get_ap_id_and_handle_from_public_key(
     state: &Arc<AppState>,
@@ -970,14 +1768,12 @@ async fn get_ap_id_and_handle_from_public_key(
         if let NostrUser::Proxied(id) = &a.as_ref().ok()? {
             match state.get_actor_data(id).await {
                 Ok(ActorOrProxied::Actor(a)) => {
-                    return Some((
-                        a.id.clone(),
-                        format!(
-                            "@{}@{}",
-                            a.preferred_username.as_ref().unwrap_or(&a.name),
-                            axum::http::Uri::from_str(&a.id).ok()?.host()?
-                        ),
-                    ));
+                    let handle = normalize_handle(&format!(
+                        "{}@{}",
+                        a.preferred_username.as_ref().unwrap_or(&a.name),
+                        axum::http::Uri::from_str(&a.id).ok()?.host()?
+                    ))?;
+                    return Some((a.id.clone(), format!("@{handle}")));
                 }
                 Err(e) => {
                     error!("could not get actor data from {id}: {e:?}");
@@ -995,7 +1791,124 @@ async fn get_ap_id_and_handle_from_public_key(
     a
 }
 
-pub async fn update_follow_list(state: &AppState, event: Arc<Event>) {
+/// NIP-62 request to vanish.
+pub const REQUEST_TO_VANISH: u64 = 62;
+
+/// Whether a request to vanish is addressed to `ALL_RELAYS` or to one of
+/// `relays`.
+fn vanishes_from(event: &Event, relays: &[url::Url]) -> bool {
+    event.tags.iter().any(|t| match &t.as_vec()[..] {
+        [k, r, ..] if k == "relay" => {
+            r == "ALL_RELAYS"
+                || url::Url::parse(r).is_ok_and(|r| {
+                    relays.iter().any(|a| {
+                        a.as_str().trim_end_matches('/') == r.as_str().trim_end_matches('/')
+                    })
+                })
+        }
+        _ => false,
+    })
+}
+
+/// What a deletion by a Nostr user removes from their proxied actor.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum AccountDeletion {
+    /// Only the profile. The actor stays, with empty metadata.
+    Metadata,
+    /// The whole account. The actor becomes a `Tombstone`.
+    Account,
+}
+
+fn account_deletion(event: &Event) -> Option<AccountDeletion> {
+    if event.kind == nostr_lib::Kind::from(REQUEST_TO_VANISH) {
+        return Some(AccountDeletion::Account);
+    }
+    if event.kind != nostr_lib::Kind::EventDeletion {
+        return None;
+    }
+    event
+        .tags
+        .iter()
+        .any(|t| match t {
+            Tag::Kind(k) => *k == nostr_lib::Kind::Metadata,
+            Tag::A { coordinate, .. } => {
+                coordinate.kind == nostr_lib::Kind::Metadata
+                    && coordinate.public_key == event.pubkey
+            }
+            _ => false,
+        })
+        .then_some(AccountDeletion::Metadata)
+}
+
+async fn delete_account(state: &Arc<AppState>, event: &Event, deletion: AccountDeletion) {
+    info!("{deletion:?} deletion of {}", event.pubkey);
+    let actor = format!("{USER_ID_PREFIX}{}", event.pubkey.to_bech32().unwrap());
+    let id = event.id.to_bech32().unwrap();
+    match deletion {
+        AccountDeletion::Metadata => {
+            let Some(followers) = state
+                .nostr_account_to_followers
+                .lock()
+                .get(&event.pubkey)
+                .cloned()
+            else {
+                return;
+            };
+            let empty = Metadata::default();
+            let metadata = metadata_to_activity(state, event.pubkey, &empty).await;
+            let published = event.created_at.to_human_datetime();
+            broadcast_to_actors(
+                state,
+                UpdateForSer {
+                    actor: &actor,
+                    id: &id,
+                    published: &published,
+                    object: metadata,
+                },
+                &actor,
+                followers.iter(),
+                false,
+            )
+            .await;
+        }
+        AccountDeletion::Account => {
+            let followers = state
+                .nostr_account_to_followers
+                .lock()
+                .remove(&event.pubkey);
+            state.db.delete_npub(&event.pubkey);
+            let Some(followers) = followers else {
+                return;
+            };
+            {
+                let mut rev = state.nostr_account_to_followers_rev.lock();
+                for f in followers.iter() {
+                    if let Some(l) = rev.get_mut(f) {
+                        l.remove(&event.pubkey);
+                    }
+                }
+            }
+            // persisted before the deletions go out, so that a restart
+            // doesn't bring the followers back
+            if let Err(e) = state.snapshot_accounts().await {
+                error!("could not snapshot accounts: {e:?}");
+            }
+            broadcast_to_actors(
+                state,
+                DeleteActorForSer {
+                    actor: &actor,
+                    id: &id,
+                },
+                &actor,
+                followers.iter(),
+                false,
+            )
+            .await;
+        }
+    }
+}
+
+pub async fn update_follow_list(state: &Arc<AppState>, event: Arc<Event>) {
     let follow_list_old = state.db.get_followee_of_nostr(event.author_ref());
     let follow_list_new: FxHashSet<_> = event
         .tags
@@ -1007,20 +1920,29 @@ pub async fn update_follow_list(state: &AppState, event: Arc<Event>) {
                 ..
             } = t
             {
-                state
-                    .activitypub_accounts
-                    .lock()
-                    .get(public_key)
-                    .map(|a| a.clone())
+                state.activitypub_accounts.lock().get(public_key).cloned()
             } else {
                 None
             }
         })
         .collect();
+    let changed = match &follow_list_old {
+        Some(old) => **old != follow_list_new,
+        None => !follow_list_new.is_empty(),
+    };
+    if !changed {
+        return;
+    }
+    debug!("updated follow list: {:?}", follow_list_new);
+    let follow_list_new = Arc::new(follow_list_new);
+    // recorded before the follows are sent, so that an `Accept` arriving
+    // right away isn't taken for one of an unfollowed actor
+    state
+        .db
+        .insert_followee_of_nostr(event.author(), follow_list_new.clone());
     let npub = event.author_ref().to_bech32().unwrap();
     let author = format!("{USER_ID_PREFIX}{npub}",);
-    let mut changed = false;
-    for actor_id in &follow_list_new {
+    for actor_id in &*follow_list_new {
         if follow_list_old
             .as_ref()
             .map(|a| a.contains(&**actor_id))
@@ -1028,7 +1950,6 @@ pub async fn update_follow_list(state: &AppState, event: Arc<Event>) {
         {
             continue;
         }
-        changed = true;
         if let Ok(ActorOrProxied::Actor(a)) = state.get_actor_data(actor_id).await {
             if let Actor {
                 inbox: Some(inbox),
@@ -1036,6 +1957,10 @@ pub async fn update_follow_list(state: &AppState, event: Arc<Event>) {
                 ..
             } = &*a
             {
+                let follow_id = follow_id(&npub, id);
+                state
+                    .db
+                    .insert_pending_follow(&follow_id, Timestamp::now().as_u64());
                 if let Err(e) = state
                     .send_activity(
                         inbox,
@@ -1043,10 +1968,7 @@ pub async fn update_follow_list(state: &AppState, event: Arc<Event>) {
                         FollowActivity {
                             actor: &author,
                             object: id,
-                            id: Some(&format!(
-                                "{HTTPS_DOMAIN}/follow/{npub}/{}",
-                                utf8_percent_encode(id, NON_ALPHANUMERIC)
-                            )),
+                            id: Some(&follow_id),
                         },
                     )
                     .await
@@ -1060,7 +1982,6 @@ pub async fn update_follow_list(state: &AppState, event: Arc<Event>) {
         if follow_list_new.contains(actor_id) {
             continue;
         }
-        changed = true;
         if let Ok(ActorOrProxied::Actor(a)) = state.get_actor_data(actor_id).await {
             if let Actor {
                 inbox: Some(inbox),
@@ -1068,42 +1989,26 @@ pub async fn update_follow_list(state: &AppState, event: Arc<Event>) {
                 ..
             } = &*a
             {
-                let escaped_id = utf8_percent_encode(id, NON_ALPHANUMERIC);
-                if let Err(e) = state
-                    .send_activity(
-                        inbox,
-                        &author,
-                        UndoFollowActivity {
-                            object: FollowActivity {
-                                actor: &author,
-                                object: id,
-                                id: Some(&format!("{HTTPS_DOMAIN}/follow/{author}/{escaped_id}")),
-                            },
-                            actor: &author,
-                            id: &format!("{HTTPS_DOMAIN}/unfollow/{author}/{escaped_id}"),
-                        },
-                    )
-                    .await
-                {
-                    error!("could not send activity: {e:?}");
-                }
+                send_unfollow(state, &npub, inbox, id).await;
             }
         }
     }
-    if changed {
-        debug!("updated follow list: {:?}", follow_list_new);
-        state
-            .db
-            .insert_followee_of_nostr(event.author(), follow_list_new.into());
-    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::media;
+    use super::{
+        account_deletion, bolt11_msats, content_mentions, get_ap_id_from_proxied_event,
+        is_list_kind, link_finder, lnurl_nostr_pubkey, lnurlp_url, media, native_quote_url,
+        note_recipients, note_url, q_tag, reaction_content, strip_bot_mention, summary, text_cut,
+        vanishes_from, zap, AccountDeletion, Article, Content, Zap, REQUEST_TO_VANISH,
+    };
+    use crate::activity::ReactionForSer;
     use crate::db::Db;
     use crate::event_deletion_queue::EventDeletionQueue;
+    use crate::server::event_tag;
     use crate::server::AppState;
+    use crate::util::{CircuitBreaker, HostBatcher, HostLimiter, RateLimiter};
     use crate::{RelayId, NOTE_ID_PREFIX, USER_AGENT};
     use cached::TimedSizedCache;
     use itertools::Itertools;
@@ -1139,7 +2044,10 @@ mod tests {
                 let nostr = RelayPool::new(USER_AGENT.to_string()).await;
                 let mut main_relays = FxHashSet::default();
                 for (i, l) in relays.iter().enumerate() {
-                    nostr.add_relay(RelayId(i as u32), l.clone()).await.unwrap();
+                    nostr
+                        .add_relay(RelayId(i as u32), l.clone(), None)
+                        .await
+                        .unwrap();
                     main_relays.insert(RelayId(i as u32));
                 }
                 let main_relays = Arc::new(main_relays);
@@ -1154,14 +2062,40 @@ mod tests {
                     http_client: http_client.clone(),
                     note_cache: Mutex::new(LruCache::new(NonZeroUsize::new(1000).unwrap())),
                     actor_cache: Mutex::new(LruCache::new(NonZeroUsize::new(1000).unwrap())),
+                    actor_activity: Mutex::new(LruCache::new(NonZeroUsize::new(1000).unwrap())),
+                    poll_votes: Mutex::new(LruCache::new(NonZeroUsize::new(1000).unwrap())),
+                    zap_receipts: Mutex::new(LruCache::new(NonZeroUsize::new(1000).unwrap())),
+                    seen_events: Mutex::new(LruCache::new(NonZeroUsize::new(1000).unwrap())),
+                    outbox_relays: Mutex::new(crate::outbox::OutboxRelays::new(0, 1000)),
                     nostr_user_cache: Mutex::new(TimedSizedCache::with_size_and_lifespan(
                         1000,
                         60 * 10,
                     )),
+                    software_cache: Mutex::new(TimedSizedCache::with_size_and_lifespan(
+                        1000,
+                        60 * 60 * 24,
+                    )),
+                    webfinger_cache: Mutex::new(TimedSizedCache::with_size_and_lifespan(
+                        1000,
+                        60 * 10,
+                    )),
+                    webfinger_miss_cache: Mutex::new(TimedSizedCache::with_size_and_lifespan(
+                        1000, 60,
+                    )),
+                    nip05_cache: Mutex::new(TimedSizedCache::with_size_and_lifespan(1000, 60)),
+                    host_limiter: HostLimiter::new(4),
+                    in_flight_fetches: Default::default(),
+                    delivery_log: Mutex::new(LruCache::new(NonZeroUsize::new(100).unwrap())),
+                    inbox_rate_limiter: RateLimiter::new(0),
+                    circuit_breaker: CircuitBreaker::new(0, std::time::Duration::ZERO),
+                    delivery_batcher: HostBatcher::new(std::time::Duration::ZERO),
+                    inbox_limiter: Arc::new(tokio::sync::Semaphore::new(16)),
                     db: Db::new().await,
                     metadata_relays: main_relays.clone(),
                     main_relays,
                     event_deletion_queue: EventDeletionQueue::new(Arc::new(http_client)),
+                    metrics: Default::default(),
+                    account_writes: Default::default(),
                 })
             })
             .await
@@ -1244,7 +2178,11 @@ https://pbs.twimg.com/profile_banners/12/1688241283/1500x500
         assert_eq!(content.html, format!("<span>: </span><br><span><br>RE: </span><a href=\"https://coracle.social/{event}\">https://coracle.social/{event}</a>"));
         assert_eq!(media.len(), 0);
         assert_eq!(
-            q.unwrap().ap_id.strip_prefix(NOTE_ID_PREFIX).unwrap(),
+            q.unwrap()
+                .ap_id
+                .unwrap()
+                .strip_prefix(NOTE_ID_PREFIX)
+                .unwrap(),
             Nip19Event::from_bech32(event)
                 .unwrap()
                 .event_id
@@ -1261,7 +2199,11 @@ https://pbs.twimg.com/profile_banners/12/1688241283/1500x500
         assert_eq!(content.misskey, ": \n");
         assert_eq!(media.len(), 0);
         assert_eq!(
-            q.unwrap().ap_id.strip_prefix(NOTE_ID_PREFIX).unwrap(),
+            q.unwrap()
+                .ap_id
+                .unwrap()
+                .strip_prefix(NOTE_ID_PREFIX)
+                .unwrap(),
             Nip19Event::from_bech32(event)
                 .unwrap()
                 .event_id
@@ -1281,7 +2223,11 @@ https://pbs.twimg.com/profile_banners/12/1688241283/1500x500
         );
         assert_eq!(media.len(), 0);
         assert_eq!(
-            q.unwrap().ap_id.strip_prefix(NOTE_ID_PREFIX).unwrap(),
+            q.unwrap()
+                .ap_id
+                .unwrap()
+                .strip_prefix(NOTE_ID_PREFIX)
+                .unwrap(),
             Nip19Event::from_bech32(event)
                 .unwrap()
                 .event_id
@@ -1313,4 +2259,489 @@ https://pbs.twimg.com/profile_banners/12/1688241283/1500x500
         let (_, content, _) = media(get_state().await, s, &mut FxHashMap::default()).await;
         assert_eq!(content.html, "<span>test🍆<br></span><span><br>RE: </span><a href=\"https://mastodon.social/@pixelfed/112342975213580101\">https://mastodon.social/@pixelfed/112342975213580101</a>");
     }
+
+    #[test]
+    fn reaction_to_bridged_note() {
+        let keys = nostr_lib::Keys::generate();
+        let reacted = nostr_lib::EventBuilder::new(
+            nostr_lib::Kind::TextNote,
+            "test",
+            event_tag("https://example.com/notes/1".to_string(), []),
+        )
+        .to_event(&keys)
+        .unwrap();
+        let object = get_ap_id_from_proxied_event(&reacted).unwrap();
+        assert_eq!(object, "https://example.com/notes/1");
+        let content = reaction_content("+", false);
+        let activity = ReactionForSer {
+            actor: "https://momostr.pink/users/npub1",
+            id: "note1",
+            object: &object,
+            content: content.as_deref(),
+            tag: None,
+            emoji_react: true,
+        };
+        let activity = serde_json::to_value(&activity).unwrap();
+        assert_eq!(activity["type"], "Like");
+        assert_eq!(activity["object"], "https://example.com/notes/1");
+        assert!(activity.get("content").is_none());
+
+        let content = reaction_content("🍉", false);
+        let activity = ReactionForSer {
+            actor: "https://momostr.pink/users/npub1",
+            id: "note1",
+            object: &object,
+            content: content.as_deref(),
+            tag: None,
+            emoji_react: true,
+        };
+        let activity = serde_json::to_value(&activity).unwrap();
+        assert_eq!(activity["type"], "EmojiReact");
+        assert_eq!(activity["content"], "🍉");
+    }
+
+    #[test]
+    fn list_kinds_are_skipped() {
+        assert!(is_list_kind(nostr_lib::Kind::FollowSets));
+        assert!(is_list_kind(nostr_lib::Kind::MuteList));
+        assert!(is_list_kind(nostr_lib::Kind::from(30000)));
+        assert!(!is_list_kind(nostr_lib::Kind::ContactList));
+        assert!(!is_list_kind(nostr_lib::Kind::TextNote));
+        assert!(!is_list_kind(nostr_lib::Kind::LongFormTextNote));
+    }
+
+    #[test]
+    fn subject_to_summary() {
+        use nostr_lib::Tag;
+        let subject = Tag::Subject("hello".to_string());
+        assert_eq!(summary(&[]), (None, false));
+        assert_eq!(
+            summary(std::slice::from_ref(&subject)),
+            (Some("hello".to_string()), false)
+        );
+        assert_eq!(
+            summary(&[
+                subject.clone(),
+                Tag::ContentWarning {
+                    reason: Some("spoiler".to_string())
+                }
+            ]),
+            (Some("spoiler".to_string()), true)
+        );
+        assert_eq!(
+            summary(&[subject, Tag::ContentWarning { reason: None }]),
+            (Some("hello".to_string()), true)
+        );
+    }
+
+    #[test]
+    fn bot_mention_is_stripped() {
+        let bot = nostr_lib::Keys::generate().public_key();
+        let other = nostr_lib::Keys::generate()
+            .public_key()
+            .to_bech32()
+            .unwrap();
+        let bot_npub = bot.to_bech32().unwrap();
+        assert_eq!(
+            strip_bot_mention(&format!("nostr:{bot_npub} stop my mirror"), &bot),
+            ("stop my mirror", false)
+        );
+        let s = format!("nostr:{other} hi");
+        assert_eq!(strip_bot_mention(&s, &bot), (s.as_str(), false));
+        let s = format!("I use nostr:{bot_npub} to bridge");
+        assert_eq!(strip_bot_mention(&s, &bot), (s.as_str(), true));
+    }
+
+    #[test]
+    fn bare_url_and_hashtag() {
+        let s = "see (https://example.com/a_(b)) and https://example.com/c. #tag";
+        let links = link_finder().links(s).map(|l| l.as_str()).collect_vec();
+        assert_eq!(
+            links,
+            vec!["https://example.com/a_(b)", "https://example.com/c"]
+        );
+        let mut content = Content {
+            html: String::new(),
+            misskey: String::new(),
+        };
+        content.span("see ");
+        content.link("https://example.com/?a=1&b=2");
+        content.span(" <#tag>");
+        assert_eq!(
+            content.html,
+            "<span>see </span><a href=\"https://example.com/?a=1&amp;b=2\">https://example.com/?a=1&amp;b=2</a>\
+            <span> &lt;<a href=\"https://coracle.social/topics/tag\" rel=\"tag\">#tag</a>&gt;</span>"
+        );
+    }
+
+    #[test]
+    fn truncate_content() {
+        let mut content = Content {
+            html: String::new(),
+            misskey: String::new(),
+        };
+        content.span("a&b ");
+        content.link("https://example.com/");
+        content.span(" 👩‍💻🇯🇵é");
+        let url = "https://momostr.pink/notes/note1";
+        let mut short = Content {
+            html: content.html.clone(),
+            misskey: content.misskey.clone(),
+        };
+        short.truncate(100, url);
+        assert_eq!(short.html, content.html);
+        content.truncate(2, url);
+        assert_eq!(
+            content.html,
+            "<span>a&amp;…</span><br><a href=\"https://momostr.pink/notes/note1\">https://momostr.pink/notes/note1</a>"
+        );
+        assert_eq!(content.misskey, "a&…\nhttps://momostr.pink/notes/note1");
+
+        let s = "x 👩‍💻🇯🇵🇺🇸e\u{301}y";
+        assert_eq!(text_cut(s, 3), Some("x 👩‍💻".len()));
+        assert_eq!(text_cut(s, 4), Some("x 👩‍💻🇯🇵".len()));
+        assert_eq!(text_cut(s, 6), Some("x 👩‍💻🇯🇵🇺🇸e\u{301}".len()));
+        assert_eq!(text_cut(s, 7), None);
+    }
+
+    #[test]
+    fn grapheme_categories() {
+        let count = |s: &str| (1..).find(|&n| text_cut(s, n).is_none()).unwrap();
+        // Extend: Thai vowel signs and Devanagari virama
+        assert_eq!(count("\u{E01}\u{E31}\u{E01}\u{E34}"), 2);
+        assert_eq!(count("\u{915}\u{94D}\u{937}"), 2);
+        // SpacingMark: Devanagari vowel sign I
+        assert_eq!(count("\u{915}\u{93F}"), 1);
+        // Hangul L V T, LV T and LVT followed by an L starting a new syllable
+        assert_eq!(count("\u{1100}\u{1161}\u{11A8}"), 1);
+        assert_eq!(count("\u{AC00}\u{11A8}"), 1);
+        assert_eq!(count("\u{AC01}\u{1100}"), 2);
+        assert_eq!(count("\u{AC01}\u{1161}"), 2);
+        // Extend after a base of another script, and a lone mark
+        assert_eq!(count("a\u{301}\u{20DD}"), 1);
+        assert_eq!(count("\u{301}a"), 2);
+        // no grapheme crosses a space
+        assert_eq!(count("a b"), 3);
+    }
+
+    #[tokio::test]
+    async fn reply_to_native_note() {
+        use crate::activity::Note;
+        let keys = nostr_lib::Keys::generate();
+        let parent = nostr_lib::EventBuilder::text_note("hello\n#tag", [])
+            .to_event(&keys)
+            .unwrap();
+        let url = note_url(&parent.id);
+        let note = url.strip_prefix(NOTE_ID_PREFIX).unwrap();
+        assert_eq!(nostr_lib::EventId::from_bech32(note).unwrap(), parent.id);
+        let state = get_state().await;
+        let stub = serde_json::to_value(Note::stub_from_nostr_event(state, &parent)).unwrap();
+        assert_eq!(stub["id"], url);
+        assert_eq!(stub["type"], "Note");
+        assert!(stub["content"]
+            .as_str()
+            .unwrap()
+            .starts_with("<span>hello<br>"));
+    }
+
+    #[test]
+    fn metadata_deletion() {
+        use nostr_lib::{EventBuilder, Keys, Kind, Tag};
+        let keys = Keys::generate();
+        let metadata = EventBuilder::metadata(&nostr_lib::Metadata::new().name("a"))
+            .to_event(&keys)
+            .unwrap();
+        let deletion = EventBuilder::new(
+            Kind::EventDeletion,
+            "",
+            [Tag::event(metadata.id), Tag::Kind(Kind::Metadata)],
+        )
+        .to_event(&keys)
+        .unwrap();
+        assert_eq!(account_deletion(&deletion), Some(AccountDeletion::Metadata));
+        let note_deletion = EventBuilder::delete([metadata.id]).to_event(&keys).unwrap();
+        assert_eq!(account_deletion(&note_deletion), None);
+        let vanish = EventBuilder::new(Kind::from(REQUEST_TO_VANISH), "", [])
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(account_deletion(&vanish), Some(AccountDeletion::Account));
+    }
+
+    #[test]
+    fn vanishes_from_1() {
+        use nostr_lib::{EventBuilder, Keys, Kind, Tag, TagKind};
+        let keys = Keys::generate();
+        let vanish = |relay: &str| {
+            let relay = Tag::Generic(TagKind::Custom("relay".into()), vec![relay.to_string()]);
+            EventBuilder::new(Kind::from(REQUEST_TO_VANISH), "", [relay])
+                .to_event(&keys)
+                .unwrap()
+        };
+        let relays = [url::Url::parse("wss://relay.example.com").unwrap()];
+        assert!(vanishes_from(&vanish("ALL_RELAYS"), &relays));
+        assert!(vanishes_from(&vanish("wss://relay.example.com/"), &relays));
+        assert!(!vanishes_from(&vanish("wss://other.example.com"), &relays));
+        let untagged = EventBuilder::new(Kind::from(REQUEST_TO_VANISH), "", [])
+            .to_event(&keys)
+            .unwrap();
+        assert!(!vanishes_from(&untagged, &relays));
+    }
+
+    #[tokio::test]
+    async fn native_quote_is_resolvable() {
+        use crate::activity::Note;
+        let keys = nostr_lib::Keys::generate();
+        let quoted = nostr_lib::EventBuilder::text_note("quoted", [])
+            .to_event(&keys)
+            .unwrap();
+        let url = note_url(&quoted.id);
+        assert_eq!(native_quote_url(&quoted.id), url);
+        assert!(url.starts_with(NOTE_ID_PREFIX));
+        let stub =
+            serde_json::to_value(Note::stub_from_nostr_event(get_state().await, &quoted)).unwrap();
+        assert_eq!(stub["id"], url);
+    }
+
+    #[tokio::test]
+    async fn mention_only_in_content_is_delivered() {
+        use crate::activity::{Note, NoteTagForSer};
+        use crate::nostr::NostrUser;
+        use cached::Cached;
+        let state = get_state().await;
+        let mentioned = nostr_lib::Keys::generate().public_key();
+        let actor = Arc::new("https://example.com/users/a".to_string());
+        state
+            .activitypub_accounts
+            .lock()
+            .insert(mentioned, actor.clone());
+        state.nostr_user_cache.lock().cache_set(
+            mentioned,
+            Arc::new(OnceCell::new_with(Some(Arc::new(Ok(NostrUser::Metadata(
+                Default::default(),
+            )))))),
+        );
+        let npub = mentioned.to_bech32().unwrap();
+        let event = nostr_lib::EventBuilder::text_note(format!("hi nostr:{npub}"), [])
+            .to_event(&nostr_lib::Keys::generate())
+            .unwrap();
+        let (ps, to_bot) = note_recipients(&event, &state.activitypub_accounts.lock());
+        assert_eq!(ps, [actor]);
+        assert!(!to_bot);
+        let note = Note::from_nostr_event(state, &event).await.unwrap();
+        assert!(note.tag.iter().any(|t| matches!(
+            t,
+            NoteTagForSer::Mention { name, .. } if name.contains(&npub)
+        )));
+    }
+
+    #[tokio::test]
+    async fn article_mentions_and_links() {
+        use crate::activity::Note;
+        use crate::nostr::NostrUser;
+        use cached::Cached;
+        let state = get_state().await;
+        let mentioned = nostr_lib::Keys::generate().public_key();
+        state.nostr_user_cache.lock().cache_set(
+            mentioned,
+            Arc::new(OnceCell::new_with(Some(Arc::new(Ok(NostrUser::Metadata(
+                Default::default(),
+            )))))),
+        );
+        let npub = mentioned.to_bech32().unwrap();
+        let event = nostr_lib::EventBuilder::new(
+            nostr_lib::Kind::LongFormTextNote,
+            format!("# Title\n\nhi nostr:{npub}, see https://example.com/a and [b](https://example.com/b)"),
+            [nostr_lib::Tag::Identifier("a".to_string())],
+        )
+        .to_event(&nostr_lib::Keys::generate())
+        .unwrap();
+        let note = Note::from_nostr_event(state, &event).await.unwrap();
+        assert!(note
+            .content
+            .contains(&format!(r#"/users/{npub}" class="u-url mention">@{npub}@"#)));
+        assert!(note
+            .content
+            .contains(r#"<a href="https://example.com/a">https://example.com/a</a>"#));
+        assert!(note
+            .content
+            .contains(r#"<a href="https://example.com/b">b</a>"#));
+        assert!(!note.content.contains("nostr:npub"));
+    }
+
+    #[test]
+    fn mentions_in_content() {
+        let a = nostr_lib::Keys::generate().public_key();
+        let b = nostr_lib::Keys::generate().public_key();
+        let nprofile = nostr_lib::nips::nip19::Nip19Profile::new(b, ["wss://relay.example.com"])
+            .unwrap()
+            .to_bech32()
+            .unwrap();
+        let npub = a.to_bech32().unwrap();
+        let content = format!("hi nostr:{npub} and nostr:{nprofile}, {npub} again");
+        assert_eq!(content_mentions(&content), [a, b]);
+        assert!(content_mentions("npub1 is not a key").is_empty());
+    }
+
+    #[test]
+    fn quote_with_q_tag() {
+        use crate::activity::NoteTagForSer;
+        let quoted = nostr_lib::EventId::all_zeros();
+        let q = nostr_lib::Tag::Generic(
+            nostr_lib::TagKind::Custom("q".to_string()),
+            vec![quoted.to_hex()],
+        );
+        assert_eq!(
+            q_tag(&[nostr_lib::Tag::Hashtag("a".to_string()), q]),
+            Some(quoted)
+        );
+        assert_eq!(q_tag(&[]), None);
+        let link = NoteTagForSer::Link {
+            media_type: super::OBJECT_LINK_MEDIA_TYPE,
+            href: "https://example.com/notes/1".to_string(),
+            name: "RE: https://example.com/notes/1".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_value(link).unwrap(),
+            serde_json::json!({
+                "type": "Link",
+                "mediaType": "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"",
+                "href": "https://example.com/notes/1",
+                "name": "RE: https://example.com/notes/1",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn long_form_article() {
+        let event = nostr_lib::EventBuilder::long_form_text_note(
+            "# Hello",
+            [
+                nostr_lib::Tag::Title("My post".to_string()),
+                nostr_lib::Tag::Summary("About things".to_string()),
+                nostr_lib::Tag::PublishedAt(nostr_lib::Timestamp::from(1_700_000_000)),
+            ],
+        )
+        .to_event(&nostr_lib::Keys::generate())
+        .unwrap();
+        assert_eq!(
+            Article::from_tags(&event.tags),
+            Article {
+                title: Some("My post".to_string()),
+                summary: Some("About things".to_string()),
+                published_at: Some(nostr_lib::Timestamp::from(1_700_000_000)),
+            }
+        );
+        let note = crate::activity::Note::stub_from_nostr_event(get_state().await, &event);
+        let v = serde_json::to_value(&note).unwrap();
+        assert_eq!(v["type"], "Article");
+        assert_eq!(v["name"], "My post");
+        assert_eq!(v["summary"], "About things");
+    }
+
+    #[tokio::test]
+    async fn no_stub_for_other_bridges_and_opted_out_authors() {
+        use crate::activity::Note;
+        use nostr_lib::nips::nip48::Protocol;
+        let state = get_state().await;
+        let keys = nostr_lib::Keys::generate();
+        let proxied = nostr_lib::EventBuilder::text_note(
+            "bridged",
+            [nostr_lib::Tag::Proxy {
+                id: "https://example.com/notes/1".to_string(),
+                protocol: Protocol::ActivityPub,
+            }],
+        )
+        .to_event(&keys)
+        .unwrap();
+        assert!(Note::stub_from_nostr_event(state, &proxied).is_none());
+        let native = nostr_lib::EventBuilder::text_note("native", [])
+            .to_event(&keys)
+            .unwrap();
+        assert!(Note::stub_from_nostr_event(state, &native).is_some());
+        state.db.stop_npub(&keys.public_key());
+        assert!(Note::stub_from_nostr_event(state, &native).is_none());
+        state.db.restart_npub(&keys.public_key());
+    }
+
+    #[test]
+    fn zap_receipt() {
+        use nostr_lib::nips::nip57::ZapRequestData;
+        use nostr_lib::{EventBuilder, Keys};
+        let (sender, recipient) = (Keys::generate(), Keys::generate());
+        let note = EventBuilder::text_note("a", [])
+            .to_event(&recipient)
+            .unwrap();
+        let mut data = ZapRequestData::new(recipient.public_key(), []);
+        data.event_id = Some(note.id);
+        let request = EventBuilder::public_zap_request(data.clone())
+            .to_event(&sender)
+            .unwrap();
+        let receipt = |request| {
+            EventBuilder::zap_receipt("lnbc210n1pj", None, request)
+                .to_event(&Keys::generate())
+                .unwrap()
+        };
+        let zap_of = |msats| Zap {
+            sender: sender.public_key(),
+            recipient: recipient.public_key(),
+            event: note.id,
+            msats,
+        };
+        assert_eq!(zap(&receipt(request)), Some(zap_of(Some(21_000))));
+        data.amount = Some(21_000);
+        let request = EventBuilder::public_zap_request(data.clone())
+            .to_event(&sender)
+            .unwrap();
+        assert_eq!(zap(&receipt(request)), Some(zap_of(Some(21_000))));
+        // an invoice for another amount than requested
+        data.amount = Some(1_000);
+        let request = EventBuilder::public_zap_request(data)
+            .to_event(&sender)
+            .unwrap();
+        assert_eq!(zap(&receipt(request)), None);
+        assert_eq!(zap(&note), None);
+    }
+
+    #[test]
+    fn lnurl_zap_signer() {
+        assert_eq!(
+            lnurlp_url("a@getalby.com").as_deref(),
+            Some("https://getalby.com/.well-known/lnurlp/a")
+        );
+        assert_eq!(lnurlp_url("a@b.example/c"), None);
+        assert_eq!(lnurlp_url("getalby.com"), None);
+        let signer = nostr_lib::Keys::generate().public_key();
+        let json = serde_json::json!({"allowsNostr": true, "nostrPubkey": signer.to_hex()});
+        assert_eq!(lnurl_nostr_pubkey(&json), Some(signer));
+        let json = serde_json::json!({"allowsNostr": false, "nostrPubkey": signer.to_hex()});
+        assert_eq!(lnurl_nostr_pubkey(&json), None);
+        assert_eq!(
+            lnurl_nostr_pubkey(&serde_json::json!({"allowsNostr": true})),
+            None
+        );
+    }
+
+    #[test]
+    fn bolt11_amount() {
+        assert_eq!(bolt11_msats("lnbc2500u1pvjluez"), Some(250_000_000));
+        assert_eq!(bolt11_msats("lnbc1m1pvjluez"), Some(100_000_000));
+        assert_eq!(bolt11_msats("lnbc10p1pvjluez"), Some(1));
+        assert_eq!(bolt11_msats("lnbcrt20n1pvjluez"), Some(2_000));
+        assert_eq!(bolt11_msats("lnbc1pvjluez"), None);
+    }
+
+    #[tokio::test]
+    async fn stopped_npub_is_not_bridged() {
+        use crate::activity::Note;
+        let state = get_state().await;
+        let keys = nostr_lib::Keys::generate();
+        let event = nostr_lib::EventBuilder::text_note("hello", [])
+            .to_event(&keys)
+            .unwrap();
+        assert!(Note::from_nostr_event(state, &event).await.is_some());
+        state.db.stop_npub(&keys.public_key());
+        assert!(Note::from_nostr_event(state, &event).await.is_none());
+        state.db.restart_npub(&keys.public_key());
+        assert!(Note::from_nostr_event(state, &event).await.is_some());
+    }
 }
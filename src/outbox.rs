@@ -0,0 +1,224 @@
+use crate::server::AppState;
+use crate::{RelayId, MAIN_RELAY, MAX_OUTBOX_RELAYS};
+use nostr_lib::{Event, PublicKey, RelayMetadata, Tag, Timestamp};
+use relay_pool::Filter;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+/// Write relays of a single user subscribed to at most.
+const MAX_RELAYS_PER_USER: usize = 3;
+
+/// Relays to add to or to remove from the pool.
+type Relays = Vec<(RelayId, url::Url)>;
+
+/// Write relays of NIP-65 relay lists of mirrored users, which are subscribed
+/// to in addition to the main relays so that events posted only to them are
+/// bridged too.
+#[derive(Debug)]
+pub struct OutboxRelays {
+    /// Id of the subscription to the write relays.
+    subscription: u32,
+    next_id: u32,
+    ids: FxHashMap<url::Url, RelayId>,
+    users: FxHashMap<PublicKey, Vec<url::Url>>,
+}
+
+impl OutboxRelays {
+    /// Relays are numbered from `first_id`, which must be after the ids of the
+    /// other relays.
+    pub fn new(subscription: u32, first_id: u32) -> Self {
+        Self {
+            subscription,
+            next_id: first_id,
+            ids: FxHashMap::default(),
+            users: FxHashMap::default(),
+        }
+    }
+
+    /// Sets the write relays of `user` and returns the relays to be added to
+    /// and removed from the pool. Relays no user writes to any more are freed
+    /// first, so that at most `max` are ever live.
+    fn set(&mut self, user: PublicKey, relays: Vec<url::Url>, max: usize) -> (Relays, Relays) {
+        self.users.remove(&user);
+        let mut removed = Vec::new();
+        let users = &self.users;
+        self.ids.retain(|url, id| {
+            let used = relays.contains(url) || users.values().flatten().any(|r| r == url);
+            if !used {
+                removed.push((*id, url.clone()));
+            }
+            used
+        });
+        let mut added = Vec::new();
+        let mut kept = Vec::with_capacity(relays.len());
+        for r in relays {
+            if !self.ids.contains_key(&r) {
+                if self.ids.len() >= max {
+                    continue;
+                }
+                let id = RelayId(self.next_id);
+                self.next_id += 1;
+                self.ids.insert(r.clone(), id);
+                added.push((id, r.clone()));
+            }
+            kept.push(r);
+        }
+        if !kept.is_empty() {
+            self.users.insert(user, kept);
+        }
+        (added, removed)
+    }
+
+    /// Write relays of `user`, if it published a relay list.
+    pub fn relays_of(&self, user: &PublicKey) -> &[url::Url] {
+        self.users.get(user).map_or(&[], Vec::as_slice)
+    }
+
+    /// Url of the outbox relay `id`.
+    fn url_of(&self, id: RelayId) -> Option<&url::Url> {
+        self.ids
+            .iter()
+            .find_map(|(url, i)| (*i == id).then_some(url))
+    }
+
+    /// Users with write relays and the relays any of them writes to.
+    fn subscription_target(&self) -> (BTreeSet<PublicKey>, FxHashSet<RelayId>) {
+        let relays = self
+            .users
+            .values()
+            .flatten()
+            .filter_map(|r| self.ids.get(r).copied())
+            .collect();
+        (self.users.keys().copied().collect(), relays)
+    }
+}
+
+/// Write relays of a relay list which aren't among `main_relays`.
+fn write_relays(event: &Event, main_relays: &[url::Url]) -> Vec<url::Url> {
+    let mut relays = Vec::new();
+    for t in &event.tags {
+        if let Tag::RelayMetadata(url, None | Some(RelayMetadata::Write)) = t {
+            let Ok(url) = url::Url::parse(&url.to_string()) else {
+                continue;
+            };
+            if matches!(url.scheme(), "ws" | "wss")
+                && !main_relays.contains(&url)
+                && !relays.contains(&url)
+            {
+                relays.push(url);
+            }
+        }
+    }
+    relays.truncate(MAX_RELAYS_PER_USER);
+    relays
+}
+
+impl AppState {
+    /// Url of the main or outbox relay `id`, falling back to the main relay.
+    pub fn relay_url_of(&self, id: RelayId) -> url::Url {
+        self.relay_url
+            .get(id.0 as usize)
+            .cloned()
+            .or_else(|| self.outbox_relays.lock().url_of(id).cloned())
+            .unwrap_or_else(|| self.relay_url[MAIN_RELAY.0 as usize].clone())
+    }
+
+    /// Subscribes to the write relays of the relay list `event`, and
+    /// unsubscribes from those no other user writes to.
+    pub async fn update_outbox_relays(&self, event: &Event, filter: Filter) {
+        let relays = write_relays(event, &self.relay_url);
+        let ((added, removed), subscription, (authors, relays)) = {
+            let mut outbox = self.outbox_relays.lock();
+            let changes = outbox.set(event.pubkey, relays, *MAX_OUTBOX_RELAYS);
+            (changes, outbox.subscription, outbox.subscription_target())
+        };
+        for (id, url) in removed {
+            info!("unsubscribing from the outbox relay {url}");
+            if let Err(e) = self.nostr.remove_relay(id).await {
+                info!("could not remove a relay: {e}");
+            }
+        }
+        if !added.is_empty() {
+            for (id, url) in added {
+                info!("subscribing to the outbox relay {url}");
+                if let Err(e) = self.nostr.add_relay(id, url, None).await {
+                    info!("could not add a relay: {e}");
+                }
+            }
+            // lets the pool start the relays before the filter is sent to them
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let filter = Filter {
+            authors: Some(authors),
+            since: Some(Timestamp::now()),
+            ..filter
+        };
+        self.nostr
+            .change_filter(subscription, vec![filter], Arc::new(relays))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_relays, OutboxRelays};
+    use crate::RelayId;
+    use nostr_lib::{EventBuilder, Keys, RelayMetadata, UncheckedUrl};
+
+    #[test]
+    fn outbox_relays() {
+        let keys = Keys::generate();
+        let main = url::Url::parse("wss://relay.example.com").unwrap();
+        let event = EventBuilder::relay_list([
+            (UncheckedUrl::from("wss://relay.example.com"), None),
+            (UncheckedUrl::from("wss://a.example.com"), None),
+            (
+                UncheckedUrl::from("wss://b.example.com"),
+                Some(RelayMetadata::Read),
+            ),
+            (
+                UncheckedUrl::from("wss://c.example.com"),
+                Some(RelayMetadata::Write),
+            ),
+            (UncheckedUrl::from("https://d.example.com"), None),
+        ])
+        .to_event(&keys)
+        .unwrap();
+        let relays = write_relays(&event, &[main]);
+        let a = url::Url::parse("wss://a.example.com").unwrap();
+        let c = url::Url::parse("wss://c.example.com").unwrap();
+        assert_eq!(relays, [a.clone(), c.clone()]);
+
+        let mut outbox = OutboxRelays::new(0, 10);
+        assert_eq!(
+            outbox.set(keys.public_key(), relays.clone(), 1),
+            (vec![(RelayId(10), a.clone())], vec![])
+        );
+        assert_eq!(
+            outbox.relays_of(&keys.public_key()),
+            std::slice::from_ref(&a)
+        );
+        let other = Keys::generate().public_key();
+        assert_eq!(outbox.set(other, vec![a.clone()], 1), (vec![], vec![]));
+        let (authors, ids) = outbox.subscription_target();
+        assert_eq!(authors.len(), 2);
+        assert_eq!(ids.into_iter().collect::<Vec<_>>(), [RelayId(10)]);
+        assert_eq!(outbox.url_of(RelayId(10)), Some(&a));
+        assert_eq!(outbox.url_of(RelayId(11)), None);
+        outbox.set(other, Vec::new(), 1);
+        assert!(outbox.relays_of(&other).is_empty());
+        // `a` is no longer written to by anyone, which frees room for `c`
+        assert_eq!(
+            outbox.set(keys.public_key(), vec![c.clone()], 1),
+            (
+                vec![(RelayId(11), c.clone())],
+                vec![(RelayId(10), a.clone())]
+            )
+        );
+        assert_eq!(outbox.url_of(RelayId(10)), None);
+        assert_eq!(outbox.subscription_target().1.len(), 1);
+    }
+}
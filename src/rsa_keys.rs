@@ -13,6 +13,28 @@ pub static RSA_PRIVATE_KEY_FOR_SIGH: Lazy<sigh::PrivateKey> = Lazy::new(|| {
     sigh::PrivateKey::from_pem(s.as_bytes()).unwrap()
 });
 
+/// Key of the instance actor, the same as of users unless
+/// `INSTANCE_RSA_PRIVATE_KEY` is set.
+static INSTANCE_RSA_PRIVATE_KEY: Lazy<RsaPrivateKey> = Lazy::new(|| {
+    RsaPrivateKey::from_pkcs8_pem(
+        option_env!("INSTANCE_RSA_PRIVATE_KEY").unwrap_or(RSA_PRIVATE_KEY_STRING),
+    )
+    .unwrap()
+});
+
+pub static INSTANCE_RSA_PRIVATE_KEY_FOR_SIGH: Lazy<sigh::PrivateKey> = Lazy::new(|| {
+    let s = INSTANCE_RSA_PRIVATE_KEY
+        .to_pkcs8_pem(LineEnding::default())
+        .unwrap();
+    sigh::PrivateKey::from_pem(s.as_bytes()).unwrap()
+});
+
+pub static INSTANCE_RSA_PUBLIC_KEY_STRING: Lazy<String> = Lazy::new(|| {
+    RsaPublicKey::from(&*INSTANCE_RSA_PRIVATE_KEY)
+        .to_public_key_pem(LineEnding::default())
+        .unwrap()
+});
+
 pub static RSA_PUBLIC_KEY: Lazy<RsaPublicKey> = Lazy::new(|| RsaPublicKey::from(&*RSA_PRIVATE_KEY));
 pub static RSA_PUBLIC_KEY_STRING: Lazy<String> = Lazy::new(|| {
     RSA_PUBLIC_KEY
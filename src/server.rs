@@ -1,26 +1,46 @@
+mod accounts;
+mod admin;
+mod delivery;
+mod health;
 mod inbox;
+mod metrics;
+mod nip98;
 mod nodeinfo;
 
-use crate::activity::{ActorOrProxied, Note};
+use crate::activity::{is_retryable_status, ActorOrProxied, Note};
 use crate::db::Db;
 use crate::error::Error;
 use crate::event_deletion_queue::EventDeletionQueue;
 use crate::nostr::{get_nostr_user_data, NostrUser};
 use crate::nostr_to_ap::{replace_npub_with_ap_handle, Content};
-use crate::rsa_keys::RSA_PUBLIC_KEY_STRING;
+use crate::outbox::OutboxRelays;
+use crate::rsa_keys::{INSTANCE_RSA_PUBLIC_KEY_STRING, RSA_PUBLIC_KEY_STRING};
+use crate::server::health::healthz;
 use crate::server::inbox::http_post_inbox;
-pub use crate::server::inbox::{event_tag, InternalApId};
+use crate::server::inbox::PollVote;
+pub use crate::server::inbox::{event_tag, EditStrategy, InternalApId};
+use crate::server::metrics::metrics;
+pub use crate::server::metrics::Metrics;
 use crate::server::nodeinfo::well_known_nodeinfo;
-use crate::util::Merge;
+use crate::software::Software;
+use crate::util::{
+    normalize_handle, CircuitBreaker, HostBatcher, HostLimiter, InFlight, Merge, RateLimiter,
+};
 use crate::{
-    RelayId, BIND_ADDRESS, DOMAIN, HTTPS_DOMAIN, OUTBOX_RELAYS, RELAYS, USER_AGENT, USER_ID_PREFIX,
+    RelayId, BIND_ADDRESS, DOMAIN, HTTPS_DOMAIN, INSTANCE_ACTOR_ID, NOSTR_VIEWER, NOTE_ID_PREFIX,
+    OUTBOX_RELAYS, RELAYS, SERVE_NOTE_STUBS, USER_AGENT, USER_ID_PREFIX,
 };
+use accounts::{delete_stopped, get_stopped, put_stopped};
+use admin::post_cache_clear;
 use axum::extract::{Path, Query, Request, State};
-use axum::response::{IntoResponse, Response};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Redirect, Response};
 use axum::routing::{get, post};
 use axum::{Json, Router};
 use axum_macros::debug_handler;
-use cached::TimedSizedCache;
+use cached::{Cached, TimedSizedCache};
+use delivery::http_get_delivery;
+pub use delivery::DeliveryRecord;
 use itertools::Itertools;
 use linkify::{LinkFinder, LinkKind};
 use lru::LruCache;
@@ -37,13 +57,16 @@ use serde::ser::SerializeMap;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashSet;
+use std::future::Future;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::Semaphore;
 use tracing::{debug, info};
 
 type LazyNote = Arc<tokio::sync::OnceCell<Option<EventWithRelayId<RelayId>>>>;
 type LazyUser = Arc<tokio::sync::OnceCell<Arc<Result<NostrUser, Error>>>>;
+type LazySoftware = Arc<tokio::sync::OnceCell<Option<Software>>>;
 
 #[derive(Debug)]
 pub struct AppState {
@@ -54,22 +77,71 @@ pub struct AppState {
     pub http_client: reqwest::Client,
     pub note_cache: Mutex<LruCache<EventId, LazyNote>>,
     pub actor_cache: Mutex<LruCache<String, ActorOrProxied>>,
+    /// Unix time of the last activity of each cached actor, see `IDLE_ACTOR_SECS`.
+    pub actor_activity: Mutex<LruCache<String, u64>>,
+    /// Latest vote of each voter on a poll, as votes on several options
+    /// arrive one at a time.
+    pub poll_votes: Mutex<LruCache<(nostr_lib::PublicKey, EventId), PollVote>>,
+    /// Zap receipts already bridged, as relays may send them more than once.
+    pub zap_receipts: Mutex<LruCache<EventId, ()>>,
+    /// Events received recently, as they may come from both the main and
+    /// the outbox relays.
+    pub seen_events: Mutex<LruCache<EventId, ()>>,
+    pub outbox_relays: Mutex<OutboxRelays>,
     pub nostr_user_cache: Mutex<TimedSizedCache<nostr_lib::PublicKey, LazyUser>>,
+    pub software_cache: Mutex<TimedSizedCache<String, LazySoftware>>,
+    /// WebFinger handle to actor id.
+    pub webfinger_cache: Mutex<TimedSizedCache<String, String>>,
+    /// WebFinger handles which could not be resolved.
+    pub webfinger_miss_cache: Mutex<TimedSizedCache<String, ()>>,
+    /// Whether a NIP-05 identifier resolves to a pubkey, keyed by both.
+    pub nip05_cache: Mutex<TimedSizedCache<(String, PublicKey), bool>>,
+    pub host_limiter: HostLimiter,
+    /// Hosts failing deliveries, see `CIRCUIT_BREAKER_THRESHOLD`.
+    pub circuit_breaker: CircuitBreaker,
+    /// Outbound deliveries being coalesced per host, see `DELIVERY_BATCH_WINDOW_MS`.
+    pub delivery_batcher:
+        HostBatcher<(axum::http::Uri, String, String), Result<reqwest::StatusCode, Error>>,
+    /// Fetches of remote objects in progress, keyed by URL.
+    pub in_flight_fetches: InFlight<Result<(String, url::Url), Error>>,
+    /// Results of the deliveries of recently bridged notes.
+    pub delivery_log: Mutex<LruCache<EventId, Vec<DeliveryRecord>>>,
+    /// Inbound activities per actor, see `INBOX_RATE_LIMIT`.
+    pub inbox_rate_limiter: RateLimiter,
+    /// Inbound activities being processed, see `MAX_CONCURRENT_INBOX`.
+    pub inbox_limiter: Arc<Semaphore>,
     pub relay_url: Vec<url::Url>,
     pub main_relays: Arc<FxHashSet<RelayId>>,
     pub metadata_relays: Arc<FxHashSet<RelayId>>,
     pub event_deletion_queue: EventDeletionQueue,
     pub db: Db,
+    pub metrics: Metrics,
+    /// Held while the account maps are written, and from shutdown on.
+    pub account_writes: tokio::sync::Mutex<()>,
 }
 
-pub async fn listen(state: Arc<AppState>) -> Result<(), Error> {
+/// Serves until `shutdown` resolves and the open requests are answered.
+pub async fn listen(
+    state: Arc<AppState>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), Error> {
     info!("Listening on {BIND_ADDRESS}");
     let app = Router::new()
         .route("/", get(root))
         .route("/nodeinfo/2.1", get(nodeinfo))
+        .route("/healthz", get(healthz))
+        .route("/metrics", get(metrics))
         .route("/inbox", post(http_post_inbox))
+        .route("/actor", get(http_get_instance_actor))
         .route("/users/:user", get(http_get_user))
+        .route("/users/:user/followers", get(http_get_followers))
+        .route(
+            "/api/accounts/:npub/stopped",
+            get(get_stopped).put(put_stopped).delete(delete_stopped),
+        )
         .route("/notes/:note", get(http_get_note))
+        .route("/delivery/:event_id", get(http_get_delivery))
+        .route("/admin/cache/clear", post(post_cache_clear))
         .route("/.well-known/webfinger", get(webfinger))
         .route("/.well-known/nostr.json", get(nostr_json))
         .route("/.well-known/nodeinfo", get(well_known_nodeinfo))
@@ -77,7 +149,9 @@ pub async fn listen(state: Arc<AppState>) -> Result<(), Error> {
         .with_state(state);
 
     let listener = tokio::net::TcpListener::bind(BIND_ADDRESS).await.unwrap();
-    Ok(axum::serve(listener, app).await?)
+    Ok(axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown)
+        .await?)
 }
 
 #[debug_handler]
@@ -133,12 +207,25 @@ pub async fn webfinger(
         Lazy::new(|| Regex::new(&format!(r"^(?:acct:)?([^@]+)@{DOMAIN}$")).unwrap());
 
     debug!("webfinger?resource={resource}");
+    let resource = normalize_handle(&resource).ok_or(Error::NotFound)?;
     let npub = R
         .captures(&resource)
         .ok_or(Error::NotFound)?
         .get(1)
         .unwrap()
         .as_str();
+    if npub == DOMAIN {
+        return Ok(Json(json!({
+            "subject": format_args!("acct:{DOMAIN}@{DOMAIN}"),
+            "links": [
+                {
+                    "rel": "self",
+                    "type": "application/activity+json",
+                    "href": *INSTANCE_ACTOR_ID,
+                },
+            ]
+        })));
+    }
     let pub_key = nostr_lib::PublicKey::from_bech32(npub).map_err(|_| Error::NotFound)?;
     let a = &*get_nostr_user_data(&state, pub_key).await;
     if let NostrUser::Proxied(url) = a.as_ref().map_err(|e| e.clone())? {
@@ -171,6 +258,92 @@ pub async fn webfinger(
     }
 }
 
+impl AppState {
+    /// Actor id of the normalized `handle`, looked up with WebFinger. Failures
+    /// are cached too, for a shorter time, to not ask the remote repeatedly.
+    pub async fn webfinger_actor_id(&self, handle: &str) -> Result<String, Error> {
+        if let Some(r) = cached_webfinger(&self.webfinger_cache, &self.webfinger_miss_cache, handle)
+        {
+            return r;
+        }
+        let r = self.webfinger_actor_id_without_cache(handle).await;
+        match &r {
+            Ok(id) => {
+                self.webfinger_cache
+                    .lock()
+                    .cache_set(handle.to_string(), id.clone());
+            }
+            Err(e) => {
+                debug!("could not find {handle}: {e:?}");
+                self.webfinger_miss_cache
+                    .lock()
+                    .cache_set(handle.to_string(), ());
+            }
+        }
+        r
+    }
+
+    async fn webfinger_actor_id_without_cache(&self, handle: &str) -> Result<String, Error> {
+        let (_, host) = handle.rsplit_once('@').ok_or(Error::NotFound)?;
+        #[derive(Deserialize, Debug)]
+        struct WebfingerResponse {
+            links: Vec<WebfingerLink>,
+        }
+        #[derive(Deserialize, Debug)]
+        struct WebfingerLink {
+            r#type: Option<mediatype::MediaTypeBuf>,
+            href: Option<String>,
+        }
+        let WebfingerResponse { links } = self
+            .http_client
+            .get(format!(
+                "https://{host}/.well-known/webfinger?resource=acct:{handle}"
+            ))
+            .header(reqwest::header::USER_AGENT, &*USER_AGENT)
+            .send()
+            .await
+            .map_err(|e| Error::NotFoundWithMsg(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| Error::NotFoundWithMsg(e.to_string()))?;
+        let param_profile = mediatype::Name::new("profile").unwrap();
+        let value_activitystreams =
+            mediatype::Value::new("\"https://www.w3.org/ns/activitystreams\"").unwrap();
+        links
+            .into_iter()
+            .find(|l| {
+                if let Some(t) = &l.r#type {
+                    t.ty() == mediatype::names::APPLICATION
+                        && t.suffix() == Some(mediatype::names::JSON)
+                        && (t.subty() == mediatype::names::ACTIVITY
+                            || t.subty() == mediatype::names::LD
+                                && t.get_param(param_profile) == Some(value_activitystreams))
+                } else {
+                    false
+                }
+            })
+            .ok_or(Error::NotFound)?
+            .href
+            .ok_or(Error::NotFound)
+    }
+}
+
+/// Cached result of the WebFinger lookup of `handle`, if any.
+fn cached_webfinger(
+    hits: &Mutex<TimedSizedCache<String, String>>,
+    misses: &Mutex<TimedSizedCache<String, ()>>,
+    handle: &str,
+) -> Option<Result<String, Error>> {
+    if let Some(id) = hits.lock().cache_get(handle) {
+        return Some(Ok(id.clone()));
+    }
+    misses.lock().cache_get(handle).map(|_| {
+        Err(Error::NotFoundWithMsg(format!(
+            "{handle} was not found recently"
+        )))
+    })
+}
+
 #[derive(Deserialize)]
 pub struct NostrJsonQuery {
     name: String,
@@ -185,46 +358,8 @@ pub async fn nostr_json(
     debug!("nostr.json?name={name}");
     let (name_decoded, host) = name.rsplit_once("_at_").ok_or_else(|| Error::NotFound)?;
     let host = host.replace(".at_", "at_");
-    #[derive(Deserialize, Debug)]
-    struct WebfingerResponse {
-        links: Vec<WebfingerLink>,
-    }
-    #[derive(Deserialize, Debug)]
-    struct WebfingerLink {
-        r#type: Option<mediatype::MediaTypeBuf>,
-        href: Option<String>,
-    }
-    let WebfingerResponse { links } = state
-        .http_client
-        .get(format!(
-            "https://{host}/.well-known/webfinger?resource=acct:{name_decoded}@{host}"
-        ))
-        .header(reqwest::header::USER_AGENT, &*USER_AGENT)
-        .send()
-        .await
-        .map_err(|e| Error::NotFoundWithMsg(e.to_string()))?
-        .json()
-        .await
-        .map_err(|e| Error::NotFoundWithMsg(e.to_string()))?;
-    let param_profile = mediatype::Name::new("profile").unwrap();
-    let value_activitystreams =
-        mediatype::Value::new("\"https://www.w3.org/ns/activitystreams\"").unwrap();
-    let id = links
-        .into_iter()
-        .find(|l| {
-            if let Some(t) = &l.r#type {
-                t.ty() == mediatype::names::APPLICATION
-                    && t.suffix() == Some(mediatype::names::JSON)
-                    && (t.subty() == mediatype::names::ACTIVITY
-                        || t.subty() == mediatype::names::LD
-                            && t.get_param(param_profile) == Some(value_activitystreams))
-            } else {
-                false
-            }
-        })
-        .ok_or(Error::NotFound)?
-        .href
-        .ok_or(Error::NotFound)?;
+    let handle = normalize_handle(&format!("{name_decoded}@{host}")).ok_or(Error::NotFound)?;
+    let id = state.webfinger_actor_id(&handle).await?;
     let (ActorOrProxied::Actor(actor), new) = state.get_actor_data_and_if_its_new(&id).await?
     else {
         return Err(Error::NotFound);
@@ -249,6 +384,36 @@ pub async fn nostr_json(
 }
 
 const ACTIVITY_STREAMS_URL: &str = "https://www.w3.org/ns/activitystreams";
+/// Content type of the objects served.
+const ACTIVITY_JSON: &str =
+    "application/activity+json; profile=\"https://www.w3.org/ns/activitystreams\"";
+
+/// `@context` of the actors, notes and activities of this server, which
+/// defines the Mastodon, Misskey and Pleroma extensions they use.
+static CONTEXT: Lazy<serde_json::Value> = Lazy::new(|| {
+    json!([
+        ACTIVITY_STREAMS_URL,
+        "https://w3id.org/security/v1",
+        {
+            "toot": "http://joinmastodon.org/ns#",
+            "misskey": "https://misskey-hub.net/ns#",
+            "litepub": "http://litepub.social/ns#",
+            "schema": "http://schema.org#",
+            "sensitive": "as:sensitive",
+            "Hashtag": "as:Hashtag",
+            "quoteUrl": "as:quoteUrl",
+            "quote": { "@id": "https://w3id.org/fep/044f#quote", "@type": "@id" },
+            "Emoji": "toot:Emoji",
+            "discoverable": "toot:discoverable",
+            "indexable": "toot:indexable",
+            "EmojiReact": "litepub:EmojiReact",
+            "PropertyValue": "schema:PropertyValue",
+            "value": "schema:value",
+            "_misskey_content": "misskey:_misskey_content",
+            "_misskey_quote": "misskey:_misskey_quote",
+        }
+    ])
+});
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -277,13 +442,7 @@ impl<T: Serialize> Serialize for WithContext<T> {
                 S: serde::Serializer,
             {
                 let mut m = serializer.serialize_map(Some(1))?;
-                m.serialize_entry(
-                    "@context",
-                    &[
-                        "https://www.w3.org/ns/activitystreams",
-                        "https://w3id.org/security/v1",
-                    ],
-                )?;
+                m.serialize_entry("@context", &*CONTEXT)?;
                 m.end()
             }
         }
@@ -301,6 +460,10 @@ pub struct MetadataActivity<'a> {
     metadata: &'a Metadata,
     npub: PublicKey,
     sumarry: Option<String>,
+    /// `nip05` of the metadata if it resolves to `npub`.
+    nip05: Option<String>,
+    /// Write relays of the relay list of `npub`.
+    relays: Vec<url::Url>,
 }
 
 impl Serialize for MetadataActivity<'_> {
@@ -321,10 +484,7 @@ impl Serialize for MetadataActivity<'_> {
         let id = format!("{USER_ID_PREFIX}{npub}");
         let inbox = format!("{HTTPS_DOMAIN}/inbox");
         let mut m = serializer.serialize_map(None)?;
-        m.serialize_entry(
-            "@context",
-            &[ACTIVITY_STREAMS_URL, "https://w3id.org/security/v1"],
-        )?;
+        m.serialize_entry("@context", &*CONTEXT)?;
         m.serialize_entry("type", "Person")?;
         m.serialize_entry("id", &id)?;
         m.serialize_entry("preferredUsername", &npub)?;
@@ -342,7 +502,7 @@ impl Serialize for MetadataActivity<'_> {
 
         // needed to work with threads.net
         // m.serialize_entry("outbox", &format_args!("{id}/outbox"))?;
-        // m.serialize_entry("followers", &format_args!("{id}/followers"))?;
+        m.serialize_entry("followers", &format_args!("{id}/followers"))?;
         // m.serialize_entry("following", &format_args!("{id}/following"))?;
 
         m.serialize_entry("endpoints", &json!({ "sharedInbox": inbox }))?;
@@ -394,19 +554,34 @@ impl Serialize for MetadataActivity<'_> {
                 "publicKeyPem": *RSA_PUBLIC_KEY_STRING,
             }),
         )?;
+        let mut attachment = Vec::new();
         match &self.metadata.website {
             Some(website) if !website.is_empty() => {
-                m.serialize_entry(
-                    "attachment",
-                    &[json!({
-                        "type": "PropertyValue",
-                        "name": "Website",
-                        "value": website
-                    })],
-                )?;
+                attachment.push(json!({
+                    "type": "PropertyValue",
+                    "name": "Website",
+                    "value": website
+                }));
             }
             _ => (),
         }
+        if let Some(nip05) = &self.nip05 {
+            attachment.push(json!({
+                "type": "PropertyValue",
+                "name": "Nostr Address",
+                "value": nip05
+            }));
+        }
+        if !self.relays.is_empty() {
+            attachment.push(json!({
+                "type": "PropertyValue",
+                "name": "Nostr Relays",
+                "value": self.relays.iter().join(" ")
+            }));
+        }
+        if !attachment.is_empty() {
+            m.serialize_entry("attachment", &attachment)?;
+        }
         m.end()
     }
 }
@@ -416,7 +591,7 @@ impl IntoResponse for MetadataActivity<'_> {
         Response::builder()
             .header(
                 axum::http::header::CONTENT_TYPE,
-                axum::http::HeaderValue::from_static("application/activity+json"),
+                axum::http::HeaderValue::from_static(ACTIVITY_JSON),
             )
             .body(serde_json::to_string(&self).unwrap())
             .unwrap()
@@ -457,21 +632,192 @@ pub async fn metadata_to_activity<'a>(
     } else {
         None
     };
+    let nip05 = match &metadata.nip05 {
+        Some(nip05) if state.verify_nip05(nip05, npub).await => Some(nip05.clone()),
+        _ => None,
+    };
+    let relays = state.outbox_relays.lock().relays_of(&npub).to_vec();
     MetadataActivity {
         metadata,
         npub,
         sumarry,
+        nip05,
+        relays,
+    }
+}
+
+/// The name and domain of a NIP-05 identifier, where a bare domain is `_`.
+/// IP addresses and local domains are rejected, as the bridge would fetch
+/// them.
+fn split_nip05(nip05: &str) -> Option<(String, String)> {
+    let nip05 = nip05.trim().to_lowercase();
+    let (name, domain) = nip05.rsplit_once('@').unwrap_or(("_", &nip05));
+    let valid = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+    };
+    let domain = domain.trim_end_matches('.');
+    let tld = domain.rsplit('.').next().unwrap_or_default();
+    let public = domain.contains('.')
+        && !tld.chars().all(|c| c.is_ascii_digit())
+        && !["localhost", "local", "internal", "lan", "home", "arpa"].contains(&tld);
+    (valid(name) && valid(domain) && public).then(|| (name.to_string(), domain.to_string()))
+}
+
+/// Whether `names` of a `nostr.json` maps `name` to `pubkey`.
+fn nostr_json_has(nostr_json: &serde_json::Value, name: &str, pubkey: PublicKey) -> bool {
+    nostr_json["names"][name]
+        .as_str()
+        .is_some_and(|p| PublicKey::from_hex(p).is_ok_and(|p| p == pubkey))
+}
+
+impl AppState {
+    /// Whether the NIP-05 identifier `nip05` resolves to `pubkey`. The lookup
+    /// runs in the background so that a slow host delays the conversion by a
+    /// second at most, and is cached once the host has answered.
+    async fn verify_nip05(self: &Arc<Self>, nip05: &str, pubkey: PublicKey) -> bool {
+        let key = (nip05.to_string(), pubkey);
+        if let Some(v) = self.nip05_cache.lock().cache_get(&key) {
+            return *v;
+        }
+        let Some((name, domain)) = split_nip05(nip05) else {
+            return false;
+        };
+        let state = self.clone();
+        let lookup = tokio::spawn(async move {
+            let verified = state.fetch_nip05(&name, &domain, pubkey).await;
+            if let Some(v) = verified {
+                state.nip05_cache.lock().cache_set(key, v);
+            }
+            verified.unwrap_or(false)
+        });
+        tokio::time::timeout(std::time::Duration::from_secs(1), lookup)
+            .await
+            .is_ok_and(|v| v.unwrap_or(false))
+    }
+
+    /// Whether `nostr.json` of `domain` maps `name` to `pubkey`, or `None` if
+    /// it could not be checked and may be on a later attempt.
+    async fn fetch_nip05(&self, name: &str, domain: &str, pubkey: PublicKey) -> Option<bool> {
+        let r = self
+            .http_client
+            .get(format!(
+                "https://{domain}/.well-known/nostr.json?name={name}"
+            ))
+            .header(reqwest::header::USER_AGENT, &*USER_AGENT)
+            .timeout(std::time::Duration::from_secs(5))
+            .send()
+            .await;
+        match r {
+            Ok(r) if is_retryable_status(r.status()) => {
+                debug!("could not verify {name}@{domain}: {}", r.status());
+                None
+            }
+            Ok(r) => Some(
+                r.json::<serde_json::Value>()
+                    .await
+                    .is_ok_and(|j| nostr_json_has(&j, name, pubkey)),
+            ),
+            Err(e) => {
+                debug!("could not verify {name}@{domain}: {e}");
+                None
+            }
+        }
     }
 }
 
+/// The instance actor, see `INSTANCE_ACTOR_ID`.
+fn instance_actor(id: &str, public_key_pem: &str) -> serde_json::Value {
+    let inbox = format!("{HTTPS_DOMAIN}/inbox");
+    json!({
+        "@context": *CONTEXT,
+        "type": "Application",
+        "id": id,
+        "preferredUsername": DOMAIN,
+        "name": DOMAIN,
+        "inbox": inbox,
+        "endpoints": { "sharedInbox": inbox },
+        "url": HTTPS_DOMAIN,
+        "manuallyApprovesFollowers": true,
+        "discoverable": false,
+        "publicKey": {
+            "id": format_args!("{id}#main-key"),
+            "type": "Key",
+            "owner": id,
+            "publicKeyPem": public_key_pem,
+        },
+    })
+}
+
 #[debug_handler]
-#[tracing::instrument(skip(state))]
+#[tracing::instrument]
+pub async fn http_get_instance_actor() -> Response {
+    JsonActivity(instance_actor(&INSTANCE_ACTOR_ID, &INSTANCE_RSA_PUBLIC_KEY_STRING).to_string())
+        .into_response()
+}
+
+/// A `410 Gone` response for a deleted object.
+fn tombstone(id: String, former_type: &str) -> Response {
+    let tombstone = json!({
+        "@context": ACTIVITY_STREAMS_URL,
+        "type": "Tombstone",
+        "id": id,
+        "formerType": former_type,
+    });
+    let mut r = JsonActivity(tombstone.to_string()).into_response();
+    *r.status_mut() = axum::http::StatusCode::GONE;
+    r
+}
+
+/// Whether a request accepting `accept` is from a browser rather than from an
+/// ActivityPub client, so that it is redirected to a web view of the object.
+fn prefers_html(accept: Option<&str>) -> bool {
+    let Some(accept) = accept else {
+        return false;
+    };
+    let mut html = false;
+    for t in mediatype::MediaTypeList::new(accept).flatten() {
+        if t.ty == mediatype::names::APPLICATION
+            && t.suffix == Some(mediatype::names::JSON)
+            && (t.subty == mediatype::names::ACTIVITY || t.subty == mediatype::names::LD)
+        {
+            return false;
+        }
+        html |= t.ty == mediatype::names::TEXT && t.subty == mediatype::names::HTML;
+    }
+    html
+}
+
+/// Redirects browsers to `NOSTR_VIEWER`, where `bech32` is shown.
+fn web_view(headers: &HeaderMap, bech32: &str) -> Option<Response> {
+    let accept = headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|a| a.to_str().ok());
+    prefers_html(accept).then(|| {
+        (
+            [(axum::http::header::VARY, "Accept")],
+            Redirect::to(&format!("{}/{bech32}", *NOSTR_VIEWER)),
+        )
+            .into_response()
+    })
+}
+
+#[debug_handler]
+#[tracing::instrument(skip(state, headers))]
 pub async fn http_get_user(
     Path(npub): Path<String>,
+    headers: HeaderMap,
     State(state): State<Arc<AppState>>,
 ) -> Result<axum::http::Response<axum::body::Body>, Error> {
     debug!("get user");
     let public_key = nostr_lib::PublicKey::from_bech32(&npub).map_err(|_| Error::NotFound)?;
+    if let Some(r) = web_view(&headers, &npub) {
+        return Ok(r);
+    }
+    if state.db.is_deleted_npub(&public_key) {
+        return Ok(tombstone(format!("{USER_ID_PREFIX}{npub}"), "Person"));
+    }
     let a = &*get_nostr_user_data(&state, public_key).await;
     match a.as_ref().map_err(|e| e.clone())? {
         NostrUser::Proxied(_) => Err(Error::NotFound),
@@ -488,7 +834,7 @@ impl IntoResponse for JsonActivity {
         Response::builder()
             .header(
                 axum::http::header::CONTENT_TYPE,
-                axum::http::HeaderValue::from_static("application/activity+json"),
+                axum::http::HeaderValue::from_static(ACTIVITY_JSON),
             )
             .body(self.0)
             .unwrap()
@@ -496,20 +842,118 @@ impl IntoResponse for JsonActivity {
     }
 }
 
+/// Number of items of a page of a followers collection.
+const FOLLOWERS_PAGE_SIZE: usize = 50;
+
+#[derive(Deserialize)]
+pub struct FollowersQuery {
+    /// `true` or a page number starting from 1.
+    page: Option<String>,
+}
+
+/// The followers collection of the actor `id`, or its `page`th page.
+/// `followers` are sorted so that pages are stable.
+fn followers_collection(id: &str, followers: &[&str], page: Option<usize>) -> serde_json::Value {
+    let collection = format!("{id}/followers");
+    let Some(page) = page else {
+        return json!({
+            "@context": ACTIVITY_STREAMS_URL,
+            "id": collection,
+            "type": "OrderedCollection",
+            "totalItems": followers.len(),
+            "first": format!("{collection}?page=1"),
+        });
+    };
+    let start = (page - 1).saturating_mul(FOLLOWERS_PAGE_SIZE);
+    let items = followers
+        .iter()
+        .skip(start)
+        .take(FOLLOWERS_PAGE_SIZE)
+        .collect_vec();
+    let mut p = json!({
+        "@context": ACTIVITY_STREAMS_URL,
+        "id": format!("{collection}?page={page}"),
+        "type": "OrderedCollectionPage",
+        "totalItems": followers.len(),
+        "partOf": collection,
+        "orderedItems": items,
+    });
+    if start.saturating_add(FOLLOWERS_PAGE_SIZE) < followers.len() {
+        p["next"] = json!(format!("{collection}?page={}", page + 1));
+    }
+    if page > 1 {
+        p["prev"] = json!(format!("{collection}?page={}", page - 1));
+    }
+    p
+}
+
+/// Fediverse accounts following a Nostr user, so that remote servers show
+/// follower counts.
 #[debug_handler]
 #[tracing::instrument(skip(state))]
+pub async fn http_get_followers(
+    Path(npub): Path<String>,
+    Query(FollowersQuery { page }): Query<FollowersQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<JsonActivity, Error> {
+    let public_key = nostr_lib::PublicKey::from_bech32(&npub).map_err(|_| Error::NotFound)?;
+    if state.db.is_deleted_npub(&public_key) {
+        return Err(Error::NotFound);
+    }
+    let page = match page.as_deref() {
+        None => None,
+        Some("true") => Some(1),
+        Some(p) => Some(
+            p.parse()
+                .ok()
+                .filter(|p| *p > 0)
+                .ok_or_else(|| Error::BadRequest(Some("invalid page".to_string())))?,
+        ),
+    };
+    let followers = state
+        .nostr_account_to_followers
+        .lock()
+        .get(&public_key)
+        .cloned()
+        .unwrap_or_default();
+    let followers = followers.iter().map(String::as_str).sorted().collect_vec();
+    let c = followers_collection(&format!("{USER_ID_PREFIX}{npub}"), &followers, page);
+    Ok(JsonActivity(c.to_string()))
+}
+
+#[debug_handler]
+#[tracing::instrument(skip(state, headers))]
 pub async fn http_get_note(
     Path(note): Path<String>,
+    headers: HeaderMap,
     State(state): State<Arc<AppState>>,
-) -> Result<JsonActivity, Error> {
+) -> Result<Response, Error> {
     info!("");
     let note_id = EventId::from_bech32(&note).map_err(|_| Error::NotFound)?;
-    let note = state.get_note(note_id).await.ok_or(Error::NotFound)?;
-    let note = Note::from_nostr_event(&state, &note.event)
-        .await
-        .ok_or(Error::NotFound)?;
+    if let Some(r) = web_view(&headers, &note) {
+        return Ok(r);
+    }
+    let note = state.get_note(note_id).await;
+    // Deletions by anyone but the author are ignored.
+    if note
+        .as_ref()
+        .is_some_and(|n| state.db.get_deleted_event(&note_id) == Some(n.event.pubkey))
+    {
+        return Ok(tombstone(
+            format!("{NOTE_ID_PREFIX}{}", note_id.to_bech32().unwrap()),
+            "Note",
+        ));
+    }
+    let note = note.ok_or(Error::NotFound)?;
+    let note = match Note::from_nostr_event(&state, &note.event).await {
+        Some(n) => n,
+        None if *SERVE_NOTE_STUBS => {
+            Note::stub_from_nostr_event(&state, &note.event).ok_or(Error::NotFound)?
+        }
+        None => return Err(Error::NotFound),
+    };
     let s = serde_json::to_string(&WithContext(&note)).unwrap();
-    Ok(JsonActivity(s))
+    Ok(JsonActivity(s).into_response())
 }
 
 #[debug_handler]
@@ -518,3 +962,146 @@ async fn handler_404(request: Request) -> Error {
     info!("handler_404: {}", request.uri());
     Error::NotFound
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cached_webfinger, followers_collection, instance_actor, nostr_json_has, prefers_html,
+        split_nip05, WithContext, ACTIVITY_JSON, FOLLOWERS_PAGE_SIZE,
+    };
+    use crate::error::Error;
+    use cached::{Cached, TimedSizedCache};
+    use mediatype::ReadParams;
+    use parking_lot::Mutex;
+
+    #[test]
+    fn nip05() {
+        assert_eq!(
+            split_nip05("Alice@Example.com"),
+            Some(("alice".to_string(), "example.com".to_string()))
+        );
+        assert_eq!(
+            split_nip05("example.com"),
+            Some(("_".to_string(), "example.com".to_string()))
+        );
+        assert_eq!(split_nip05("alice@localhost"), None);
+        assert_eq!(split_nip05("alice@app.localhost"), None);
+        assert_eq!(split_nip05("alice@printer.local"), None);
+        assert_eq!(split_nip05("alice@127.0.0.1"), None);
+        assert_eq!(split_nip05("alice@10.0.0.1."), None);
+        assert_eq!(split_nip05("a/b@example.com"), None);
+        let pubkey = nostr_lib::Keys::generate().public_key();
+        let j = serde_json::json!({"names": {"alice": pubkey.to_hex()}});
+        assert!(nostr_json_has(&j, "alice", pubkey));
+        assert!(!nostr_json_has(&j, "bob", pubkey));
+        assert!(!nostr_json_has(
+            &j,
+            "alice",
+            nostr_lib::Keys::generate().public_key()
+        ));
+    }
+
+    #[test]
+    fn accept_negotiation() {
+        assert!(prefers_html(Some(
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+        )));
+        assert!(!prefers_html(Some("application/activity+json")));
+        assert!(!prefers_html(Some(
+            "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\""
+        )));
+        assert!(!prefers_html(Some(
+            "text/html, application/activity+json;q=0.9"
+        )));
+        assert!(!prefers_html(Some("application/json")));
+        assert!(!prefers_html(None));
+    }
+
+    #[test]
+    fn instance_actor_key() {
+        let id = "https://momostr.example.com/actor";
+        let a = instance_actor(id, "-----BEGIN PUBLIC KEY-----");
+        assert_eq!(a["type"], "Application");
+        assert_eq!(a["id"], id);
+        assert_eq!(a["publicKey"]["id"], format!("{id}#main-key"));
+        assert_eq!(a["publicKey"]["owner"], id);
+        assert_eq!(a["publicKey"]["publicKeyPem"], "-----BEGIN PUBLIC KEY-----");
+    }
+
+    #[test]
+    fn context() {
+        let a = serde_json::to_value(WithContext(serde_json::json!({
+            "type": "Note",
+            "sensitive": true,
+            "_misskey_content": "a",
+        })))
+        .unwrap();
+        assert_eq!(a["type"], "Note");
+        let context = a["@context"].as_array().unwrap();
+        assert_eq!(context[0], "https://www.w3.org/ns/activitystreams");
+        assert_eq!(context[1], "https://w3id.org/security/v1");
+        for term in ["sensitive", "_misskey_content", "Emoji", "quoteUrl"] {
+            assert!(context[2].get(term).is_some(), "{term} is not defined");
+        }
+        let t = ACTIVITY_JSON.parse::<mediatype::MediaTypeBuf>().unwrap();
+        assert_eq!(t.subty(), mediatype::names::ACTIVITY);
+        assert_eq!(
+            t.get_param(mediatype::Name::new("profile").unwrap())
+                .unwrap()
+                .unquoted_str(),
+            "https://www.w3.org/ns/activitystreams"
+        );
+    }
+
+    #[test]
+    fn webfinger_cache() {
+        let hits = Mutex::new(TimedSizedCache::with_size_and_lifespan(10, 600));
+        let misses = Mutex::new(TimedSizedCache::with_size_and_lifespan(10, 60));
+        assert!(cached_webfinger(&hits, &misses, "a@example.com").is_none());
+        hits.lock().cache_set(
+            "a@example.com".to_string(),
+            "https://example.com/users/a".to_string(),
+        );
+        misses.lock().cache_set("b@example.com".to_string(), ());
+        assert_eq!(
+            cached_webfinger(&hits, &misses, "a@example.com")
+                .unwrap()
+                .unwrap(),
+            "https://example.com/users/a"
+        );
+        assert!(matches!(
+            cached_webfinger(&hits, &misses, "b@example.com"),
+            Some(Err(Error::NotFoundWithMsg(_)))
+        ));
+    }
+
+    #[test]
+    fn followers_pages() {
+        let followers = (0..FOLLOWERS_PAGE_SIZE + 1)
+            .map(|i| format!("https://example.com/users/{i:03}"))
+            .collect::<Vec<_>>();
+        let followers = followers.iter().map(String::as_str).collect::<Vec<_>>();
+        let id = "https://momostr.pink/users/npub1";
+        let c = followers_collection(id, &followers, None);
+        assert_eq!(c["type"], "OrderedCollection");
+        assert_eq!(c["totalItems"], FOLLOWERS_PAGE_SIZE + 1);
+        assert_eq!(c["first"], format!("{id}/followers?page=1"));
+        let p = followers_collection(id, &followers, Some(1));
+        assert_eq!(p["type"], "OrderedCollectionPage");
+        assert_eq!(p["partOf"], format!("{id}/followers"));
+        assert_eq!(
+            p["orderedItems"].as_array().unwrap().len(),
+            FOLLOWERS_PAGE_SIZE
+        );
+        assert_eq!(p["orderedItems"][0], "https://example.com/users/000");
+        assert_eq!(p["next"], format!("{id}/followers?page=2"));
+        assert!(p.get("prev").is_none());
+        let p = followers_collection(id, &followers, Some(2));
+        assert_eq!(
+            p["orderedItems"],
+            serde_json::json!([followers[FOLLOWERS_PAGE_SIZE]])
+        );
+        assert!(p.get("next").is_none());
+        assert_eq!(p["prev"], format!("{id}/followers?page=1"));
+    }
+}
@@ -0,0 +1,67 @@
+//! Pausing the mirror of a Nostr account, as with `stop my mirror` sent to
+//! the bot. Changes are authenticated with NIP-98 events signed by the account.
+
+use super::nip98::Nip98;
+use super::AppState;
+use crate::bot::restart_npub;
+use crate::error::Error;
+use axum::extract::{Path, State};
+use axum::Json;
+use axum_macros::debug_handler;
+use nostr_lib::{FromBech32, PublicKey};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::info;
+
+fn parse_npub(npub: &str) -> Result<PublicKey, Error> {
+    PublicKey::from_bech32(npub).map_err(|_| Error::NotFound)
+}
+
+/// Only the account itself may change its mirror.
+fn authorize(npub: &str, signer: PublicKey) -> Result<PublicKey, Error> {
+    let public_key = parse_npub(npub)?;
+    if signer != public_key {
+        return Err(Error::Unauthorized(format!("not signed by {npub}")));
+    }
+    Ok(public_key)
+}
+
+#[debug_handler]
+#[tracing::instrument(skip(state))]
+pub async fn get_stopped(
+    Path(npub): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let public_key = parse_npub(&npub)?;
+    Ok(Json(
+        json!({ "stopped": state.db.is_stopped_npub(&public_key) }),
+    ))
+}
+
+#[debug_handler]
+#[tracing::instrument(skip(state))]
+pub async fn put_stopped(
+    Path(npub): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Nip98(signer): Nip98,
+) -> Result<Json<serde_json::Value>, Error> {
+    let public_key = authorize(&npub, signer)?;
+    info!("stopped the mirror of {npub}");
+    state.db.stop_npub(&public_key);
+    Ok(Json(json!({ "stopped": true })))
+}
+
+#[debug_handler]
+#[tracing::instrument(skip(state))]
+pub async fn delete_stopped(
+    Path(npub): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Nip98(signer): Nip98,
+) -> Result<Json<serde_json::Value>, Error> {
+    let public_key = authorize(&npub, signer)?;
+    if state.db.is_stopped_npub(&public_key) {
+        info!("restarted the mirror of {npub}");
+        restart_npub(&state, &public_key).await;
+    }
+    Ok(Json(json!({ "stopped": false })))
+}
@@ -0,0 +1,108 @@
+//! Endpoints for the operators listed in `ADMIN_NPUBS`, authenticated with
+//! NIP-98.
+
+use super::nip98::Nip98;
+use super::AppState;
+use crate::error::Error;
+use crate::ADMIN_NPUBS;
+use axum::extract::State;
+use axum::Json;
+use axum_macros::debug_handler;
+use cached::Cached;
+use nostr_lib::PublicKey;
+use serde_json::json;
+use std::sync::Arc;
+use tracing::info;
+
+fn authorize(signer: PublicKey, admins: &[PublicKey]) -> Result<(), Error> {
+    if admins.contains(&signer) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized("not signed by an admin".to_string()))
+    }
+}
+
+impl AppState {
+    /// Empties the in-memory caches, returning how many entries each had.
+    /// Each cache is locked on its own so that no two locks are held at once.
+    fn clear_caches(&self) -> Vec<(&'static str, usize)> {
+        let note = {
+            let mut c = self.note_cache.lock();
+            let n = c.len();
+            c.clear();
+            n
+        };
+        let actor = {
+            let mut c = self.actor_cache.lock();
+            let n = c.len();
+            c.clear();
+            n
+        };
+        let nostr_user = {
+            let mut c = self.nostr_user_cache.lock();
+            let n = c.cache_size();
+            c.cache_clear();
+            n
+        };
+        let webfinger = {
+            let mut c = self.webfinger_cache.lock();
+            let n = c.cache_size();
+            c.cache_clear();
+            n
+        };
+        let webfinger_miss = {
+            let mut c = self.webfinger_miss_cache.lock();
+            let n = c.cache_size();
+            c.cache_clear();
+            n
+        };
+        let nip05 = {
+            let mut c = self.nip05_cache.lock();
+            let n = c.cache_size();
+            c.cache_clear();
+            n
+        };
+        vec![
+            ("note", note),
+            ("actor", actor),
+            ("nostr_user", nostr_user),
+            ("webfinger", webfinger),
+            ("webfinger_miss", webfinger_miss),
+            ("nip05", nip05),
+        ]
+    }
+}
+
+/// Clears the caches, e.g. to drop stale actor data without a restart.
+#[debug_handler]
+#[tracing::instrument(skip(state))]
+pub async fn post_cache_clear(
+    State(state): State<Arc<AppState>>,
+    Nip98(signer): Nip98,
+) -> Result<Json<serde_json::Value>, Error> {
+    authorize(signer, &ADMIN_NPUBS)?;
+    let evicted = state.clear_caches();
+    for (cache, n) in &evicted {
+        info!("evicted {n} entries of the {cache} cache");
+    }
+    Ok(Json(json!({
+        "evicted": evicted
+            .into_iter()
+            .map(|(cache, n)| (cache.to_string(), json!(n)))
+            .collect::<serde_json::Map<_, _>>(),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::authorize;
+    use nostr_lib::Keys;
+
+    #[test]
+    fn admin_only() {
+        let (admin, other) = (Keys::generate().public_key(), Keys::generate().public_key());
+        assert!(authorize(admin, &[admin]).is_ok());
+        assert!(authorize(other, &[admin]).is_err());
+        assert!(authorize(admin, &[]).is_err());
+    }
+}
@@ -0,0 +1,229 @@
+//! Outcomes of the deliveries of bridged notes, so that an author can find
+//! out why followers on some instance don't see them.
+
+use super::AppState;
+use crate::activity::{is_retryable_status, Delivery};
+use crate::error::Error;
+use crate::NOTE_ID_PREFIX;
+use axum::extract::{Path, State};
+use axum::http::Uri;
+use axum::Json;
+use axum_macros::debug_handler;
+use nostr_lib::{EventId, FromBech32, Timestamp};
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+
+/// Latest outcome of delivering a note to an inbox.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeliveryRecord {
+    pub inbox: String,
+    /// `delivered`, `retrying` if it was queued for redelivery, `failed` if
+    /// it won't be retried, `dropped` if the circuit breaker of the host was
+    /// open, `skipped` by the federation policy or `queued` in read-only mode.
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub at: u64,
+}
+
+/// Status and error of a delivery with the result `r`.
+fn delivery_status(r: &Result<Delivery, Error>, retrying: bool) -> (&'static str, Option<String>) {
+    let failed = if retrying { "retrying" } else { "failed" };
+    match r {
+        Ok(Delivery::Sent(status)) if status.is_success() => ("delivered", None),
+        Ok(Delivery::Sent(status)) => (failed, Some(format!("HTTP {status}"))),
+        Ok(Delivery::Skipped) => ("skipped", None),
+        Ok(Delivery::Queued) => ("queued", None),
+        Err(e @ Error::Unavailable(_)) if !retrying => ("dropped", Some(format!("{e:?}"))),
+        Err(e) => (failed, Some(format!("{e:?}"))),
+    }
+}
+
+/// Whether `send_activity` queued a delivery with the result `r` for redelivery.
+fn queued_for_redelivery(r: &Result<Delivery, Error>) -> bool {
+    match r {
+        Ok(Delivery::Sent(status)) => is_retryable_status(*status),
+        Ok(Delivery::Skipped | Delivery::Queued) | Err(Error::Unavailable(_)) => false,
+        Err(_) => true,
+    }
+}
+
+#[allow(clippy::mutable_key_type)]
+fn delivery_records(
+    results: &FxHashMap<Uri, Result<Delivery, Error>>,
+    now: u64,
+) -> Vec<DeliveryRecord> {
+    let mut records = results
+        .iter()
+        .map(|(inbox, r)| {
+            let (status, error) = delivery_status(r, queued_for_redelivery(r));
+            DeliveryRecord {
+                inbox: inbox.to_string(),
+                status,
+                error,
+                at: now,
+            }
+        })
+        .collect::<Vec<_>>();
+    records.sort_by(|a, b| a.inbox.cmp(&b.inbox));
+    records
+}
+
+/// Event id of the note created by the activity `body`.
+fn created_note(body: &str) -> Option<EventId> {
+    let activity: serde_json::Value = serde_json::from_str(body).ok()?;
+    if activity["type"] != "Create" {
+        return None;
+    }
+    let id = activity["object"]["id"]
+        .as_str()?
+        .strip_prefix(NOTE_ID_PREFIX)?;
+    EventId::from_bech32(id).ok()
+}
+
+impl AppState {
+    /// Records the results of delivering the note bridged from `event_id`.
+    #[allow(clippy::mutable_key_type)]
+    pub fn record_deliveries(
+        &self,
+        event_id: EventId,
+        results: &FxHashMap<Uri, Result<Delivery, Error>>,
+    ) {
+        let records = delivery_records(results, Timestamp::now().as_u64());
+        self.delivery_log.lock().put(event_id, records);
+    }
+
+    /// Updates the record of a delivery of the activity `body` with the result
+    /// `r` of its redelivery.
+    pub fn update_delivery(
+        &self,
+        body: &str,
+        inbox: &Uri,
+        r: &Result<Delivery, Error>,
+        retrying: bool,
+    ) {
+        let Some(event_id) = created_note(body) else {
+            return;
+        };
+        let inbox = inbox.to_string();
+        let mut log = self.delivery_log.lock();
+        let Some(record) = log
+            .get_mut(&event_id)
+            .and_then(|records| records.iter_mut().find(|a| a.inbox == inbox))
+        else {
+            return;
+        };
+        (record.status, record.error) = delivery_status(r, retrying);
+        record.at = Timestamp::now().as_u64();
+    }
+}
+
+#[debug_handler]
+#[tracing::instrument(skip(state))]
+pub async fn http_get_delivery(
+    Path(event_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let event_id = EventId::from_bech32(&event_id)
+        .or_else(|_| EventId::from_hex(&event_id))
+        .map_err(|_| Error::NotFound)?;
+    let records = state
+        .delivery_log
+        .lock()
+        .get(&event_id)
+        .cloned()
+        .ok_or(Error::NotFound)?;
+    Ok(Json(json!({
+        "event_id": event_id.to_hex(),
+        "deliveries": records,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{created_note, delivery_records, delivery_status, DeliveryRecord};
+    use crate::activity::Delivery;
+    use crate::error::Error;
+    use crate::NOTE_ID_PREFIX;
+    use axum::http::Uri;
+    use nostr_lib::ToBech32;
+    use reqwest::StatusCode;
+    use rustc_hash::FxHashMap;
+
+    #[test]
+    #[allow(clippy::mutable_key_type)]
+    fn delivery_records_1() {
+        let inbox = |s: &str| s.parse::<Uri>().unwrap();
+        let results = FxHashMap::from_iter([
+            (
+                inbox("https://b.example.com/inbox"),
+                Ok(Delivery::Sent(StatusCode::ACCEPTED)),
+            ),
+            (
+                inbox("https://a.example.com/inbox"),
+                Err(Error::Unavailable(60)),
+            ),
+            (inbox("https://c.example.com/inbox"), Err(Error::NotFound)),
+            (
+                inbox("https://d.example.com/inbox"),
+                Ok(Delivery::Sent(StatusCode::GONE)),
+            ),
+            (
+                inbox("https://e.example.com/inbox"),
+                Ok(Delivery::Sent(StatusCode::BAD_GATEWAY)),
+            ),
+            (inbox("https://f.example.com/inbox"), Ok(Delivery::Skipped)),
+        ]);
+        let record = |inbox: &str, status, error: Option<&str>| DeliveryRecord {
+            inbox: inbox.to_string(),
+            status,
+            error: error.map(str::to_string),
+            at: 10,
+        };
+        assert_eq!(
+            delivery_records(&results, 10),
+            [
+                record(
+                    "https://a.example.com/inbox",
+                    "dropped",
+                    Some("Unavailable(60)")
+                ),
+                record("https://b.example.com/inbox", "delivered", None),
+                record("https://c.example.com/inbox", "retrying", Some("NotFound")),
+                record(
+                    "https://d.example.com/inbox",
+                    "failed",
+                    Some("HTTP 410 Gone")
+                ),
+                record(
+                    "https://e.example.com/inbox",
+                    "retrying",
+                    Some("HTTP 502 Bad Gateway")
+                ),
+                record("https://f.example.com/inbox", "skipped", None),
+            ]
+        );
+        // the final status of a redelivery
+        assert_eq!(
+            delivery_status(&Ok(Delivery::Sent(StatusCode::BAD_GATEWAY)), false).0,
+            "failed"
+        );
+        assert_eq!(
+            delivery_status(&Err(Error::Unavailable(60)), true).0,
+            "retrying"
+        );
+    }
+
+    #[test]
+    fn created_note_1() {
+        let id = nostr_lib::EventId::all_zeros();
+        let body = serde_json::json!({
+            "type": "Create",
+            "object": {"id": format!("{NOTE_ID_PREFIX}{}", id.to_bech32().unwrap())},
+        });
+        assert_eq!(created_note(&body.to_string()), Some(id));
+        assert_eq!(created_note(r#"{"type":"Like","object":"x"}"#), None);
+    }
+}
@@ -0,0 +1,82 @@
+use super::AppState;
+use crate::RelayId;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use axum_macros::debug_handler;
+use relay_pool::{ConnectionState, RelayStatus};
+use rustc_hash::FxHashSet;
+use serde_json::json;
+use std::sync::Arc;
+
+#[debug_handler]
+#[tracing::instrument(skip_all)]
+pub async fn healthz(State(state): State<Arc<AppState>>) -> Response {
+    let (status, body) = health(
+        state.nostr.relay_statuses(),
+        &state.main_relays,
+        state.nostr.subscription_count(),
+    );
+    (status, Json(body)).into_response()
+}
+
+/// Healthy if any of `main_relays` is connected.
+fn health(
+    mut relays: Vec<RelayStatus<RelayId>>,
+    main_relays: &FxHashSet<RelayId>,
+    subscriptions: usize,
+) -> (StatusCode, serde_json::Value) {
+    relays.sort_by_key(|r| r.id);
+    let healthy = relays
+        .iter()
+        .any(|r| main_relays.contains(&r.id) && r.state == ConnectionState::Connected);
+    let body = json!({
+        "relays": relays
+            .iter()
+            .map(|r| json!({
+                "url": r.url.as_str(),
+                "state": r.state,
+                "main": main_relays.contains(&r.id),
+            }))
+            .collect::<Vec<_>>(),
+        "subscriptions": subscriptions,
+    });
+    if healthy {
+        (StatusCode::OK, body)
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::health;
+    use crate::RelayId;
+    use axum::http::StatusCode;
+    use relay_pool::{ConnectionState, RelayStatus};
+
+    #[test]
+    fn health_1() {
+        let relay = |id, state| RelayStatus {
+            id: RelayId(id),
+            url: url::Url::parse(&format!("wss://relay{id}.example.com")).unwrap(),
+            state,
+        };
+        let main_relays = [RelayId(0)].into_iter().collect();
+        let (status, body) = health(
+            vec![
+                relay(1, ConnectionState::Connected),
+                relay(0, ConnectionState::Disconnected),
+            ],
+            &main_relays,
+            2,
+        );
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["relays"][0]["state"], "disconnected");
+        assert_eq!(body["relays"][0]["main"], true);
+        assert_eq!(body["subscriptions"], 2);
+        let (status, _) = health(vec![relay(0, ConnectionState::Connected)], &main_relays, 2);
+        assert_eq!(status, StatusCode::OK);
+    }
+}
@@ -1,35 +1,138 @@
 use super::AppState;
 use crate::activity::{
-    AcceptActivity, ActivityForDe, ActivityForDeInner, Actor, ActorOrProxied, Delete,
-    FollowActivity, NoteForDe, NoteTagForDe, HASHTAG_LINK_REGEX,
+    actor_nsec, AcceptActivity, ActivityForDe, ActivityForDeInner, Actor, ActorOrProxied,
+    AttachedImage, FollowActivity, ListOrSingle, NoteForDe, NoteTagForDe, UpdateObject,
+    HASHTAG_LINK_REGEX,
 };
+use crate::bot::amplify;
+use crate::db::QueuedActivity;
 use crate::error::Error;
+use crate::nostr_to_ap::send_unfollow;
+use crate::software::misskey_markdown;
+use crate::util::sanitize_unicode;
 use crate::{
-    html_to_text, RelayId, CONTACT_LIST_LEN_LIMIT, DOMAIN, MAIN_RELAY, NOTE_ID_PREFIX, REVERSE_DNS,
-    USER_ID_PREFIX,
+    federates_with, html_to_text, url_federates, RelayId, ACCEPT_FORWARDED_NOTES,
+    ANNOUNCE_MAX_AGE_SECS, BOOST_AS_QUOTE, BOT_SEC, BRIDGE_MEDIA_FILE_EVENTS,
+    CONTACT_LIST_LEN_LIMIT, CREATE_MAX_AGE_SECS, DEFER_UNRESOLVED_MOVE, DOMAIN, EDIT_STRATEGY,
+    HTTPS_DOMAIN, INBOX_RETRY_AFTER_SECS, MAIN_RELAY, MAX_EVENT_SIZE, MAX_P_TAGS, MAX_THREAD_DEPTH,
+    MAX_THREAD_FETCHES, MENTION_CO_AUTHORS, MODERATOR_NPUB, NORMALIZE_REACTIONS, NOTE_ID_PREFIX,
+    READ_ONLY, READ_ONLY_MAX_BODY_SIZE, READ_ONLY_QUEUE_LEN, REVERSE_DNS, SANITIZE_UNICODE,
+    SENSITIVE_WARNING, UNVERIFIED_DELETE_GRACE, USER_ID_PREFIX,
 };
-use axum::body::to_bytes;
+use axum::body::{to_bytes, Bytes};
 use axum::extract::{Request, State};
-use axum::http::uri;
+use axum::http::{uri, HeaderMap};
 use axum_macros::debug_handler;
+use base64::Engine;
+use chrono::{DateTime, Utc};
 use itertools::Itertools;
 use nostr_lib::types::{Alphabet, SingleLetterTag};
 use nostr_lib::{
-    Event, EventBuilder, FromBech32, Kind, Marker, PublicKey, Tag, TagKind, Timestamp, ToBech32,
+    Event, EventBuilder, FromBech32, JsonUtil, Kind, Marker, PublicKey, Tag, TagKind, Timestamp,
+    ToBech32,
 };
 use once_cell::sync::Lazy;
-use parking_lot::Mutex;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use regex::Regex;
 use relay_pool::{EventWithRelayId, Filter};
 use rustc_hash::{FxHashMap, FxHashSet};
+use sha2::Digest;
 use std::borrow::{Borrow, Cow};
 use std::collections::HashSet;
 use std::fmt::Write;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::AsyncWriteExt;
-use tracing::{debug, error, info, trace};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, error, info, trace, warn};
+use unicode_normalization::UnicodeNormalization;
+
+/// Permit to process an inbound activity, or `503` if too many are being
+/// processed. Nothing of the activity has been handled at that point, so its
+/// redelivery is processed like a first delivery.
+fn inbox_permit(limiter: &Arc<Semaphore>, retry_after: u64) -> Result<OwnedSemaphorePermit, Error> {
+    limiter.clone().try_acquire_owned().map_err(|_| {
+        warn!("overloaded, asking to retry an activity in {retry_after}s");
+        Error::Unavailable(retry_after)
+    })
+}
+
+#[derive(serde::Deserialize)]
+struct ActorOfActivity<'a> {
+    #[serde(borrow)]
+    actor: Cow<'a, str>,
+}
+
+/// The request of a queued activity, without its body.
+fn queued_request(activity: &QueuedActivity) -> Result<Request<()>, Error> {
+    let mut r = Request::builder()
+        .method(activity.method.as_str())
+        .uri(activity.uri.as_str());
+    for (name, value) in &activity.headers {
+        r = r.header(name.as_str(), value.as_slice());
+    }
+    Ok(r.body(())?)
+}
+
+/// Attempts to process a queued activity before it is dropped.
+const QUEUED_ACTIVITY_ATTEMPTS: u32 = 5;
+
+impl AppState {
+    /// Processes the activities received in read-only mode. Those which fail
+    /// for good are dropped, and the others after `QUEUED_ACTIVITY_ATTEMPTS`.
+    pub async fn drain_queued_activities(self: &Arc<Self>) -> Result<(), Error> {
+        if *READ_ONLY {
+            return Ok(());
+        }
+        let events = self.db.queued_events();
+        if !events.is_empty() {
+            info!(
+                "publishing {} events queued in read-only mode",
+                events.len()
+            );
+        }
+        for (key, e) in events {
+            self.nostr_send(Arc::new(e)).await;
+            self.db.remove_queued_event(&key);
+        }
+        let activities = self.db.queued_activities();
+        if !activities.is_empty() {
+            info!(
+                "processing {} activities queued in read-only mode",
+                activities.len()
+            );
+        }
+        for (key, mut a) in activities {
+            let r = match queued_request(&a) {
+                Ok(r) => {
+                    process_activity(
+                        self.clone(),
+                        sigh::Signature::from(&r),
+                        r.headers(),
+                        a.body.clone().into(),
+                        true,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            match r {
+                Ok(()) => self.db.remove_queued_activity(&key),
+                Err(e) if e.is_retryable() && a.attempts + 1 < QUEUED_ACTIVITY_ATTEMPTS => {
+                    warn!("could not process a queued activity, will retry: {e:?}");
+                    a.attempts += 1;
+                    self.db.replace_queued_activity(&key, &a);
+                }
+                Err(e) => {
+                    error!("dropped a queued activity: {e:?}");
+                    self.db.remove_queued_activity(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+}
 
 #[debug_handler]
 #[tracing::instrument(skip_all)]
@@ -37,28 +140,205 @@ pub async fn http_post_inbox(
     State(state): State<Arc<AppState>>,
     request: Request,
 ) -> Result<(), Error> {
-    let signature = sigh::Signature::from(&request);
-    let body = to_bytes(request.into_body(), 1_000_000_000).await?;
+    let _permit = inbox_permit(&state.inbox_limiter, *INBOX_RETRY_AFTER_SECS)?;
+    let (parts, body) = request.into_parts();
+    let limit = if *READ_ONLY {
+        *READ_ONLY_MAX_BODY_SIZE
+    } else {
+        1_000_000_000
+    };
+    let body = to_bytes(body, limit).await?;
+    let ActorOfActivity { actor } = serde_json::from_slice(&body)?;
+    if let Some(host) = url::Url::parse(&actor)
+        .ok()
+        .as_ref()
+        .and_then(url::Url::host_str)
+    {
+        if !federates_with(host) {
+            info!("refused an activity of {actor}");
+            return Err(Error::Forbidden);
+        }
+    }
+    if *READ_ONLY {
+        if state.db.queued_activity_count() >= *READ_ONLY_QUEUE_LEN {
+            warn!("the read-only queue is full, asking to retry an activity");
+            return Err(Error::Unavailable(*INBOX_RETRY_AFTER_SECS));
+        }
+        debug!("queued an activity in read-only mode");
+        state.db.push_queued_activity(&QueuedActivity {
+            method: parts.method.to_string(),
+            uri: parts.uri.to_string(),
+            headers: parts
+                .headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            body: body.to_vec(),
+            attempts: 0,
+        });
+        return Ok(());
+    }
+    process_activity(
+        state,
+        sigh::Signature::from(&parts),
+        &parts.headers,
+        body,
+        false,
+    )
+    .await
+}
+
+/// Minimum age of a cached actor to fetch it again when its signature can't
+/// be verified, so that invalid signatures don't make us fetch it every time.
+const KEY_REFETCH_INTERVAL_SECS: u64 = 60;
+
+/// Names of the headers covered by the HTTP signature in `signature`.
+fn signed_headers(signature: &str) -> Vec<String> {
+    signature
+        .split(',')
+        .find_map(|p| p.trim().strip_prefix("headers="))
+        .map(|h| {
+            h.trim_matches('"')
+                .split_whitespace()
+                .map(str::to_lowercase)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Checks that the `Digest` header is the SHA-256 of `body`, so that a signed
+/// set of headers can't be paired with another body. A missing digest is only
+/// accepted if the signature doesn't cover it.
+fn verify_digest(headers: &HeaderMap, body: &[u8]) -> Result<(), Error> {
+    let digest = headers
+        .get_all("digest")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .find_map(|d| {
+            let (algorithm, value) = d.trim().split_once('=')?;
+            algorithm.eq_ignore_ascii_case("SHA-256").then_some(value)
+        });
+    match digest {
+        Some(digest) => {
+            let expected = base64::prelude::BASE64_STANDARD.encode(sha2::Sha256::digest(body));
+            if digest != expected {
+                return Err(Error::BadRequest(Some("digest mismatch".to_string())));
+            }
+        }
+        None => {
+            let signature = headers
+                .get("signature")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+            if signed_headers(signature).iter().any(|h| h == "digest") {
+                return Err(Error::BadRequest(Some("missing digest".to_string())));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `signature` is valid for the key of `actor` in the cache, which
+/// isn't refetched.
+fn verified_by_cached_key(state: &AppState, actor: &str, signature: &sigh::Signature<'_>) -> bool {
+    let cached = state.actor_cache.lock().peek(actor).cloned();
+    matches!(
+        cached,
+        Some(ActorOrProxied::Actor(a)) if signature.verify(&a.public_key).unwrap_or(false)
+    )
+}
+
+/// Activities drained from the read-only queue are `queued`, and aren't
+/// dropped or backdated for how long they waited in it.
+async fn process_activity(
+    state: Arc<AppState>,
+    signature: sigh::Signature<'_>,
+    headers: &HeaderMap,
+    body: Bytes,
+    queued: bool,
+) -> Result<(), Error> {
     debug!("/inbox <== {}", std::str::from_utf8(&body).unwrap());
+    verify_digest(headers, &body)?;
     let activity: ActivityForDe = serde_json::from_slice(&body)?;
-    if let ActivityForDeInner::Delete(Delete::User { .. }) = &*activity.activity_inner {
-        trace!("ignored user delete activity");
-        return Ok(());
+    state.metrics.activity_received(&activity.activity_inner);
+    if let ActivityForDeInner::Delete(d) = &*activity.activity_inner {
+        if d.object_id(&activity.actor).is_none() {
+            // The actor is gone, so its deletion is verified with a cached key,
+            // or else by refetching it.
+            if verified_by_cached_key(&state, &activity.actor, &signature)
+                || state.is_gone(&activity.actor).await
+            {
+                let nsec = actor_nsec(&activity.actor)?;
+                delete_actor(&state, &activity.actor, nsec).await;
+            } else {
+                trace!("ignored unverifiable user delete activity");
+            }
+            return Ok(());
+        }
     }
-    let actor = state.get_actor_data(activity.actor.as_ref()).await?;
+    let actor = match state.get_actor_data(activity.actor.as_ref()).await {
+        Ok(actor) => actor,
+        Err(e) => {
+            if let ActivityForDeInner::Delete(d) = &*activity.activity_inner {
+                let object_id = d.object_id(&activity.actor).unwrap();
+                // the actor can't be fetched, so the deletion is verified with a
+                // cached key, or else by the object being gone
+                if *UNVERIFIED_DELETE_GRACE
+                    && (verified_by_cached_key(&state, &activity.actor, &signature)
+                        || state.is_gone(object_id).await)
+                {
+                    let event_ids = event_to_delete(&activity.actor, object_id, |id| {
+                        state.db.get_event_ids_from_ap_id(id)
+                    });
+                    if !event_ids.is_empty() {
+                        info!("deleting {object_id} of unreachable {}", activity.actor);
+                        let nsec = actor_nsec(&activity.actor)?;
+                        tokio::spawn(async move {
+                            for e in event_ids {
+                                state.delete_event(e, nsec.clone()).await;
+                            }
+                        });
+                        return Ok(());
+                    }
+                }
+            }
+            return Err(e);
+        }
+    };
     let ActorOrProxied::Actor(actor) = actor else {
         return Err(Error::BadRequest(Some(
             "proxied activitypub account cannot follow accounts of this server".to_string(),
         )));
     };
+    let verified = |a: &Actor| signature.verify(&a.public_key).unwrap_or(false);
+    let actor = if verified(&actor) {
+        actor
+    } else {
+        // the actor may have rotated its key since it was cached
+        let refetched = if actor.is_stale(Timestamp::now().as_u64(), KEY_REFETCH_INTERVAL_SECS) {
+            info!("fetching {} again to verify its signature", actor.id);
+            match state.refetch_actor_data(&actor.id).await? {
+                ActorOrProxied::Actor(a) if verified(&a) => Some(a),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        refetched.ok_or_else(|| {
+            Error::BadRequest(Some("failded to verify HTTP signature".to_string()))
+        })?
+    };
+    // keyed on the host of the verified signer so that it can't be spoofed
+    if let Some(host) = url::Url::parse(&actor.id)
+        .ok()
+        .as_ref()
+        .and_then(url::Url::host_str)
+        .filter(|_| !queued)
     {
-        if !signature
-            .verify(&actor.public_key)
-            .map_err(|e| Error::BadRequest(Some(e.to_string())))?
-        {
-            return Err(Error::BadRequest(Some(
-                "failded to verify HTTP signature".to_string(),
-            )));
+        if let Err(secs) = state.inbox_rate_limiter.check(host, Instant::now()) {
+            info!("rate limited {host}");
+            return Err(Error::TooManyRequests(secs));
         }
     }
     let ActivityForDe {
@@ -66,7 +346,7 @@ pub async fn http_post_inbox(
         actor: actor_id,
     } = activity;
     match *activity_inner {
-        ActivityForDeInner::Follow { object, .. } => {
+        ActivityForDeInner::Follow { object, id } => {
             info!("{actor_id} followed {object}");
             let followed = get_npub_from_actor_id(object.as_ref())
                 .ok_or_else(|| Error::BadRequest(Some("object not found".to_string())))?;
@@ -85,6 +365,7 @@ pub async fn http_post_inbox(
                 }
             }
             let object = object.to_string();
+            let follow_id = id.map(|a| a.to_string());
             let inbox = actor.inbox.clone();
             let actor_id = actor_id.to_string();
             tokio::spawn(async move {
@@ -98,7 +379,7 @@ pub async fn http_post_inbox(
                                 object: FollowActivity {
                                     actor: actor_id.as_str(),
                                     object: object.as_str(),
-                                    id: None,
+                                    id: follow_id.as_deref(),
                                 },
                             },
                         )
@@ -109,21 +390,18 @@ pub async fn http_post_inbox(
                         let mut l = state.nostr_account_to_followers_rev.lock();
                         let l = l.entry(actor_id).or_default();
                         l.insert(followed);
-                        if l.len() < CONTACT_LIST_LEN_LIMIT {
-                            l.iter()
-                                .map(|p| nostr_lib::Tag::public_key(*p))
-                                .collect_vec()
-                        } else {
-                            Vec::new()
-                        }
+                        contact_list_tags(l)
                     };
-                    let l = EventBuilder::new(nostr_lib::Kind::ContactList, "", tags)
-                        .custom_created_at(Timestamp::now())
-                        .to_event(&nostr_lib::Keys::new(actor.nsec.clone()))
-                        .unwrap();
-                    state.nostr_send(Arc::new(l)).await;
+                    // a list too long to publish is left as it is rather than emptied
+                    if let Some(tags) = tags {
+                        let l = EventBuilder::new(nostr_lib::Kind::ContactList, "", tags)
+                            .custom_created_at(Timestamp::now())
+                            .to_event(&nostr_lib::Keys::new(actor.nsec.clone()))
+                            .unwrap();
+                        state.nostr_send(Arc::new(l)).await;
+                    }
                 }
-                backup_nostr_accounts(&state.nostr_account_to_followers).await;
+                backup_nostr_accounts(&state).await;
             });
         }
         ActivityForDeInner::Undo {
@@ -156,15 +434,7 @@ pub async fn http_post_inbox(
                         let mut l = state.nostr_account_to_followers_rev.lock();
                         let l = l.entry(actor_id.to_string()).or_default();
                         l.remove(&object);
-                        if l.len() < CONTACT_LIST_LEN_LIMIT {
-                            Some(
-                                l.iter()
-                                    .map(|p| nostr_lib::Tag::public_key(*p))
-                                    .collect_vec(),
-                            )
-                        } else {
-                            None
-                        }
+                        contact_list_tags(l)
                     };
                     if let Some(tags) = tags {
                         let l = EventBuilder::new(nostr_lib::Kind::ContactList, "", tags)
@@ -174,9 +444,10 @@ pub async fn http_post_inbox(
                         state.nostr_send(Arc::new(l)).await;
                     }
                 }
-                backup_nostr_accounts(&state.nostr_account_to_followers).await;
+                backup_nostr_accounts(&state).await;
             }
-            ActivityForDeInner::Like { object, id, .. } => {
+            ActivityForDeInner::Like { object, id, .. }
+            | ActivityForDeInner::EmojiReact { object, id, .. } => {
                 let note = get_note_from_this_server(&state, object.as_ref())
                     .await
                     .ok_or_else(|| Error::BadRequest(Some("object not found".to_string())))?;
@@ -188,12 +459,9 @@ pub async fn http_post_inbox(
                     generic_tags: [
                         (
                             SingleLetterTag::lowercase(Alphabet::L),
-                            [nostr_lib::GenericTagValue::String(format!(
-                                "{}.activitypub:{id}",
-                                *REVERSE_DNS
-                            ))]
-                            .into_iter()
-                            .collect(),
+                            [nostr_lib::GenericTagValue::String(ap_id_label(&id))]
+                                .into_iter()
+                                .collect(),
                         ),
                         (
                             SingleLetterTag::lowercase(Alphabet::E),
@@ -208,7 +476,7 @@ pub async fn http_post_inbox(
                 };
                 let nsec = actor.nsec.clone();
                 let undo_id = undo_id.to_string();
-                let ap_id = InternalApId::get(id, actor_id.as_ref())?.into_owned();
+                let ap_id = InternalApId::get(id, &actor.id)?.into_owned();
                 tokio::spawn(async move {
                     match state
                         .get_nostr_event_with_timeout(f, Duration::from_secs(10))
@@ -239,6 +507,11 @@ pub async fn http_post_inbox(
                     }
                 });
             }
+            // some servers undo a `Create` instead of sending `Delete`
+            ActivityForDeInner::Create { object } => {
+                info!("undo of create {}", object.id);
+                delete_bridged_event(state, &actor, &object.id);
+            }
             _ => {
                 info!("undo of this activity is not supported: {object:?}");
             }
@@ -250,16 +523,70 @@ pub async fn http_post_inbox(
                     format!("{npub} is already a nostr event").into(),
                 ));
             }
-            let ap_id = InternalApId::get(Cow::Borrowed(&object.id), &actor.id)?.into_owned();
+            if is_from_this_server(&object, actor_id.as_ref()) {
+                info!("ignored note {} originating from this server", object.id);
+                return Ok(());
+            }
+            if !queued && is_too_old(object.published, Timestamp::now(), *CREATE_MAX_AGE_SECS) {
+                info!(
+                    "ignored note {} published at {}",
+                    object.id, object.published
+                );
+                return Ok(());
+            }
+            let (ap_id, forwarded) =
+                create_ap_id(&object.id, &object.attributed_to.author, &actor.id)?;
             if state.db.get_event_id_from_ap_id(&ap_id).is_some() {
                 error!("note {} already exists", object.id);
                 return Ok(());
             }
+            if forwarded {
+                if !*ACCEPT_FORWARDED_NOTES {
+                    info!("ignored note {} forwarded by {actor_id}", object.id);
+                    return Ok(());
+                }
+                // the signature is of the forwarder, so the note is fetched
+                // from its origin instead of trusting the embedded copy
+                info!("note {} forwarded by {actor_id}", object.id);
+                tokio::spawn(async move {
+                    match get_event_from_object_id(
+                        &state,
+                        object.id,
+                        Cow::Borrowed(&[]),
+                        &FetchBudget::default(),
+                    )
+                    .await
+                    {
+                        Ok(e) => amplify(&state, &e.event, &object.attributed_to.author).await,
+                        Err(e) => {
+                            error!("could not convert forwarded AP note to Nostr note: {e:?}")
+                        }
+                    }
+                });
+                return Ok(());
+            }
             tokio::spawn(async move {
-                if let Err(e) =
-                    get_event_from_note(&state, *object, actor.clone(), Cow::Borrowed(&[])).await
+                if is_vote(&object) {
+                    if let Some(vote) = get_poll_response(&state, &object, &actor).await {
+                        info!("vote on a poll {}", object.id);
+                        send_event(&state, Arc::new(vote), ap_id).await;
+                    } else {
+                        debug!("dropped vote {}", object.id);
+                    }
+                    return;
+                }
+                match get_event_from_note(
+                    &state,
+                    *object,
+                    actor.clone(),
+                    Cow::Borrowed(&[]),
+                    &FetchBudget::default(),
+                    None,
+                )
+                .await
                 {
-                    error!("could not convert AP note to Nostr note: {e:?}");
+                    Ok(event) => amplify(&state, &event, &actor.id).await,
+                    Err(e) => error!("could not convert AP note to Nostr note: {e:?}"),
                 }
             });
         }
@@ -268,11 +595,17 @@ pub async fn http_post_inbox(
             content,
             id,
             tag,
+        }
+        | ActivityForDeInner::EmojiReact {
+            object,
+            content,
+            id,
+            tag,
         } => {
             if state.db.is_stopped_ap(actor_id.as_ref()) {
                 return Ok(());
             }
-            let ap_id = InternalApId::get(Cow::from(id.as_ref()), actor_id.as_ref())?.into_owned();
+            let ap_id = InternalApId::get(Cow::from(id.as_ref()), &actor.id)?.into_owned();
             if state.db.get_event_id_from_ap_id(&ap_id).is_some() {
                 error!("like {} already exists", id);
                 return Ok(());
@@ -281,27 +614,8 @@ pub async fn http_post_inbox(
                 .await
                 .ok_or_else(|| Error::BadRequest(Some("object not found".to_string())))?;
             let mut tags = vec![Tag::event(note.id), Tag::public_key(note.pubkey)];
-            let mut content_converted = Cow::Borrowed("+");
-            if let Some(content) = content {
-                let emoij = tag.iter().find_map(|t| {
-                    if let NoteTagForDe::Emoji { name, icon } = t {
-                        if name.as_ref() == content {
-                            Some(Tag::Emoji {
-                                shortcode: content.trim_matches(':').to_string(),
-                                url: icon.url.clone().into(),
-                            })
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                });
-                content_converted = content;
-                if let Some(e) = emoij {
-                    tags.push(e);
-                }
-            }
+            let (content_converted, emoji) = reaction(content, &tag);
+            tags.extend(emoji);
             send_event(
                 &state,
                 Arc::new(
@@ -338,56 +652,197 @@ pub async fn http_post_inbox(
             if is_private {
                 return Ok(());
             }
-            let ap_id =
-                InternalApId::get(Cow::Borrowed(id.as_ref()), actor_id.as_ref())?.into_owned();
+            let Some(note_id) = object.note_id() else {
+                debug!("ignored announce of {object:?}");
+                return Ok(());
+            };
+            let ap_id = InternalApId::get(Cow::Borrowed(id.as_ref()), &actor.id)?.into_owned();
             if state.db.get_event_id_from_ap_id(&ap_id).is_some() {
                 error!("repost {} already exists", id);
                 return Ok(());
             }
-            if let Ok(event) =
-                get_event_from_object_id(&state, object.to_string(), Cow::Borrowed(&[])).await
+            if let Ok(event) = get_event_from_object_id(
+                &state,
+                note_id.to_string(),
+                Cow::Borrowed(&[]),
+                &FetchBudget::default(),
+            )
+            .await
             {
-                let event = EventBuilder::new(
-                    nostr_lib::Kind::Repost,
-                    "",
-                    event_tag(
-                        id.to_string(),
-                        [
-                            Tag::Event {
-                                event_id: event.event.id,
-                                relay_url: Some(
-                                    state.relay_url[event.relay_id.0 as usize].clone().into(),
-                                ),
-                                marker: None,
-                            },
-                            Tag::public_key(event.event.pubkey),
-                        ],
-                    ),
-                )
-                .custom_created_at(Timestamp::from(published.timestamp() as u64))
-                .to_event(&nostr_lib::Keys::new(actor.nsec.clone()))
-                .unwrap();
+                amplify(&state, &event.event, &actor.id).await;
+                let relay_url = state.relay_url_of(event.relay_id);
+                let (kind, content, tags) = repost(&event.event, relay_url, *BOOST_AS_QUOTE);
+                let event = EventBuilder::new(kind, content, event_tag(id.to_string(), tags))
+                    .custom_created_at(repost_created_at(
+                        published,
+                        Timestamp::now(),
+                        if queued {
+                            u64::MAX
+                        } else {
+                            *ANNOUNCE_MAX_AGE_SECS
+                        },
+                    ))
+                    .to_event(&nostr_lib::Keys::new(actor.nsec.clone()))
+                    .unwrap();
                 send_event(&state, Arc::new(event), ap_id.into_owned()).await;
             }
         }
-        ActivityForDeInner::Delete(Delete::Note { object }) => {
-            let object_id =
-                InternalApId::get(Cow::Owned(object.id.to_string()), actor_id.as_ref())?;
-            if let Some(e) = state.db.get_event_id_from_ap_id(&object_id) {
-                info!("sending delete request ...");
-                let nsec = actor.nsec.clone();
-                tokio::spawn(async move {
-                    state.delete_event(e, nsec).await;
-                });
+        ActivityForDeInner::Accept { object } => {
+            let follow_id = object.id();
+            let followee = utf8_percent_encode(&actor.id, NON_ALPHANUMERIC).to_string();
+            if !follow_id.ends_with(&format!("/{followee}")) {
+                info!("ignored accept of {follow_id} by {actor_id}");
+            } else if let Some(npub) =
+                unwanted_follow(follow_id, &actor.id, |p| state.db.get_followee_of_nostr(p))
+            {
+                // the Nostr user unfollowed while the follow was pending
+                info!("{actor_id} accepted {follow_id} after the unfollow; undoing it");
+                if let Some(inbox) = &actor.inbox {
+                    send_unfollow(&state, &npub, inbox, &actor.id).await;
+                }
+            } else if state
+                .db
+                .confirm_follow(follow_id, Timestamp::now().as_u64())
+            {
+                info!("{actor_id} accepted {follow_id}");
             } else {
-                info!("tried to delete a event but could not find it");
+                debug!("accept of unknown or confirmed follow {follow_id}");
+            }
+        }
+        ActivityForDeInner::Delete(d) => {
+            if let Some(object_id) = d.object_id(&actor_id) {
+                delete_bridged_event(state, &actor, object_id);
             }
         }
-        ActivityForDeInner::Update { object } => {
+        ActivityForDeInner::Update {
+            object: UpdateObject::Actor(object),
+        } => {
             info!("update of actor");
             state.update_actor_metadata(&object).await?;
         }
-        ActivityForDeInner::Delete(Delete::User { .. }) => panic!(),
+        ActivityForDeInner::Update {
+            object: UpdateObject::Note(object),
+        } => {
+            if is_from_this_server(&object, actor_id.as_ref()) {
+                info!("ignored edit of {} originating from this server", object.id);
+                return Ok(());
+            }
+            let ap_id = InternalApId::get(Cow::Borrowed(&object.id), &actor.id)?.into_owned();
+            let versions = state.db.get_event_ids_from_ap_id(&ap_id);
+            if versions.is_empty() {
+                info!("ignored edit of unknown note {}", object.id);
+                return Ok(());
+            }
+            info!("edit of {}", object.id);
+            let (delete, edit_of) = edit_actions(*EDIT_STRATEGY, &versions);
+            if !delete.is_empty() {
+                // the reposted note replaces every deleted version
+                state.db.remove_ap_id_to_event_id(&ap_id);
+            }
+            tokio::spawn(async move {
+                for e in delete {
+                    state.delete_event(e, actor.nsec.clone()).await;
+                }
+                if let Err(e) = get_event_from_note(
+                    &state,
+                    *object,
+                    actor.clone(),
+                    Cow::Borrowed(&[]),
+                    &FetchBudget::default(),
+                    edit_of,
+                )
+                .await
+                {
+                    error!("could not convert edited AP note to Nostr note: {e:?}");
+                }
+            });
+        }
+        ActivityForDeInner::Move { object, target } => {
+            if object != actor_id {
+                return Err(Error::BadRequest(Some(
+                    "actor can only move itself".to_string(),
+                )));
+            }
+            info!("{actor_id} moved to {target}");
+            state.actor_cache.lock().pop(target.as_ref());
+            match state.get_actor_data(target.as_ref()).await {
+                Ok(ActorOrProxied::Actor(target)) => {
+                    check_also_known_as(&actor.id, &target)?;
+                    move_followers(&state, &actor, &target).await;
+                }
+                Ok(ActorOrProxied::Proxied(_)) => {
+                    return Err(Error::BadRequest(Some(
+                        "cannot move to a proxied account".to_string(),
+                    )));
+                }
+                Err(e) if *DEFER_UNRESOLVED_MOVE => {
+                    info!("deferred move to {target}: {e:?}");
+                    let target = target.to_string();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(Duration::from_secs(60 * 10)).await;
+                        match state.get_actor_data(&target).await {
+                            Ok(ActorOrProxied::Actor(target)) => {
+                                if let Err(e) = check_also_known_as(&actor.id, &target) {
+                                    error!("rejected move to {target:?}: {e:?}");
+                                    return;
+                                }
+                                move_followers(&state, &actor, &target).await;
+                            }
+                            Ok(ActorOrProxied::Proxied(_)) => {
+                                error!("cannot move to a proxied account {target}");
+                            }
+                            Err(e) => error!("could not resolve move target {target}: {e:?}"),
+                        }
+                    });
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        ActivityForDeInner::Add { object, target }
+        | ActivityForDeInner::Remove { object, target }
+            if target.as_ref().map(|t| t.id()) != actor.featured.as_deref() =>
+        {
+            info!(
+                "ignored Add or Remove of {} to another collection",
+                object.id()
+            );
+        }
+        ActivityForDeInner::Add { object, .. } => {
+            tokio::spawn(async move {
+                if let Err(e) = update_pin_list(&state, &actor, Some(object.id()), None).await {
+                    error!("could not pin {}: {e:?}", object.id());
+                }
+            });
+        }
+        ActivityForDeInner::Remove { object, .. } => {
+            tokio::spawn(async move {
+                if let Err(e) = update_pin_list(&state, &actor, None, Some(object.id())).await {
+                    error!("could not unpin {}: {e:?}", object.id());
+                }
+            });
+        }
+        ActivityForDeInner::Flag { object, content } => {
+            let objects: Vec<String> = match object {
+                ListOrSingle::Single(o) => vec![o],
+                ListOrSingle::Vec(l) => l.into_iter().filter_map(Option::from).collect(),
+            };
+            warn!(
+                reporter = actor_id.as_ref(),
+                objects = ?objects,
+                content = content.as_deref().unwrap_or_default(),
+                "received a report"
+            );
+            if let Some(moderator) = *MODERATOR_NPUB {
+                let message = report_message(&actor_id, content.as_deref(), &objects);
+                let keys = nostr_lib::Keys::new(BOT_SEC.clone());
+                let event = EventBuilder::encrypted_direct_msg(&keys, moderator, message, None)
+                    .and_then(|e| e.to_event(&keys));
+                match event {
+                    Ok(event) => state.nostr_send(Arc::new(event)).await,
+                    Err(e) => error!("could not forward the report: {e}"),
+                }
+            }
+        }
         ActivityForDeInner::Other(a) => {
             info!("not implemented {}", a);
         }
@@ -395,6 +850,304 @@ pub async fn http_post_inbox(
     Ok(())
 }
 
+/// The pinned notes after pinning `added` or unpinning `removed`, in case the
+/// collection isn't updated yet.
+fn pinned_notes(
+    mut featured: Vec<String>,
+    added: Option<&str>,
+    removed: Option<&str>,
+) -> Vec<String> {
+    featured.retain(|id| Some(id.as_str()) != removed);
+    if let Some(added) = added {
+        if !featured.iter().any(|id| id == added) {
+            featured.insert(0, added.to_string());
+        }
+    }
+    featured
+}
+
+/// Publishes the pinned notes of `actor` as its Kind 10001, bridging those
+/// not bridged yet.
+async fn update_pin_list(
+    state: &Arc<AppState>,
+    actor: &Actor,
+    added: Option<&str>,
+    removed: Option<&str>,
+) -> Result<(), Error> {
+    let Some(featured) = &actor.featured else {
+        return Ok(());
+    };
+    let notes = pinned_notes(state.featured_ids(featured).await?, added, removed);
+    let mut tags = Vec::with_capacity(notes.len());
+    for id in notes {
+        match get_event_from_object_id(
+            state,
+            id.clone(),
+            Cow::Borrowed(&[]),
+            &FetchBudget::default(),
+        )
+        .await
+        {
+            Ok(e) => tags.push(Tag::event(e.event.id)),
+            Err(e) => info!("could not bridge pinned note {id}: {e:?}"),
+        }
+    }
+    info!("{} pinned {} notes", actor.id, tags.len());
+    let event = EventBuilder::new(Kind::PinList, "", tags)
+        .to_event(&nostr_lib::Keys::new(actor.nsec.clone()))
+        .unwrap();
+    state.nostr_send(Arc::new(event)).await;
+    Ok(())
+}
+
+/// Text of a report forwarded to `MODERATOR_NPUB`, in which bridged users and
+/// notes are Nostr URIs.
+fn report_message(reporter: &str, content: Option<&str>, objects: &[String]) -> String {
+    let mut message = format!("Report from {reporter}");
+    if let Some(content) = content.filter(|c| !c.is_empty()) {
+        message.push_str(":\n");
+        message.push_str(content);
+    }
+    for o in objects {
+        message.push_str("\n- ");
+        match o
+            .strip_prefix(NOTE_ID_PREFIX)
+            .or_else(|| o.strip_prefix(USER_ID_PREFIX))
+        {
+            Some(id) => {
+                message.push_str("nostr:");
+                message.push_str(id);
+            }
+            None => message.push_str(o),
+        }
+    }
+    message
+}
+
+/// `p` tags of the contact list following `followees`, or `None` if it is too
+/// long to be published.
+fn contact_list_tags(followees: &FxHashSet<PublicKey>) -> Option<Vec<Tag>> {
+    (followees.len() < CONTACT_LIST_LEN_LIMIT).then(|| {
+        followees
+            .iter()
+            .map(|p| nostr_lib::Tag::public_key(*p))
+            .collect_vec()
+    })
+}
+
+/// Replaces `old` with `new` as a follower of nostr accounts and returns the
+/// accounts `new` follows afterwards.
+fn migrate_followers<'a>(
+    followers: &mut FxHashMap<PublicKey, Arc<HashSet<String>>>,
+    followers_rev: &'a mut FxHashMap<String, FxHashSet<PublicKey>>,
+    old: &str,
+    new: &str,
+) -> Option<&'a FxHashSet<PublicKey>> {
+    let followees = followers_rev.remove(old)?;
+    for p in &followees {
+        if let Some(s) = followers.get_mut(p) {
+            let mut s_cloned = (**s).clone();
+            s_cloned.remove(old);
+            s_cloned.insert(new.to_string());
+            *s = Arc::new(s_cloned);
+        }
+    }
+    let l = followers_rev.entry(new.to_string()).or_default();
+    l.extend(followees);
+    Some(l)
+}
+
+/// Removes `id` as a follower of nostr accounts and returns whether it
+/// followed any.
+fn remove_follower(
+    followers: &mut FxHashMap<PublicKey, Arc<HashSet<String>>>,
+    followers_rev: &mut FxHashMap<String, FxHashSet<PublicKey>>,
+    id: &str,
+) -> bool {
+    let Some(followees) = followers_rev.remove(id) else {
+        return false;
+    };
+    for p in &followees {
+        if let Some(s) = followers.get_mut(p) {
+            let mut s_cloned = (**s).clone();
+            s_cloned.remove(id);
+            if s_cloned.is_empty() {
+                followers.remove(p);
+            } else {
+                *s = Arc::new(s_cloned);
+            }
+        }
+    }
+    !followees.is_empty()
+}
+
+/// Cleans up after the actor `id` deleted itself: it is dropped from the
+/// caches and the account maps, and its contact list and metadata are
+/// cleared, so that no zombie account is left on Nostr.
+async fn delete_actor(state: &AppState, id: &str, nsec: nostr_lib::SecretKey) {
+    info!("{id} deleted itself");
+    let keys = nostr_lib::Keys::new(nsec);
+    state.actor_cache.lock().pop(id);
+    state.actor_activity.lock().pop(id);
+    state.activitypub_accounts.lock().remove(&keys.public_key());
+    let followed = {
+        let mut followers = state.nostr_account_to_followers.lock();
+        let mut followers_rev = state.nostr_account_to_followers_rev.lock();
+        remove_follower(&mut followers, &mut followers_rev, id)
+    };
+    if followed {
+        let l = EventBuilder::new(nostr_lib::Kind::ContactList, "", [])
+            .custom_created_at(Timestamp::now())
+            .to_event(&keys)
+            .unwrap();
+        state.nostr_send(Arc::new(l)).await;
+    }
+    // Kind 0 is replaced rather than deleted, as relays don't all honor
+    // deletions of replaceable events.
+    let metadata = EventBuilder::metadata(&nostr_lib::Metadata::new())
+        .custom_created_at(Timestamp::now())
+        .to_event(&keys)
+        .unwrap();
+    state
+        .nostr
+        .send(Arc::new(metadata), state.metadata_relays.clone())
+        .await;
+    if let Err(e) = state.snapshot_accounts().await {
+        error!("could not snapshot accounts: {e:?}");
+    }
+}
+
+/// A `Move` is only honored if the target lists the origin in `alsoKnownAs`.
+fn check_also_known_as(origin: &str, target: &Actor) -> Result<(), Error> {
+    if target.also_known_as.iter().any(|a| a == origin) {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(Some(format!(
+            "{} does not list {origin} in alsoKnownAs",
+            target.id
+        ))))
+    }
+}
+
+async fn move_followers(state: &AppState, old: &Actor, new: &Actor) {
+    {
+        let mut cache = state.actor_cache.lock();
+        cache.pop(&old.id);
+        cache.pop(&new.id);
+    }
+    let tags = {
+        let mut followers = state.nostr_account_to_followers.lock();
+        let mut followers_rev = state.nostr_account_to_followers_rev.lock();
+        let Some(l) = migrate_followers(&mut followers, &mut followers_rev, &old.id, &new.id)
+        else {
+            return;
+        };
+        contact_list_tags(l)
+    };
+    info!("moved followees of {} to {}", old.id, new.id);
+    // a list too long to publish is left on the old account rather than lost
+    if let Some(tags) = tags {
+        let l = EventBuilder::new(nostr_lib::Kind::ContactList, "", [])
+            .custom_created_at(Timestamp::now())
+            .to_event(&nostr_lib::Keys::new(old.nsec.clone()))
+            .unwrap();
+        state.nostr_send(Arc::new(l)).await;
+        let l = EventBuilder::new(nostr_lib::Kind::ContactList, "", tags)
+            .custom_created_at(Timestamp::now())
+            .to_event(&nostr_lib::Keys::new(new.nsec.clone()))
+            .unwrap();
+        state.nostr_send(Arc::new(l)).await;
+    }
+    backup_nostr_accounts(state).await;
+}
+
+/// Deletes the note, reaction or repost bridged from `object_id`. Relays only
+/// honor the deletion if the actor authored the event, as it signs it.
+fn delete_bridged_event(state: Arc<AppState>, actor: &Actor, object_id: &str) {
+    let event_ids = event_to_delete(&actor.id, object_id, |id| {
+        state.db.get_event_ids_from_ap_id(id)
+    });
+    if !event_ids.is_empty() {
+        info!("sending delete request ...");
+        let nsec = actor.nsec.clone();
+        tokio::spawn(async move {
+            for e in event_ids {
+                state.delete_event(e, nsec.clone()).await;
+            }
+        });
+    } else {
+        info!("tried to delete a event but could not find it");
+    }
+}
+
+/// How edits of bridged notes are published.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditStrategy {
+    /// Delete the bridged event and publish the edited note as a new one.
+    /// Every client shows the edit, but reactions and replies to the
+    /// original event are orphaned.
+    DeleteRepost,
+    /// Keep the bridged event and publish the edited note with an `edit`
+    /// tag pointing to it. The original keeps its replies and reactions,
+    /// but clients which don't know the tag show both versions.
+    AppendEdit,
+}
+
+impl FromStr for EditStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delete-repost" => Ok(Self::DeleteRepost),
+            "append-edit" => Ok(Self::AppendEdit),
+            s => Err(format!("unknown edit strategy: {s}")),
+        }
+    }
+}
+
+/// Id of a created note, and whether it was forwarded by an actor other than
+/// its author. The host of the id is checked against that of the author, so
+/// the id is the same however the note is delivered.
+fn create_ap_id(
+    object_id: &str,
+    author: &str,
+    actor_id: &str,
+) -> Result<(InternalApId<'static>, bool), Error> {
+    let ap_id = InternalApId::get(Cow::Borrowed(object_id), author)?.into_owned();
+    Ok((ap_id, author != actor_id))
+}
+
+/// Events to delete and event to link from the edited note, for an edit of a
+/// note bridged as `versions`, oldest first. Appended edits all point to the
+/// first version.
+fn edit_actions(
+    strategy: EditStrategy,
+    versions: &[nostr_lib::EventId],
+) -> (Vec<nostr_lib::EventId>, Option<nostr_lib::EventId>) {
+    match strategy {
+        EditStrategy::DeleteRepost => (versions.to_vec(), None),
+        EditStrategy::AppendEdit => (Vec::new(), versions.first().copied()),
+    }
+}
+
+fn edit_tag(original: nostr_lib::EventId) -> Tag {
+    Tag::Generic(TagKind::Custom("edit".into()), vec![original.to_hex()])
+}
+
+/// Events to delete for a `Delete` of `object_id` by `actor_id`, including
+/// every edited version. Only events already bridged from the host of the
+/// actor qualify.
+fn event_to_delete(
+    actor_id: &str,
+    object_id: &str,
+    get_event_ids: impl FnOnce(&InternalApId<'static>) -> Vec<nostr_lib::EventId>,
+) -> Vec<nostr_lib::EventId> {
+    match InternalApId::get(Cow::Owned(object_id.to_string()), actor_id) {
+        Ok(id) => get_event_ids(&id),
+        Err(_) => Vec::new(),
+    }
+}
+
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
 pub struct InternalApId<'a>(Cow<'a, str>);
 
@@ -425,20 +1178,458 @@ impl<'a> InternalApId<'a> {
         }
     }
 
-    fn get_unchecked(ap_id: Cow<'a, str>) -> InternalApId<'a> {
+    pub(crate) fn get_unchecked(ap_id: Cow<'a, str>) -> InternalApId<'a> {
         Self(ap_id)
     }
 }
 
 async fn send_event(state: &AppState, event: Arc<Event>, ap_id: InternalApId<'static>) {
+    let size = event.as_json().len();
+    if size > *MAX_EVENT_SIZE {
+        warn!(
+            "skipped {} of {size} bytes, relays would reject it",
+            event.id
+        );
+        return;
+    }
     state.db.insert_ap_id_to_event_id(ap_id, event.id);
     state.nostr_send(event).await;
 }
 
-async fn get_note_from_this_server(state: &AppState, url: &str) -> Option<Arc<Event>> {
-    let object = url.get(NOTE_ID_PREFIX.len()..)?;
-    let object = nostr_lib::EventId::from_bech32(object).ok()?;
-    state.get_note(object).await.map(|e| e.event)
+/// Length of `c` in a JSON string.
+fn json_len(c: char) -> usize {
+    match c {
+        '"' | '\\' | '\n' | '\r' | '\t' | '\u{8}' | '\u{c}' => 2,
+        c if c < ' ' => 6,
+        c => c.len_utf8(),
+    }
+}
+
+/// Fits a note into `max` bytes of serialized event by dropping `imeta` tags
+/// and then truncating `content` with a link to `original`. `None` if even
+/// that isn't enough.
+fn fit_to_size(
+    content: String,
+    mut tags: Vec<Tag>,
+    original: &str,
+    max: usize,
+) -> Option<(String, Vec<Tag>)> {
+    // id, pubkey, sig, created_at, kind and the keys
+    const OVERHEAD: usize = 64 + 64 + 128 + 20 + 10 + 100;
+    let size = |content: &str, tags: &[Tag]| {
+        OVERHEAD
+            + content.chars().map(json_len).sum::<usize>()
+            + serde_json::to_string(tags).unwrap().len()
+    };
+    if size(&content, &tags) <= max {
+        return Some((content, tags));
+    }
+    tags.retain(|t| t.kind() != TagKind::Custom("imeta".to_string()));
+    if size(&content, &tags) <= max {
+        return Some((content, tags));
+    }
+    let suffix = format!("…\n{original}");
+    let budget = max.checked_sub(size(&suffix, &tags))?;
+    let mut len = 0;
+    let mut end = 0;
+    for (i, c) in content.char_indices() {
+        len += json_len(c);
+        if len > budget {
+            break;
+        }
+        end = i + c.len_utf8();
+    }
+    Some((format!("{}{suffix}", &content[..end]), tags))
+}
+
+async fn get_note_from_this_server(state: &AppState, url: &str) -> Option<Arc<Event>> {
+    let object = url.get(NOTE_ID_PREFIX.len()..)?;
+    let object = nostr_lib::EventId::from_bech32(object).ok()?;
+    state.get_note(object).await.map(|e| e.event)
+}
+
+/// The content warning of a note, with `default_reason` for sensitive notes
+/// without a summary.
+fn content_warning(
+    summary: Option<String>,
+    sensitive: bool,
+    default_reason: Option<&str>,
+) -> Option<Tag> {
+    match summary {
+        Some(r) if !r.is_empty() => Some(Tag::ContentWarning { reason: Some(r) }),
+        _ if sensitive => Some(Tag::ContentWarning {
+            reason: default_reason.map(str::to_string),
+        }),
+        _ => None,
+    }
+}
+
+/// Votes on polls are sent as replies to the poll without content, whose
+/// `name` is the chosen option.
+fn is_vote(note: &NoteForDe) -> bool {
+    note.name.is_some() && note.in_reply_to.is_some() && note.content.is_empty()
+}
+
+/// The NIP-88 poll response for a vote, if the poll it replies to is known.
+/// It carries every option the voter chose so far, replacing their previous
+/// response.
+async fn get_poll_response(
+    state: &Arc<AppState>,
+    note: &NoteForDe,
+    actor: &Actor,
+) -> Option<Event> {
+    let name = note.name.as_deref()?;
+    let in_reply_to = note.in_reply_to.clone()?;
+    let poll = match get_event_from_object_id(
+        state,
+        in_reply_to,
+        Cow::Borrowed(&[]),
+        &FetchBudget::default(),
+    )
+    .await
+    {
+        Ok(e) => e.event,
+        Err(e) => {
+            debug!("could not get the poll of {}: {e:?}", note.id);
+            return None;
+        }
+    };
+    let Some(option) = poll_option(&poll, name) else {
+        debug!("{} is not an option of {}", name, poll.id);
+        return None;
+    };
+    let (options, created_at) = {
+        let mut votes = state.poll_votes.lock();
+        let key = (actor.npub, poll.id);
+        let vote = merge_vote(
+            votes.get(&key),
+            option,
+            is_multiple_choice(&poll),
+            Timestamp::now(),
+        );
+        votes.put(key, vote.clone());
+        vote
+    };
+    EventBuilder::new(
+        Kind::from(1018),
+        "",
+        event_tag(
+            note.id.clone(),
+            [Tag::event(poll.id)].into_iter().chain(
+                options
+                    .into_iter()
+                    .map(|o| Tag::Generic(TagKind::Custom("response".into()), vec![o])),
+            ),
+        ),
+    )
+    .custom_created_at(created_at)
+    .to_event(&nostr_lib::Keys::new(actor.nsec.clone()))
+    .ok()
+}
+
+/// Options chosen by a voter on a poll and the time of their latest response.
+pub type PollVote = (Vec<String>, Timestamp);
+
+/// Adds `option` to the `previous` vote on a poll. The response is created
+/// after the previous one, so that it is the latest even within a second.
+fn merge_vote(
+    previous: Option<&PollVote>,
+    option: &str,
+    multiple_choice: bool,
+    now: Timestamp,
+) -> PollVote {
+    let Some((options, created_at)) = previous else {
+        return (vec![option.to_string()], now);
+    };
+    let mut options = if multiple_choice {
+        options.clone()
+    } else {
+        Vec::new()
+    };
+    if !options.iter().any(|o| o == option) {
+        options.push(option.to_string());
+    }
+    (options, now.max(*created_at + 1_u64))
+}
+
+fn is_multiple_choice(poll: &Event) -> bool {
+    poll.tags.iter().any(|t| {
+        matches!(t, Tag::Generic(TagKind::Custom(k), v)
+            if k == "polltype" && v.first().map(|a| a.as_str()) == Some("multiplechoice"))
+    })
+}
+
+/// NIP-88 tags of a `Question`, with its options in the order received.
+fn poll_tags(note: &NoteForDe) -> Option<Vec<Tag>> {
+    let (options, poll_type) = match (&note.one_of, &note.any_of) {
+        (Some(o), _) => (o, "singlechoice"),
+        (None, Some(o)) => (o, "multiplechoice"),
+        (None, None) => return None,
+    };
+    let custom = |k: &str, v: Vec<String>| Tag::Generic(TagKind::Custom(k.into()), v);
+    Some(
+        options
+            .iter()
+            .enumerate()
+            .map(|(i, o)| custom("option", vec![i.to_string(), o.name.clone()]))
+            .chain([custom("polltype", vec![poll_type.to_string()])])
+            .chain(
+                note.end_time
+                    .or(note.closed)
+                    .map(|t| custom("endsAt", vec![t.timestamp().to_string()])),
+            )
+            .collect(),
+    )
+}
+
+/// Npub of the follower of `follow_id` if they no longer follow `followee`,
+/// in which case an `Accept` of it must be undone.
+fn unwanted_follow(
+    follow_id: &str,
+    followee: &str,
+    followees: impl FnOnce(&PublicKey) -> Option<Arc<FxHashSet<Arc<String>>>>,
+) -> Option<String> {
+    let rest = follow_id
+        .strip_prefix(HTTPS_DOMAIN)?
+        .strip_prefix("/follow/")?;
+    let (npub, _) = rest.split_once('/')?;
+    let p = PublicKey::from_bech32(npub).ok()?;
+    (!followees(&p).is_some_and(|f| f.iter().any(|a| a.as_str() == followee)))
+        .then(|| npub.to_string())
+}
+
+/// NIP-32 label of the ISO 639-1 language of a note, from its `language` or
+/// else its `contentMap`. Of several languages, the one of `content` is used.
+fn language_tags(note: &NoteForDe) -> Option<[Tag; 2]> {
+    let language = note.language.as_deref().or_else(|| {
+        note.content_map
+            .iter()
+            .find(|(_, c)| **c == note.content)
+            .or_else(|| note.content_map.iter().next())
+            .map(|(l, _)| l.as_str())
+    })?;
+    // `en-US` is labeled `en`
+    let code = language.split(['-', '_']).next()?.to_ascii_lowercase();
+    if code.len() != 2 || !code.bytes().all(|b| b.is_ascii_lowercase()) {
+        return None;
+    }
+    let custom = |k: &str, v: Vec<String>| Tag::Generic(TagKind::Custom(k.into()), v);
+    Some([
+        custom("L", vec!["ISO-639-1".to_string()]),
+        custom("l", vec![code, "ISO-639-1".to_string()]),
+    ])
+}
+
+/// NIP-94 `imeta` tag of an attachment.
+fn imeta_tag(a: &AttachedImage) -> Tag {
+    Tag::custom(
+        TagKind::Custom("imeta".to_string()),
+        [format!("url {}", a.url)]
+            .into_iter()
+            .chain(a.media_type.as_ref().map(|m| format!("m {m}")))
+            .chain(a.blurhash.as_ref().map(|b| format!("blurhash {b}")))
+            .chain(
+                a.name
+                    .as_ref()
+                    .filter(|n| !n.trim().is_empty())
+                    .map(|n| format!("alt {n}")),
+            ),
+    )
+}
+
+/// NIP-94 file metadata event of an audio or video attachment of `note`.
+fn file_metadata(a: &AttachedImage, note: nostr_lib::EventId) -> Option<EventBuilder> {
+    let m = a
+        .media_type
+        .as_ref()
+        .filter(|m| m.starts_with("audio/") || m.starts_with("video/"))?;
+    let alt = a.name.as_deref().filter(|n| !n.trim().is_empty());
+    let custom =
+        |k: &str, v: &str| Tag::Generic(TagKind::Custom(k.to_string()), vec![v.to_string()]);
+    let tags = [custom("url", &a.url), custom("m", m), Tag::event(note)]
+        .into_iter()
+        .chain(alt.map(|n| custom("alt", n)))
+        .collect_vec();
+    Some(EventBuilder::new(
+        Kind::FileMetadata,
+        alt.unwrap_or_default(),
+        tags,
+    ))
+}
+
+/// Id of the option labeled `name` if `poll` is a NIP-88 poll.
+fn poll_option<'a>(poll: &'a Event, name: &str) -> Option<&'a str> {
+    if poll.kind != Kind::from(1068) {
+        return None;
+    }
+    poll.tags.iter().find_map(|t| match t {
+        Tag::Generic(TagKind::Custom(k), v)
+            if k == "option" && v.get(1).map(|a| a.as_str()) == Some(name) =>
+        {
+            v.first().map(|a| a.as_str())
+        }
+        _ => None,
+    })
+}
+
+/// Whether a note claims to come from this server, in which case bridging it
+/// would loop our own content back to Nostr.
+fn is_from_this_server(note: &NoteForDe, actor_id: &str) -> bool {
+    note.url.proxied_from.is_some()
+        || [
+            note.id.as_str(),
+            note.attributed_to.author.as_str(),
+            actor_id,
+        ]
+        .into_iter()
+        .any(|id| {
+            id.starts_with(NOTE_ID_PREFIX)
+                || id.starts_with(USER_ID_PREFIX)
+                || url::Url::parse(id).is_ok_and(|u| u.domain() == Some(DOMAIN))
+        })
+}
+
+/// Whether a note delivered by `Create` is older than `max_age`. Notes fetched
+/// as parents of replies are not subject to this.
+fn is_too_old(published: DateTime<Utc>, now: Timestamp, max_age: Option<u64>) -> bool {
+    max_age.is_some_and(|max_age| published.timestamp() + (max_age as i64) < now.as_u64() as i64)
+}
+
+/// Kind, content and tags of a repost of `event`, either as a NIP-18 repost
+/// or as a quote without comment for clients which don't show reposts well.
+fn repost(event: &Event, relay_url: url::Url, as_quote: bool) -> (Kind, String, Vec<Tag>) {
+    if as_quote {
+        (
+            Kind::TextNote,
+            format!("nostr:{}", event.id.to_bech32().unwrap()),
+            vec![
+                Tag::Generic(
+                    TagKind::Custom("q".to_string()),
+                    vec![event.id.to_string(), relay_url.to_string()],
+                ),
+                Tag::public_key(event.pubkey),
+            ],
+        )
+    } else {
+        (
+            Kind::Repost,
+            String::new(),
+            vec![
+                Tag::Event {
+                    event_id: event.id,
+                    relay_url: Some(relay_url.into()),
+                    marker: None,
+                },
+                Tag::public_key(event.pubkey),
+            ],
+        )
+    }
+}
+
+/// `created_at` of a repost. Some servers omit `published` of `Announce` or
+/// send a bogus one, which would sort the repost far in the past.
+fn repost_created_at(published: Option<DateTime<Utc>>, now: Timestamp, max_age: u64) -> Timestamp {
+    match published {
+        Some(p)
+            if p.timestamp() > 0
+                && (p.timestamp() as u64).saturating_add(max_age) >= now.as_u64() =>
+        {
+            Timestamp::from(p.timestamp() as u64)
+        }
+        _ => now,
+    }
+}
+
+/// Content and `emoji` tag of the kind 7 event for a `Like` or `EmojiReact`.
+/// Custom emojis are matched with `tag` with or without the colons.
+fn reaction<'a>(
+    content: Option<Cow<'a, str>>,
+    tag: &[NoteTagForDe],
+) -> (Cow<'a, str>, Option<Tag>) {
+    let Some(content) = content else {
+        return (Cow::Borrowed("+"), None);
+    };
+    let shortcode = content.trim_matches(':');
+    let emoji = tag.iter().find_map(|t| match t {
+        NoteTagForDe::Emoji { name, icon } if name.trim_matches(':') == shortcode => {
+            emoji_tag(name, &icon.url)
+        }
+        _ => None,
+    });
+    if let Some(emoji) = emoji {
+        let content = Cow::Owned(format!(":{shortcode}:"));
+        (content, Some(emoji))
+    } else if *NORMALIZE_REACTIONS {
+        (Cow::Owned(normalize_reaction(&content)), None)
+    } else {
+        (content, None)
+    }
+}
+
+/// A NIP-30 `emoji` tag for the custom emoji `name`, with or without the
+/// colons. `None` if the shortcode isn't alphanumeric with underscores or
+/// the image isn't an http(s) URL, such as a `data:` URI.
+fn emoji_tag(name: &str, url: &str) -> Option<Tag> {
+    let shortcode = name.trim_matches(':');
+    let valid_shortcode = !shortcode.is_empty()
+        && shortcode
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+    let valid_url = url::Url::parse(url).is_ok_and(|u| matches!(u.scheme(), "http" | "https"));
+    (valid_shortcode && valid_url).then(|| Tag::Emoji {
+        shortcode: shortcode.to_string(),
+        url: url.into(),
+    })
+}
+
+/// Normalizes an emoji reaction to NFC with a variation selector-16 after
+/// characters which are rendered as text without it, so that `❤` and `❤️`
+/// become the same reaction.
+fn normalize_reaction(content: &str) -> String {
+    let mut s = String::with_capacity(content.len() + 3);
+    let mut chars = content
+        .nfc()
+        .filter(|c| !matches!(c, '\u{FE0E}' | '\u{FE0F}'))
+        .peekable();
+    while let Some(c) = chars.next() {
+        s.push(c);
+        let text_default = is_text_default_emoji(c)
+            || (matches!(c, '0'..='9' | '#' | '*') && chars.peek() == Some(&'\u{20E3}'));
+        if text_default {
+            s.push('\u{FE0F}');
+        }
+    }
+    s
+}
+
+/// Characters with `Emoji=Yes` and `Emoji_Presentation=No` in emoji-data.txt,
+/// other than the keycap bases.
+fn is_text_default_emoji(c: char) -> bool {
+    matches!(
+        c as u32,
+        0xA9 | 0xAE
+            | 0x203C | 0x2049 | 0x2122 | 0x2139 | 0x2194..=0x2199 | 0x21A9..=0x21AA
+            | 0x2328 | 0x23CF | 0x23ED..=0x23EF | 0x23F1..=0x23F2 | 0x23F8..=0x23FA
+            | 0x24C2 | 0x25AA..=0x25AB | 0x25B6 | 0x25C0 | 0x25FB..=0x25FC
+            | 0x2600..=0x2604 | 0x260E | 0x2611 | 0x2618 | 0x261D | 0x2620 | 0x2622..=0x2623
+            | 0x2626 | 0x262A | 0x262E..=0x262F | 0x2638..=0x263A | 0x2640 | 0x2642
+            | 0x265F..=0x2660 | 0x2663 | 0x2665..=0x2666 | 0x2668 | 0x267B | 0x267E
+            | 0x2692 | 0x2694..=0x2697 | 0x2699 | 0x269B..=0x269C | 0x26A0 | 0x26A7
+            | 0x26B0..=0x26B1 | 0x26C8 | 0x26CF | 0x26D1 | 0x26D3 | 0x26E9 | 0x26F0..=0x26F1
+            | 0x26F4 | 0x26F7..=0x26F9 | 0x2702 | 0x2708..=0x2709 | 0x270C..=0x270D | 0x270F
+            | 0x2712 | 0x2714 | 0x2716 | 0x271D | 0x2721 | 0x2733..=0x2734 | 0x2744 | 0x2747
+            | 0x2763..=0x2764 | 0x27A1 | 0x2934..=0x2935 | 0x2B05..=0x2B07
+            | 0x3030 | 0x303D | 0x3297 | 0x3299
+            | 0x1F170..=0x1F171 | 0x1F17E..=0x1F17F | 0x1F202 | 0x1F237
+            | 0x1F321 | 0x1F324..=0x1F32C | 0x1F336 | 0x1F37D | 0x1F396..=0x1F397
+            | 0x1F399..=0x1F39B | 0x1F39E..=0x1F39F | 0x1F3CB..=0x1F3CE | 0x1F3D4..=0x1F3DF
+            | 0x1F3F3 | 0x1F3F5 | 0x1F3F7 | 0x1F43F | 0x1F441 | 0x1F4FD | 0x1F549..=0x1F54A
+            | 0x1F56F..=0x1F570 | 0x1F573..=0x1F579 | 0x1F587 | 0x1F58A..=0x1F58D | 0x1F590
+            | 0x1F5A5 | 0x1F5A8 | 0x1F5B1..=0x1F5B2 | 0x1F5BC | 0x1F5C2..=0x1F5C4
+            | 0x1F5D1..=0x1F5D3 | 0x1F5DC..=0x1F5DE | 0x1F5E1 | 0x1F5E3 | 0x1F5E8 | 0x1F5EF
+            | 0x1F5F3 | 0x1F5FA | 0x1F6CB | 0x1F6CD..=0x1F6CF | 0x1F6E0..=0x1F6E5 | 0x1F6E9
+            | 0x1F6F0 | 0x1F6F3
+    )
 }
 
 fn get_npub_from_actor_id(id: &str) -> Option<PublicKey> {
@@ -446,9 +1637,32 @@ fn get_npub_from_actor_id(id: &str) -> Option<PublicKey> {
         .and_then(|npub| PublicKey::from_bech32(npub).ok())
 }
 
+/// Our `l` tag value for an AP id. Whitespace and control characters are
+/// percent-encoded so that the label stays a single well-formed token.
+fn ap_id_label(id: &str) -> String {
+    let mut l = format!("{}.activitypub:", *REVERSE_DNS);
+    for c in id.trim().chars() {
+        if c.is_whitespace() || c.is_control() {
+            let mut b = [0; 4];
+            for b in c.encode_utf8(&mut b).bytes() {
+                write!(l, "%{b:02X}").unwrap();
+            }
+        } else {
+            l.push(c);
+        }
+    }
+    l
+}
+
 pub fn event_tag(id: String, tags: impl IntoIterator<Item = Tag>) -> Vec<Tag> {
-    let id_for_l = format!("{}.activitypub:{id}", *REVERSE_DNS);
+    let id_for_l = ap_id_label(&id);
+    let mut labels = HashSet::new();
     tags.into_iter()
+        .filter(|t| match t {
+            Tag::LabelNamespace(n) => n != &*REVERSE_DNS && labels.insert(t.clone()),
+            Tag::Label(l) => l.get(1) != Some(&*REVERSE_DNS) && labels.insert(t.clone()),
+            _ => true,
+        })
         .chain([
             Tag::Proxy {
                 id,
@@ -460,24 +1674,26 @@ pub fn event_tag(id: String, tags: impl IntoIterator<Item = Tag>) -> Vec<Tag> {
         .collect()
 }
 
-async fn backup_nostr_accounts(
-    nostr_accounts: &Mutex<FxHashMap<nostr_lib::PublicKey, Arc<HashSet<String>>>>,
-) {
-    let s = { serde_json::to_vec(&*nostr_accounts.lock()).unwrap() };
-    tokio::fs::File::create("nostr_accounts.json")
+async fn backup_nostr_accounts(state: &AppState) {
+    let s = { serde_json::to_vec(&*state.nostr_account_to_followers.lock()).unwrap() };
+    if let Err(e) = state
+        .write_accounts(
+            std::path::Path::new(crate::snapshot::NOSTR_ACCOUNTS_PATH),
+            &s,
+        )
         .await
-        .unwrap()
-        .write_all(&s)
-        .await
-        .unwrap()
+    {
+        error!("could not write the Nostr accounts: {e}");
+    }
 }
 
 #[tracing::instrument(skip_all)]
 #[async_recursion::async_recursion]
 async fn get_event_from_object_id<'a>(
-    state: &'a AppState,
+    state: &'a Arc<AppState>,
     url: String,
     mut visited: Cow<'a, [String]>,
+    budget: &'a FetchBudget,
 ) -> Result<EventWithRelayId<RelayId>, NostrConversionError> {
     if let Some(event_id) = url.strip_prefix(NOTE_ID_PREFIX) {
         let event_id = nostr_lib::EventId::from_bech32(event_id)
@@ -487,10 +1703,14 @@ async fn get_event_from_object_id<'a>(
             .await
             .ok_or(NostrConversionError::CouldNotGetEventFromNostr);
     }
+    if !url_federates(&url) {
+        info!("refused {url} by the federation policy");
+        return Err(NostrConversionError::Blocked);
+    }
     if visited.contains(&url) {
         return Err(NostrConversionError::CyclicRefernce);
     }
-    if visited.len() > 100 {
+    if visited.len() > *MAX_THREAD_DEPTH {
         return Err(NostrConversionError::TooLongThread);
     }
     if let Some(e) = state
@@ -508,6 +1728,9 @@ async fn get_event_from_object_id<'a>(
             return Ok(event);
         }
     }
+    if !budget.take() {
+        return Err(NostrConversionError::TooLongThread);
+    }
     let note: NoteForDe = state
         .get_activity_json_with_retry(&url.parse::<uri::Uri>().unwrap())
         .await
@@ -521,14 +1744,14 @@ async fn get_event_from_object_id<'a>(
             .ok_or(NostrConversionError::CouldNotGetEventFromNostr);
     }
     let ActorOrProxied::Actor(actor) = state
-        .get_actor_data(&note.attributed_to)
+        .get_actor_data(&note.attributed_to.author)
         .await
         .map_err(|_| NostrConversionError::CouldNotGetObjectFromAp)?
     else {
         return Err(NostrConversionError::IsProxied);
     };
     visited.to_mut().push(url);
-    get_event_from_note(state, note, actor, visited)
+    get_event_from_note(state, note, actor, visited, budget, None)
         .await
         .map(|event| EventWithRelayId {
             event,
@@ -536,6 +1759,113 @@ async fn get_event_from_object_id<'a>(
         })
 }
 
+/// Remote objects which may still be fetched to convert a note, its parents and
+/// its quotes, so that a note with many references can't fan out into
+/// unbounded fetches. See `MAX_THREAD_FETCHES`.
+#[derive(Debug)]
+struct FetchBudget(AtomicUsize);
+
+impl Default for FetchBudget {
+    fn default() -> Self {
+        Self(AtomicUsize::new(*MAX_THREAD_FETCHES))
+    }
+}
+
+impl FetchBudget {
+    fn take(&self) -> bool {
+        self.0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+    }
+}
+
+/// Nostr users of this server in `to` and `cc` of a note.
+fn addressed_npubs(note: &NoteForDe) -> impl Iterator<Item = PublicKey> + '_ {
+    note.to
+        .iter()
+        .chain(note.cc.iter())
+        .filter_map(|a| get_npub_from_actor_id(a))
+}
+
+/// The first `cap` distinct of `ps`, which are ordered by priority: the
+/// author of the parent, mentions, then addressed accounts and the `p` tags of
+/// the parent. Also returns the number of dropped ones.
+fn capped_p_tags(ps: impl IntoIterator<Item = PublicKey>, cap: usize) -> (Vec<PublicKey>, usize) {
+    let mut seen = FxHashSet::default();
+    let (kept, dropped): (Vec<_>, Vec<_>) = ps
+        .into_iter()
+        .filter(|p| seen.insert(*p))
+        .enumerate()
+        .partition(|(i, _)| *i < cap);
+    (kept.into_iter().map(|(_, p)| p).collect(), dropped.len())
+}
+
+/// Nostr accounts of the co-authors of a note.
+fn co_author_npubs(note: &NoteForDe) -> impl Iterator<Item = PublicKey> + '_ {
+    note.attributed_to.co_authors.iter().filter_map(|id| {
+        get_npub_from_actor_id(id).or_else(|| {
+            actor_nsec(id)
+                .ok()
+                .map(|nsec| nostr_lib::Keys::new(nsec).public_key())
+        })
+    })
+}
+
+/// Bridged accounts of the groups a note was posted to, such as a Lemmy
+/// community, unless the group is its author.
+fn group_npubs(note: &NoteForDe) -> impl Iterator<Item = PublicKey> + '_ {
+    note.attributed_to
+        .groups
+        .iter()
+        .chain(&note.audience)
+        .filter(|id| **id != note.attributed_to.author)
+        .unique()
+        .filter_map(|id| {
+            actor_nsec(id)
+                .ok()
+                .map(|nsec| nostr_lib::Keys::new(nsec).public_key())
+        })
+}
+
+/// Root of the thread of `parent` per NIP-10: its `root` tag, or the first
+/// of its `e` tags if none of them is marked, as older Nostr clients do.
+/// `None` if `parent` is the root itself.
+fn thread_root(parent: &Event) -> Option<nostr_lib::EventId> {
+    let mut positional = None;
+    for t in &parent.tags {
+        match t {
+            Tag::Event {
+                event_id,
+                marker: Some(Marker::Root),
+                ..
+            } => return Some(*event_id),
+            Tag::Event {
+                marker: Some(_), ..
+            } => positional = Some(None),
+            Tag::Event {
+                event_id,
+                marker: None,
+                ..
+            } => {
+                positional.get_or_insert(Some(*event_id));
+            }
+            _ => (),
+        }
+    }
+    positional.flatten().filter(|r| *r != parent.id)
+}
+
+/// The `e` tag of a reply to a note of this server whose event we couldn't
+/// find. The parent's author and root are unknown, so it is marked as root.
+fn fallback_reply_tag(in_reply_to: &str) -> Option<Tag> {
+    let event_id = in_reply_to.strip_prefix(NOTE_ID_PREFIX)?;
+    Some(Tag::Event {
+        event_id: nostr_lib::EventId::from_bech32(event_id).ok()?,
+        relay_url: None,
+        marker: Some(Marker::Root),
+    })
+}
+
 async fn get_npub_of_actor(state: &AppState, id: &str) -> Result<PublicKey, NostrConversionError> {
     match state
         .get_actor_data(id)
@@ -576,16 +1906,28 @@ enum NostrConversionError {
     CouldNotGetObjectFromAp,
     InvalidEventId,
     InvalidActorId,
+    InvalidSigningKey,
     TooLongThread,
+    TooLarge,
+    Blocked,
 }
 
 #[tracing::instrument(skip_all)]
 async fn get_event_from_note<'a>(
-    state: &AppState,
+    state: &Arc<AppState>,
     note: NoteForDe,
     actor: Arc<Actor>,
     visited: Cow<'_, [String]>,
+    budget: &FetchBudget,
+    edit_of: Option<nostr_lib::EventId>,
 ) -> Result<Arc<Event>, NostrConversionError> {
+    // Notes by blocked hosts may still be embedded in activities of others.
+    if !url_federates(&note.attributed_to.author) {
+        info!("refused {} of {}", note.id, note.attributed_to.author);
+        return Err(NostrConversionError::Blocked);
+    }
+    let poll_tags = poll_tags(&note);
+    let language_tags = language_tags(&note);
     let is_private_note = !note.to.iter().chain(note.cc.iter()).any(|a| {
         [
             "https://www.w3.org/ns/activitystreams#Public",
@@ -594,39 +1936,64 @@ async fn get_event_from_note<'a>(
         ]
         .contains(&a.as_str())
     });
+    let software = match url::Url::parse(&note.id) {
+        Ok(u) => match u.host_str() {
+            Some(host) => state.get_software(host),
+            None => None,
+        },
+        Err(_) => None,
+    };
+    let markdown = misskey_markdown(&note, software.as_ref()).map(str::to_string);
     let mut tags = FxHashSet::default();
-    if let Some(r) = note.summary {
-        if !r.is_empty() {
-            tags.insert(Tag::ContentWarning { reason: Some(r) });
-        }
-    } else if note.sensitive.unwrap_or(false) {
-        tags.insert(Tag::ContentWarning { reason: None });
+    // `p` tags by priority, see `capped_p_tags`
+    let mut reply_to = None;
+    let mut mentioned = Vec::new();
+    let mut addressed = addressed_npubs(&note).collect_vec();
+    if *MENTION_CO_AUTHORS {
+        mentioned.extend(co_author_npubs(&note));
     }
+    mentioned.extend(group_npubs(&note));
+    tags.extend(content_warning(
+        note.summary,
+        note.sensitive.unwrap_or(false),
+        *SENSITIVE_WARNING,
+    ));
     let is_reply = note.in_reply_to.is_some();
-    if let Some(r) = note.in_reply_to {
-        let e = get_event_from_object_id(state, r, Cow::Borrowed(visited.borrow())).await?;
-        let mut root = None;
+    let parent = if let Some(r) = note.in_reply_to {
+        match get_event_from_object_id(state, r.clone(), Cow::Borrowed(visited.borrow()), budget)
+            .await
+        {
+            Ok(e) => Some(e),
+            Err(NostrConversionError::TooLongThread) => {
+                info!("thread limit reached at {r}; bridging without its parent");
+                tags.extend(fallback_reply_tag(&r));
+                None
+            }
+            Err(NostrConversionError::CouldNotGetEventFromNostr) => {
+                let tag = fallback_reply_tag(&r)
+                    .ok_or(NostrConversionError::CouldNotGetEventFromNostr)?;
+                info!("could not get {r}; replying without its tags");
+                tags.insert(tag);
+                None
+            }
+            Err(e) => return Err(e),
+        }
+    } else {
+        None
+    };
+    if let Some(e) = parent {
         for t in &e.event.tags {
-            match t {
-                Tag::PublicKey {
-                    public_key,
-                    uppercase: false,
-                    ..
-                } => {
-                    tags.insert(Tag::public_key(*public_key));
-                }
-                Tag::Event {
-                    event_id,
-                    relay_url: _,
-                    marker: Some(Marker::Root),
-                } => {
-                    root = Some(*event_id);
-                }
-                _ => (),
+            if let Tag::PublicKey {
+                public_key,
+                uppercase: false,
+                ..
+            } = t
+            {
+                addressed.push(*public_key);
             }
         }
-        tags.insert(Tag::public_key(e.event.pubkey));
-        if let Some(root) = root {
+        reply_to = Some(e.event.pubkey);
+        if let Some(root) = thread_root(&e.event) {
             tags.insert(Tag::Event {
                 event_id: root,
                 relay_url: None,
@@ -649,16 +2016,13 @@ async fn get_event_from_note<'a>(
         match t {
             NoteTagForDe::Mention { href, name: _ } => {
                 if let Ok(npub) = get_npub_of_actor(state, href).await {
-                    tags.insert(Tag::public_key(npub));
+                    mentioned.push(npub);
                 } else {
                     error!("could not get npub of actor = {href}");
                 }
             }
             NoteTagForDe::Emoji { name, icon } => {
-                tags.insert(Tag::Emoji {
-                    shortcode: name.trim_matches(':').to_string(),
-                    url: icon.url.clone().into(),
-                });
+                tags.extend(emoji_tag(name, &icon.url));
             }
             NoteTagForDe::Hashtag { name } => {
                 tags.insert(Tag::Hashtag(
@@ -669,9 +2033,9 @@ async fn get_event_from_note<'a>(
         }
     }
     let content_tmp: String;
-    let content = match &note.source {
-        Some(source) if source.media_type == "text/x.misskeymarkdown" => Cow::from(&source.content),
-        _ => {
+    let content = match markdown {
+        Some(markdown) => Cow::from(markdown),
+        None => {
             content_tmp = html_to_text(&note.content);
             HASHTAG_LINK_REGEX.replace_all(&content_tmp, "$tag")
         }
@@ -691,7 +2055,7 @@ async fn get_event_from_note<'a>(
         let content = content.as_ref();
         for caps in MENTION_REGEX.captures_iter(content) {
             let m = caps.get(0).unwrap();
-            let npub = if caps.name("domain").map_or(false, |d| d.as_str() == DOMAIN) {
+            let npub = if caps.name("domain").is_some_and(|d| d.as_str() == DOMAIN) {
                 PublicKey::from_bech32(caps.name("username").unwrap().as_str()).ok()
             } else if let Ok(a) = state
                 .get_actor_data(caps.name("url").unwrap().as_str().trim_end())
@@ -735,17 +2099,17 @@ async fn get_event_from_note<'a>(
         }
         for a in &note.attachment {
             writeln!(&mut content, "{}", a.url).unwrap();
-            tags.insert(Tag::custom(
-                TagKind::Custom("imeta".to_string()),
-                [format!("url {}", a.url)]
-                    .into_iter()
-                    .chain(a.media_type.as_ref().map(|m| format!("m {m}"))),
-            ));
+            tags.insert(imeta_tag(a));
         }
         Cow::Owned(content)
     };
-    if let Some(url) = note.quote_url.or(note.misskey_quote) {
-        if let Ok(e) = get_event_from_object_id(state, url.clone(), visited).await {
+    if let Some(url) = note
+        .quote
+        .map(|q| q.id().to_string())
+        .or(note.quote_url)
+        .or(note.misskey_quote)
+    {
+        if let Ok(e) = get_event_from_object_id(state, url.clone(), visited, budget).await {
             tags.insert(Tag::Generic(
                 TagKind::Custom("q".to_string()),
                 vec![e.event.id.to_string()],
@@ -769,12 +2133,22 @@ async fn get_event_from_note<'a>(
             error!("could not get event id from {url}");
         }
     }
+    let original = note.url.url.clone().unwrap_or_else(|| note.id.clone());
     if let Some(url) = note.url.url {
         tags.insert(Tag::Proxy {
             id: url,
             protocol: nostr_lib::nips::nip48::Protocol::Web,
         });
     }
+    tags.extend(language_tags.into_iter().flatten());
+    let (ps, dropped) = capped_p_tags(
+        reply_to.into_iter().chain(mentioned).chain(addressed),
+        *MAX_P_TAGS,
+    );
+    if dropped > 0 {
+        info!("dropped {dropped} p tags of {} over the limit", note.id);
+    }
+    tags.extend(ps.into_iter().map(Tag::public_key));
     if is_private_note {
         info!("skipped private note as it's not supported");
         return Err(NostrConversionError::IsPrivate);
@@ -797,28 +2171,85 @@ async fn get_event_from_note<'a>(
         }
         return Err(NostrConversionError::OptOutedAccount);
     }
-    let event = EventBuilder::new(
-        nostr_lib::Kind::TextNote,
-        content,
+    let (content, tags) = fit_to_size(
+        sanitize_unicode(&content, *SANITIZE_UNICODE).into_owned(),
         event_tag(note.id.clone(), tags),
+        &original,
+        *MAX_EVENT_SIZE,
     )
-    .custom_created_at(Timestamp::from(note.published.timestamp() as u64))
-    .to_event(&nostr_lib::Keys::new(actor.nsec.clone()))
-    .unwrap();
+    .ok_or_else(|| {
+        error!("{} is too large to bridge", note.id);
+        NostrConversionError::TooLarge
+    })?;
+    let (kind, tags) = match poll_tags {
+        Some(poll_tags) => (
+            Kind::from(1068),
+            tags.into_iter().chain(poll_tags).collect(),
+        ),
+        None => (Kind::TextNote, tags),
+    };
+    let (tags, created_at) = match edit_of {
+        Some(original) => (
+            tags.into_iter().chain([edit_tag(original)]).collect(),
+            note.updated.unwrap_or_else(Utc::now),
+        ),
+        None => (tags, note.published),
+    };
+    let event = EventBuilder::new(kind, content, tags)
+        .custom_created_at(Timestamp::from(created_at.timestamp() as u64))
+        .to_event(&nostr_lib::Keys::new(actor.nsec.clone()))
+        .map_err(|e| {
+            error!("could not sign a note of {}: {e}", actor.id);
+            NostrConversionError::InvalidSigningKey
+        })?;
     let event = Arc::new(event);
     let ap_id = InternalApId::get(note.id.into(), &actor.id)
         .map_err(|_| NostrConversionError::InvalidActorId)?
         .into_owned();
     send_event(state, event.clone(), ap_id).await;
+    if edit_of.is_some() {
+        return Ok(event);
+    }
+    if *BRIDGE_MEDIA_FILE_EVENTS {
+        for a in &note.attachment {
+            if let Some(e) = file_metadata(a, event.id) {
+                let e = e
+                    .custom_created_at(event.created_at)
+                    .to_event(&nostr_lib::Keys::new(actor.nsec.clone()))
+                    .unwrap();
+                state.nostr_send(Arc::new(e)).await;
+            }
+        }
+    }
     Ok(event)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::HEAD_MENTIONS_REGEX;
+    use super::{
+        addressed_npubs, capped_p_tags, check_also_known_as, contact_list_tags, content_warning,
+        create_ap_id, edit_actions, edit_tag, emoji_tag, event_tag, event_to_delete,
+        fallback_reply_tag, file_metadata, fit_to_size, group_npubs, imeta_tag, inbox_permit,
+        is_from_this_server, is_too_old, is_vote, language_tags, merge_vote, migrate_followers,
+        normalize_reaction, pinned_notes, poll_option, poll_tags, queued_request, reaction,
+        remove_follower, report_message, repost, repost_created_at, thread_root, unwanted_follow,
+        verify_digest, EditStrategy, HEAD_MENTIONS_REGEX,
+    };
+    use crate::activity::{
+        ActivityForDe, ActivityForDeInner, ActorOrProxied, AttachedImage, NoteForDe, UpdateObject,
+    };
     use crate::server::inbox::HASHTAG_LINK_REGEX;
+    use crate::{CONTACT_LIST_LEN_LIMIT, NOTE_ID_PREFIX, REVERSE_DNS, USER_ID_PREFIX};
     use chrono::{DateTime, Utc};
-    use nostr_lib::{EventBuilder, FromBech32, SecretKey, Timestamp, ToBech32};
+    use itertools::Itertools;
+    use nostr_lib::{
+        EventBuilder, EventId, FromBech32, JsonUtil, Keys, Kind, Marker, SecretKey, Tag, TagKind,
+        Timestamp, ToBech32,
+    };
+    use rustc_hash::{FxHashMap, FxHashSet};
+    use std::borrow::Cow;
+    use std::collections::HashSet;
+    use std::sync::Arc;
 
     #[test]
     fn deterministic_event_id() {
@@ -843,6 +2274,132 @@ mod tests {
         );
     }
 
+    #[test]
+    fn delete_from_unreachable_actor() {
+        let event_id = EventId::all_zeros();
+        let edit = EventBuilder::text_note("edited", [])
+            .to_event(&Keys::generate())
+            .unwrap()
+            .id;
+        let known = |id: &super::InternalApId<'static>| {
+            if id.as_bytes() == b"https://example.com/notes/1" {
+                vec![event_id, edit]
+            } else {
+                Vec::new()
+            }
+        };
+        let actor = "https://example.com/users/a";
+        assert_eq!(
+            event_to_delete(actor, "https://example.com/notes/1", known),
+            vec![event_id, edit]
+        );
+        assert!(event_to_delete(actor, "https://example.com/notes/2", known).is_empty());
+        assert!(event_to_delete(
+            "https://other.example.com/users/a",
+            "https://example.com/notes/1",
+            known
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn digest() {
+        let body = br#"{"type":"Follow"}"#;
+        let digest = "SHA-256=GYwYnH3BiO6aICFt0ThC5bUIJ4byvqdpWtR8m5fNkww=";
+        let headers = |h: &[(&'static str, &'static str)]| {
+            h.iter()
+                .map(|(k, v)| (k.parse().unwrap(), v.parse().unwrap()))
+                .collect::<axum::http::HeaderMap>()
+        };
+        let signature = r#"keyId="https://example.com/users/a#main-key",algorithm="rsa-sha256",headers="(request-target) host date digest",signature="..."#;
+        assert!(verify_digest(
+            &headers(&[("digest", digest), ("signature", signature)]),
+            body
+        )
+        .is_ok());
+        assert!(matches!(
+            verify_digest(&headers(&[("digest", digest)]), br#"{"type":"Undo"}"#),
+            Err(crate::error::Error::BadRequest(_))
+        ));
+        assert!(matches!(
+            verify_digest(&headers(&[("signature", signature)]), body),
+            Err(crate::error::Error::BadRequest(_))
+        ));
+        let unsigned = r#"keyId="https://example.com/users/a#main-key",headers="(request-target) host date",signature="..."#;
+        assert!(verify_digest(&headers(&[("signature", unsigned)]), body).is_ok());
+    }
+
+    #[test]
+    fn fetch_budget() {
+        let b = super::FetchBudget(std::sync::atomic::AtomicUsize::new(2));
+        assert!(b.take());
+        assert!(b.take());
+        assert!(!b.take());
+        assert!(!b.take());
+    }
+
+    #[test]
+    fn queued_activity_request() {
+        let a = crate::db::QueuedActivity {
+            method: "POST".to_string(),
+            uri: "/inbox".to_string(),
+            headers: vec![
+                ("host".to_string(), b"momostr.pink".to_vec()),
+                ("signature".to_string(), b"keyId=\"a\"".to_vec()),
+            ],
+            body: b"{}".to_vec(),
+            attempts: 0,
+        };
+        let r = queued_request(&a).unwrap();
+        assert_eq!(r.method(), "POST");
+        assert_eq!(r.uri(), "/inbox");
+        assert_eq!(r.headers()["host"], "momostr.pink");
+        assert_eq!(r.headers()["signature"], "keyId=\"a\"");
+    }
+
+    #[test]
+    fn sensitive_without_summary() {
+        let cw = |reason: Option<&str>| {
+            Some(Tag::ContentWarning {
+                reason: reason.map(str::to_string),
+            })
+        };
+        assert_eq!(content_warning(None, true, Some("NSFW")), cw(Some("NSFW")));
+        assert_eq!(content_warning(None, true, None), cw(None));
+        assert_eq!(
+            content_warning(Some("spoilers".to_string()), true, Some("NSFW")),
+            cw(Some("spoilers"))
+        );
+        assert_eq!(content_warning(None, false, Some("NSFW")), None);
+    }
+
+    #[test]
+    fn delete_of_like() {
+        let a = r#"{"type":"Delete","id":"https://example.com/likes/1#delete","actor":"https://example.com/users/a","object":"https://example.com/likes/1"}"#;
+        let a: ActivityForDe = serde_json::from_str(a).unwrap();
+        let ActivityForDeInner::Delete(d) = &*a.activity_inner else {
+            panic!()
+        };
+        let object_id = d.object_id(&a.actor).unwrap();
+        // The ap id the Like was recorded under when its Kind 7 was bridged.
+        let reaction = EventId::all_zeros();
+        let known = |id: &super::InternalApId<'static>| {
+            if id.as_bytes() == b"https://example.com/likes/1" {
+                vec![reaction]
+            } else {
+                Vec::new()
+            }
+        };
+        assert_eq!(event_to_delete(&a.actor, object_id, known), vec![reaction]);
+        assert!(event_to_delete("https://other.example.com/users/a", object_id, known).is_empty());
+        let a = r#"{"type":"Delete","id":"https://example.com/users/a#delete","actor":"https://example.com/users/a","object":"https://example.com/users/a"}"#;
+        let a: ActivityForDe = serde_json::from_str(a).unwrap();
+        let ActivityForDeInner::Delete(d) = &*a.activity_inner else {
+            panic!()
+        };
+        assert_eq!(d.object_id(&a.actor), None);
+    }
+
     #[test]
     fn remove_mention_1() {
         let s = "[@momo_test](https://example.com/@momo_test ) test🍉";
@@ -873,4 +2430,788 @@ mod tests {
         let s = HASHTAG_LINK_REGEX.replace_all(s, "$tag");
         debug_assert_eq!(s, "🍉 #example 🍉");
     }
+
+    #[test]
+    fn event_tag_label_1() {
+        let tags = event_tag(
+            "https://example.com/notes/a:b c\n".to_string(),
+            [
+                Tag::LabelNamespace(REVERSE_DNS.to_string()),
+                Tag::Label(vec!["x".to_string(), REVERSE_DNS.to_string()]),
+                Tag::LabelNamespace("other".to_string()),
+                Tag::LabelNamespace("other".to_string()),
+            ],
+        );
+        let labels = tags
+            .iter()
+            .filter(|t| matches!(t, Tag::Label(_) | Tag::LabelNamespace(_)))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            vec![
+                Tag::LabelNamespace("other".to_string()),
+                Tag::LabelNamespace(REVERSE_DNS.to_string()),
+                Tag::Label(vec![
+                    format!(
+                        "{}.activitypub:https://example.com/notes/a:b%20c",
+                        *REVERSE_DNS
+                    ),
+                    REVERSE_DNS.to_string()
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn move_requires_also_known_as() {
+        let target = |also_known_as: serde_json::Value| {
+            let a: ActorOrProxied = serde_json::from_value(serde_json::json!({
+                "type": "Person",
+                "id": "https://new.example.com/users/a",
+                "alsoKnownAs": also_known_as,
+                "publicKey": {"publicKeyPem": "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAs8T30Ro4ga5Fo4ArMUfB\niBXwMtHIThmBZEYBhLFUOXNswDADd1LyIZ0yt2qDlIae646C9RWqXB3qrhr3TpcA\nBDBKc1XxffSAmOzNzoFJ2FdXET97KJ2hXhfILcuMPz3MMBBNbpmgOMb4tKFpiFqH\nYhZIJGeTOUQ8VjWaiH8szixKBByVbgZOWisD9Zf39nCSQ3JJ2LvrzUIhfmocfidL\nekUtwSSi7gzr/53KpS08jP5fCaHs7S5NsgeOE6KnWpNrM19hxk7CtRJqvEbAw4yG\nxcDdvW/UYqI6hHYVmYRRkYs4NO34ZfM6v/xcFgmsMwEBaNBE0itMCMziPJ9pvyCc\nQwIDAQAB\n-----END PUBLIC KEY-----\n"},
+            }))
+            .unwrap();
+            match a {
+                ActorOrProxied::Actor(a) => a,
+                ActorOrProxied::Proxied(_) => panic!(),
+            }
+        };
+        let old = "https://old.example.com/users/a";
+        assert!(check_also_known_as(old, &target(serde_json::json!([old]))).is_ok());
+        assert!(check_also_known_as(old, &target(serde_json::json!(old))).is_ok());
+        assert!(check_also_known_as(old, &target(serde_json::json!([]))).is_err());
+        assert!(check_also_known_as(old, &target(serde_json::Value::Null)).is_err());
+    }
+
+    #[test]
+    fn oversized_event_is_trimmed() {
+        let keys = Keys::generate();
+        let content = "a\"\n🍉".repeat(10_000);
+        let tags = (0..100)
+            .map(|i| {
+                Tag::custom(
+                    TagKind::Custom("imeta".to_string()),
+                    [format!("url https://example.com/{i}.png")],
+                )
+            })
+            .chain([Tag::Hashtag("tag".to_string())])
+            .collect_vec();
+        let original = "https://example.com/notes/1";
+        let (content, tags) = fit_to_size(content, tags, original, 10_000).unwrap();
+        assert!(content.ends_with(&format!("…\n{original}")));
+        assert_eq!(tags, vec![Tag::Hashtag("tag".to_string())]);
+        let event = EventBuilder::new(Kind::TextNote, content, tags)
+            .to_event(&keys)
+            .unwrap();
+        let size = event.as_json().len();
+        assert!(size <= 10_000 && size > 9_000, "{size}");
+        let (content, _) = fit_to_size("a".to_string(), Vec::new(), original, 10_000).unwrap();
+        assert_eq!(content, "a");
+        assert!(fit_to_size("a".to_string(), Vec::new(), original, 100).is_none());
+    }
+
+    #[test]
+    fn question_to_poll() {
+        let note = |options: serde_json::Value| -> NoteForDe {
+            let mut q = serde_json::json!({
+                "type": "Question",
+                "id": "https://example.com/notes/1",
+                "content": "which?",
+                "published": "2024-03-18T02:24:24Z",
+                "attributedTo": "https://example.com/users/a",
+                "endTime": "2024-03-19T02:24:24Z",
+                "closed": "2024-03-19T02:24:24Z",
+            });
+            q.as_object_mut()
+                .unwrap()
+                .extend(options.as_object().unwrap().clone());
+            serde_json::from_value(q).unwrap()
+        };
+        let options = serde_json::json!([
+            {"type": "Note", "name": "b", "replies": {"type": "Collection", "totalItems": 1}},
+            {"type": "Note", "name": "a", "replies": {"type": "Collection", "totalItems": 0}},
+        ]);
+        let tags = poll_tags(&note(serde_json::json!({ "oneOf": options }))).unwrap();
+        let custom = |k: &str, v: &[&str]| {
+            Tag::Generic(
+                TagKind::Custom(k.into()),
+                v.iter().map(|a| a.to_string()).collect(),
+            )
+        };
+        assert_eq!(
+            tags,
+            vec![
+                custom("option", &["0", "b"]),
+                custom("option", &["1", "a"]),
+                custom("polltype", &["singlechoice"]),
+                custom("endsAt", &["1710815064"]),
+            ]
+        );
+        let tags = poll_tags(&note(serde_json::json!({ "anyOf": options }))).unwrap();
+        assert_eq!(tags[2], custom("polltype", &["multiplechoice"]));
+        assert!(poll_tags(&note(serde_json::json!({}))).is_none());
+    }
+
+    #[test]
+    fn contact_list_tags_1() {
+        let followees = FxHashSet::from_iter([Keys::generate().public_key()]);
+        assert_eq!(contact_list_tags(&followees).unwrap().len(), 1);
+        let many = (0..CONTACT_LIST_LEN_LIMIT)
+            .map(|_| Keys::generate().public_key())
+            .collect();
+        assert_eq!(contact_list_tags(&many), None);
+    }
+
+    #[test]
+    fn migrate_followers_1() {
+        let (old, new, other) = (
+            "https://old.example.com/users/a",
+            "https://new.example.com/users/a",
+            "https://example.com/users/b",
+        );
+        let (p1, p2) = (Keys::generate().public_key(), Keys::generate().public_key());
+        let mut followers = FxHashMap::default();
+        followers.insert(
+            p1,
+            Arc::new(HashSet::from([old.to_string(), other.to_string()])),
+        );
+        followers.insert(p2, Arc::new(HashSet::from([old.to_string()])));
+        let mut followers_rev = FxHashMap::default();
+        followers_rev.insert(old.to_string(), FxHashSet::from_iter([p1, p2]));
+        followers_rev.insert(other.to_string(), FxHashSet::from_iter([p1]));
+
+        let followees = migrate_followers(&mut followers, &mut followers_rev, old, new).cloned();
+        assert_eq!(followees, Some(FxHashSet::from_iter([p1, p2])));
+        assert_eq!(
+            *followers[&p1],
+            HashSet::from([new.to_string(), other.to_string()])
+        );
+        assert_eq!(*followers[&p2], HashSet::from([new.to_string()]));
+        assert!(!followers_rev.contains_key(old));
+        assert_eq!(followers_rev[other], FxHashSet::from_iter([p1]));
+
+        assert!(migrate_followers(&mut followers, &mut followers_rev, old, new).is_none());
+    }
+
+    #[test]
+    fn remove_follower_1() {
+        let (deleted, other) = ("https://example.com/users/a", "https://example.com/users/b");
+        let (p1, p2) = (Keys::generate().public_key(), Keys::generate().public_key());
+        let mut followers = FxHashMap::default();
+        followers.insert(
+            p1,
+            Arc::new(HashSet::from([deleted.to_string(), other.to_string()])),
+        );
+        followers.insert(p2, Arc::new(HashSet::from([deleted.to_string()])));
+        let mut followers_rev = FxHashMap::default();
+        followers_rev.insert(deleted.to_string(), FxHashSet::from_iter([p1, p2]));
+        followers_rev.insert(other.to_string(), FxHashSet::from_iter([p1]));
+
+        assert!(remove_follower(&mut followers, &mut followers_rev, deleted));
+        assert_eq!(*followers[&p1], HashSet::from([other.to_string()]));
+        assert!(!followers.contains_key(&p2));
+        assert!(!followers_rev.contains_key(deleted));
+        assert!(!remove_follower(
+            &mut followers,
+            &mut followers_rev,
+            deleted
+        ));
+    }
+
+    #[test]
+    fn is_from_this_server_1() {
+        let note = |id: &str, attributed_to: &str| -> NoteForDe {
+            serde_json::from_value(serde_json::json!({
+                "id": id,
+                "content": "test",
+                "published": "2024-03-18T02:24:24Z",
+                "attributedTo": attributed_to,
+            }))
+            .unwrap()
+        };
+        let remote = note(
+            "https://example.com/users/a/statuses/1",
+            "https://example.com/users/a",
+        );
+        assert!(!is_from_this_server(&remote, "https://example.com/users/a"));
+        let own_id = note(
+            &format!("{NOTE_ID_PREFIX}note1"),
+            "https://example.com/users/a",
+        );
+        assert!(is_from_this_server(&own_id, "https://example.com/users/a"));
+        let own_actor = note(
+            "https://example.com/users/a/statuses/1",
+            &format!("{USER_ID_PREFIX}npub1"),
+        );
+        assert!(is_from_this_server(
+            &own_actor,
+            "https://example.com/users/a"
+        ));
+        let mut proxied = remote.clone();
+        proxied.url.proxied_from = Some("note1".to_string());
+        assert!(is_from_this_server(&proxied, "https://example.com/users/a"));
+    }
+
+    #[test]
+    fn fallback_reply_tag_1() {
+        let id = "note1hlwtagk67vs4tgvke2f3c0z2azp7q3667c3j550clfu9cg8md3qsvceynx";
+        assert_eq!(
+            fallback_reply_tag(&format!("{NOTE_ID_PREFIX}{id}")),
+            Some(Tag::Event {
+                event_id: EventId::from_bech32(id).unwrap(),
+                relay_url: None,
+                marker: Some(Marker::Root),
+            })
+        );
+        assert_eq!(
+            fallback_reply_tag(&format!("{NOTE_ID_PREFIX}invalid")),
+            None
+        );
+        assert_eq!(
+            fallback_reply_tag(&format!("https://example.com/notes/{id}")),
+            None
+        );
+    }
+
+    #[test]
+    fn thread_root_1() {
+        let keys = Keys::generate();
+        let (root, reply) = (
+            EventBuilder::text_note("root", []).to_event(&keys).unwrap(),
+            EventBuilder::text_note("reply", [])
+                .to_event(&keys)
+                .unwrap(),
+        );
+        let note = |tags: Vec<Tag>| {
+            EventBuilder::text_note("note", tags)
+                .to_event(&keys)
+                .unwrap()
+        };
+        assert_eq!(thread_root(&note(Vec::new())), None);
+        let marked = note(vec![
+            Tag::Event {
+                event_id: reply.id,
+                relay_url: None,
+                marker: Some(Marker::Reply),
+            },
+            Tag::Event {
+                event_id: root.id,
+                relay_url: None,
+                marker: Some(Marker::Root),
+            },
+        ]);
+        assert_eq!(thread_root(&marked), Some(root.id));
+        let positional = note(vec![Tag::event(root.id), Tag::event(reply.id)]);
+        assert_eq!(thread_root(&positional), Some(root.id));
+        let mention = note(vec![Tag::Event {
+            event_id: reply.id,
+            relay_url: None,
+            marker: Some(Marker::Mention),
+        }]);
+        assert_eq!(thread_root(&mention), None);
+    }
+
+    #[test]
+    fn addressed_npubs_1() {
+        let p = Keys::generate().public_key();
+        let npub = p.to_bech32().unwrap();
+        let note: NoteForDe = serde_json::from_value(serde_json::json!({
+            "id": "https://example.com/users/a/statuses/2",
+            "content": "test",
+            "published": "2024-03-18T02:24:24Z",
+            "attributedTo": "https://example.com/users/a",
+            "inReplyTo": "https://example.com/users/a/statuses/1",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "cc": [
+                "https://example.com/users/a/followers",
+                format!("{USER_ID_PREFIX}{npub}"),
+                format!("{USER_ID_PREFIX}{npub}/followers"),
+                "https://example.com/users/b",
+            ],
+        }))
+        .unwrap();
+        assert_eq!(addressed_npubs(&note).collect::<Vec<_>>(), vec![p]);
+    }
+
+    #[test]
+    fn emoji_react() {
+        let a = r#"{"type":"EmojiReact","id":"https://misskey.example.com/likes/1","actor":"https://misskey.example.com/users/a","object":"https://momostr.pink/notes/note1","content":":blobcat:","tag":[{"id":"https://misskey.example.com/emojis/blobcat","type":"Emoji","name":":blobcat:","icon":{"type":"Image","mediaType":"image/png","url":"https://misskey.example.com/files/blobcat.png"}}]}"#;
+        let a: ActivityForDe = serde_json::from_str(a).unwrap();
+        let ActivityForDeInner::EmojiReact { content, tag, .. } = *a.activity_inner else {
+            panic!()
+        };
+        let (content, emoji) = reaction(content, &tag);
+        assert_eq!(content, ":blobcat:");
+        assert_eq!(
+            emoji,
+            Some(Tag::Emoji {
+                shortcode: "blobcat".to_string(),
+                url: "https://misskey.example.com/files/blobcat.png".into(),
+            })
+        );
+        let a = r#"{"type":"EmojiReact","id":"https://pleroma.example.com/activities/1","actor":"https://pleroma.example.com/users/a","object":"https://momostr.pink/notes/note1","content":"👍"}"#;
+        let a: ActivityForDe = serde_json::from_str(a).unwrap();
+        let ActivityForDeInner::EmojiReact { content, tag, .. } = *a.activity_inner else {
+            panic!()
+        };
+        assert_eq!(reaction(content, &tag), (Cow::Borrowed("👍"), None));
+    }
+
+    #[test]
+    fn misskey_custom_emoji_reaction() {
+        let a = r#"{"type":"Like","id":"https://misskey.example.com/likes/2","actor":"https://misskey.example.com/users/a","object":"https://momostr.pink/notes/note1","content":":blob_cat_42:","_misskey_reaction":":blob_cat_42:","tag":[{"id":"https://misskey.example.com/emojis/blob_cat_42","type":"Emoji","name":":blob_cat_42:","icon":{"type":"Image","mediaType":"image/webp","url":"https://misskey.example.com/files/blob_cat_42.webp"}}]}"#;
+        let a: ActivityForDe = serde_json::from_str(a).unwrap();
+        let ActivityForDeInner::Like { content, tag, .. } = *a.activity_inner else {
+            panic!()
+        };
+        let (content, emoji) = reaction(content, &tag);
+        assert_eq!(content, ":blob_cat_42:");
+        assert_eq!(
+            emoji,
+            Some(Tag::Emoji {
+                shortcode: "blob_cat_42".to_string(),
+                url: "https://misskey.example.com/files/blob_cat_42.webp".into(),
+            })
+        );
+        assert_eq!(
+            emoji_tag(":blob_cat_42:", "data:image/png;base64,iVBORw0KGgo="),
+            None
+        );
+        assert_eq!(emoji_tag(":blob-cat:", "https://example.com/a.png"), None);
+    }
+
+    #[test]
+    fn normalize_reaction_1() {
+        assert_eq!(normalize_reaction("❤"), normalize_reaction("❤️"));
+        assert_eq!(normalize_reaction("❤︎"), "❤️");
+        assert_eq!(normalize_reaction("👍"), "👍");
+        assert_eq!(normalize_reaction("1⃣"), "1️⃣");
+        assert_eq!(normalize_reaction("❤️‍🔥"), "❤️‍🔥");
+        assert_eq!(normalize_reaction("+"), "+");
+        assert_eq!(normalize_reaction("☺"), "☺️");
+        assert_eq!(normalize_reaction("🕊"), "🕊️");
+        assert_eq!(normalize_reaction("⭐"), "⭐");
+        assert_eq!(normalize_reaction("→"), "→");
+        assert_eq!(normalize_reaction("★"), "★");
+    }
+
+    #[test]
+    fn repost_created_at_1() {
+        let now = Timestamp::from(1_710_000_000);
+        let published = "2024-03-09T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(
+            repost_created_at(Some(published), now, 60 * 60 * 24 * 30),
+            Timestamp::from(published.timestamp() as u64)
+        );
+        assert_eq!(repost_created_at(None, now, 60 * 60 * 24 * 30), now);
+        let old = "2020-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(
+            repost_created_at(Some(old), now, u64::MAX),
+            Timestamp::from(old.timestamp() as u64)
+        );
+        assert_eq!(
+            repost_created_at(Some(DateTime::<Utc>::UNIX_EPOCH), now, 60 * 60 * 24 * 30),
+            now
+        );
+    }
+
+    #[test]
+    fn announce_without_published() {
+        let a = r##"{"id":"https://example.com/users/a/statuses/1/activity","type":"Announce","actor":"https://example.com/users/a","object":"https://example.com/users/b/statuses/1","to":["https://www.w3.org/ns/activitystreams#Public"]}"##;
+        let a: ActivityForDe = serde_json::from_str(a).unwrap();
+        let ActivityForDeInner::Announce { published, .. } = *a.activity_inner else {
+            panic!();
+        };
+        assert_eq!(published, None);
+    }
+
+    #[test]
+    fn poll_option_1() {
+        let option = |id: &str, label: &str| {
+            Tag::Generic(
+                TagKind::Custom("option".into()),
+                vec![id.to_string(), label.to_string()],
+            )
+        };
+        let keys = Keys::generate();
+        let poll = EventBuilder::new(
+            Kind::from(1068),
+            "poll",
+            [option("a", "Yes"), option("b", "No")],
+        )
+        .to_event(&keys)
+        .unwrap();
+        assert_eq!(poll_option(&poll, "No"), Some("b"));
+        assert_eq!(poll_option(&poll, "Maybe"), None);
+
+        let note = EventBuilder::new(Kind::TextNote, "not a poll", [option("a", "Yes")])
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(poll_option(&note, "Yes"), None);
+    }
+
+    #[test]
+    fn merge_vote_1() {
+        let now = Timestamp::from(1_700_000_000);
+        let first = merge_vote(None, "a", true, now);
+        assert_eq!(first, (vec!["a".to_string()], now));
+        let second = merge_vote(Some(&first), "b", true, now);
+        assert_eq!(second.0, ["a", "b"]);
+        assert_eq!(second.1, now + 1_u64);
+        assert_eq!(merge_vote(Some(&second), "b", true, now).0, ["a", "b"]);
+        let single = merge_vote(Some(&first), "b", false, now + 10_u64);
+        assert_eq!(single, (vec!["b".to_string()], now + 10_u64));
+    }
+
+    #[test]
+    fn mastodon_vote() {
+        let a = r#"{"id":"https://mastodon.example.com/users/a#votes/1/activity","type":"Create","actor":"https://mastodon.example.com/users/a","to":"https://momostr.pink/users/npub1","object":{"id":"https://mastodon.example.com/users/a#votes/1","type":"Note","name":"No","attributedTo":"https://mastodon.example.com/users/a","to":"https://momostr.pink/users/npub1","inReplyTo":"https://momostr.pink/notes/note1"}}"#;
+        let a: ActivityForDe = serde_json::from_str(a).unwrap();
+        let ActivityForDeInner::Create { object } = *a.activity_inner else {
+            panic!()
+        };
+        assert!(is_vote(&object));
+        assert_eq!(object.name.as_deref(), Some("No"));
+        let reply: NoteForDe = serde_json::from_value(serde_json::json!({
+            "id": "https://mastodon.example.com/notes/2",
+            "type": "Note",
+            "name": "No",
+            "content": "<p>no</p>",
+            "attributedTo": "https://mastodon.example.com/users/a",
+            "inReplyTo": "https://momostr.pink/notes/note1",
+        }))
+        .unwrap();
+        assert!(!is_vote(&reply));
+    }
+
+    #[test]
+    fn old_create_is_dropped() {
+        let now = Timestamp::from(1_700_000_000);
+        let old = DateTime::from_timestamp(1_600_000_000, 0).unwrap();
+        let recent = DateTime::from_timestamp(1_699_999_000, 0).unwrap();
+        assert!(is_too_old(old, now, Some(60 * 60 * 24)));
+        assert!(!is_too_old(recent, now, Some(60 * 60 * 24)));
+        assert!(!is_too_old(old, now, None));
+    }
+
+    #[test]
+    fn overloaded_inbox() {
+        use axum::response::IntoResponse;
+        let limiter = Arc::new(tokio::sync::Semaphore::new(1));
+        let permit = inbox_permit(&limiter, 30).unwrap();
+        let res = inbox_permit(&limiter, 30).unwrap_err().into_response();
+        assert_eq!(res.status(), axum::http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[axum::http::header::RETRY_AFTER], "30");
+        drop(permit);
+        assert!(inbox_permit(&limiter, 30).is_ok());
+    }
+
+    #[test]
+    fn incoming_edit() {
+        let a = serde_json::json!({
+            "type": "Update",
+            "actor": "https://example.com/users/a",
+            "object": {
+                "type": "Note",
+                "id": "https://example.com/users/a/statuses/1",
+                "content": "edited",
+                "published": "2024-03-18T02:24:24Z",
+                "updated": "2024-03-18T03:00:00Z",
+                "attributedTo": "https://example.com/users/a",
+            },
+        })
+        .to_string();
+        let a: ActivityForDe = serde_json::from_str(&a).unwrap();
+        let ActivityForDeInner::Update {
+            object: UpdateObject::Note(note),
+        } = *a.activity_inner
+        else {
+            panic!();
+        };
+        assert_eq!(note.content, "edited");
+        assert!(note.updated.is_some());
+        let original = EventBuilder::text_note("original", [])
+            .to_event(&Keys::generate())
+            .unwrap()
+            .id;
+        let edit = EventBuilder::text_note("edited", [])
+            .to_event(&Keys::generate())
+            .unwrap()
+            .id;
+        assert_eq!(
+            edit_actions(EditStrategy::DeleteRepost, &[original, edit]),
+            (vec![original, edit], None)
+        );
+        assert_eq!(
+            edit_actions(EditStrategy::AppendEdit, &[original, edit]),
+            (Vec::new(), Some(original))
+        );
+        assert_eq!(
+            edit_tag(original).as_vec(),
+            ["edit".to_string(), original.to_hex()]
+        );
+        assert_eq!(
+            "append-edit".parse::<EditStrategy>(),
+            Ok(EditStrategy::AppendEdit)
+        );
+    }
+
+    #[test]
+    fn boost_as_quote() {
+        let boosted = EventBuilder::text_note("boosted", [])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let relay = url::Url::parse("wss://relay.example.com").unwrap();
+        let (kind, content, tags) = repost(&boosted, relay.clone(), true);
+        assert_eq!(kind, Kind::TextNote);
+        assert_eq!(
+            content,
+            format!("nostr:{}", boosted.id.to_bech32().unwrap())
+        );
+        assert!(tags
+            .iter()
+            .any(|t| t.as_vec() == ["q".to_string(), boosted.id.to_hex(), relay.to_string()]));
+        assert!(tags.contains(&Tag::public_key(boosted.pubkey)));
+        let (kind, content, _) = repost(&boosted, relay, false);
+        assert_eq!((kind, content.as_str()), (Kind::Repost, ""));
+    }
+
+    #[test]
+    fn language_tag() {
+        let note = |extra: serde_json::Value| {
+            let mut note = serde_json::json!({
+                "id": "https://example.com/users/a/statuses/1",
+                "content": "<p>こんにちは</p>",
+                "attributedTo": "https://example.com/users/a",
+            });
+            note.as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            serde_json::from_value::<NoteForDe>(note).unwrap()
+        };
+        let l = |code: &str| {
+            Some([
+                Tag::Generic(TagKind::Custom("L".into()), vec!["ISO-639-1".to_string()]),
+                Tag::Generic(
+                    TagKind::Custom("l".into()),
+                    vec![code.to_string(), "ISO-639-1".to_string()],
+                ),
+            ])
+        };
+        let ja = note(serde_json::json!({ "contentMap": { "ja": "<p>こんにちは</p>" } }));
+        assert_eq!(language_tags(&ja), l("ja"));
+        assert_eq!(
+            language_tags(&note(serde_json::json!({ "language": "en-US" }))),
+            l("en")
+        );
+        let multilingual = note(serde_json::json!({
+            "contentMap": { "en": "<p>hello</p>", "ja": "<p>こんにちは</p>" }
+        }));
+        assert_eq!(language_tags(&multilingual), l("ja"));
+        assert_eq!(language_tags(&note(serde_json::json!({}))), None);
+    }
+
+    #[test]
+    fn accept_after_unfollow() {
+        let p = Keys::generate().public_key();
+        let npub = p.to_bech32().unwrap();
+        let followee = "https://example.com/users/a";
+        let follow_id = crate::nostr_to_ap::follow_id(&npub, followee);
+        let following = Arc::new([Arc::new(followee.to_string())].into_iter().collect());
+        let followees = |f: &Arc<FxHashSet<Arc<String>>>| {
+            let f = f.clone();
+            move |q: &nostr_lib::PublicKey| (*q == p).then_some(f)
+        };
+        assert_eq!(
+            unwanted_follow(&follow_id, followee, followees(&following)),
+            None
+        );
+        let unfollowed = Arc::new(FxHashSet::default());
+        assert_eq!(
+            unwanted_follow(&follow_id, followee, followees(&unfollowed)),
+            Some(npub.clone())
+        );
+        assert_eq!(unwanted_follow(&follow_id, followee, |_| None), Some(npub));
+        assert_eq!(
+            unwanted_follow("https://example.com/follow/1", followee, |_| None),
+            None
+        );
+    }
+
+    #[test]
+    fn p_tag_cap() {
+        let reply_to = Keys::generate().public_key();
+        let addressed = (0..100)
+            .map(|_| Keys::generate().public_key())
+            .collect_vec();
+        let (ps, dropped) = capped_p_tags(
+            addressed
+                .iter()
+                .take(1)
+                .chain([&reply_to])
+                .chain(&addressed)
+                .copied(),
+            50,
+        );
+        assert_eq!((ps.len(), dropped), (50, 51));
+        assert_eq!(ps[..2], [addressed[0], reply_to]);
+        let (ps, dropped) = capped_p_tags([reply_to], 50);
+        assert_eq!((ps, dropped), (vec![reply_to], 0));
+    }
+
+    #[test]
+    fn lemmy_post() {
+        let a = r#"{"type":"Announce","id":"https://lemmy.example.com/activities/announce/1","actor":"https://lemmy.example.com/c/rust","to":["https://www.w3.org/ns/activitystreams#Public"],"cc":["https://lemmy.example.com/c/rust/followers"],"object":{"type":"Create","id":"https://lemmy.example.com/activities/create/1","actor":"https://lemmy.example.com/u/a","object":{"type":"Page","id":"https://lemmy.example.com/post/1","attributedTo":"https://lemmy.example.com/u/a","audience":"https://lemmy.example.com/c/rust","name":"Title","content":"<p>body</p>"}}}"#;
+        let a: ActivityForDe = serde_json::from_str(a).unwrap();
+        let ActivityForDeInner::Announce { object, .. } = *a.activity_inner else {
+            panic!()
+        };
+        assert_eq!(object.note_id(), Some("https://lemmy.example.com/post/1"));
+        let page: NoteForDe = serde_json::from_value(serde_json::json!({
+            "type": "Page",
+            "id": "https://lemmy.example.com/post/1",
+            "attributedTo": "https://lemmy.example.com/u/a",
+            "audience": "https://lemmy.example.com/c/rust",
+            "content": "<p>body</p>",
+        }))
+        .unwrap();
+        assert_eq!(page.attributed_to.author, "https://lemmy.example.com/u/a");
+        let group = nostr_lib::Keys::new(
+            crate::activity::actor_nsec("https://lemmy.example.com/c/rust").unwrap(),
+        )
+        .public_key();
+        assert_eq!(group_npubs(&page).collect_vec(), vec![group]);
+        let a = r#"{"type":"Announce","id":"https://lemmy.example.com/activities/announce/2","actor":"https://lemmy.example.com/c/rust","object":{"type":"Like","id":"https://lemmy.example.com/activities/like/1","actor":"https://lemmy.example.com/u/a","object":"https://lemmy.example.com/post/1"}}"#;
+        let a: ActivityForDe = serde_json::from_str(a).unwrap();
+        let ActivityForDeInner::Announce { object, .. } = *a.activity_inner else {
+            panic!()
+        };
+        assert_eq!(object.note_id(), None);
+    }
+
+    #[test]
+    fn imeta_alt_text() {
+        let a: AttachedImage = serde_json::from_value(serde_json::json!({
+            "type": "Document",
+            "mediaType": "image/png",
+            "url": "https://example.com/a.png",
+            "name": "A cat sleeping",
+            "blurhash": "UBL_:rOpGG-oBUNG,qRj2so|=eE1w^n4S5NH",
+        }))
+        .unwrap();
+        assert_eq!(
+            imeta_tag(&a).as_vec(),
+            [
+                "imeta",
+                "url https://example.com/a.png",
+                "m image/png",
+                "blurhash UBL_:rOpGG-oBUNG,qRj2so|=eE1w^n4S5NH",
+                "alt A cat sleeping",
+            ]
+        );
+        let a: AttachedImage = serde_json::from_value(
+            serde_json::json!({ "url": "https://example.com/a.png", "name": "" }),
+        )
+        .unwrap();
+        assert_eq!(
+            imeta_tag(&a).as_vec(),
+            ["imeta", "url https://example.com/a.png"]
+        );
+    }
+
+    #[test]
+    fn audio_file_metadata() {
+        let note = EventId::all_zeros();
+        let a: AttachedImage = serde_json::from_value(serde_json::json!({
+            "type": "Document",
+            "mediaType": "audio/mpeg",
+            "url": "https://example.com/episode-1.mp3",
+            "name": "Episode 1",
+        }))
+        .unwrap();
+        let e = file_metadata(&a, note)
+            .unwrap()
+            .to_event(&Keys::generate())
+            .unwrap();
+        assert_eq!(e.kind, Kind::FileMetadata);
+        assert_eq!(e.content, "Episode 1");
+        assert_eq!(
+            e.tags.iter().map(|t| t.as_vec()).collect_vec(),
+            [
+                vec!["url", "https://example.com/episode-1.mp3"],
+                vec!["m", "audio/mpeg"],
+                vec!["e", &note.to_hex()],
+                vec!["alt", "Episode 1"],
+            ]
+        );
+        let image: AttachedImage = serde_json::from_value(
+            serde_json::json!({ "url": "https://example.com/a.png", "mediaType": "image/png" }),
+        )
+        .unwrap();
+        assert!(file_metadata(&image, note).is_none());
+    }
+
+    #[test]
+    fn forwarded_create() {
+        let note = "https://example.com/notes/1";
+        let author = "https://example.com/users/a";
+        let (direct, forwarded) = create_ap_id(note, author, author).unwrap();
+        assert!(!forwarded);
+        let (relayed, forwarded) =
+            create_ap_id(note, author, "https://relay.example.org/actor").unwrap();
+        assert!(forwarded);
+        assert_eq!(relayed, direct);
+        assert!(create_ap_id(
+            "https://evil.example.org/notes/1",
+            author,
+            "https://evil.example.org/actor"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn flag() {
+        let note = format!("{}note1abc", crate::NOTE_ID_PREFIX);
+        let a = format!(
+            r#"{{"type":"Flag","id":"https://example.com/1","actor":"https://example.com/actor","content":"spam","object":["https://example.com/users/a","{note}"]}}"#
+        );
+        let a: ActivityForDe = serde_json::from_str(&a).unwrap();
+        let ActivityForDeInner::Flag { object, content } = *a.activity_inner else {
+            panic!()
+        };
+        let crate::activity::ListOrSingle::Vec(objects) = object else {
+            panic!()
+        };
+        let objects: Vec<String> = objects.into_iter().filter_map(Option::from).collect();
+        assert_eq!(
+            report_message("https://example.com/actor", content.as_deref(), &objects),
+            "Report from https://example.com/actor:\nspam\n- https://example.com/users/a\n- nostr:note1abc"
+        );
+        assert_eq!(
+            report_message("https://example.com/actor", Some(""), &[]),
+            "Report from https://example.com/actor"
+        );
+    }
+
+    #[test]
+    fn pins() {
+        let a = r#"{"type":"Add","id":"https://example.com/users/a#add/1","actor":"https://example.com/users/a","object":"https://example.com/users/a/statuses/2","target":"https://example.com/users/a/collections/featured"}"#;
+        let a: ActivityForDe = serde_json::from_str(a).unwrap();
+        let ActivityForDeInner::Add { object, target } = *a.activity_inner else {
+            panic!()
+        };
+        assert_eq!(
+            target.unwrap().id(),
+            "https://example.com/users/a/collections/featured"
+        );
+        let featured = vec!["1".to_string(), "3".to_string()];
+        assert_eq!(
+            pinned_notes(featured.clone(), Some(object.id()), None),
+            ["https://example.com/users/a/statuses/2", "1", "3"]
+        );
+        assert_eq!(pinned_notes(featured.clone(), Some("1"), None), ["1", "3"]);
+        assert_eq!(pinned_notes(featured, None, Some("1")), ["3"]);
+    }
 }
@@ -0,0 +1,180 @@
+use super::AppState;
+use crate::activity::ActivityForDeInner;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum_macros::debug_handler;
+use cached::Cached;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const ACTIVITY_TYPES: [&str; 7] = [
+    "follow", "create", "like", "announce", "delete", "flag", "other",
+];
+/// Upper bounds in seconds of the buckets of the fetch latency histogram.
+const FETCH_BUCKETS: [f64; 8] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 60.0];
+
+/// Counters exposed at `/metrics` in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    activities_received: [AtomicU64; ACTIVITY_TYPES.len()],
+    deliveries_succeeded: AtomicU64,
+    deliveries_failed: AtomicU64,
+    /// Cumulative, as in the exposition format.
+    fetch_buckets: [AtomicU64; FETCH_BUCKETS.len()],
+    fetch_count: AtomicU64,
+    fetch_micros: AtomicU64,
+}
+
+impl Metrics {
+    pub fn activity_received(&self, activity: &ActivityForDeInner) {
+        let i = match activity {
+            ActivityForDeInner::Follow { .. } => 0,
+            ActivityForDeInner::Create { .. } => 1,
+            ActivityForDeInner::Like { .. } | ActivityForDeInner::EmojiReact { .. } => 2,
+            ActivityForDeInner::Announce { .. } => 3,
+            ActivityForDeInner::Delete(_) => 4,
+            ActivityForDeInner::Flag { .. } => 5,
+            _ => 6,
+        };
+        self.activities_received[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an outbound POST of an activity.
+    pub fn delivery(&self, succeeded: bool) {
+        if succeeded {
+            &self.deliveries_succeeded
+        } else {
+            &self.deliveries_failed
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the latency of `get_activity_json_and_url_with_retry`.
+    pub fn fetch(&self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        for (bucket, le) in self.fetch_buckets.iter().zip(FETCH_BUCKETS) {
+            if secs <= le {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.fetch_count.fetch_add(1, Ordering::Relaxed);
+        self.fetch_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// `follows` are the numbers of pending and confirmed follows we sent.
+    fn render(
+        &self,
+        events_sent: u64,
+        caches: &[(&str, usize)],
+        follows: (usize, usize),
+    ) -> String {
+        let load = |a: &AtomicU64| a.load(Ordering::Relaxed);
+        let mut s = String::new();
+        s.push_str("# TYPE momostr_activities_received_total counter\n");
+        for (t, n) in ACTIVITY_TYPES.iter().zip(&self.activities_received) {
+            let _ = writeln!(
+                s,
+                "momostr_activities_received_total{{type=\"{t}\"}} {}",
+                load(n)
+            );
+        }
+        s.push_str("# TYPE momostr_relay_events_sent_total counter\n");
+        let _ = writeln!(s, "momostr_relay_events_sent_total {events_sent}");
+        s.push_str("# TYPE momostr_deliveries_total counter\n");
+        let _ = writeln!(
+            s,
+            "momostr_deliveries_total{{result=\"success\"}} {}",
+            load(&self.deliveries_succeeded)
+        );
+        let _ = writeln!(
+            s,
+            "momostr_deliveries_total{{result=\"failure\"}} {}",
+            load(&self.deliveries_failed)
+        );
+        s.push_str("# TYPE momostr_cache_entries gauge\n");
+        for (cache, n) in caches {
+            let _ = writeln!(s, "momostr_cache_entries{{cache=\"{cache}\"}} {n}");
+        }
+        s.push_str("# TYPE momostr_follows gauge\n");
+        let _ = writeln!(s, "momostr_follows{{state=\"pending\"}} {}", follows.0);
+        let _ = writeln!(s, "momostr_follows{{state=\"confirmed\"}} {}", follows.1);
+        s.push_str("# TYPE momostr_fetch_duration_seconds histogram\n");
+        for (le, n) in FETCH_BUCKETS.iter().zip(&self.fetch_buckets) {
+            let _ = writeln!(
+                s,
+                "momostr_fetch_duration_seconds_bucket{{le=\"{le}\"}} {}",
+                load(n)
+            );
+        }
+        let count = load(&self.fetch_count);
+        let _ = writeln!(
+            s,
+            "momostr_fetch_duration_seconds_bucket{{le=\"+Inf\"}} {count}"
+        );
+        let _ = writeln!(
+            s,
+            "momostr_fetch_duration_seconds_sum {}",
+            load(&self.fetch_micros) as f64 / 1e6
+        );
+        let _ = writeln!(s, "momostr_fetch_duration_seconds_count {count}");
+        s
+    }
+}
+
+#[debug_handler]
+#[tracing::instrument(skip_all)]
+pub async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let caches = [
+        ("note", state.note_cache.lock().len()),
+        ("actor", state.actor_cache.lock().len()),
+        ("nostr_user", state.nostr_user_cache.lock().cache_size()),
+    ];
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(
+            state.nostr.sent_event_count(),
+            &caches,
+            state.db.follow_counts(),
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use crate::activity::ActivityForDe;
+    use std::time::Duration;
+
+    #[test]
+    fn metrics_1() {
+        let m = Metrics::default();
+        let a = r#"{"type":"Follow","id":"https://example.com/follows/1","actor":"https://example.com/users/a","object":"https://momostr.pink/users/npub1"}"#;
+        let a: ActivityForDe = serde_json::from_str(a).unwrap();
+        m.activity_received(&a.activity_inner);
+        m.delivery(true);
+        m.delivery(false);
+        m.delivery(false);
+        m.fetch(Duration::from_millis(300));
+        let s = m.render(7, &[("note", 3)], (2, 5));
+        for line in [
+            "momostr_activities_received_total{type=\"follow\"} 1",
+            "momostr_activities_received_total{type=\"like\"} 0",
+            "momostr_relay_events_sent_total 7",
+            "momostr_deliveries_total{result=\"success\"} 1",
+            "momostr_deliveries_total{result=\"failure\"} 2",
+            "momostr_cache_entries{cache=\"note\"} 3",
+            "momostr_follows{state=\"pending\"} 2",
+            "momostr_follows{state=\"confirmed\"} 5",
+            "momostr_fetch_duration_seconds_bucket{le=\"0.25\"} 0",
+            "momostr_fetch_duration_seconds_bucket{le=\"0.5\"} 1",
+            "momostr_fetch_duration_seconds_bucket{le=\"+Inf\"} 1",
+            "momostr_fetch_duration_seconds_sum 0.3",
+        ] {
+            assert!(s.lines().any(|l| l == line), "{line} is missing from {s}");
+        }
+    }
+}
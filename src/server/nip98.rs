@@ -0,0 +1,156 @@
+//! NIP-98 HTTP auth: requests carry a kind 27235 event signed for their URL
+//! and method in the `Authorization` header.
+
+use crate::error::Error;
+use crate::HTTPS_DOMAIN;
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{header, Method};
+use base64::Engine;
+use nostr_lib::{Event, JsonUtil, Kind, PublicKey, Timestamp};
+
+/// How far the `created_at` of an auth event may be from now.
+const AUTH_MAX_SKEW_SECS: u64 = 60;
+
+/// Extracts the pubkey which signed the NIP-98 authorization of a request,
+/// rejecting it with `401` if there is none or it is invalid.
+#[derive(Debug, Clone, Copy)]
+pub struct Nip98(pub PublicKey);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Nip98 {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Error> {
+        let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+        let url = format!("{HTTPS_DOMAIN}{path}");
+        let authorization = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|a| a.to_str().ok());
+        verify_nip98(
+            authorization,
+            url.trim_end_matches('/'),
+            &parts.method,
+            Timestamp::now(),
+        )
+        .map(Nip98)
+    }
+}
+
+/// The pubkey which signed the NIP-98 event in `authorization` for `method`
+/// on `url`.
+fn verify_nip98(
+    authorization: Option<&str>,
+    url: &str,
+    method: &Method,
+    now: Timestamp,
+) -> Result<PublicKey, Error> {
+    let unauthorized = |msg: &str| Error::Unauthorized(msg.to_string());
+    let event = authorization
+        .and_then(|a| a.strip_prefix("Nostr "))
+        .ok_or_else(|| unauthorized("a NIP-98 authorization is required"))?;
+    let event = base64::prelude::BASE64_STANDARD
+        .decode(event.trim())
+        .map_err(|_| unauthorized("authorization is not base64"))?;
+    let event =
+        Event::from_json(event).map_err(|_| unauthorized("authorization is not an event"))?;
+    event
+        .verify()
+        .map_err(|_| unauthorized("invalid signature"))?;
+    if event.kind != Kind::HttpAuth {
+        return Err(unauthorized("authorization is not of kind 27235"));
+    }
+    if event.created_at.as_u64().abs_diff(now.as_u64()) > AUTH_MAX_SKEW_SECS {
+        return Err(unauthorized("authorization has expired"));
+    }
+    let tag = |kind: &str| {
+        event.tags.iter().find_map(|t| {
+            let mut t = t.as_vec().into_iter();
+            (t.next().as_deref() == Some(kind))
+                .then(|| t.next())
+                .flatten()
+        })
+    };
+    if tag("u").as_deref().map(|u| u.trim_end_matches('/')) != Some(url) {
+        return Err(unauthorized("authorization is for another URL"));
+    }
+    if !tag("method").is_some_and(|m| m.eq_ignore_ascii_case(method.as_str())) {
+        return Err(unauthorized("authorization is for another method"));
+    }
+    Ok(event.pubkey)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_nip98, Nip98};
+    use crate::HTTPS_DOMAIN;
+    use axum::extract::FromRequestParts;
+    use axum::http::{Method, Request};
+    use base64::Engine;
+    use nostr_lib::{EventBuilder, JsonUtil, Keys, Kind, Tag, Timestamp};
+
+    fn auth(keys: &Keys, url: &str, method: &str, kind: Kind) -> String {
+        let e = EventBuilder::new(
+            kind,
+            "",
+            [
+                Tag::parse(&["u", url]).unwrap(),
+                Tag::parse(&["method", method]).unwrap(),
+            ],
+        )
+        .to_event(keys)
+        .unwrap();
+        format!(
+            "Nostr {}",
+            base64::prelude::BASE64_STANDARD.encode(e.as_json())
+        )
+    }
+
+    #[test]
+    fn nip98() {
+        let keys = Keys::generate();
+        let url = "https://momostr.example.com/api/accounts/npub1a/stopped";
+        let now = Timestamp::now();
+        let a = auth(&keys, url, "PUT", Kind::HttpAuth);
+        assert_eq!(
+            verify_nip98(Some(&a), url, &Method::PUT, now).unwrap(),
+            keys.public_key()
+        );
+        assert!(verify_nip98(Some(&a), url, &Method::DELETE, now).is_err());
+        assert!(verify_nip98(
+            Some(&a),
+            url,
+            &Method::PUT,
+            Timestamp::from(now.as_u64() + 3600)
+        )
+        .is_err());
+        let other = auth(&keys, "https://example.com/", "PUT", Kind::HttpAuth);
+        assert!(verify_nip98(Some(&other), url, &Method::PUT, now).is_err());
+        let note = auth(&keys, url, "PUT", Kind::TextNote);
+        assert!(verify_nip98(Some(&note), url, &Method::PUT, now).is_err());
+        assert!(verify_nip98(None, url, &Method::PUT, now).is_err());
+    }
+
+    #[tokio::test]
+    async fn nip98_extractor() {
+        let keys = Keys::generate();
+        let url = format!("{HTTPS_DOMAIN}/api/a?b=c");
+        let request = |authorization: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/api/a?b=c")
+                .header("authorization", authorization)
+                .body(())
+                .unwrap()
+                .into_parts()
+                .0
+        };
+        let mut parts = request(&auth(&keys, &url, "POST", Kind::HttpAuth));
+        let Nip98(signer) = Nip98::from_request_parts(&mut parts, &()).await.unwrap();
+        assert_eq!(signer, keys.public_key());
+        let mut parts = request(&auth(&keys, &url, "GET", Kind::HttpAuth));
+        assert!(Nip98::from_request_parts(&mut parts, &()).await.is_err());
+    }
+}
@@ -18,7 +18,7 @@ pub async fn nodeinfo(
         "version": "2.1",
         "software": {
             "name": "momostr",
-            "version": "0.1.0",
+            "version": env!("CARGO_PKG_VERSION"),
         },
         "protocols": [
             "activitypub"
@@ -30,7 +30,7 @@ pub async fn nodeinfo(
         "openRegistrations": false,
         "usage": {
             "users": {
-                "total": state.nostr_account_to_followers.lock().len(),
+                "total": state.activitypub_accounts.lock().len(),
             },
         },
         "metadata": {
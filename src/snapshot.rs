@@ -0,0 +1,139 @@
+use crate::error::Error;
+use crate::server::AppState;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::path::Path;
+use std::time::Duration;
+use tracing::{info, warn};
+
+pub const NOSTR_ACCOUNTS_PATH: &str = "nostr_accounts.json";
+pub const FEDIVERSE_ACCOUNTS_PATH: &str = "fediverse_accounts.json";
+/// How long queued deletions may take to be sent on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Compares `map` with the snapshot at `path`, logs the entries that differ
+/// and writes `map` as the new snapshot. Returns the number of differing entries.
+pub async fn reconcile<K, V, S>(path: &Path, map: &HashMap<K, V, S>) -> Result<usize, Error>
+where
+    K: Serialize + DeserializeOwned + Hash + Eq + std::fmt::Debug,
+    V: Serialize + DeserializeOwned + PartialEq,
+    S: BuildHasher,
+{
+    let on_disk: HashMap<K, V> = match tokio::fs::read(path).await {
+        Ok(s) => serde_json::from_slice(&s)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut discrepancies = 0;
+    for (k, v) in map {
+        match on_disk.get(k) {
+            None => {
+                warn!("{k:?} is missing from {}", path.display());
+                discrepancies += 1;
+            }
+            Some(d) if d != v => {
+                warn!("{k:?} is outdated in {}", path.display());
+                discrepancies += 1;
+            }
+            _ => {}
+        }
+    }
+    for k in on_disk.keys().filter(|k| !map.contains_key(*k)) {
+        warn!("{k:?} is only in {}", path.display());
+        discrepancies += 1;
+    }
+    write_atomically(path, &serde_json::to_vec(map)?).await?;
+    Ok(discrepancies)
+}
+
+/// Writes `contents` to a temporary file renamed to `path`, so that readers
+/// never see a partially written file.
+pub async fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension(format!("{}.tmp", rand::random::<u32>()));
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+impl AppState {
+    /// Periodically snapshots the account maps in full, in case an incremental
+    /// write was lost.
+    pub async fn reconcile_accounts(&self, interval: Option<Duration>) -> Result<(), Error> {
+        let Some(interval) = interval else {
+            return Ok(());
+        };
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = self.snapshot_accounts().await {
+                warn!("could not snapshot accounts: {e:?}");
+            }
+        }
+    }
+
+    /// Writes `contents` of an account map to `path` unless the process is
+    /// shutting down, when the final snapshot is written instead.
+    pub async fn write_accounts(&self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+        let _writes = self.account_writes.lock().await;
+        write_atomically(path, contents).await
+    }
+
+    /// Writes the account maps in full.
+    pub async fn snapshot_accounts(&self) -> Result<(), Error> {
+        let _writes = self.account_writes.lock().await;
+        self.write_snapshots().await
+    }
+
+    /// Callers hold `account_writes`.
+    async fn write_snapshots(&self) -> Result<(), Error> {
+        let followers = self.nostr_account_to_followers.lock().clone();
+        let n = reconcile(Path::new(NOSTR_ACCOUNTS_PATH), &followers).await?;
+        let accounts = self.activitypub_accounts.lock().clone();
+        let m = reconcile(Path::new(FEDIVERSE_ACCOUNTS_PATH), &accounts).await?;
+        info!("reconciled account snapshots: {n} + {m} discrepancies");
+        Ok(())
+    }
+
+    /// Flushes what is pending before the process exits: queued deletions,
+    /// the account snapshots and the relay connections. Writes of the account
+    /// maps in progress are waited for, and later ones never happen, so that
+    /// they can't overwrite the final snapshot.
+    pub async fn shut_down(&self) {
+        info!("shutting down");
+        let _writes = self.account_writes.lock().await;
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, self.event_deletion_queue.flush())
+            .await
+            .is_err()
+        {
+            warn!("timed out flushing the deletion queue");
+        }
+        if let Err(e) = self.write_snapshots().await {
+            warn!("could not snapshot accounts: {e:?}");
+        }
+        self.nostr.close(Duration::from_secs(5)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reconcile;
+    use rustc_hash::FxHashMap;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn snapshot_captures_recent_changes() {
+        let path =
+            std::env::temp_dir().join(format!("momostr-snapshot-{}.json", std::process::id()));
+        let mut m = FxHashMap::default();
+        m.insert("a".to_string(), 1);
+        assert_eq!(reconcile(&path, &m).await.unwrap(), 1);
+        assert_eq!(reconcile(&path, &m).await.unwrap(), 0);
+        m.insert("a".to_string(), 2);
+        m.insert("b".to_string(), 3);
+        assert_eq!(reconcile(&path, &m).await.unwrap(), 2);
+        let on_disk: HashMap<String, i32> =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(on_disk, m.into_iter().collect());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
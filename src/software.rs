@@ -0,0 +1,201 @@
+use crate::activity::NoteForDe;
+use crate::error::Error;
+use crate::server::AppState;
+use crate::USER_AGENT;
+use cached::Cached;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tracing::{debug, warn};
+
+/// Server software of a remote instance, as reported by its NodeInfo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Software {
+    Mastodon,
+    /// Misskey and its forks
+    Misskey,
+    /// Pleroma and Akkoma
+    Pleroma,
+    Lemmy,
+    PeerTube,
+    Other(String),
+}
+
+impl Software {
+    pub fn from_name(name: &str) -> Self {
+        match name.to_lowercase().as_str() {
+            "mastodon" | "hometown" | "glitchcafe" => Software::Mastodon,
+            "misskey" | "calckey" | "firefish" | "foundkey" | "sharkey" | "iceshrimp"
+            | "cherrypick" | "catodon" => Software::Misskey,
+            "pleroma" | "akkoma" => Software::Pleroma,
+            "lemmy" => Software::Lemmy,
+            "peertube" => Software::PeerTube,
+            name => Software::Other(name.to_string()),
+        }
+    }
+}
+
+/// Misskey-flavored markdown of a note, if it has one. Misskey forks don't
+/// always send `source.mediaType`, so the software of the host is used as a hint.
+pub fn misskey_markdown<'a>(note: &'a NoteForDe, software: Option<&Software>) -> Option<&'a str> {
+    match &note.source {
+        Some(source) if source.media_type == "text/x.misskeymarkdown" => Some(&source.content),
+        source if software == Some(&Software::Misskey) => source
+            .as_ref()
+            .map(|s| s.content.as_str())
+            .or(note.misskey_content.as_deref()),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+struct NodeInfoLinks {
+    links: Vec<NodeInfoLink>,
+}
+
+#[derive(Deserialize)]
+struct NodeInfoLink {
+    rel: String,
+    href: String,
+}
+
+#[derive(Deserialize)]
+struct NodeInfo {
+    software: NodeInfoSoftware,
+}
+
+#[derive(Deserialize)]
+struct NodeInfoSoftware {
+    name: String,
+}
+
+/// The NodeInfo 2.x document linked from `links`, if it is served by `host`.
+fn nodeinfo_href(links: NodeInfoLinks, host: &str) -> Option<String> {
+    links
+        .links
+        .into_iter()
+        .filter(|l| {
+            l.rel
+                .starts_with("http://nodeinfo.diaspora.software/ns/schema/2.")
+                && url::Url::parse(&l.href)
+                    .is_ok_and(|u| u.scheme() == "https" && u.host_str() == Some(host))
+        })
+        .max_by(|a, b| a.rel.cmp(&b.rel))
+        .map(|l| l.href)
+}
+
+impl AppState {
+    /// Software of `host`, cached per host. `None` if the host doesn't serve
+    /// NodeInfo, or until it has been looked up in the background, so that
+    /// conversions never wait for it.
+    #[tracing::instrument(skip(self))]
+    pub fn get_software(self: &Arc<Self>, host: &str) -> Option<Software> {
+        let c = {
+            let mut m = self.software_cache.lock();
+            m.cache_get_or_set_with(host.to_string(), || Arc::new(OnceCell::new()))
+                .clone()
+        };
+        if let Some(s) = c.get() {
+            return s.clone();
+        }
+        let state = self.clone();
+        let host = host.to_string();
+        tokio::spawn(async move {
+            c.get_or_init(|| async {
+                match state.get_software_without_cache(&host).await {
+                    Ok(s) => {
+                        debug!("{host} is {s:?}");
+                        Some(s)
+                    }
+                    Err(e) => {
+                        warn!("could not get nodeinfo of {host}: {e:?}");
+                        None
+                    }
+                }
+            })
+            .await;
+        });
+        None
+    }
+
+    async fn get_software_without_cache(&self, host: &str) -> Result<Software, Error> {
+        let _permit = self.host_limiter.acquire(host).await;
+        let links: NodeInfoLinks = self
+            .http_client
+            .get(format!("https://{host}/.well-known/nodeinfo"))
+            .header(axum::http::header::USER_AGENT, &*USER_AGENT)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await?
+            .json()
+            .await?;
+        let href = nodeinfo_href(links, host).ok_or(Error::NotFound)?;
+        let nodeinfo: NodeInfo = self
+            .http_client
+            .get(href)
+            .header(axum::http::header::USER_AGENT, &*USER_AGENT)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(Software::from_name(&nodeinfo.software.name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{misskey_markdown, nodeinfo_href, NodeInfoLinks, Software};
+    use crate::activity::NoteForDe;
+
+    #[test]
+    fn software_from_name_1() {
+        assert_eq!(Software::from_name("Sharkey"), Software::Misskey);
+        assert_eq!(Software::from_name("akkoma"), Software::Pleroma);
+        assert_eq!(
+            Software::from_name("gotosocial"),
+            Software::Other("gotosocial".to_string())
+        );
+    }
+
+    #[test]
+    fn nodeinfo_href_1() {
+        let links = |href: &str| -> NodeInfoLinks {
+            serde_json::from_value(serde_json::json!({"links": [
+                {"rel": "http://nodeinfo.diaspora.software/ns/schema/2.0", "href": href},
+            ]}))
+            .unwrap()
+        };
+        assert_eq!(
+            nodeinfo_href(links("https://example.com/nodeinfo/2.0"), "example.com"),
+            Some("https://example.com/nodeinfo/2.0".to_string())
+        );
+        assert_eq!(
+            nodeinfo_href(links("https://169.254.169.254/nodeinfo"), "example.com"),
+            None
+        );
+        assert_eq!(
+            nodeinfo_href(links("http://example.com/nodeinfo/2.0"), "example.com"),
+            None
+        );
+    }
+
+    #[test]
+    fn misskey_markdown_1() {
+        let note: NoteForDe = serde_json::from_value(serde_json::json!({
+            "id": "https://misskey.example.com/notes/1",
+            "content": "<p><b>test</b></p>",
+            "_misskey_content": "**test**",
+            "published": "2024-03-18T02:24:24Z",
+            "attributedTo": "https://misskey.example.com/users/1",
+        }))
+        .unwrap();
+        assert_eq!(misskey_markdown(&note, None), None);
+        assert_eq!(misskey_markdown(&note, Some(&Software::Mastodon)), None);
+        assert_eq!(
+            misskey_markdown(&note, Some(&Software::Misskey)),
+            Some("**test**")
+        );
+    }
+}
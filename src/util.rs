@@ -1,4 +1,14 @@
+use lru::LruCache;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
 use serde::Serialize;
+use std::borrow::Cow;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
 
 #[derive(Serialize)]
 pub struct Merge<T1, T2> {
@@ -7,3 +17,555 @@ pub struct Merge<T1, T2> {
     #[serde(flatten)]
     pub f2: T2,
 }
+
+/// Caps the number of in-flight requests to each host.
+#[derive(Debug)]
+pub struct HostLimiter {
+    permits: usize,
+    semaphores: Mutex<LruCache<String, Arc<Semaphore>>>,
+}
+
+impl HostLimiter {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            permits,
+            semaphores: Mutex::new(LruCache::new(NonZeroUsize::new(1_000).unwrap())),
+        }
+    }
+
+    pub async fn acquire(&self, host: &str) -> OwnedSemaphorePermit {
+        let s = self
+            .semaphores
+            .lock()
+            .get_or_insert(host.to_string(), || Arc::new(Semaphore::new(self.permits)))
+            .clone();
+        s.acquire_owned().await.unwrap()
+    }
+}
+
+/// Token bucket per key allowing `per_minute` requests a minute, in bursts of
+/// up to as many.
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_minute: u32,
+    /// Tokens left and when they were counted.
+    buckets: Mutex<LruCache<String, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    /// A zero `per_minute` disables the limiter.
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: Mutex::new(LruCache::new(NonZeroUsize::new(10_000).unwrap())),
+        }
+    }
+
+    /// Takes a token of `key`, or returns the seconds until one is available.
+    pub fn check(&self, key: &str, now: Instant) -> Result<(), u64> {
+        if self.per_minute == 0 {
+            return Ok(());
+        }
+        let capacity = self.per_minute as f64;
+        let per_sec = capacity / 60.0;
+        let mut buckets = self.buckets.lock();
+        let (tokens, at) = buckets.get_or_insert_mut(key.to_string(), || (capacity, now));
+        *tokens =
+            (*tokens + now.saturating_duration_since(*at).as_secs_f64() * per_sec).min(capacity);
+        *at = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - *tokens) / per_sec).ceil() as u64)
+        }
+    }
+}
+
+/// Stops requests to a host for `cooldown` after `threshold` consecutive
+/// failures. The first request after the cooldown decides whether it closes.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: u64,
+    /// Consecutive failures and the time of the last one of each host.
+    hosts: Mutex<LruCache<String, (u32, u64)>>,
+}
+
+impl CircuitBreaker {
+    /// A zero `threshold` disables the breaker.
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown: cooldown.as_secs(),
+            hosts: Mutex::new(LruCache::new(NonZeroUsize::new(1_000).unwrap())),
+        }
+    }
+
+    /// Seconds until requests to `host` are allowed again, if its breaker is open.
+    pub fn open_for(&self, host: &str, now: u64) -> Option<u64> {
+        if self.threshold == 0 {
+            return None;
+        }
+        let (failures, failed_at) = *self.hosts.lock().peek(host)?;
+        let until = failed_at + self.cooldown;
+        (failures >= self.threshold && now < until).then_some(until - now)
+    }
+
+    pub fn record(&self, host: &str, succeeded: bool, now: u64) {
+        let mut hosts = self.hosts.lock();
+        if succeeded {
+            hosts.pop(host);
+        } else {
+            let h = hosts.get_or_insert_mut(host.to_string(), || (0, now));
+            *h = (h.0 + 1, now);
+        }
+    }
+}
+
+/// Lets concurrent callers for the same key await a single computation, e.g.
+/// of a fetch of a URL.
+#[derive(Debug)]
+pub struct InFlight<T> {
+    cells: Mutex<FxHashMap<String, Arc<tokio::sync::OnceCell<T>>>>,
+}
+
+impl<T> Default for InFlight<T> {
+    fn default() -> Self {
+        Self {
+            cells: Default::default(),
+        }
+    }
+}
+
+impl<T: Clone> InFlight<T> {
+    /// Runs `f` unless it is already running for `key`, in which case its
+    /// result is awaited instead.
+    pub async fn run<F: Future<Output = T>>(&self, key: &str, f: F) -> T {
+        let cell = self
+            .cells
+            .lock()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        let r = cell.get_or_init(|| f).await.clone();
+        let mut cells = self.cells.lock();
+        if cells.get(key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+            cells.remove(key);
+        }
+        r
+    }
+}
+
+/// Items waiting to be sent, with the channels to send their results to.
+type Batch<T, R> = Vec<(T, oneshot::Sender<R>)>;
+
+/// Coalesces items sent to the same host within a short window. The first
+/// item of a window spawns a task which sends the whole batch concurrently
+/// once it closes, so that the requests share pooled connections, and a
+/// cancelled caller doesn't hold back the others.
+#[derive(Debug)]
+pub struct HostBatcher<T, R> {
+    window: Duration,
+    batches: Arc<Mutex<FxHashMap<String, Batch<T, R>>>>,
+}
+
+impl<T, R> HostBatcher<T, R> {
+    /// A zero `window` disables batching.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            batches: Default::default(),
+        }
+    }
+
+    /// Sends `item` with `send`, possibly as part of a batch. Returns `None`
+    /// if the task sending the batch panicked before sending `item`.
+    pub async fn send<F>(
+        &self,
+        host: &str,
+        item: T,
+        send: impl Fn(T) -> F + Send + 'static,
+    ) -> Option<R>
+    where
+        F: Future<Output = R> + Send + 'static,
+        T: Send + 'static,
+        R: Send + 'static,
+    {
+        if self.window.is_zero() {
+            return Some(send(item).await);
+        }
+        let (tx, rx) = oneshot::channel();
+        let leader = {
+            let mut batches = self.batches.lock();
+            let batch = batches.entry(host.to_string()).or_default();
+            batch.push((item, tx));
+            batch.len() == 1
+        };
+        if leader {
+            let (batches, host, window) = (self.batches.clone(), host.to_string(), self.window);
+            tokio::spawn(async move {
+                tokio::time::sleep(window).await;
+                let batch = batches.lock().remove(&host).unwrap_or_default();
+                futures_util::future::join_all(batch.into_iter().map(|(item, tx)| {
+                    let r = send(item);
+                    async move {
+                        let _ = tx.send(r.await);
+                    }
+                }))
+                .await;
+            });
+        }
+        rx.await.ok()
+    }
+}
+
+/// Parses a TLS version such as `1.2`.
+pub fn tls_version(s: &str) -> Option<reqwest::tls::Version> {
+    match s {
+        "1.0" => Some(reqwest::tls::Version::TLS_1_0),
+        "1.1" => Some(reqwest::tls::Version::TLS_1_1),
+        "1.2" => Some(reqwest::tls::Version::TLS_1_2),
+        "1.3" => Some(reqwest::tls::Version::TLS_1_3),
+        _ => None,
+    }
+}
+
+/// Identity the NIP-42 `AUTH` of a relay is answered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayIdentity {
+    /// The bot account.
+    Bot,
+    /// The author of each event published.
+    Author,
+}
+
+impl FromStr for RelayIdentity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bot" => Ok(Self::Bot),
+            "author" => Ok(Self::Author),
+            s => Err(format!("unknown identity: {s}")),
+        }
+    }
+}
+
+/// Parses a comma-separated list of `url=identity`, returning the valid
+/// entries and an error message for each invalid one.
+pub fn relay_auth(s: &str) -> (FxHashMap<&str, RelayIdentity>, Vec<String>) {
+    let mut errors = Vec::new();
+    let identities = s
+        .split(',')
+        .filter(|a| !a.is_empty())
+        .filter_map(|a| {
+            let parsed = a
+                .rsplit_once('=')
+                .ok_or_else(|| "expected url=identity".to_string())
+                .and_then(|(u, i)| Ok((u, i.parse()?)));
+            parsed.map_err(|e| errors.push(format!("{a}: {e}"))).ok()
+        })
+        .collect();
+    (identities, errors)
+}
+
+/// Normalizes a handle such as `@User@Instance.COM` or `acct:user@host` to
+/// `User@instance.com`: the host is lowercased, punycode-encoded and stripped
+/// of trailing dots. The user part is kept as is.
+pub fn normalize_handle(handle: &str) -> Option<String> {
+    let handle = handle.strip_prefix("acct:").unwrap_or(handle);
+    let handle = handle.strip_prefix('@').unwrap_or(handle);
+    let (user, host) = handle.rsplit_once('@')?;
+    if user.is_empty() {
+        return None;
+    }
+    match url::Host::parse(host.trim_end_matches('.')).ok()? {
+        url::Host::Domain(host) => Some(format!("{user}@{host}")),
+        _ => None,
+    }
+}
+
+/// Whether `host` is `pattern`, where `*.example.com` matches `example.com`
+/// and its subdomains.
+fn domain_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => {
+            host.eq_ignore_ascii_case(domain)
+                || host.len() > domain.len()
+                    && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain)
+                    && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+        }
+        None => host.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// Whether to federate with `host`: it matches none of `blocked` and, if
+/// `allowed` is set, one of `allowed`.
+pub fn is_federated(host: &str, blocked: &[&str], allowed: Option<&[&str]>) -> bool {
+    let host = host.trim_end_matches('.');
+    !blocked.iter().any(|p| domain_matches(p, host))
+        && allowed.is_none_or(|a| a.iter().any(|p| domain_matches(p, host)))
+}
+
+/// How much invisible unicode to strip from bridged content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnicodeSanitization {
+    Off,
+    /// Bidi overrides, embeddings and isolates (U+202A–202E, U+2066–2069),
+    /// which can reorder the following text. Bidi marks are kept as they are
+    /// needed to write mixed RTL and LTR text.
+    Bidi,
+    /// Also control characters, zero-width spaces, bidi marks and blank
+    /// fillers. Zero-width joiners and variation selectors are kept for emoji.
+    Strict,
+}
+
+impl FromStr for UnicodeSanitization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "bidi" => Ok(Self::Bidi),
+            "strict" => Ok(Self::Strict),
+            s => Err(format!("unknown unicode sanitization: {s}")),
+        }
+    }
+}
+
+fn is_dangerous(c: char, level: UnicodeSanitization) -> bool {
+    let bidi = matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}');
+    match level {
+        UnicodeSanitization::Off => false,
+        UnicodeSanitization::Bidi => bidi,
+        UnicodeSanitization::Strict => {
+            let control = c.is_control() && !matches!(c, '\n' | '\r' | '\t');
+            let invisible = matches!(
+                c,
+                '\u{200B}' | '\u{200E}' | '\u{200F}' | '\u{061C}' | '\u{180E}' | '\u{2060}'
+                    ..='\u{2064}' | '\u{FEFF}' | '\u{115F}' | '\u{1160}' | '\u{3164}' | '\u{FFA0}'
+            );
+            bidi || control || invisible
+        }
+    }
+}
+
+/// Strips invisible characters which can be used to spoof content.
+pub fn sanitize_unicode(s: &str, level: UnicodeSanitization) -> Cow<'_, str> {
+    if s.chars().any(|c| is_dangerous(c, level)) {
+        Cow::Owned(s.chars().filter(|c| !is_dangerous(*c, level)).collect())
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_federated, normalize_handle, relay_auth, sanitize_unicode, tls_version, CircuitBreaker,
+        HostBatcher, HostLimiter, InFlight, RateLimiter, RelayIdentity, UnicodeSanitization,
+    };
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn federation_policy() {
+        let blocked = ["*.bad.example", "spam.example"];
+        assert!(is_federated("good.example", &blocked, None));
+        assert!(!is_federated("bad.example", &blocked, None));
+        assert!(!is_federated("a.b.BAD.example", &blocked, None));
+        assert!(is_federated("notbad.example", &blocked, None));
+        assert!(!is_federated("spam.example.", &blocked, None));
+        assert!(is_federated("a.spam.example", &blocked, None));
+        let allowed = ["*.friends.example"];
+        assert!(is_federated("a.friends.example", &blocked, Some(&allowed)));
+        assert!(!is_federated("good.example", &blocked, Some(&allowed)));
+    }
+
+    #[tokio::test]
+    async fn host_limiter_1() {
+        let l = HostLimiter::new(2);
+        let _a1 = l.acquire("a.example.com").await;
+        let a2 = l.acquire("a.example.com").await;
+        let timeout = Duration::from_millis(50);
+        assert!(tokio::time::timeout(timeout, l.acquire("a.example.com"))
+            .await
+            .is_err());
+        assert!(tokio::time::timeout(timeout, l.acquire("b.example.com"))
+            .await
+            .is_ok());
+        drop(a2);
+        assert!(tokio::time::timeout(timeout, l.acquire("a.example.com"))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn in_flight_1() {
+        let in_flight = InFlight::default();
+        let runs = std::sync::atomic::AtomicU32::new(0);
+        let f = || async {
+            runs.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            7
+        };
+        let (a, b, c) = tokio::join!(
+            in_flight.run("a", f()),
+            in_flight.run("a", f()),
+            in_flight.run("b", f()),
+        );
+        assert_eq!((a, b, c), (7, 7, 7));
+        assert_eq!(runs.load(std::sync::atomic::Ordering::Relaxed), 2);
+        assert!(in_flight.cells.lock().is_empty());
+        assert_eq!(in_flight.run("a", f()).await, 7);
+        assert_eq!(runs.load(std::sync::atomic::Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn rate_limiter_1() {
+        let l = RateLimiter::new(60);
+        let start = std::time::Instant::now();
+        for _ in 0..60 {
+            assert_eq!(l.check("https://example.com/users/bot", start), Ok(()));
+        }
+        assert_eq!(l.check("https://example.com/users/bot", start), Err(1));
+        assert_eq!(l.check("https://example.com/users/a", start), Ok(()));
+        let later = start + Duration::from_secs(2);
+        assert_eq!(l.check("https://example.com/users/bot", later), Ok(()));
+        assert_eq!(l.check("https://example.com/users/bot", later), Ok(()));
+        assert_eq!(l.check("https://example.com/users/bot", later), Err(1));
+        assert_eq!(RateLimiter::new(0).check("a", start), Ok(()));
+    }
+
+    #[test]
+    fn circuit_breaker_1() {
+        let b = CircuitBreaker::new(3, Duration::from_secs(600));
+        for _ in 0..2 {
+            b.record("a.example.com", false, 100);
+        }
+        assert_eq!(b.open_for("a.example.com", 100), None);
+        b.record("a.example.com", false, 100);
+        assert_eq!(b.open_for("a.example.com", 160), Some(540));
+        assert_eq!(b.open_for("b.example.com", 160), None);
+        assert_eq!(b.open_for("a.example.com", 700), None);
+        b.record("a.example.com", false, 700);
+        assert_eq!(b.open_for("a.example.com", 700), Some(600));
+        b.record("a.example.com", true, 1_300);
+        assert_eq!(b.open_for("a.example.com", 1_300), None);
+        b.record("a.example.com", false, 1_300);
+        assert_eq!(b.open_for("a.example.com", 1_300), None);
+    }
+
+    #[tokio::test]
+    async fn host_batcher_1() {
+        let b = HostBatcher::new(Duration::from_millis(50));
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let in_flight = Arc::new(Mutex::new((0, 0)));
+        let send = {
+            let (sent, in_flight) = (sent.clone(), in_flight.clone());
+            move |(host, n): (&'static str, i32)| {
+                let (sent, in_flight) = (sent.clone(), in_flight.clone());
+                async move {
+                    {
+                        let mut f = in_flight.lock();
+                        f.0 += 1;
+                        f.1 = f.1.max(f.0);
+                    }
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    sent.lock().push((host, n));
+                    in_flight.lock().0 -= 1;
+                    n * 10
+                }
+            }
+        };
+        let start = tokio::time::Instant::now();
+        let (r1, r2, r3) = tokio::join!(
+            b.send("a.example.com", ("a.example.com", 1), send.clone()),
+            b.send("a.example.com", ("a.example.com", 2), send.clone()),
+            b.send("a.example.com", ("a.example.com", 3), send.clone()),
+        );
+        assert_eq!((r1, r2, r3), (Some(10), Some(20), Some(30)));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(
+            in_flight.lock().1,
+            3,
+            "batched requests are sent concurrently"
+        );
+        assert_eq!(sent.lock().len(), 3);
+        // the batch is sent even if the first caller is cancelled
+        let first = b.send("a.example.com", ("a.example.com", 4), send.clone());
+        let _ = tokio::time::timeout(Duration::from_millis(1), first).await;
+        assert_eq!(
+            b.send("a.example.com", ("a.example.com", 5), send.clone())
+                .await,
+            Some(50)
+        );
+        assert_eq!(sent.lock().len(), 5);
+        let unbatched = HostBatcher::new(Duration::ZERO);
+        let start = tokio::time::Instant::now();
+        assert_eq!(
+            unbatched
+                .send("a.example.com", ("a.example.com", 6), send)
+                .await,
+            Some(60)
+        );
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn tls_version_1() {
+        assert_eq!(tls_version("1.2"), Some(reqwest::tls::Version::TLS_1_2));
+        assert_eq!(tls_version("1.3"), Some(reqwest::tls::Version::TLS_1_3));
+        assert_eq!(tls_version("TLS1.2"), None);
+    }
+
+    #[test]
+    fn relay_auth_1() {
+        let (identities, errors) = relay_auth(
+            "wss://a.example=bot,wss://b.example=author,wss://c.example=admin,wss://d.example",
+        );
+        assert_eq!(identities.len(), 2);
+        assert_eq!(identities["wss://a.example"], RelayIdentity::Bot);
+        assert_eq!(identities["wss://b.example"], RelayIdentity::Author);
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("wss://c.example=admin"));
+        assert!(relay_auth("").1.is_empty());
+    }
+
+    #[test]
+    fn normalize_handle_1() {
+        let a = normalize_handle("@User@Instance.COM").unwrap();
+        assert_eq!(a, "User@instance.com");
+        assert_eq!(normalize_handle("acct:User@instance.com.").unwrap(), a);
+        assert_eq!(normalize_handle("User@INSTANCE.com").unwrap(), a);
+        assert_eq!(
+            normalize_handle("a@Bücher.example").unwrap(),
+            "a@xn--bcher-kva.example"
+        );
+        assert_eq!(normalize_handle("@instance.com"), None);
+        assert_eq!(normalize_handle("a"), None);
+    }
+
+    #[test]
+    fn sanitize_rtl_override() {
+        let s = "invoice_\u{202E}fdp.exe";
+        assert_eq!(
+            sanitize_unicode(s, UnicodeSanitization::Bidi),
+            "invoice_fdp.exe"
+        );
+        assert_eq!(sanitize_unicode(s, UnicodeSanitization::Off), s);
+        let s = "a\u{200B}b \u{1F469}\u{200D}\u{1F4BB} \u{2764}\u{FE0F} e\u{0301}\u{7}\n";
+        assert_eq!(sanitize_unicode(s, UnicodeSanitization::Bidi), s);
+        assert_eq!(
+            sanitize_unicode(s, UnicodeSanitization::Strict),
+            "ab \u{1F469}\u{200D}\u{1F4BB} \u{2764}\u{FE0F} e\u{0301}\n"
+        );
+        let s = "\u{5E9}\u{5DC}\u{5D5}\u{5DD} (1)\u{200F} \u{2067}abc\u{2069}\u{200E}";
+        assert_eq!(
+            sanitize_unicode(s, UnicodeSanitization::Bidi),
+            "\u{5E9}\u{5DC}\u{5D5}\u{5DD} (1)\u{200F} abc\u{200E}"
+        );
+    }
+}